@@ -5,11 +5,14 @@ extern crate conrod;
 #[macro_use]
 extern crate gfx;
 extern crate camera_controllers;
+extern crate shared;
 
 mod scene;
 mod graphics;
+mod net;
 
 use std::thread;
+use std::sync::mpsc::channel;
 use std::path::Path;
 use std::fmt;
 
@@ -34,16 +37,37 @@ pub struct Client {
 
 impl Client {
     pub fn new() -> Client {
+        Client::with_address(String::new())
+    }
+
+    /// Starts the client with `address` prefilled as the server to join
+    pub fn with_address(address: String) -> Client {
         let thread = thread::Builder::new().name("Client".to_string()).spawn(move || {
             let window: PistonWindow =
             WindowSettings::new(
                     "Rust RPG".to_string(), [1024, 768]
                     ).samples(2).into();
 
+            // Every network session sends what it receives through here
+            let (net_tx, net_rx) = channel();
+
             let mut scenes : Vec<Box<Scene>> = Vec::with_capacity(8);
-            scenes.push(Box::new(scene::MainMenu::new(&window)));
+            scenes.push(Box::new(scene::MainMenu::new(&window, net_tx, address)));
             for event in window {
-                let post_action;
+                let mut post_action;
+                let mut net_action = SceneModifier::Nothing;
+
+                // Every scene on the stack gets to see server messages, not
+                // just the one on top
+                while let Ok(message) = net_rx.try_recv() {
+                    for scene in scenes.iter_mut() {
+                        match scene.on_message(&event, &message) {
+                            SceneModifier::Nothing => (),
+                            action => net_action = action
+                        }
+                    }
+                }
+
                 {
                     let mut scenes = scenes.as_mut_slice();
 
@@ -58,6 +82,10 @@ impl Client {
                     }
                 }
 
+                if let SceneModifier::Nothing = post_action {
+                    post_action = net_action;
+                }
+
                 match post_action {
                     SceneModifier::Quit => break, // TODO: Graceful shutdown
                     SceneModifier::Push(mut sc) => {
@@ -0,0 +1,334 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
+
+use shared::net::{receive_packet, receive_packet_with, send_packet, send_packet_with};
+use shared::net::{Fragmenter, PacketError, Reassembler, ReassemblyLimits, MAX_PACKET_SIZE};
+use shared::net::fragment::MAX_MESSAGE_SIZE;
+use shared::net::secure::{fingerprint, Cipher, Handshake, KnownServers, Pin, SessionKeys};
+use shared::packets::{Packet, PROTOCOL_VERSION};
+
+/// Where the identities of the servers we talked to are remembered
+pub const KNOWN_SERVERS_PATH: &'static str = "known_servers";
+
+/// How long the server may take to answer during the handshake
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 10 * 1000;
+
+/// What the network thread hands to the scene stack
+pub enum ServerMessage {
+    /// The handshake is done and the login is sent, packets may be sent
+    Connected,
+    /// A decoded packet sent by the server
+    Packet(Packet),
+    /// The connection is gone, with a human readable reason
+    Disconnected(String),
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// Could not reach the server at all
+    Connect(io::Error),
+    /// The connection broke during the handshake
+    Packet(PacketError),
+    /// The server did not want us
+    Rejected(String),
+    /// The server answered the handshake with something unexpected
+    Unexpected,
+    /// The server has another identity than the last time, as fingerprints
+    KeyChanged {
+        server: String,
+        pinned: String,
+        offered: String,
+    },
+    /// Could not remember the identity of a new server
+    KnownServers(io::Error),
+}
+
+impl Error for SessionError {
+    fn description(&self) -> &str {
+        match *self {
+            SessionError::Connect(..) => "Could not connect to the server.",
+            SessionError::Packet(..) => "The connection broke during the handshake.",
+            SessionError::Rejected(..) => "The server rejected us.",
+            SessionError::Unexpected => "The server sent an unexpected packet.",
+            SessionError::KeyChanged { .. } => "The server is not the one it was before.",
+            SessionError::KnownServers(..) => "Could not remember the server.",
+        }
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SessionError::Connect(ref e) => write!(fmt, "Could not connect: {}", e),
+            SessionError::Packet(ref e) => write!(fmt, "Handshake failed: {}", e),
+            SessionError::Rejected(ref r) => write!(fmt, "Rejected by the server: {}", r),
+            SessionError::KeyChanged { ref server, ref pinned, ref offered } => {
+                write!(fmt, "The server at {} identifies with another key than before, someone \
+                             may be listening in. It used to be {}, now it is {}. If the server \
+                             got a new key on purpose, remove its line from '{}'.",
+                       server, pinned, offered, KNOWN_SERVERS_PATH)
+            }
+            SessionError::KnownServers(ref e) => {
+                write!(fmt, "Could not remember the server: {}", e)
+            }
+            ref e => e.description().fmt(fmt)
+        }
+    }
+}
+
+impl From<PacketError> for SessionError {
+    fn from(err: PacketError) -> SessionError {
+        SessionError::Packet(err)
+    }
+}
+
+/// A connection to a server
+///
+/// Everything happens on a thread named "Network": it connects, does the
+/// handshake, logs in and says so with a `ServerMessage::Connected`. After
+/// that it reads every packet the server sends and forwards it as a
+/// `ServerMessage` to the given channel. Pings are answered right there,
+/// so a slow frame does not look like a dead connection. If anything fails
+/// the thread ends with a `ServerMessage::Disconnected`.
+///
+/// Packets too large for a frame are sent and received in fragments, and
+/// frames are compressed if the server says so in its `Welcome`. Every
+/// frame after the handshake is encrypted, with a server whose identity is
+/// the one it had the first time.
+pub struct Session {
+    /// Filled in by the network thread once it is connected, shared with
+    /// it for the pongs
+    writer: Arc<Mutex<Option<Writer>>>,
+    fragmenter: Fragmenter,
+    /// Tells the network thread to give up on the connection
+    closed: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+
+/// The sending half of the connection
+struct Writer {
+    stream: TcpStream,
+    cipher: Cipher,
+    /// Payloads of at least this many bytes get compressed, if the server
+    /// asked for compression
+    compression: Option<usize>,
+}
+
+/// Connects, does the handshake and logs in
+///
+/// Returns the connection with the cipher the server seals its frames with.
+fn establish(address: &str, name: String, password: String, register: bool,
+             known: &Mutex<KnownServers>) -> Result<(Writer, Cipher), SessionError> {
+    let mut stream = match TcpStream::connect(address) {
+        Ok(s) => s,
+        Err(e) => return Err(SessionError::Connect(e))
+    };
+    // A server that stops answering should not keep us waiting forever
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS))) {
+        return Err(SessionError::Connect(e));
+    }
+
+    try!(send_packet(&mut stream, &Packet::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_build: env!("CARGO_PKG_VERSION").to_string(),
+        compression: true,
+    }));
+
+    let compression = match try!(receive_packet(&mut stream)) {
+        Packet::Welcome { compression } => compression.map(|t| t as usize),
+        Packet::Rejected { reason } => return Err(SessionError::Rejected(reason)),
+        _ => return Err(SessionError::Unexpected)
+    };
+
+    let handshake = try!(Handshake::new().map_err(PacketError::from));
+    try!(send_packet_with(&mut stream, &handshake.packet(), MAX_PACKET_SIZE, compression,
+                          None));
+    let (identity, keys) = try!(finish_exchange(&mut stream, handshake, compression.is_some()));
+    match known.lock().unwrap().check(address, &identity) {
+        Ok(Pin::New) => {
+            println!("First time at {}, trusting its key {}", address, fingerprint(&identity));
+        }
+        Ok(Pin::Known) => (),
+        Ok(Pin::Changed(pinned)) => {
+            return Err(SessionError::KeyChanged {
+                server: address.to_string(),
+                pinned: fingerprint(&pinned),
+                offered: fingerprint(&identity),
+            });
+        }
+        Err(e) => return Err(SessionError::KnownServers(e))
+    }
+    let (mut send_cipher, receive_cipher) = (keys.send, keys.receive);
+
+    try!(send_packet_with(&mut stream, &if register {
+        Packet::Register { name: name, password: password }
+    } else {
+        Packet::AuthPlayer { name: name, password: password }
+    }, MAX_PACKET_SIZE, compression, Some(&mut send_cipher)));
+
+    // From now on the server only has to ping us every now and then
+    if let Err(e) = stream.set_read_timeout(None) {
+        return Err(SessionError::Connect(e));
+    }
+    Ok((Writer {
+        stream: stream,
+        cipher: send_cipher,
+        compression: compression,
+    }, receive_cipher))
+}
+
+/// Reads packets until the connection breaks, answering pings right away
+fn read_packets(mut stream: TcpStream, mut cipher: Cipher, compression: Option<usize>,
+                writer: &Mutex<Option<Writer>>, tx: &Sender<ServerMessage>) {
+    let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+    loop {
+        match reassembler.receive(&mut stream, MAX_PACKET_SIZE, compression.is_some(),
+                                  Some(&mut cipher)) {
+            Ok(Packet::Ping(value)) => {
+                if let Some(ref mut writer) = *writer.lock().unwrap() {
+                    let _ = send_packet_with(&mut writer.stream, &Packet::Pong(value),
+                                             MAX_PACKET_SIZE, compression,
+                                             Some(&mut writer.cipher));
+                }
+            }
+            Ok(p) => {
+                if tx.send(ServerMessage::Packet(p)).is_err() {
+                    // Nobody is listening anymore
+                    return;
+                }
+            }
+            Err(e) => {
+                let _ = tx.send(ServerMessage::Disconnected(format!("{}", e)));
+                return;
+            }
+        }
+    }
+}
+
+/// Waits for the answer to our `KeyExchange`, returns the identity of the
+/// server and the keys of the session
+fn finish_exchange(stream: &mut TcpStream, handshake: Handshake, compressed: bool)
+    -> Result<(Vec<u8>, SessionKeys), SessionError> {
+    loop {
+        match try!(receive_packet_with(stream, MAX_PACKET_SIZE, compressed, None)) {
+            Packet::KeyExchangeReply { identity, key, signature } => {
+                let keys = try!(handshake.finish(&identity, &key, &signature));
+                return Ok((identity, keys));
+            }
+            // The server may have pinged before it got to our packet
+            Packet::Ping(..) => (),
+            Packet::Rejected { reason } => return Err(SessionError::Rejected(reason)),
+            _ => return Err(SessionError::Unexpected)
+        }
+    }
+}
+
+impl Session {
+    /// Starts connecting and logging in, creating the account first if
+    /// `register`
+    ///
+    /// Returns right away, the network thread reports through `tx`. The
+    /// identity of the server is checked against `known`, and remembered
+    /// there if the server is new. Whether the server accepted the
+    /// credentials arrives later as an `AuthResult`.
+    pub fn connect(address: &str, name: &str, password: &str, register: bool,
+                   known: Arc<Mutex<KnownServers>>, tx: Sender<ServerMessage>)
+        -> Result<Session, SessionError> {
+        let writer = Arc::new(Mutex::new(None));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let (address, name, password) = (address.to_string(), name.to_string(),
+                                         password.to_string());
+        let (shared_writer, given_up) = (writer.clone(), closed.clone());
+        let spawned = Builder::new().name("Network".to_string()).spawn(move|| {
+            let established = establish(&address, name, password, register, &known)
+                .and_then(|(connection, cipher)| match connection.stream.try_clone() {
+                    Ok(stream) => Ok((connection, stream, cipher)),
+                    Err(e) => Err(SessionError::Connect(e))
+                });
+            let (connection, stream, cipher) = match established {
+                Ok(established) => established,
+                Err(e) => {
+                    // Nobody waits for this session anymore
+                    if !given_up.load(Ordering::SeqCst) {
+                        let _ = tx.send(ServerMessage::Disconnected(format!("{}", e)));
+                    }
+                    return;
+                }
+            };
+            let compression = connection.compression;
+            {
+                let mut writer = shared_writer.lock().unwrap();
+                if given_up.load(Ordering::SeqCst) {
+                    let _ = connection.stream.shutdown(Shutdown::Both);
+                    return;
+                }
+                *writer = Some(connection);
+            }
+            if tx.send(ServerMessage::Connected).is_err() {
+                return;
+            }
+            read_packets(stream, cipher, compression, &shared_writer, &tx);
+        });
+        let reader = match spawned {
+            Ok(reader) => reader,
+            Err(e) => return Err(SessionError::Connect(e))
+        };
+
+        Ok(Session {
+            writer: writer,
+            fragmenter: Fragmenter::new(MAX_PACKET_SIZE, MAX_MESSAGE_SIZE),
+            closed: closed,
+            reader: Some(reader),
+        })
+    }
+
+    /// Sends a `Packet` to the server, fails until `Connected` arrived
+    pub fn send(&mut self, packet: &Packet) -> Result<(), PacketError> {
+        match *self.writer.lock().unwrap() {
+            Some(ref mut writer) => {
+                self.fragmenter.send(&mut writer.stream, packet, writer.compression,
+                                     Some(&mut writer.cipher))
+            }
+            None => Err(PacketError::IoError(io::Error::new(io::ErrorKind::NotConnected,
+                                                            "Not connected yet")))
+        }
+    }
+
+    /// Closes the connection and waits for the network thread to end
+    ///
+    /// A thread that is still connecting is left to give up on its own,
+    /// which can take until the handshake times out.
+    pub fn disconnect(&mut self) {
+        let connected = {
+            let writer = self.writer.lock().unwrap();
+            self.closed.store(true, Ordering::SeqCst);
+            match *writer {
+                Some(ref writer) => {
+                    let _ = writer.stream.shutdown(Shutdown::Both);
+                    true
+                }
+                None => false
+            }
+        };
+        match self.reader.take() {
+            Some(reader) if connected => {
+                let _ = reader.join();
+            }
+            _ => ()
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
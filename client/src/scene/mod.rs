@@ -1,8 +1,11 @@
+use std::cmp;
 use std::fmt;
 use std::path::Path;
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::net::TcpStream;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
 
 use gfx::ClearData;
 use gfx::extra::stream::Stream;
@@ -17,6 +20,11 @@ use piston_window::GenericEvent;
 
 use conrod::*;
 
+use shared::packets::{Packet, AuthResult, ChatChannel, PlayerListEntry};
+
+use net::{Session, ServerMessage, KNOWN_SERVERS_PATH};
+use shared::net::secure::KnownServers;
+
 pub type SceneId    = usize;
 
 pub trait Scene {
@@ -27,6 +35,12 @@ pub trait Scene {
     fn on_enter(&mut self, &PistonWindow) { }
 
     fn on_leave(&mut self, &PistonWindow) { }
+
+    /// Called for every message coming from the network session, on every
+    /// `Scene` of the stack
+    fn on_message(&mut self, &PistonWindow, &ServerMessage) -> SceneModifier {
+        SceneModifier::Nothing
+    }
 }
 
 /// Return value of a Scene Tick
@@ -47,10 +61,12 @@ pub struct MainMenu {
     ui: Rc<RefCell<Ui<Glyphs>>>,
     should_quit: Rc<RefCell<bool>>,
     should_start: Rc<RefCell<bool>>,
+    network: Sender<ServerMessage>,
+    address: String,
 }
 
 impl MainMenu {
-    pub fn new(window: &PistonWindow) -> MainMenu {
+    pub fn new(window: &PistonWindow, network: Sender<ServerMessage>, address: String) -> MainMenu {
         let path = Path::new("assets/ShareTechMono-Regular.ttf");
         let glyph_cache = Glyphs::new(&path, window.factory.borrow().clone()).unwrap();
         let mut ui = Ui::new(glyph_cache, Theme::default());
@@ -61,6 +77,8 @@ impl MainMenu {
             ui: Rc::new(RefCell::new(ui)),
             should_quit: Rc::new(RefCell::new(false)),
             should_start: Rc::new(RefCell::new(false)),
+            network: network,
+            address: address,
         }
     }
 }
@@ -77,7 +95,7 @@ impl Scene for MainMenu {
 
         if *self.should_start.borrow() {
             *self.should_start.borrow_mut() = false;
-            return SceneModifier::Push(Box::new(GameMenu::new(window)));
+            return SceneModifier::Push(Box::new(GameMenu::new(window, self.network.clone(), self.address.clone())));
         }
 
         SceneModifier::Nothing
@@ -106,25 +124,110 @@ impl Scene for MainMenu {
     fn get_id(&self) -> usize { 0 }
 }
 
+/// How many chat lines are kept around
+const CHAT_HISTORY: usize = 50;
+
+/// How many of them the chat overlay shows
+const CHAT_LINES: usize = 12;
+
+/// The chat lines received so far, newest last
+pub type ChatLog = Rc<RefCell<VecDeque<String>>>;
+
+fn log_chat(log: &ChatLog, line: String) {
+    let mut log = log.borrow_mut();
+    if log.len() == CHAT_HISTORY {
+        log.pop_front();
+    }
+    log.push_back(line);
+}
+
+/// How a chat message is shown in the log
+fn format_chat(channel: &ChatChannel, from: &str, message: &str) -> String {
+    match *channel {
+        ChatChannel::Global => format!("<{}> {}", from, message),
+        ChatChannel::Local => format!("[Local] <{}> {}", from, message),
+        ChatChannel::Party => format!("[Party] <{}> {}", from, message),
+        ChatChannel::Whisper(ref to) => format!("[{} -> {}] {}", from, to, message),
+        ChatChannel::Server => format!("[Server] {}", message),
+        ChatChannel::Emote => format!("* {} {}", from, message),
+    }
+}
+
+/// How a line of the player list is shown in the log
+fn format_player_list_entry(entry: &PlayerListEntry) -> String {
+    match entry.ping_ms {
+        Some(ping) => format!("  {} ({}ms)", entry.name, ping),
+        None => format!("  {} (?ms)", entry.name),
+    }
+}
+
+/// Turns a line typed into the chat into the packet to send
+///
+/// Lines go to everyone, unless they start with one of:
+///
+/// - `/l <message>` for players close by
+/// - `/p <message>` for the party
+/// - `/w <name> <message>` for a single player
+/// - `/party <name>` to join a party, `/leave` to leave it
+///
+/// Any other slash command is left for the server to run.
+fn parse_chat_input(line: &str) -> Result<Option<Packet>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.starts_with("/") {
+        return Ok(Some(chat(ChatChannel::Global, line)));
+    }
+
+    let (command, rest) = match line.find(' ') {
+        Some(idx) => (&line[..idx], line[idx..].trim()),
+        None => (line, "")
+    };
+    let packet = match command {
+        "/l" => chat(ChatChannel::Local, rest),
+        "/p" => chat(ChatChannel::Party, rest),
+        "/w" => match rest.find(' ') {
+            Some(idx) => chat(ChatChannel::Whisper(rest[..idx].to_string()), rest[idx..].trim()),
+            None => return Err("Usage: /w <name> <message>".to_string())
+        },
+        "/party" if !rest.is_empty() => Packet::SetParty(Some(rest.to_string())),
+        "/party" => return Err("Usage: /party <name>".to_string()),
+        "/leave" => Packet::SetParty(None),
+        _ => Packet::Command(line[1..].to_string())
+    };
+    Ok(Some(packet))
+}
+
+fn chat(channel: ChatChannel, message: &str) -> Packet {
+    Packet::SendChat {
+        channel: channel,
+        message: message.to_string(),
+    }
+}
+
 pub struct GameTest {
     ui: Rc<RefCell<Ui<Glyphs>>>,
     should_quit: Rc<RefCell<bool>>,
-    connection: TcpStream
+    session: Rc<RefCell<Session>>,
+    player_id: Option<u64>,
+    players: HashMap<u64, String>,
+    chat_log: ChatLog,
 }
 
 impl GameTest {
-    pub fn new(window: &PistonWindow, address: &str) -> GameTest {
+    pub fn new(window: &PistonWindow, session: Session) -> GameTest {
         let path = Path::new("assets/ShareTechMono-Regular.ttf");
         let glyph_cache = Glyphs::new(&path, window.factory.borrow().clone()).unwrap();
         let mut ui = Ui::new(glyph_cache, Theme::default());
 
-        println!("Trying to connect to: {}", address);
-        let stream = TcpStream::connect(address).unwrap();
-
         GameTest {
             ui: Rc::new(RefCell::new(ui)),
             should_quit: Rc::new(RefCell::new(false)),
-            connection: stream
+            session: Rc::new(RefCell::new(session)),
+            player_id: None,
+            players: HashMap::new(),
+            chat_log: Rc::new(RefCell::new(VecDeque::with_capacity(CHAT_HISTORY))),
         }
     }
 }
@@ -136,8 +239,20 @@ impl Scene for GameTest {
         let mut ui = self.ui.borrow_mut();
         ui.handle_event(window);
 
-        if let Some(Button::Keyboard(Key::Escape)) = window.press_args() {
-            return SceneModifier::Push(Box::new(IngameMenu::new(window)));
+        match window.press_args() {
+            Some(Button::Keyboard(Key::Escape)) => {
+                return SceneModifier::Push(Box::new(IngameMenu::new(window)));
+            }
+            Some(Button::Keyboard(Key::Return)) | Some(Button::Keyboard(Key::T)) => {
+                return SceneModifier::Push(Box::new(ChatOverlay::new(window, self.session.clone(),
+                                                                     self.chat_log.clone())));
+            }
+            Some(Button::Keyboard(Key::Tab)) => {
+                if let Err(e) = self.session.borrow_mut().send(&Packet::RequestPlayerList) {
+                    println!("Could not ask for the player list: {}", e);
+                }
+            }
+            _ => ()
         }
         SceneModifier::Nothing
     }
@@ -165,6 +280,156 @@ impl Scene for GameTest {
         println!("Left");
         window.clone().set_capture_cursor(false);
     }
+
+    fn on_message(&mut self, window: &PistonWindow, message: &ServerMessage) -> SceneModifier {
+        let packet = match *message {
+            ServerMessage::Packet(ref p) => p,
+            ServerMessage::Disconnected(ref reason) => {
+                println!("Lost connection: {}", reason);
+                window.clone().set_capture_cursor(false);
+                return SceneModifier::PopUntil(0);
+            }
+        };
+
+        match *packet {
+            Packet::AuthResult(AuthResult::Accepted { player_id }) => {
+                self.player_id = Some(player_id);
+            }
+            Packet::AuthResult(AuthResult::Refused { ref reason }) => {
+                println!("Could not log in: {}", reason);
+                window.clone().set_capture_cursor(false);
+                return SceneModifier::PopUntil(0);
+            }
+            Packet::WorldSnapshot { ref players } => {
+                self.players = players.iter()
+                    .map(|p| (p.player_id, p.name.clone())).collect();
+            }
+            Packet::PlayerJoined(ref info) => {
+                log_chat(&self.chat_log, format!("{} joined the game", info.name));
+                self.players.insert(info.player_id, info.name.clone());
+            }
+            Packet::PlayerLeft { player_id } => {
+                if let Some(name) = self.players.remove(&player_id) {
+                    log_chat(&self.chat_log, format!("{} left the game", name));
+                }
+            }
+            Packet::Chat { ref channel, ref from, ref message } => {
+                log_chat(&self.chat_log, format_chat(channel, from, message));
+            }
+            Packet::PlayerList(ref entries) => {
+                log_chat(&self.chat_log, format!("{} online", entries.len()));
+                for entry in entries.iter() {
+                    log_chat(&self.chat_log, format_player_list_entry(entry));
+                }
+            }
+            Packet::Kick { ref reason } => {
+                println!("Kicked: {}", reason);
+                window.clone().set_capture_cursor(false);
+                return SceneModifier::PopUntil(0);
+            }
+            _ => ()
+        }
+
+        SceneModifier::Nothing
+    }
+}
+
+/// The chat history with a line to type into, drawn over the game
+///
+/// Chat keeps arriving while the overlay is open, the `GameTest` below
+/// records it into the shared log.
+pub struct ChatOverlay {
+    ui: Rc<RefCell<Ui<Glyphs>>>,
+    session: Rc<RefCell<Session>>,
+    log: ChatLog,
+    input: Rc<RefCell<String>>,
+    should_send: Rc<RefCell<bool>>,
+}
+
+impl ChatOverlay {
+    pub fn new(window: &PistonWindow, session: Rc<RefCell<Session>>, log: ChatLog) -> ChatOverlay {
+        let path = Path::new("assets/ShareTechMono-Regular.ttf");
+        let glyph_cache = Glyphs::new(&path, window.factory.borrow().clone()).unwrap();
+        let ui = Ui::new(glyph_cache, Theme::default());
+
+        ChatOverlay {
+            ui: Rc::new(RefCell::new(ui)),
+            session: session,
+            log: log,
+            input: Rc::new(RefCell::new(String::new())),
+            should_send: Rc::new(RefCell::new(false)),
+        }
+    }
+}
+
+impl Scene for ChatOverlay {
+    fn tick(&mut self, window: &PistonWindow, other: &[Box<Scene>]) -> SceneModifier {
+        use piston_window::Button;
+
+        let mut ui = self.ui.borrow_mut();
+        ui.handle_event(window);
+
+        if *self.should_send.borrow() {
+            *self.should_send.borrow_mut() = false;
+            let line = self.input.borrow().clone();
+            match parse_chat_input(&line) {
+                Ok(Some(packet)) => {
+                    if let Err(e) = self.session.borrow_mut().send(&packet) {
+                        log_chat(&self.log, format!("Could not send: {}", e));
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => log_chat(&self.log, e)
+            }
+            self.input.borrow_mut().clear();
+            return SceneModifier::Pop;
+        }
+
+        if let Some(Button::Keyboard(Key::Escape)) = window.press_args() {
+            return SceneModifier::Pop;
+        }
+
+        SceneModifier::Nothing
+    }
+
+    fn draw(&self, window: &PistonWindow, other: &[Box<Scene>]) {
+        {
+            let len = other.len();
+            let (stack, last) = other.split_at(len-1);
+            last[0].draw(&window, stack);
+        }
+
+        window.draw_2d(|c, gl| {
+            let mut ui = self.ui.borrow_mut();
+
+            // Ids 1 and up are the lines, the newest one is at the bottom
+            let log = self.log.borrow();
+            let skip = log.len() - cmp::min(log.len(), CHAT_LINES);
+            for (idx, line) in log.iter().skip(skip).enumerate() {
+                let label = Label::new(line).font_size(16).rgb(1., 1., 1.);
+                if idx == 0 {
+                    label.top_left().set(1, &mut ui);
+                } else {
+                    label.down_from(idx, 4.0).set(idx + 1, &mut ui);
+                }
+            }
+
+            let send = self.should_send.clone();
+            TextBox::new(&mut *self.input.borrow_mut()).bottom_left().dimensions(600.0, 30.0)
+            .font_size(16)
+            .react(|_string: &mut String| {
+                *send.borrow_mut() = true;
+            }).set(0, &mut ui);
+
+            ui.draw(c, gl);
+        });
+    }
+
+    fn get_id(&self) -> usize { 4 }
+
+    fn on_enter(&mut self, window: &PistonWindow) {
+        window.clone().set_capture_cursor(false);
+    }
 }
 
 pub struct IngameMenu {
@@ -241,24 +506,69 @@ impl Scene for IngameMenu {
     fn get_id(&self) -> usize { 2 }
 }
 
+/// Shown in the password box in place of every character
+const PASSWORD_MASK: char = '*';
+
+/// Takes what was typed into the masked password box over into `password`
+///
+/// Masked characters stand for the ones of `password`, in order, anything
+/// else was just typed. A deletion always takes the last character, the
+/// box does not tell which one it was. Leaves `shown` fully masked again.
+fn unmask(password: &mut String, shown: &mut String) {
+    let mut unmasked = String::new();
+    {
+        let mut kept = password.chars();
+        for c in shown.chars() {
+            let old = if c == PASSWORD_MASK { kept.next() } else { None };
+            unmasked.push(old.unwrap_or(c));
+        }
+    }
+    *shown = unmasked.chars().map(|_| PASSWORD_MASK).collect();
+    *password = unmasked;
+}
+
 pub struct GameMenu {
     ui: Rc<RefCell<Ui<Glyphs>>>,
     address: Rc<RefCell<String>>,
+    name: Rc<RefCell<String>>,
+    password: Rc<RefCell<String>>,
+    /// What the password box shows and edits, one mask per character
+    password_shown: Rc<RefCell<String>>,
     should_go: Rc<RefCell<bool>>,
+    should_register: Rc<RefCell<bool>>,
     go_back: Rc<RefCell<bool>>,
+    network: Sender<ServerMessage>,
+    /// The identities servers had the first time we connected
+    known_servers: Arc<Mutex<KnownServers>>,
+    /// Connecting in the background, until it says `Connected`
+    pending: Option<Session>,
 }
 
 impl GameMenu {
-    pub fn new(window: &PistonWindow) -> GameMenu {
+    pub fn new(window: &PistonWindow, network: Sender<ServerMessage>, address: String) -> GameMenu {
         let path = Path::new("assets/ShareTechMono-Regular.ttf");
         let glyph_cache = Glyphs::new(&path, window.factory.borrow().clone()).unwrap();
         let mut ui = Ui::new(glyph_cache, Theme::default());
+        let known_servers = match KnownServers::open(KNOWN_SERVERS_PATH) {
+            Ok(known) => known,
+            Err(e) => {
+                println!("Could not read the known servers, every server counts as new: {}", e);
+                KnownServers::in_memory()
+            }
+        };
 
         GameMenu {
             ui: Rc::new(RefCell::new(ui)),
-            address: Rc::new(RefCell::new(String::new())),
+            address: Rc::new(RefCell::new(address)),
+            name: Rc::new(RefCell::new(String::new())),
+            password: Rc::new(RefCell::new(String::new())),
+            password_shown: Rc::new(RefCell::new(String::new())),
             should_go: Rc::new(RefCell::new(false)),
+            should_register: Rc::new(RefCell::new(false)),
             go_back: Rc::new(RefCell::new(false)),
+            network: network,
+            known_servers: Arc::new(Mutex::new(known_servers)),
+            pending: None,
         }
     }
 }
@@ -270,8 +580,22 @@ impl Scene for GameMenu {
         let mut ui = self.ui.borrow_mut();
         ui.handle_event(window);
 
-        if *self.should_go.borrow() {
-            return SceneModifier::Push(Box::new(GameTest::new(window, &self.address.borrow()[..])));
+        let register = *self.should_register.borrow();
+        if *self.should_go.borrow() || register {
+            *self.should_go.borrow_mut() = false;
+            *self.should_register.borrow_mut() = false;
+            if self.pending.is_some() {
+                println!("Still connecting, hold on");
+            } else {
+                let address = self.address.borrow();
+                println!("Trying to connect to: {}", &address[..]);
+                match Session::connect(&address[..], &self.name.borrow()[..],
+                                       &self.password.borrow()[..], register,
+                                       self.known_servers.clone(), self.network.clone()) {
+                    Ok(session) => self.pending = Some(session),
+                    Err(e) => println!("{}", e)
+                }
+            }
         }
 
         if *self.go_back.borrow() {
@@ -284,6 +608,26 @@ impl Scene for GameMenu {
 
         SceneModifier::Nothing
     }
+
+    fn on_message(&mut self, window: &PistonWindow, message: &ServerMessage) -> SceneModifier {
+        // Once in the game, the session belongs to the `GameTest`
+        if self.pending.is_none() {
+            return SceneModifier::Nothing;
+        }
+        match *message {
+            ServerMessage::Connected => {
+                let session = self.pending.take().unwrap();
+                SceneModifier::Push(Box::new(GameTest::new(window, session)))
+            }
+            ServerMessage::Disconnected(ref reason) => {
+                println!("{}", reason);
+                self.pending = None;
+                SceneModifier::Nothing
+            }
+            ServerMessage::Packet(..) => SceneModifier::Nothing
+        }
+    }
+
     fn draw(&self, window: &PistonWindow, other: &[Box<Scene>]) {
 
         {
@@ -313,6 +657,25 @@ impl Scene for GameMenu {
                 *go.borrow_mut() = true;
             }).set(2, &mut ui);
 
+            let go = self.should_go.clone();
+            TextBox::new(&mut *self.name.borrow_mut()).up_from(2, 30.0).dimensions(300.0, 40.0).font_size(20)
+            .react(|_string: &mut String| {
+                *go.borrow_mut() = true;
+            }).set(3, &mut ui);
+
+            let go = self.should_go.clone();
+            TextBox::new(&mut *self.password_shown.borrow_mut()).down_from(2, 30.0).dimensions(300.0, 40.0).font_size(20)
+            .react(|_string: &mut String| {
+                *go.borrow_mut() = true;
+            }).set(4, &mut ui);
+            unmask(&mut *self.password.borrow_mut(), &mut *self.password_shown.borrow_mut());
+
+            let reg = self.should_register.clone();
+            Button::new().right_from(1, 30.0).dimensions(100., 100.).label("Register")
+            .react(|| {
+                *reg.borrow_mut() = true;
+            }).set(5, &mut ui);
+
             ui.draw(c, gl);
         });
     }
@@ -0,0 +1,399 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crypto::scrypt::{scrypt_simple, scrypt_check, ScryptParams};
+use rustc_serialize::json;
+
+use names::{validate_name, name_key, NameError};
+use permissions::DEFAULT_ROLE;
+
+/// Passwords shorter than this are refused on registration
+pub const MIN_PASSWORD_LENGTH: usize = 6;
+
+/// Default scrypt work factor, as log2 of the iteration count
+pub const DEFAULT_WORK_FACTOR: u8 = 14;
+
+/// Checked instead of a password hash when an account does not exist
+const DUMMY_PASSWORD: &'static str = "not a password";
+
+/// A registered account, as stored on disk
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug)]
+pub struct Account {
+    pub name: String,
+    /// Salted scrypt hash in the `$rscrypt$` format
+    password_hash: String,
+    /// `DEFAULT_ROLE` if `None`, older files don't have it
+    role: Option<String>,
+    /// Nodes granted or, with a `-` in front, taken away on top of the role
+    permissions: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    /// No account with this name, or the password did not match. These
+    /// are deliberately the same, so clients can't probe for names.
+    WrongCredentials,
+    AlreadyExists,
+    /// There is no account with this name, only for admin tools
+    UnknownAccount,
+    PasswordTooShort,
+    InvalidName(NameError),
+    Io(io::Error),
+    /// The account file could not be parsed
+    Corrupt(String),
+}
+
+impl Error for AccountError {
+    fn description(&self) -> &str {
+        match *self {
+            AccountError::WrongCredentials => "Wrong name or password.",
+            AccountError::AlreadyExists => "An account with this name already exists.",
+            AccountError::UnknownAccount => "There is no account with this name.",
+            AccountError::PasswordTooShort => "The password is too short.",
+            AccountError::InvalidName(..) => "The name is not allowed.",
+            AccountError::Io(..) => "Could not access the account file.",
+            AccountError::Corrupt(..) => "The account file is corrupt.",
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AccountError::PasswordTooShort => {
+                write!(fmt, "The password needs at least {} characters.", MIN_PASSWORD_LENGTH)
+            }
+            AccountError::InvalidName(ref e) => e.fmt(fmt),
+            AccountError::Io(ref e) => write!(fmt, "Could not access the account file: {}", e),
+            AccountError::Corrupt(ref e) => write!(fmt, "The account file is corrupt: {}", e),
+            ref e => e.description().fmt(fmt)
+        }
+    }
+}
+
+impl From<io::Error> for AccountError {
+    fn from(err: io::Error) -> AccountError {
+        AccountError::Io(err)
+    }
+}
+
+/// All known accounts, optionally backed by a JSON file
+///
+/// Accounts are looked up by `name_key`, so no two accounts differ only
+/// in the case of their name.
+///
+/// Hashing is slow on purpose, so it always happens outside of the lock
+/// and callers should not use this from the tick thread.
+pub struct AccountStore {
+    path: Option<PathBuf>,
+    work_factor: u8,
+    accounts: Mutex<HashMap<String, Account>>,
+    /// Hash of `DUMMY_PASSWORD`, made on the first unknown name
+    dummy_hash: Mutex<Option<String>>,
+}
+
+impl AccountStore {
+    /// A store that forgets everything once dropped
+    pub fn in_memory() -> AccountStore {
+        AccountStore {
+            path: None,
+            work_factor: DEFAULT_WORK_FACTOR,
+            accounts: Mutex::new(HashMap::new()),
+            dummy_hash: Mutex::new(None),
+        }
+    }
+
+    /// Loads the accounts from `path`, every change is written back to it
+    ///
+    /// A missing file is treated as no accounts at all.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<AccountStore, AccountError> {
+        let path = path.as_ref().to_path_buf();
+        let mut accounts = HashMap::new();
+
+        match File::open(&path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                let list: Vec<Account> = match json::decode(&contents) {
+                    Ok(l) => l,
+                    Err(e) => return Err(AccountError::Corrupt(format!("{}", e)))
+                };
+                for account in list.into_iter() {
+                    accounts.insert(name_key(&account.name), account);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(AccountError::Io(e))
+        }
+
+        Ok(AccountStore {
+            path: Some(path),
+            work_factor: DEFAULT_WORK_FACTOR,
+            accounts: Mutex::new(accounts),
+            dummy_hash: Mutex::new(None),
+        })
+    }
+
+    /// Changes the scrypt work factor used for new passwords
+    ///
+    /// Existing hashes keep the factor they were created with.
+    pub fn set_work_factor(&mut self, log_n: u8) {
+        self.work_factor = log_n;
+        *self.dummy_hash.lock().unwrap() = None;
+    }
+
+    /// Creates a new account
+    pub fn register(&self, name: &str, password: &str) -> Result<(), AccountError> {
+        if let Err(e) = validate_name(name) {
+            return Err(AccountError::InvalidName(e));
+        }
+        if password.chars().count() < MIN_PASSWORD_LENGTH {
+            return Err(AccountError::PasswordTooShort);
+        }
+
+        let key = name_key(name);
+        if self.accounts.lock().unwrap().contains_key(&key) {
+            return Err(AccountError::AlreadyExists);
+        }
+
+        let params = ScryptParams::new(self.work_factor, 8, 1);
+        let hash = try!(scrypt_simple(password, &params));
+
+        let mut accounts = self.accounts.lock().unwrap();
+        // Someone might have been faster while we were hashing
+        if accounts.contains_key(&key) {
+            return Err(AccountError::AlreadyExists);
+        }
+        accounts.insert(key.clone(), Account {
+            name: name.to_string(),
+            password_hash: hash,
+            role: None,
+            permissions: None,
+        });
+
+        // An account that is not on disk would be gone after a restart
+        if let Err(e) = self.save(&accounts) {
+            accounts.remove(&key);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Checks the password of an account
+    ///
+    /// Returns the name as it was registered, which might differ in case
+    /// from the one given. Unknown names still cost a hash, so they can't
+    /// be told apart from wrong passwords by timing.
+    pub fn verify(&self, name: &str, password: &str) -> Result<String, AccountError> {
+        let account = self.accounts.lock().unwrap().get(&name_key(name))
+            .map(|account| (account.name.clone(), account.password_hash.clone()));
+        let (name, hash) = match account {
+            Some(account) => account,
+            None => {
+                let hash = try!(self.dummy_hash());
+                let _ = scrypt_check(password, &hash);
+                return Err(AccountError::WrongCredentials);
+            }
+        };
+
+        match scrypt_check(password, &hash) {
+            Ok(true) => Ok(name),
+            Ok(false) => Err(AccountError::WrongCredentials),
+            Err(e) => Err(AccountError::Corrupt(e.to_string()))
+        }
+    }
+
+    /// A hash with the current work factor to check unknown names against
+    fn dummy_hash(&self) -> Result<String, AccountError> {
+        let mut dummy = self.dummy_hash.lock().unwrap();
+        if dummy.is_none() {
+            let params = ScryptParams::new(self.work_factor, 8, 1);
+            *dummy = Some(try!(scrypt_simple(DUMMY_PASSWORD, &params)));
+        }
+        Ok(dummy.clone().unwrap())
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(&name_key(name))
+    }
+
+    /// The role and the permission overrides of an account
+    pub fn grants(&self, name: &str) -> Option<(String, Vec<String>)> {
+        self.accounts.lock().unwrap().get(&name_key(name)).map(|account| {
+            (account.role.clone().unwrap_or(DEFAULT_ROLE.to_string()),
+             account.permissions.clone().unwrap_or(Vec::new()))
+        })
+    }
+
+    /// Changes the role of an account
+    ///
+    /// The role is not checked, roles are up to the `ServerConfig`.
+    pub fn set_role(&self, name: &str, role: &str) -> Result<(), AccountError> {
+        self.update(name, |account| account.role = Some(role.to_string()))
+    }
+
+    /// Grants a permission node to an account, or takes it away
+    ///
+    /// With `None` the account goes back to whatever its role says.
+    pub fn set_permission(&self, name: &str, node: &str, granted: Option<bool>)
+        -> Result<(), AccountError> {
+        self.update(name, |account| {
+            let mut permissions = account.permissions.take().unwrap_or(Vec::new());
+            permissions.retain(|p| p.trim_left_matches('-') != node);
+            match granted {
+                Some(true) => permissions.push(node.to_string()),
+                Some(false) => permissions.push(format!("-{}", node)),
+                None => ()
+            }
+            account.permissions = Some(permissions);
+        })
+    }
+
+    /// Changes an account and writes it to disk
+    fn update<F>(&self, name: &str, change: F) -> Result<(), AccountError>
+        where F: FnOnce(&mut Account) {
+        let mut accounts = self.accounts.lock().unwrap();
+        match accounts.get_mut(&name_key(name)) {
+            Some(account) => change(account),
+            None => return Err(AccountError::UnknownAccount)
+        }
+        self.save(&accounts)
+    }
+
+    /// Writes all accounts to disk, if this store has a file
+    ///
+    /// The file is replaced atomically, so a crash never leaves half an
+    /// account list behind.
+    fn save(&self, accounts: &HashMap<String, Account>) -> Result<(), AccountError> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(())
+        };
+
+        let list: Vec<&Account> = accounts.values().collect();
+        let encoded = match json::encode(&list) {
+            Ok(e) => e,
+            Err(e) => return Err(AccountError::Corrupt(format!("{}", e)))
+        };
+
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(encoded.as_bytes()));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&tmp, path));
+        Ok(())
+    }
+}
+
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn store() -> AccountStore {
+        let mut store = AccountStore::in_memory();
+        store.set_work_factor(4);
+        store
+    }
+
+    #[test]
+    fn register_and_verify() {
+        let store = store();
+        store.register("Neikos", "hunter22").unwrap();
+
+        assert!(store.exists("Neikos"));
+        assert!(store.verify("Neikos", "hunter22").is_ok());
+        assert_eq!(store.verify("neikos", "hunter22").unwrap(), "Neikos");
+    }
+
+    #[test]
+    fn wrong_password() {
+        let store = store();
+        store.register("Neikos", "hunter22").unwrap();
+
+        match store.verify("Neikos", "hunter23") {
+            Err(AccountError::WrongCredentials) => (),
+            other => panic!("Expected WrongCredentials, got {:?}", other),
+        }
+        match store.verify("Nobody", "hunter22") {
+            Err(AccountError::WrongCredentials) => (),
+            other => panic!("Expected WrongCredentials, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_and_short() {
+        let store = store();
+        store.register("Neikos", "hunter22").unwrap();
+
+        match store.register("Neikos", "hunter22") {
+            Err(AccountError::AlreadyExists) => (),
+            other => panic!("Expected AlreadyExists, got {:?}", other),
+        }
+        match store.register("NEIKOS", "hunter22") {
+            Err(AccountError::AlreadyExists) => (),
+            other => panic!("Expected AlreadyExists, got {:?}", other),
+        }
+        match store.register("Other", "abc") {
+            Err(AccountError::PasswordTooShort) => (),
+            other => panic!("Expected PasswordTooShort, got {:?}", other),
+        }
+        match store.register("Admin", "hunter22") {
+            Err(AccountError::InvalidName(_)) => (),
+            other => panic!("Expected InvalidName, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn persistence() {
+        let path = env::temp_dir().join("rpg_accounts_test.json");
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut store = AccountStore::open(&path).unwrap();
+            store.set_work_factor(4);
+            store.register("Neikos", "hunter22").unwrap();
+        }
+
+        let store = AccountStore::open(&path).unwrap();
+        assert!(store.verify("Neikos", "hunter22").is_ok());
+        assert_eq!(store.grants("neikos"), Some(("player".to_string(), Vec::new())));
+
+        store.set_role("Neikos", "moderator").unwrap();
+        store.set_permission("Neikos", "command.give", Some(true)).unwrap();
+        store.set_permission("Neikos", "moderation.ban", Some(false)).unwrap();
+        store.set_permission("Neikos", "command.give", None).unwrap();
+
+        let store = AccountStore::open(&path).unwrap();
+        assert_eq!(store.grants("Neikos"), Some(("moderator".to_string(),
+                                                 vec!["-moderation.ban".to_string()])));
+        match store.set_role("Nobody", "admin") {
+            Err(AccountError::UnknownAccount) => (),
+            other => panic!("Expected UnknownAccount, got {:?}", other),
+        }
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn failed_save() {
+        // The directory does not exist, so nothing can be written
+        let path = env::temp_dir().join("rpg_accounts_missing").join("accounts.json");
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let mut store = AccountStore::open(&path).unwrap();
+        store.set_work_factor(4);
+        match store.register("Neikos", "hunter22") {
+            Err(AccountError::Io(_)) => (),
+            other => panic!("Expected Io, got {:?}", other),
+        }
+        assert!(!store.exists("Neikos"));
+    }
+}
@@ -0,0 +1,691 @@
+//! Administration of a running server
+//!
+//! Commands are parsed from plain text lines, then sent to the tick thread
+//! as a `ServerEvent::Admin`, which executes them against the `WorldState`
+//! and replies with the output.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::mpsc::{channel, Sender};
+
+use clock_ticks::precise_time_ns;
+
+use accounts::AccountStore;
+use chat;
+use commands::{Caller, CommandRegistry};
+use config::ServerConfig;
+use moderation::{self, format_duration, BanTarget};
+use netstats::format_bytes;
+use permissions;
+use player::PlayerStatus;
+use servermessage::ServerEvent;
+use worldstate::WorldState;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AdminCommand {
+    Help,
+    /// Lists everyone connected
+    List,
+    Kick {
+        name: String,
+        reason: String,
+    },
+    /// Bans an account, or an address if `by_ip` is set
+    Ban {
+        target: String,
+        by_ip: bool,
+        /// In seconds, permanent if `None`
+        duration: Option<u64>,
+        reason: String,
+    },
+    /// Lifts the ban of an account or address
+    Unban(String),
+    /// Lists the bans in effect
+    Bans,
+    Whitelist(WhitelistCommand),
+    /// Changes the role of an account
+    SetRole {
+        name: String,
+        role: String,
+    },
+    /// Grants a permission node to an account, takes it away, or with
+    /// `None` goes back to what the role says
+    Permission {
+        name: String,
+        node: String,
+        granted: Option<bool>,
+    },
+    /// Shows the role and overrides of an account
+    Permissions(String),
+    /// Broadcasts a chat message from the server
+    Say(String),
+    /// A chat command, without the slash, run as the console
+    Slash(String),
+    Status,
+    TickStats,
+    /// Shows the ping and traffic of everyone connected
+    NetStats,
+    Stop,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum WhitelistCommand {
+    On,
+    Off,
+    Add(String),
+    Remove(String),
+    List,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Empty,
+    Unknown(String),
+    MissingArgument(&'static str),
+    /// The argument with the given name could not be understood
+    InvalidArgument(&'static str, String),
+}
+
+impl Error for CommandError {
+    fn description(&self) -> &str {
+        match *self {
+            CommandError::Empty => "No command given.",
+            CommandError::Unknown(..) => "Unknown command.",
+            CommandError::MissingArgument(..) => "Missing argument.",
+            CommandError::InvalidArgument(..) => "Invalid argument.",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Unknown(ref c) => write!(fmt, "Unknown command '{}', try 'help'", c),
+            CommandError::MissingArgument(a) => write!(fmt, "Missing argument <{}>", a),
+            CommandError::InvalidArgument(a, ref v) => {
+                write!(fmt, "Invalid argument <{}>: '{}'", a, v)
+            }
+            ref e => e.description().fmt(fmt)
+        }
+    }
+}
+
+pub const HELP: &'static str = "\
+list                                List connected players
+kick <name> [reason]                Disconnect a player
+ban <name> [reason]                 Disconnect a player and keep them out
+ban-ip <name|ip> [reason]           Same, for everyone from that address
+tempban <name> <time> [reason]      Ban for a while, like 30m, 12h or 7d
+tempban-ip <name|ip> <time> [reason]
+unban <name|ip>                     Lift a ban
+bans                                List the bans in effect
+whitelist <on|off|list>             Only let whitelisted accounts in
+whitelist <add|remove> <name>       Change who is on the whitelist
+role <name> <role>                  Change the role of an account
+perm <name> <grant|deny|reset> <node>
+                                    Override a permission of an account
+perms <name>                        Show the role and permissions of an account
+say <message>                       Send a chat message to everyone
+/<command>                          Run a chat command, /help lists them
+status                              Show the server status
+tick-stats                          Show how long ticks take
+net-stats                           Show the ping and traffic of every player
+stop                                Shut the server down";
+
+/// Splits off the first word, the rest is trimmed
+pub fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    match s.find(char::is_whitespace) {
+        Some(idx) => (&s[..idx], s[idx..].trim()),
+        None => (s, "")
+    }
+}
+
+/// Parses a duration like `90s`, `30m`, `12h` or `7d` into seconds
+///
+/// Durations too long to count in seconds are `None` as well.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let unit = match s.chars().last() {
+        Some(u) => u,
+        None => return None
+    };
+    let amount: u64 = match s[..s.len() - unit.len_utf8()].parse() {
+        Ok(a) => a,
+        Err(_) => return None
+    };
+    let factor = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None
+    };
+    amount.checked_mul(factor)
+}
+
+/// Parses a single line into an `AdminCommand`
+pub fn parse_command(line: &str) -> Result<AdminCommand, CommandError> {
+    let line = line.trim();
+    if line.starts_with("/") {
+        return Ok(AdminCommand::Slash(line[1..].to_string()));
+    }
+    let (command, rest) = split_word(line);
+
+    // The first word of the arguments, with the free text after it
+    let required = |args: &str, name: &'static str| {
+        match split_word(args) {
+            ("", _) => Err(CommandError::MissingArgument(name)),
+            (word, rest) => Ok((word.to_string(), rest.to_string()))
+        }
+    };
+    let or_default = |reason: String, default: &str| {
+        if reason.is_empty() { default.to_string() } else { reason }
+    };
+
+    match command {
+        "" => Err(CommandError::Empty),
+        "help" => Ok(AdminCommand::Help),
+        "list" => Ok(AdminCommand::List),
+        "kick" => {
+            let (name, reason) = try!(required(rest, "name"));
+            Ok(AdminCommand::Kick {
+                name: name,
+                reason: or_default(reason, "Kicked by an admin"),
+            })
+        }
+        "ban" | "ban-ip" | "tempban" | "tempban-ip" => {
+            let (target, rest) = try!(required(rest, "target"));
+            let (duration, reason) = if command.starts_with("tempban") {
+                let (duration, reason) = try!(required(&rest, "time"));
+                match parse_duration(&duration) {
+                    Some(d) => (Some(d), reason),
+                    None => return Err(CommandError::InvalidArgument("time", duration))
+                }
+            } else {
+                (None, rest)
+            };
+            Ok(AdminCommand::Ban {
+                target: target,
+                by_ip: command.ends_with("-ip"),
+                duration: duration,
+                reason: or_default(reason, "Banned by an admin"),
+            })
+        }
+        "unban" => {
+            let (target, _) = try!(required(rest, "target"));
+            Ok(AdminCommand::Unban(target))
+        }
+        "bans" => Ok(AdminCommand::Bans),
+        "whitelist" => {
+            let (action, rest) = try!(required(rest, "action"));
+            let whitelist = match &action[..] {
+                "on" => WhitelistCommand::On,
+                "off" => WhitelistCommand::Off,
+                "list" => WhitelistCommand::List,
+                "add" => WhitelistCommand::Add(try!(required(&rest, "name")).0),
+                "remove" => WhitelistCommand::Remove(try!(required(&rest, "name")).0),
+                _ => return Err(CommandError::InvalidArgument("action", action))
+            };
+            Ok(AdminCommand::Whitelist(whitelist))
+        }
+        "role" => {
+            let (name, rest) = try!(required(rest, "name"));
+            let (role, _) = try!(required(&rest, "role"));
+            Ok(AdminCommand::SetRole { name: name, role: role })
+        }
+        "perm" => {
+            let (name, rest) = try!(required(rest, "name"));
+            let (action, rest) = try!(required(&rest, "action"));
+            let (node, _) = try!(required(&rest, "node"));
+            let granted = match &action[..] {
+                "grant" => Some(true),
+                "deny" => Some(false),
+                "reset" => None,
+                _ => return Err(CommandError::InvalidArgument("action", action))
+            };
+            Ok(AdminCommand::Permission { name: name, node: node, granted: granted })
+        }
+        "perms" => {
+            let (name, _) = try!(required(rest, "name"));
+            Ok(AdminCommand::Permissions(name))
+        }
+        "say" => {
+            if rest.is_empty() {
+                return Err(CommandError::MissingArgument("message"));
+            }
+            Ok(AdminCommand::Say(rest.to_string()))
+        }
+        "status" => Ok(AdminCommand::Status),
+        "tick-stats" => Ok(AdminCommand::TickStats),
+        "net-stats" => Ok(AdminCommand::NetStats),
+        "stop" => Ok(AdminCommand::Stop),
+        other => Err(CommandError::Unknown(other.to_string()))
+    }
+}
+
+/// How long the server loop spends on its ticks
+pub struct TickStats {
+    pub started_at: u64,
+    pub ticks: u64,
+    pub events: u64,
+    pub last_ns: u64,
+    pub max_ns: u64,
+    pub total_ns: u64,
+}
+
+impl TickStats {
+    pub fn new() -> TickStats {
+        TickStats {
+            started_at: precise_time_ns(),
+            ticks: 0,
+            events: 0,
+            last_ns: 0,
+            max_ns: 0,
+            total_ns: 0,
+        }
+    }
+
+    /// Records a tick that took `ns` and handled `events` events
+    pub fn record(&mut self, ns: u64, events: u64) {
+        self.ticks += 1;
+        self.events += events;
+        self.last_ns = ns;
+        self.total_ns += ns;
+        if ns > self.max_ns {
+            self.max_ns = ns;
+        }
+    }
+
+    pub fn average_ns(&self) -> u64 {
+        if self.ticks == 0 { 0 } else { self.total_ns / self.ticks }
+    }
+}
+
+/// Runs a command on the tick thread, returning what to show the admin
+///
+/// `Stop` only produces the output, ending the server loop is up to the
+/// caller.
+pub fn execute(state: &mut WorldState, command: &AdminCommand, config: &ServerConfig,
+               stats: &TickStats, commands: &CommandRegistry,
+               accounts: &AccountStore) -> String {
+    match *command {
+        AdminCommand::Help => HELP.to_string(),
+        AdminCommand::List => {
+            let mut lines = Vec::new();
+            for (id, player) in state.get_players().iter() {
+                lines.push(format!("{:>5} {}", id, player));
+            }
+            lines.sort();
+            let count = lines.len();
+            lines.insert(0, format!("{} connected", count));
+            lines.join("\n")
+        }
+        AdminCommand::Kick { ref name, ref reason } => kick(state, name, reason),
+        AdminCommand::Ban { ref target, by_ip, duration, ref reason } => {
+            ban(state, target, by_ip, duration, reason)
+        }
+        AdminCommand::Unban(ref target) => unban(state, target),
+        AdminCommand::Bans => {
+            let now = moderation::now();
+            let bans = state.get_moderation().active_bans();
+            let mut lines: Vec<String> = bans.iter().map(|ban| {
+                match ban.expires_at {
+                    Some(expiry) => format!("{} - {} ({} left)", ban.target, ban.reason,
+                                            format_duration(expiry - now)),
+                    None => format!("{} - {}", ban.target, ban.reason),
+                }
+            }).collect();
+            lines.sort();
+            lines.insert(0, format!("{} bans", bans.len()));
+            lines.join("\n")
+        }
+        AdminCommand::Whitelist(ref command) => {
+            let store = state.mut_get_moderation();
+            let result = match *command {
+                WhitelistCommand::On => {
+                    store.set_whitelist_enabled(true).map(|_| "Whitelist is on".to_string())
+                }
+                WhitelistCommand::Off => {
+                    store.set_whitelist_enabled(false).map(|_| "Whitelist is off".to_string())
+                }
+                WhitelistCommand::Add(ref name) => store.whitelist_add(name).map(|added| {
+                    if added { format!("Added {}", name) } else { format!("{} was on it already", name) }
+                }),
+                WhitelistCommand::Remove(ref name) => store.whitelist_remove(name).map(|removed| {
+                    if removed { format!("Removed {}", name) } else { format!("{} was not on it", name) }
+                }),
+                WhitelistCommand::List => {
+                    let names = store.whitelist();
+                    Ok(format!("Whitelist is {}, {} names\n{}",
+                               if store.whitelist_enabled() { "on" } else { "off" },
+                               names.len(), names.join("\n")))
+                }
+            };
+            match result {
+                Ok(output) => output,
+                Err(e) => format!("Could not change the whitelist: {}", e)
+            }
+        }
+        AdminCommand::SetRole { ref name, ref role } => {
+            if config.roles.get(role).is_none() {
+                return format!("Unknown role {}, there are {}", role,
+                               config.roles.names().join(", "));
+            }
+            match accounts.set_role(name, role) {
+                Ok(()) => {
+                    refresh_permissions(state, name, accounts, config);
+                    format!("{} is a {} now", name, role)
+                }
+                Err(e) => format!("Could not change the role of {}: {}", name, e)
+            }
+        }
+        AdminCommand::Permission { ref name, ref node, granted } => {
+            match accounts.set_permission(name, node, granted) {
+                Ok(()) => {
+                    refresh_permissions(state, name, accounts, config);
+                    match granted {
+                        Some(true) => format!("Granted {} to {}", node, name),
+                        Some(false) => format!("Denied {} to {}", node, name),
+                        None => format!("{} gets {} from their role again", name, node),
+                    }
+                }
+                Err(e) => format!("Could not change the permissions of {}: {}", name, e)
+            }
+        }
+        AdminCommand::Permissions(ref name) => {
+            match accounts.grants(name) {
+                Some((role, overrides)) => {
+                    let mut lines = vec![format!("{} is a {}", name, role)];
+                    lines.extend(overrides.into_iter());
+                    lines.join("\n")
+                }
+                None => format!("There is no account called {}", name)
+            }
+        }
+        AdminCommand::Say(ref message) => {
+            chat::announce(state, message);
+            format!("[Server] {}", message)
+        }
+        AdminCommand::Slash(ref line) => {
+            match commands.execute(state, &Caller::Console, line) {
+                Ok(output) => output.unwrap_or(String::new()),
+                Err(e) => format!("{}", e)
+            }
+        }
+        AdminCommand::Status => {
+            let players = state.get_players();
+            let authed = players.values()
+                .filter(|p| p.get_status() == PlayerStatus::Authenticated).count();
+            format!("Bound to {}\nUp for {}s\n{} of {} players in game, {} connecting",
+                    config.bind_address,
+                    precise_time_ns().saturating_sub(stats.started_at) / 1000000000,
+                    authed, config.max_players, players.len() - authed)
+        }
+        AdminCommand::TickStats => {
+            format!("{} ticks, {} events\nlast {}us, average {}us, max {}us, budget {}us",
+                    stats.ticks, stats.events,
+                    stats.last_ns / 1000, stats.average_ns() / 1000, stats.max_ns / 1000,
+                    config.tick_ns() / 1000)
+        }
+        AdminCommand::NetStats => {
+            let reports = state.net_reports(precise_time_ns());
+            let mut lines = vec![format!("{} connected", reports.len())];
+            for (id, report) in reports.into_iter() {
+                let name = state.get_players().get(&id)
+                                .and_then(|p| p.get_name().clone())
+                                .unwrap_or("<Unknown>".to_string());
+                let ping = match report.latency {
+                    Some(latency) => format!("{}ms (last {}ms)", latency.ms(),
+                                             latency.last / 1000000),
+                    None => "no ping yet".to_string(),
+                };
+                lines.push(format!("{:>5} {} - {}, idle {}s", id, name, ping,
+                                   report.idle_ns / 1000000000));
+                lines.push(format!("      sent {} in {} packets, received {} in {} packets, \
+                                    {} decode errors",
+                                   format_bytes(report.bytes_sent), report.packets_sent,
+                                   format_bytes(report.bytes_received), report.packets_received,
+                                   report.decode_errors));
+                let (sent, received) = (report.compression_sent, report.compression_received);
+                if let (Some(out), Some(into)) = (sent.ratio(), received.ratio()) {
+                    lines.push(format!("      compressed to {:.0}% sent, {:.0}% received",
+                                       out * 100.0, into * 100.0));
+                }
+            }
+            lines.join("\n")
+        }
+        AdminCommand::Stop => "Stopping the server".to_string(),
+    }
+}
+
+/// Kicks the player called `name`
+pub fn kick(state: &mut WorldState, name: &str, reason: &str) -> String {
+    match state.find_player(name) {
+        Some(id) => {
+            state.kick(id, reason);
+            format!("Kicked {}", name)
+        }
+        None => format!("{} is not online", name)
+    }
+}
+
+/// Bans an account or an address, kicking everyone affected
+///
+/// `duration` is in seconds, the ban is permanent if it is `None`.
+pub fn ban(state: &mut WorldState, target: &str, by_ip: bool, duration: Option<u64>,
+           reason: &str) -> String {
+    let target = if by_ip {
+        match resolve_ip(state, target) {
+            Some(ip) => BanTarget::ip(ip),
+            None => return format!("{} is neither online nor an address", target)
+        }
+    } else {
+        BanTarget::name(target)
+    };
+
+    let expires_at = match duration {
+        Some(d) => match moderation::now().checked_add(d) {
+            Some(at) => Some(at),
+            None => return format!("Can't ban {} for that long", target)
+        },
+        None => None
+    };
+    if let Err(e) = state.mut_get_moderation().ban(target.clone(), reason.to_string(),
+                                                   expires_at) {
+        return format!("Could not ban {}: {}", target, e);
+    }
+
+    let message = state.get_moderation().find_ban(&target)
+                       .map(|ban| ban.message(moderation::now()))
+                       .unwrap_or(reason.to_string());
+    let affected: Vec<usize> = state.get_players().values().filter(|p| {
+        let name = p.get_name().as_ref().map(|n| &n[..]);
+        target.matches(name, p.get_peer_addr().ip())
+    }).map(|p| p.get_id()).collect();
+    for id in affected.iter() {
+        state.kick(*id, &message);
+    }
+
+    match duration {
+        Some(d) => format!("Banned {} for {}, kicked {}", target,
+                           format_duration(d), affected.len()),
+        None => format!("Banned {}, kicked {}", target, affected.len())
+    }
+}
+
+/// Lifts the bans of an account or address
+pub fn unban(state: &mut WorldState, target: &str) -> String {
+    let store = state.mut_get_moderation();
+    let by_name = store.unban(&BanTarget::name(target));
+    let by_ip = store.unban(&BanTarget::Ip(target.to_string()));
+    match (by_name, by_ip) {
+        (Ok(false), Ok(false)) => format!("{} is not banned", target),
+        (Ok(_), Ok(_)) => format!("Unbanned {}", target),
+        (Err(e), _) | (_, Err(e)) => format!("Could not unban {}: {}", target, e),
+    }
+}
+
+/// Applies changes to an account right away, if its player is online
+fn refresh_permissions(state: &mut WorldState, name: &str, accounts: &AccountStore,
+                       config: &ServerConfig) {
+    if let Some(id) = state.find_player(name) {
+        permissions::refresh(state, id, accounts, &config.roles);
+    }
+}
+
+/// The address of the online player called `target`, or `target` itself
+fn resolve_ip(state: &WorldState, target: &str) -> Option<IpAddr> {
+    if let Some(id) = state.find_player(target) {
+        return state.get_players().get(&id).map(|p| p.get_peer_addr().ip());
+    }
+    if let Ok(ip) = target.parse::<Ipv4Addr>() {
+        return Some(IpAddr::V4(ip));
+    }
+    target.parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+}
+
+/// A way to run admin commands on a running server
+#[derive(Clone)]
+pub struct AdminHandle {
+    tx: Sender<ServerEvent>,
+}
+
+impl AdminHandle {
+    pub fn new(tx: Sender<ServerEvent>) -> AdminHandle {
+        AdminHandle { tx: tx }
+    }
+
+    /// Runs `command` on the tick thread and waits for its output
+    ///
+    /// Returns `None` if the server loop is not running.
+    pub fn execute(&self, command: AdminCommand) -> Option<String> {
+        let (reply_tx, reply_rx) = channel();
+        if self.tx.send(ServerEvent::Admin(command, reply_tx)).is_err() {
+            return None;
+        }
+        reply_rx.recv().ok()
+    }
+}
+
+/// Reads commands from `input` line by line until `stop` or the end
+///
+/// Made for stdin and stdout, but works with anything.
+pub fn run_console<R: BufRead, W: Write>(handle: &AdminHandle, input: R, output: &mut W) {
+    for line in input.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => return
+        };
+
+        let command = match parse_command(&line) {
+            Ok(c) => c,
+            Err(CommandError::Empty) => continue,
+            Err(e) => {
+                let _ = writeln!(output, "{}", e);
+                continue;
+            }
+        };
+
+        let stop = command == AdminCommand::Stop;
+        match handle.execute(command) {
+            Some(reply) => { let _ = writeln!(output, "{}", reply); }
+            None => {
+                let _ = writeln!(output, "The server is not running");
+                return;
+            }
+        }
+
+        if stop {
+            return;
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_simple() {
+        assert_eq!(parse_command("list"), Ok(AdminCommand::List));
+        assert_eq!(parse_command("  status  "), Ok(AdminCommand::Status));
+        assert_eq!(parse_command("tick-stats"), Ok(AdminCommand::TickStats));
+        assert_eq!(parse_command("net-stats"), Ok(AdminCommand::NetStats));
+        assert_eq!(parse_command("stop"), Ok(AdminCommand::Stop));
+    }
+
+    #[test]
+    fn parse_arguments() {
+        assert_eq!(parse_command("kick Neikos"), Ok(AdminCommand::Kick {
+            name: "Neikos".to_string(),
+            reason: "Kicked by an admin".to_string(),
+        }));
+        assert_eq!(parse_command("ban Neikos being  rude "), Ok(AdminCommand::Ban {
+            target: "Neikos".to_string(),
+            by_ip: false,
+            duration: None,
+            reason: "being  rude".to_string(),
+        }));
+        assert_eq!(parse_command("tempban-ip 10.0.0.1 12h"), Ok(AdminCommand::Ban {
+            target: "10.0.0.1".to_string(),
+            by_ip: true,
+            duration: Some(12 * 60 * 60),
+            reason: "Banned by an admin".to_string(),
+        }));
+        assert_eq!(parse_command("role Neikos moderator"), Ok(AdminCommand::SetRole {
+            name: "Neikos".to_string(),
+            role: "moderator".to_string(),
+        }));
+        assert_eq!(parse_command("perm Neikos deny command.tp"), Ok(AdminCommand::Permission {
+            name: "Neikos".to_string(),
+            node: "command.tp".to_string(),
+            granted: Some(false),
+        }));
+        assert_eq!(parse_command("whitelist add Neikos"),
+                   Ok(AdminCommand::Whitelist(WhitelistCommand::Add("Neikos".to_string()))));
+        assert_eq!(parse_command("say hello world"),
+                   Ok(AdminCommand::Say("hello world".to_string())));
+        assert_eq!(parse_command(" /tp 0 10 0 Neikos"),
+                   Ok(AdminCommand::Slash("tp 0 10 0 Neikos".to_string())));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_command(""), Err(CommandError::Empty));
+        assert_eq!(parse_command("kick"), Err(CommandError::MissingArgument("name")));
+        assert_eq!(parse_command("say "), Err(CommandError::MissingArgument("message")));
+        assert_eq!(parse_command("dance"), Err(CommandError::Unknown("dance".to_string())));
+        assert_eq!(parse_command("tempban Neikos"), Err(CommandError::MissingArgument("time")));
+        assert_eq!(parse_command("tempban Neikos soon"),
+                   Err(CommandError::InvalidArgument("time", "soon".to_string())));
+        assert_eq!(parse_command("whitelist add"), Err(CommandError::MissingArgument("name")));
+        assert_eq!(parse_command("perm Neikos allow x"),
+                   Err(CommandError::InvalidArgument("action", "allow".to_string())));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("30m"), Some(30 * 60));
+        assert_eq!(parse_duration("7d"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration("12"), None);
+        assert_eq!(parse_duration("1w"), None);
+        assert_eq!(parse_duration("18446744073709551615d"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn tick_stats() {
+        let mut stats = TickStats::new();
+        assert_eq!(stats.average_ns(), 0);
+        stats.record(100, 1);
+        stats.record(300, 2);
+        assert_eq!(stats.ticks, 2);
+        assert_eq!(stats.events, 3);
+        assert_eq!(stats.last_ns, 300);
+        assert_eq!(stats.max_ns, 300);
+        assert_eq!(stats.average_ns(), 200);
+    }
+}
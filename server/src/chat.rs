@@ -0,0 +1,312 @@
+//! Routing of chat messages between players
+//!
+//! Players pick a `ChatChannel` for every message, the router works out who
+//! gets to read it. Messages that are too long or come in too fast never
+//! reach anyone, the sender gets told why instead.
+
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+
+use names::{name_key, validate_name};
+use player::{Player, PlayerStatus};
+use worldstate::WorldState;
+use shared::packets::{Packet, ChatChannel, Position};
+
+/// How far, in world units, `Local` messages carry
+pub const LOCAL_CHAT_RANGE: f32 = 50.0;
+
+/// The name server messages are sent under
+pub const SERVER_NAME: &'static str = "Server";
+
+/// What a player may send, taken from the `ServerConfig`
+#[derive(Debug, Clone, Copy)]
+pub struct ChatLimits {
+    /// In characters
+    pub max_length: usize,
+    /// How many messages may be sent in a row
+    pub burst: u32,
+    /// How long it takes to earn another message, in nanoseconds
+    pub interval_ns: u64,
+}
+
+/// A token bucket, every message takes a token
+///
+/// The bucket holds `burst` tokens and regains one every `interval`.
+/// Both are passed on every call, so changing the limits applies to
+/// everyone right away.
+#[derive(Debug)]
+pub struct RateLimiter {
+    spent: u32,
+    last_refill: u64,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            spent: 0,
+            last_refill: 0,
+        }
+    }
+
+    /// Takes a token if there is one left, `now` is in nanoseconds
+    pub fn try_take(&mut self, now: u64, burst: u32, interval_ns: u64) -> bool {
+        let refilled = (now - cmp::min(now, self.last_refill)) / cmp::max(interval_ns, 1);
+        if refilled > 0 {
+            self.spent = self.spent.saturating_sub(cmp::min(refilled, burst as u64) as u32);
+            self.last_refill = if self.spent == 0 {
+                now
+            } else {
+                self.last_refill + refilled * interval_ns
+            };
+        }
+
+        if self.spent < burst {
+            self.spent += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChatError {
+    NotAuthenticated,
+    Empty,
+    /// Longer than the limit, which is given
+    TooLong(usize),
+    TooFast,
+    NoParty,
+    InvalidParty(String),
+    /// Nobody with that name is online
+    UnknownRecipient(String),
+    /// Players can't send on the `Server` or `Emote` channel
+    NotAllowed,
+}
+
+impl Error for ChatError {
+    fn description(&self) -> &str {
+        match *self {
+            ChatError::NotAuthenticated => "You are not logged in.",
+            ChatError::Empty => "The message is empty.",
+            ChatError::TooLong(..) => "The message is too long.",
+            ChatError::TooFast => "You are sending messages too fast.",
+            ChatError::NoParty => "You are not in a party.",
+            ChatError::InvalidParty(..) => "That is not a valid party name.",
+            ChatError::UnknownRecipient(..) => "Nobody with that name is online.",
+            ChatError::NotAllowed => "You can't send on that channel.",
+        }
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChatError::TooLong(max) => {
+                write!(fmt, "The message is too long, at most {} characters are allowed.", max)
+            }
+            ChatError::InvalidParty(ref reason) => write!(fmt, "Invalid party name: {}", reason),
+            ChatError::UnknownRecipient(ref name) => write!(fmt, "{} is not online.", name),
+            ref e => e.description().fmt(fmt)
+        }
+    }
+}
+
+/// Delivers a message from the player `from`
+///
+/// Returns how many players it reached, including the sender if they can
+/// read their own channel. `now` is in nanoseconds, for the rate limit.
+pub fn route(state: &mut WorldState, from: usize, channel: ChatChannel, message: &str,
+             limits: &ChatLimits, now: u64) -> Result<usize, ChatError> {
+    let message = message.trim();
+    try!(check_length(message, limits));
+    let (name, position, party) = try!(take_token(state, from, limits, now));
+
+    let (recipients, channel): (Vec<usize>, ChatChannel) = match channel {
+        ChatChannel::Global => (in_game(state, |_| true), ChatChannel::Global),
+        ChatChannel::Local => {
+            let near = in_game(state, |p| {
+                p.get_position().distance(&position) <= LOCAL_CHAT_RANGE
+            });
+            (near, ChatChannel::Local)
+        }
+        ChatChannel::Party => {
+            let party = match party {
+                Some(party) => party,
+                None => return Err(ChatError::NoParty)
+            };
+            (in_game(state, |p| p.get_party().as_ref() == Some(&party)), ChatChannel::Party)
+        }
+        ChatChannel::Whisper(to) => {
+            let id = match state.find_player(&to) {
+                Some(id) => id,
+                None => return Err(ChatError::UnknownRecipient(to))
+            };
+            // Address them by the name they logged in with
+            let to = state.get_players().get(&id)
+                          .and_then(|p| p.get_name().clone()).unwrap_or(to);
+            let mut recipients = vec![id];
+            if id != from {
+                recipients.push(from);
+            }
+            (recipients, ChatChannel::Whisper(to))
+        }
+        ChatChannel::Server | ChatChannel::Emote => return Err(ChatError::NotAllowed)
+    };
+
+    let packet = Packet::Chat {
+        channel: channel,
+        from: name,
+        message: message.to_string(),
+    };
+    for id in recipients.iter() {
+        state.send_to(*id, &packet);
+    }
+    Ok(recipients.len())
+}
+
+/// Tells everyone in the game what the player `from` is doing
+///
+/// Emotes count against the same limits as any other message.
+pub fn emote(state: &mut WorldState, from: usize, message: &str, limits: &ChatLimits, now: u64)
+    -> Result<usize, ChatError> {
+    let message = message.trim();
+    try!(check_length(message, limits));
+    let (name, _, _) = try!(take_token(state, from, limits, now));
+
+    let recipients = in_game(state, |_| true);
+    let packet = Packet::Chat {
+        channel: ChatChannel::Emote,
+        from: name,
+        message: message.to_string(),
+    };
+    for id in recipients.iter() {
+        state.send_to(*id, &packet);
+    }
+    Ok(recipients.len())
+}
+
+fn check_length(message: &str, limits: &ChatLimits) -> Result<(), ChatError> {
+    if message.is_empty() {
+        return Err(ChatError::Empty);
+    }
+    if message.chars().count() > limits.max_length {
+        return Err(ChatError::TooLong(limits.max_length));
+    }
+    Ok(())
+}
+
+/// Takes a token from the rate limit of the sender
+///
+/// Returns their name, position and party.
+fn take_token(state: &mut WorldState, from: usize, limits: &ChatLimits, now: u64)
+    -> Result<(String, Position, Option<String>), ChatError> {
+    match state.mut_get_players().get_mut(&from) {
+        Some(player) => {
+            let name = match (player.get_status(), player.get_name().clone()) {
+                (PlayerStatus::Authenticated, Some(name)) => name,
+                _ => return Err(ChatError::NotAuthenticated)
+            };
+            if !player.chat_limiter().try_take(now, limits.burst, limits.interval_ns) {
+                return Err(ChatError::TooFast);
+            }
+            Ok((name, player.get_position(), player.get_party().clone()))
+        }
+        None => Err(ChatError::NotAuthenticated)
+    }
+}
+
+/// Sends a message from the server to everyone in the game
+pub fn announce(state: &mut WorldState, message: &str) {
+    state.broadcast(&server_message(message));
+}
+
+/// Sends a message from the server to a single player
+pub fn notice(state: &mut WorldState, id: usize, message: &str) {
+    state.send_to(id, &server_message(message));
+}
+
+fn server_message(message: &str) -> Packet {
+    Packet::Chat {
+        channel: ChatChannel::Server,
+        from: SERVER_NAME.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// Moves a player into the named party, or out of theirs with `None`
+///
+/// Parties exist as long as somebody is in them, the name is compared
+/// without regard to case.
+pub fn set_party(state: &mut WorldState, id: usize, party: Option<String>)
+    -> Result<(), ChatError> {
+    let party = match party {
+        Some(party) => match validate_name(&party) {
+            Ok(()) => Some(name_key(&party)),
+            Err(e) => return Err(ChatError::InvalidParty(format!("{}", e)))
+        },
+        None => None
+    };
+
+    match state.mut_get_players().get_mut(&id) {
+        Some(player) => {
+            if player.get_status() != PlayerStatus::Authenticated {
+                return Err(ChatError::NotAuthenticated);
+            }
+            player.set_party(party);
+            Ok(())
+        }
+        None => Err(ChatError::NotAuthenticated)
+    }
+}
+
+/// Ids of the authenticated players that match `filter`
+fn in_game<F>(state: &WorldState, filter: F) -> Vec<usize>
+    where F: Fn(&Player) -> bool {
+    state.get_players().values().filter(|p| {
+        p.get_status() == PlayerStatus::Authenticated && filter(p)
+    }).map(|p| p.get_id()).collect()
+}
+
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1000000000;
+
+    #[test]
+    fn burst_then_refill() {
+        let mut limiter = RateLimiter::new();
+        let start = 100 * SECOND;
+        for _ in 0..3 {
+            assert!(limiter.try_take(start, 3, SECOND));
+        }
+        assert!(!limiter.try_take(start, 3, SECOND));
+        assert!(!limiter.try_take(start + SECOND / 2, 3, SECOND));
+
+        assert!(limiter.try_take(start + SECOND, 3, SECOND));
+        assert!(!limiter.try_take(start + SECOND, 3, SECOND));
+    }
+
+    #[test]
+    fn refill_is_capped() {
+        let mut limiter = RateLimiter::new();
+        let start = 100 * SECOND;
+        assert!(limiter.try_take(start, 2, SECOND));
+
+        // A long break only refills the bucket, it does not overflow it
+        let later = start + 1000 * SECOND;
+        assert!(limiter.try_take(later, 2, SECOND));
+        assert!(limiter.try_take(later, 2, SECOND));
+        assert!(!limiter.try_take(later, 2, SECOND));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(format!("{}", ChatError::TooLong(10)),
+                   "The message is too long, at most 10 characters are allowed.");
+        assert_eq!(format!("{}", ChatError::UnknownRecipient("Neikos".to_string())),
+                   "Neikos is not online.");
+        assert_eq!(format!("{}", ChatError::TooFast), "You are sending messages too fast.");
+    }
+}
@@ -0,0 +1,798 @@
+//! Slash commands typed into the chat
+//!
+//! Every `Command` declares its arguments and the permission it needs. The
+//! `CommandRegistry` checks both before calling the handler, so handlers
+//! only deal with arguments that are already parsed. Handlers get nothing
+//! but the `WorldState`, which keeps them testable without any sockets.
+//!
+//! The admin console runs the same commands, as the `Caller::Console`.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use clock_ticks::precise_time_ns;
+
+use admin::{self, split_word};
+use chat::{self, SERVER_NAME};
+use player::PlayerStatus;
+use worldstate::WorldState;
+use shared::packets::{Packet, ChatChannel, Position};
+
+/// Who runs a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Caller {
+    Console,
+    /// The id of an authenticated player
+    Player(usize),
+}
+
+impl Caller {
+    /// Whether the caller may run commands that need `permission`
+    ///
+    /// The console may do anything, players what their role allows.
+    pub fn has_permission(&self, state: &WorldState, permission: &str) -> bool {
+        match *self {
+            Caller::Console => true,
+            Caller::Player(id) => state.get_players().get(&id)
+                                       .map_or(false, |p| p.has_permission(permission)),
+        }
+    }
+
+    /// Whether the caller ranks above the player `target`
+    ///
+    /// The console outranks everyone.
+    pub fn outranks(&self, state: &WorldState, target: usize) -> bool {
+        match *self {
+            Caller::Console => true,
+            Caller::Player(id) => {
+                let players = state.get_players();
+                match (players.get(&id), players.get(&target)) {
+                    (Some(caller), Some(target)) => {
+                        caller.get_permissions().outranks(target.get_permissions())
+                    }
+                    _ => false
+                }
+            }
+        }
+    }
+
+    /// The name the caller shows up under
+    pub fn name(&self, state: &WorldState) -> String {
+        match *self {
+            Caller::Console => SERVER_NAME.to_string(),
+            Caller::Player(id) => state.get_players().get(&id)
+                                       .and_then(|p| p.get_name().clone())
+                                       .unwrap_or(SERVER_NAME.to_string()),
+        }
+    }
+}
+
+/// What an argument has to look like
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    /// A single word
+    Word,
+    Integer,
+    /// A decimal number, like a coordinate
+    Number,
+    /// The name of a player in the game
+    Player,
+    /// Everything up to the end of the line, only allowed last
+    Text,
+}
+
+impl fmt::Display for ArgKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", match *self {
+            ArgKind::Word => "a word",
+            ArgKind::Integer => "a whole number",
+            ArgKind::Number => "a number",
+            ArgKind::Player => "a player",
+            ArgKind::Text => "some text",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    /// Optional arguments may only be followed by other optional ones
+    pub optional: bool,
+}
+
+/// A parsed argument
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Word(String),
+    Integer(i64),
+    Number(f32),
+    /// The id of the player
+    Player(usize),
+    Text(String),
+}
+
+/// The arguments a handler gets, by name
+///
+/// Optional arguments that were left out are simply missing.
+#[derive(Debug)]
+pub struct Args {
+    values: Vec<(&'static str, Value)>,
+}
+
+impl Args {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.iter().find(|&&(n, _)| n == name).map(|&(_, ref v)| v)
+    }
+
+    pub fn word(&self, name: &str) -> Option<&str> {
+        match self.get(name) {
+            Some(&Value::Word(ref w)) | Some(&Value::Text(ref w)) => Some(w),
+            _ => None
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Option<i64> {
+        match self.get(name) {
+            Some(&Value::Integer(i)) => Some(i),
+            _ => None
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Option<f32> {
+        match self.get(name) {
+            Some(&Value::Number(n)) => Some(n),
+            _ => None
+        }
+    }
+
+    pub fn player(&self, name: &str) -> Option<usize> {
+        match self.get(name) {
+            Some(&Value::Player(id)) => Some(id),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Unknown(String),
+    PermissionDenied,
+    MissingArgument(&'static str),
+    /// The named argument is not of the kind it should be
+    InvalidArgument(&'static str, ArgKind, String),
+    /// Nobody with that name is in the game
+    UnknownPlayer(String),
+    /// More arguments than the command takes, with its usage
+    TooManyArguments(String),
+    /// Only players can run the command
+    NotAPlayer,
+    /// The target ranks at least as high as the caller
+    Outranked(String),
+    /// The handler could not do what was asked, with the reason
+    Failed(String),
+}
+
+impl Error for CommandError {
+    fn description(&self) -> &str {
+        match *self {
+            CommandError::Unknown(..) => "Unknown command.",
+            CommandError::PermissionDenied => "You are not allowed to do that.",
+            CommandError::MissingArgument(..) => "Missing argument.",
+            CommandError::InvalidArgument(..) => "Invalid argument.",
+            CommandError::UnknownPlayer(..) => "Nobody with that name is online.",
+            CommandError::TooManyArguments(..) => "Too many arguments.",
+            CommandError::NotAPlayer => "Only players can do that.",
+            CommandError::Outranked(..) => "That player ranks at least as high as you.",
+            CommandError::Failed(..) => "The command failed.",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Unknown(ref c) => write!(fmt, "Unknown command /{}, try /help", c),
+            CommandError::MissingArgument(a) => write!(fmt, "Missing argument <{}>", a),
+            CommandError::InvalidArgument(a, kind, ref v) => {
+                write!(fmt, "Invalid argument <{}>: '{}' is not {}", a, v, kind)
+            }
+            CommandError::UnknownPlayer(ref name) => write!(fmt, "{} is not online.", name),
+            CommandError::TooManyArguments(ref usage) => {
+                write!(fmt, "Too many arguments, usage: {}", usage)
+            }
+            CommandError::Outranked(ref name) => {
+                write!(fmt, "{} ranks at least as high as you.", name)
+            }
+            CommandError::Failed(ref reason) => write!(fmt, "{}", reason),
+            ref e => e.description().fmt(fmt)
+        }
+    }
+}
+
+/// Runs a command, returning what to tell the caller, if anything
+pub type Handler = fn(&mut WorldState, &Caller, &Args) -> Result<Option<String>, CommandError>;
+
+pub struct Command {
+    /// What is typed after the slash
+    pub name: &'static str,
+    pub args: &'static [Arg],
+    pub help: &'static str,
+    /// Needed to run the command at all, everyone may if `None`
+    pub permission: Option<&'static str>,
+    pub handler: Handler,
+}
+
+impl Command {
+    /// How to type the command, like `/give <player> <item> [count]`
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for arg in self.args.iter() {
+            if arg.optional {
+                usage.push_str(&format!(" [{}]", arg.name));
+            } else {
+                usage.push_str(&format!(" <{}>", arg.name));
+            }
+        }
+        usage
+    }
+
+    /// Parses `line`, everything after the name, against the arguments
+    pub fn parse_args(&self, state: &WorldState, line: &str) -> Result<Args, CommandError> {
+        let mut values = Vec::with_capacity(self.args.len());
+        let mut rest = line.trim();
+
+        for arg in self.args.iter() {
+            let word = if arg.kind == ArgKind::Text {
+                let text = rest;
+                rest = "";
+                text
+            } else {
+                let (word, after) = split_word(rest);
+                rest = after;
+                word
+            };
+
+            if word.is_empty() {
+                if arg.optional {
+                    break;
+                }
+                return Err(CommandError::MissingArgument(arg.name));
+            }
+
+            let invalid = || CommandError::InvalidArgument(arg.name, arg.kind, word.to_string());
+            let value = match arg.kind {
+                ArgKind::Word => Value::Word(word.to_string()),
+                ArgKind::Text => Value::Text(word.to_string()),
+                ArgKind::Integer => Value::Integer(try!(word.parse().map_err(|_| invalid()))),
+                ArgKind::Number => {
+                    let number: f32 = try!(word.parse().map_err(|_| invalid()));
+                    if !number.is_finite() {
+                        return Err(invalid());
+                    }
+                    Value::Number(number)
+                }
+                ArgKind::Player => match state.find_player(word) {
+                    Some(id) => Value::Player(id),
+                    None => return Err(CommandError::UnknownPlayer(word.to_string()))
+                },
+            };
+            values.push((arg.name, value));
+        }
+
+        if !rest.is_empty() {
+            return Err(CommandError::TooManyArguments(self.usage()));
+        }
+        Ok(Args { values: values })
+    }
+}
+
+/// Every command the server knows, by name
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl CommandRegistry {
+    /// A registry without any commands, but `help`
+    pub fn new() -> CommandRegistry {
+        CommandRegistry {
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// A registry with the commands that come with the server
+    pub fn with_defaults() -> CommandRegistry {
+        let mut registry = CommandRegistry::new();
+        for command in builtin_commands().into_iter() {
+            registry.register(command);
+        }
+        registry
+    }
+
+    /// Adds a command, returning the one it replaced
+    pub fn register(&mut self, command: Command) -> Option<Command> {
+        self.commands.insert(command.name, command)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name)
+    }
+
+    /// Runs a line like `/tp 0 10 0`, the slash may be left out
+    ///
+    /// `help` lists what the caller may run, or how to use a single command.
+    pub fn execute(&self, state: &mut WorldState, caller: &Caller, line: &str)
+        -> Result<Option<String>, CommandError> {
+        let line = line.trim();
+        let line = if line.starts_with("/") { &line[1..] } else { line };
+        let (name, rest) = split_word(line);
+
+        if name == "help" {
+            return self.help(state, caller, rest).map(Some);
+        }
+
+        let command = match self.commands.get(name) {
+            Some(command) => command,
+            None => return Err(CommandError::Unknown(name.to_string()))
+        };
+        if let Some(permission) = command.permission {
+            if !caller.has_permission(state, permission) {
+                return Err(CommandError::PermissionDenied);
+            }
+        }
+
+        let args = try!(command.parse_args(state, rest));
+        (command.handler)(state, caller, &args)
+    }
+
+    fn help(&self, state: &WorldState, caller: &Caller, name: &str)
+        -> Result<String, CommandError> {
+        if !name.is_empty() {
+            let name = name.trim_left_matches('/');
+            return match self.commands.get(name) {
+                Some(command) => Ok(format!("{} - {}", command.usage(), command.help)),
+                None => Err(CommandError::Unknown(name.to_string()))
+            };
+        }
+
+        let mut lines = vec!["/help [command] - Shows how to use the commands".to_string()];
+        for command in self.commands.values() {
+            let allowed = command.permission.map_or(true, |p| caller.has_permission(state, p));
+            if allowed {
+                lines.push(format!("{} - {}", command.usage(), command.help));
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+const TEXT: &'static [Arg] = &[
+    Arg { name: "text", kind: ArgKind::Text, optional: false },
+];
+
+const TP_ARGS: &'static [Arg] = &[
+    Arg { name: "x", kind: ArgKind::Number, optional: false },
+    Arg { name: "y", kind: ArgKind::Number, optional: false },
+    Arg { name: "z", kind: ArgKind::Number, optional: false },
+    Arg { name: "player", kind: ArgKind::Player, optional: true },
+];
+
+const GIVE_ARGS: &'static [Arg] = &[
+    Arg { name: "player", kind: ArgKind::Player, optional: false },
+    Arg { name: "item", kind: ArgKind::Word, optional: false },
+    Arg { name: "count", kind: ArgKind::Integer, optional: true },
+];
+
+const KICK_ARGS: &'static [Arg] = &[
+    Arg { name: "name", kind: ArgKind::Word, optional: false },
+    Arg { name: "reason", kind: ArgKind::Text, optional: true },
+];
+
+const UNBAN_ARGS: &'static [Arg] = &[
+    Arg { name: "name", kind: ArgKind::Word, optional: false },
+];
+
+const TIME_ARGS: &'static [Arg] = &[
+    Arg { name: "HH:MM", kind: ArgKind::Word, optional: true },
+];
+
+fn builtin_commands() -> Vec<Command> {
+    vec![
+        Command {
+            name: "who",
+            args: &[],
+            help: "Lists the players in the game",
+            permission: None,
+            handler: who,
+        },
+        Command {
+            name: "me",
+            args: TEXT,
+            help: "Tells everyone what you are doing",
+            permission: None,
+            handler: me,
+        },
+        Command {
+            name: "tp",
+            args: TP_ARGS,
+            help: "Moves you, or another player, to a position",
+            permission: Some("command.tp"),
+            handler: teleport,
+        },
+        Command {
+            name: "give",
+            args: GIVE_ARGS,
+            help: "Gives a player some items",
+            permission: Some("command.give"),
+            handler: give,
+        },
+        Command {
+            name: "kick",
+            args: KICK_ARGS,
+            help: "Disconnects a player",
+            permission: Some("moderation.kick"),
+            handler: kick,
+        },
+        Command {
+            name: "ban",
+            args: KICK_ARGS,
+            help: "Disconnects a player and keeps them out",
+            permission: Some("moderation.ban"),
+            handler: ban,
+        },
+        Command {
+            name: "unban",
+            args: UNBAN_ARGS,
+            help: "Lets a banned player back in",
+            permission: Some("moderation.ban"),
+            handler: unban,
+        },
+        Command {
+            name: "time",
+            args: TIME_ARGS,
+            help: "Shows the time of day, or sets it",
+            permission: None,
+            handler: clock,
+        },
+    ]
+}
+
+fn who(state: &mut WorldState, _: &Caller, _: &Args) -> Result<Option<String>, CommandError> {
+    let mut names: Vec<String> = state.get_players().values().filter(|p| {
+        p.get_status() == PlayerStatus::Authenticated
+    }).filter_map(|p| p.get_name().clone()).collect();
+    names.sort();
+    Ok(Some(format!("{} in the game: {}", names.len(), names.join(", "))))
+}
+
+fn me(state: &mut WorldState, caller: &Caller, args: &Args) -> Result<Option<String>, CommandError> {
+    let message = args.word("text").unwrap_or("");
+    match *caller {
+        Caller::Player(id) => {
+            let limits = state.get_chat_limits();
+            if let Err(e) = chat::emote(state, id, message, &limits, precise_time_ns()) {
+                return Err(CommandError::Failed(format!("{}", e)));
+            }
+        }
+        // The console is not held to any limits
+        Caller::Console => {
+            let packet = Packet::Chat {
+                channel: ChatChannel::Emote,
+                from: caller.name(state),
+                message: message.to_string(),
+            };
+            state.broadcast(&packet);
+        }
+    }
+    Ok(None)
+}
+
+fn teleport(state: &mut WorldState, caller: &Caller, args: &Args)
+    -> Result<Option<String>, CommandError> {
+    let id = match (args.player("player"), *caller) {
+        (Some(id), _) | (None, Caller::Player(id)) => id,
+        (None, Caller::Console) => return Err(CommandError::NotAPlayer)
+    };
+    let position = Position::new(args.number("x").unwrap_or(0.0),
+                                 args.number("y").unwrap_or(0.0),
+                                 args.number("z").unwrap_or(0.0));
+
+    let name = match state.mut_get_players().get_mut(&id) {
+        Some(player) => {
+            player.set_position(position);
+            player.get_name().clone().unwrap_or(String::new())
+        }
+        None => return Err(CommandError::Failed("That player just left.".to_string()))
+    };
+    state.send_to(id, &Packet::Teleport(position));
+    Ok(Some(format!("Teleported {} to {}, {}, {}", name, position.x, position.y, position.z)))
+}
+
+fn give(state: &mut WorldState, _: &Caller, args: &Args) -> Result<Option<String>, CommandError> {
+    let id = args.player("player").unwrap_or(0);
+    let item = args.word("item").unwrap_or("");
+    let count = args.integer("count").unwrap_or(1);
+    if count < 1 || count > u32::max_value() as i64 {
+        return Err(CommandError::InvalidArgument("count", ArgKind::Integer, format!("{}", count)));
+    }
+
+    match state.mut_get_players().get_mut(&id) {
+        Some(player) => {
+            let held = player.give_item(item, count as u32);
+            let name = player.get_name().clone().unwrap_or(String::new());
+            Ok(Some(format!("Gave {} {} to {}, who has {} now", count, item, name, held)))
+        }
+        None => Err(CommandError::Failed("That player just left.".to_string()))
+    }
+}
+
+fn kick(state: &mut WorldState, caller: &Caller, args: &Args)
+    -> Result<Option<String>, CommandError> {
+    let reason = format!("Kicked by {}", caller.name(state));
+    let reason = args.word("reason").map(|r| r.to_string()).unwrap_or(reason);
+    let name = args.word("name").unwrap_or("");
+    if let Some(id) = state.find_player(name) {
+        if !caller.outranks(state, id) {
+            return Err(CommandError::Outranked(name.to_string()));
+        }
+    }
+    Ok(Some(admin::kick(state, name, &reason)))
+}
+
+fn ban(state: &mut WorldState, caller: &Caller, args: &Args)
+    -> Result<Option<String>, CommandError> {
+    let reason = format!("Banned by {}", caller.name(state));
+    let reason = args.word("reason").map(|r| r.to_string()).unwrap_or(reason);
+    let name = args.word("name").unwrap_or("");
+    match state.find_player(name) {
+        Some(id) if !caller.outranks(state, id) => {
+            return Err(CommandError::Outranked(name.to_string()));
+        }
+        Some(_) => (),
+        // Without them online, there is no telling what role they have
+        None if *caller != Caller::Console => {
+            return Err(CommandError::Failed(format!(
+                "{} is not online, only the console can ban them now", name)));
+        }
+        None => ()
+    }
+    Ok(Some(admin::ban(state, name, false, None, &reason)))
+}
+
+fn unban(state: &mut WorldState, _: &Caller, args: &Args)
+    -> Result<Option<String>, CommandError> {
+    Ok(Some(admin::unban(state, args.word("name").unwrap_or(""))))
+}
+
+fn clock(state: &mut WorldState, caller: &Caller, args: &Args) -> Result<Option<String>, CommandError> {
+    let clock = match args.word("HH:MM") {
+        Some(clock) => clock,
+        None => {
+            let (hours, minutes) = state.get_time_of_day();
+            return Ok(Some(format!("It is {:02}:{:02}", hours, minutes)));
+        }
+    };
+    if !caller.has_permission(state, "command.time") {
+        return Err(CommandError::PermissionDenied);
+    }
+
+    let (hours, minutes) = match parse_clock(clock) {
+        Some(time) => time,
+        None => return Err(CommandError::InvalidArgument("HH:MM", ArgKind::Word, clock.to_string()))
+    };
+    state.set_time_of_day(hours, minutes);
+    Ok(Some(format!("It is now {:02}:{:02}", hours, minutes)))
+}
+
+/// Parses a time of day like `06:30`
+fn parse_clock(clock: &str) -> Option<(u32, u32)> {
+    let idx = match clock.find(':') {
+        Some(idx) => idx,
+        None => return None
+    };
+    match (clock[..idx].parse(), clock[idx + 1..].parse()) {
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => Some((hours, minutes)),
+        _ => None
+    }
+}
+
+mod tests {
+    use super::*;
+    use std::iter;
+    use chat::{self, ChatError};
+    use clock_ticks::precise_time_ns;
+    use player::Player;
+    use permissions::Roles;
+    use worldstate::WorldState;
+    use shared::packets::{Packet, ChatChannel, Position};
+
+    /// A world with an offline player for every name
+    fn world(names: &[&str]) -> (WorldState, Vec<usize>) {
+        let mut state = WorldState::new();
+        let mut ids = Vec::new();
+        for name in names.iter() {
+            let player = Player::offline();
+            let id = player.get_id();
+            state.mut_get_players().insert(id, player);
+            assert!(state.auth_player(id, name.to_string()));
+            ids.push(id);
+        }
+        (state, ids)
+    }
+
+    fn sent(state: &mut WorldState, id: usize) -> Vec<Packet> {
+        state.mut_get_players().get_mut(&id).unwrap().take_outbox()
+    }
+
+    #[test]
+    fn who_and_me() {
+        let registry = CommandRegistry::with_defaults();
+        let (mut state, ids) = world(&["Neikos", "Alice"]);
+
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[0]), "/who"),
+                   Ok(Some("2 in the game: Alice, Neikos".to_string())));
+
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[0]), "/me waves"), Ok(None));
+        assert_eq!(sent(&mut state, ids[1]), vec![Packet::Chat {
+            channel: ChatChannel::Emote,
+            from: "Neikos".to_string(),
+            message: "waves".to_string(),
+        }]);
+
+        let long: String = iter::repeat('a').take(257).collect();
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[0]), &format!("/me {}", long)),
+                   Err(CommandError::Failed(format!("{}", ChatError::TooLong(256)))));
+        // The first one took a token already
+        for _ in 0..4 {
+            assert_eq!(registry.execute(&mut state, &Caller::Player(ids[0]), "/me waves"),
+                       Ok(None));
+        }
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[0]), "/me waves"),
+                   Err(CommandError::Failed(format!("{}", ChatError::TooFast))));
+    }
+
+    #[test]
+    fn me_after_chat() {
+        let registry = CommandRegistry::with_defaults();
+        let (mut state, ids) = world(&["Neikos", "Alice"]);
+        let limits = state.get_chat_limits();
+
+        // Use up every token a while ago, they have all come back by now
+        let earlier = precise_time_ns() - limits.interval_ns * limits.burst as u64;
+        for _ in 0..limits.burst {
+            assert!(chat::route(&mut state, ids[0], ChatChannel::Global, "hi", &limits,
+                                earlier).is_ok());
+        }
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[0]), "/me waves"), Ok(None));
+        assert!(chat::route(&mut state, ids[0], ChatChannel::Global, "hi", &limits,
+                            precise_time_ns()).is_ok());
+    }
+
+    #[test]
+    fn argument_errors() {
+        let registry = CommandRegistry::with_defaults();
+        let (mut state, _) = world(&["Neikos"]);
+        let console = Caller::Console;
+
+        assert_eq!(registry.execute(&mut state, &console, "/dance"),
+                   Err(CommandError::Unknown("dance".to_string())));
+        assert_eq!(registry.execute(&mut state, &console, "/me"),
+                   Err(CommandError::MissingArgument("text")));
+        assert_eq!(registry.execute(&mut state, &console, "/tp 1 two 3"),
+                   Err(CommandError::InvalidArgument("y", ArgKind::Number, "two".to_string())));
+        assert_eq!(registry.execute(&mut state, &console, "/tp 1 2 3 Bob"),
+                   Err(CommandError::UnknownPlayer("Bob".to_string())));
+        assert_eq!(registry.execute(&mut state, &console, "/tp 1 2 3"),
+                   Err(CommandError::NotAPlayer));
+        assert_eq!(registry.execute(&mut state, &console, "/who now"),
+                   Err(CommandError::TooManyArguments("/who".to_string())));
+        assert_eq!(registry.execute(&mut state, &console, "/give Neikos apple 0"),
+                   Err(CommandError::InvalidArgument("count", ArgKind::Integer, "0".to_string())));
+        assert_eq!(format!("{}", CommandError::InvalidArgument("y", ArgKind::Number, "two".to_string())),
+                   "Invalid argument <y>: 'two' is not a number");
+    }
+
+    #[test]
+    fn permissions() {
+        let registry = CommandRegistry::with_defaults();
+        let (mut state, ids) = world(&["Neikos"]);
+        let player = Caller::Player(ids[0]);
+
+        assert_eq!(registry.execute(&mut state, &player, "/tp 0 0 0"),
+                   Err(CommandError::PermissionDenied));
+        assert_eq!(registry.execute(&mut state, &player, "/time 12:00"),
+                   Err(CommandError::PermissionDenied));
+        assert!(registry.execute(&mut state, &player, "/time").is_ok());
+
+        let help = registry.execute(&mut state, &player, "/help").unwrap().unwrap();
+        assert!(help.contains("/who"));
+        assert!(!help.contains("/give"));
+
+        let moderator = Roles::default().resolve("moderator", &[]);
+        state.mut_get_players().get_mut(&ids[0]).unwrap().set_permissions(moderator);
+        assert!(registry.execute(&mut state, &player, "/tp 0 0 0").is_ok());
+        assert_eq!(registry.execute(&mut state, &player, "/give Neikos apple"),
+                   Err(CommandError::PermissionDenied));
+    }
+
+    #[test]
+    fn moderation() {
+        let registry = CommandRegistry::with_defaults();
+        let (mut state, ids) = world(&["Neikos", "Alice"]);
+        let moderator = Roles::default().resolve("moderator", &[]);
+        state.mut_get_players().get_mut(&ids[0]).unwrap().set_permissions(moderator);
+
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[1]), "/ban Neikos"),
+                   Err(CommandError::PermissionDenied));
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[0]), "/ban alice"),
+                   Ok(Some("Banned alice, kicked 1".to_string())));
+        match sent(&mut state, ids[1]).pop() {
+            Some(Packet::Kick { ref reason }) if reason.contains("Banned by Neikos") => (),
+            other => panic!("Expected a Kick, got {:?}", other),
+        }
+        assert!(state.get_moderation().name_ban("Alice").is_some());
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[0]), "/unban Alice"),
+                   Ok(Some("Unbanned Alice".to_string())));
+    }
+
+    #[test]
+    fn moderation_ranks() {
+        let registry = CommandRegistry::with_defaults();
+        let (mut state, ids) = world(&["Neikos", "Alice", "Bob"]);
+        let roles = Roles::default();
+        state.mut_get_players().get_mut(&ids[0]).unwrap()
+             .set_permissions(roles.resolve("moderator", &[]));
+        state.mut_get_players().get_mut(&ids[1]).unwrap()
+             .set_permissions(roles.resolve("moderator", &[]));
+        state.mut_get_players().get_mut(&ids[2]).unwrap()
+             .set_permissions(roles.resolve("admin", &[]));
+        let neikos = Caller::Player(ids[0]);
+
+        assert_eq!(registry.execute(&mut state, &neikos, "/kick Alice"),
+                   Err(CommandError::Outranked("Alice".to_string())));
+        assert_eq!(registry.execute(&mut state, &neikos, "/ban Bob"),
+                   Err(CommandError::Outranked("Bob".to_string())));
+        assert_eq!(registry.execute(&mut state, &neikos, "/kick Neikos"),
+                   Err(CommandError::Outranked("Neikos".to_string())));
+        assert!(registry.execute(&mut state, &neikos, "/ban Carol").is_err());
+        assert!(state.get_moderation().name_ban("Carol").is_none());
+
+        assert_eq!(registry.execute(&mut state, &Caller::Player(ids[2]), "/kick Alice"),
+                   Ok(Some("Kicked Alice".to_string())));
+        assert_eq!(registry.execute(&mut state, &Caller::Console, "/ban Carol"),
+                   Ok(Some("Banned Carol, kicked 0".to_string())));
+    }
+
+    #[test]
+    fn teleport_and_give() {
+        let registry = CommandRegistry::with_defaults();
+        let (mut state, ids) = world(&["Neikos"]);
+
+        assert!(registry.execute(&mut state, &Caller::Console, "/tp 1.5 -2 30 neikos").is_ok());
+        let position = Position::new(1.5, -2.0, 30.0);
+        assert_eq!(state.get_players()[&ids[0]].get_position(), position);
+        assert_eq!(sent(&mut state, ids[0]), vec![Packet::Teleport(position)]);
+
+        assert!(registry.execute(&mut state, &Caller::Console, "/give Neikos apple").is_ok());
+        assert_eq!(registry.execute(&mut state, &Caller::Console, "/give Neikos apple 4"),
+                   Ok(Some("Gave 4 apple to Neikos, who has 5 now".to_string())));
+        assert_eq!(state.get_players()[&ids[0]].get_inventory().get("apple"), Some(&5));
+    }
+
+    #[test]
+    fn time_of_day() {
+        let registry = CommandRegistry::with_defaults();
+        let (mut state, _) = world(&[]);
+
+        assert_eq!(registry.execute(&mut state, &Caller::Console, "time 18:45"),
+                   Ok(Some("It is now 18:45".to_string())));
+        assert_eq!(state.get_time_of_day(), (18, 45));
+        assert_eq!(registry.execute(&mut state, &Caller::Console, "/time 25:00"),
+                   Err(CommandError::InvalidArgument("HH:MM", ArgKind::Word, "25:00".to_string())));
+
+        // Six hours are a quarter of a day
+        state.advance_time(::worldstate::DAY_LENGTH_NS / 4);
+        assert_eq!(state.get_time_of_day(), (0, 45));
+    }
+}
@@ -0,0 +1,663 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::{u32, u64, usize};
+
+use toml::{Parser, Table, Value};
+
+use accounts::AccountError;
+use chat::ChatLimits;
+use moderation::ModerationError;
+use worldstate::WorldError;
+use permissions::{Role, Roles};
+use rpgserver::DuplicateLogin;
+use shared::net::{MAX_PACKET_SIZE, MAX_FRAME_SIZE};
+use shared::net::ReassemblyLimits;
+use shared::net::fragment::{FRAGMENT_OVERHEAD, MAX_MESSAGE_SIZE, MAX_UNFINISHED};
+
+/// The remote admin console refuses to start with a shorter password
+pub const MIN_RCON_PASSWORD_LENGTH: usize = 8;
+
+/// More threads than this would not serve the connections any better
+pub const MAX_NETWORK_THREADS: usize = 64;
+
+/// Longest timeout or interval accepted, so it still fits in nanoseconds
+/// when added to the clock
+pub const MAX_DURATION_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+/// Everything that can be tuned about a `RpgServer`
+///
+/// Usually loaded from a TOML file, every key is optional:
+///
+/// ```toml
+/// bind_address = "0.0.0.0:7777"
+/// tick_rate = 60
+/// max_players = 32
+/// max_packet_size = 1024
+/// max_message_size = 1048576
+/// reassembly_memory = 4194304
+/// reassembly_timeout_ms = 10000
+/// compression_threshold = 256
+/// network_threads = 2
+/// world_path = "world"
+/// motd = "Welcome!"
+/// connect_timeout_ms = 10000
+/// stop_timeout_ms = 5000
+/// ping_interval_ms = 5000
+/// idle_timeout_ms = 30000
+/// afk_timeout_ms = 900000
+/// duplicate_login = "kick_old"
+/// max_chat_length = 256
+/// chat_burst = 5
+/// chat_interval_ms = 1000
+/// rcon_address = "127.0.0.1:7778"
+/// rcon_password = "change me please"
+///
+/// # Adds a role, or changes one of guest, player, moderator and admin
+/// [roles.builder]
+/// inherits = "player"
+/// permissions = ["command.tp", "-moderation"]
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    /// Ticks per second of the server loop
+    pub tick_rate: u32,
+    pub max_players: usize,
+    /// Largest frame payload accepted from or sent to players
+    pub max_packet_size: usize,
+    /// Largest packet accepted from or sent to players, larger ones than
+    /// `max_packet_size` travel in fragments
+    pub max_message_size: usize,
+    /// Bytes a player may have the server hold for unfinished fragmented
+    /// packets
+    pub reassembly_memory: usize,
+    /// How long a fragmented packet may take to arrive completely
+    pub reassembly_timeout_ms: u64,
+    /// Frames with payloads of at least this many bytes get compressed,
+    /// both ways. Compression is off if `None`.
+    pub compression_threshold: Option<usize>,
+    /// How many threads serve the player connections
+    pub network_threads: usize,
+    /// Where persistent data lives, nothing is persisted if `None`
+    pub world_path: Option<PathBuf>,
+    /// Sent to every player after logging in, unless empty
+    pub motd: String,
+    /// How long a player may take to authenticate
+    pub connect_timeout_ms: u64,
+    /// How long `RpgServer::stop` waits for threads to exit
+    pub stop_timeout_ms: u64,
+    /// How often players get pinged
+    pub ping_interval_ms: u64,
+    /// Players that send nothing for this long, not even a `Pong`, are
+    /// dropped as if they disconnected
+    pub idle_timeout_ms: u64,
+    /// Players that don't play for this long get kicked, 0 never does
+    pub afk_timeout_ms: u64,
+    pub duplicate_login: DuplicateLogin,
+    /// Longest chat message, in characters
+    pub max_chat_length: usize,
+    /// How many chat messages a player may send in a row
+    pub chat_burst: u32,
+    /// How long it takes until a player may send another one
+    pub chat_interval_ms: u64,
+    /// Where the remote admin console listens, it is off if `None`
+    pub rcon_address: Option<String>,
+    pub rcon_password: String,
+    /// What players may do, by the role of their account
+    pub roles: Roles,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// The file is not valid TOML
+    Parse(String),
+    /// A key has the wrong type or a value out of range
+    Invalid {
+        key: String,
+        reason: String,
+    },
+    Accounts(AccountError),
+    Moderation(ModerationError),
+    World(WorldError),
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Io(..) => "Could not read the configuration.",
+            ConfigError::Parse(..) => "The configuration is not valid TOML.",
+            ConfigError::Invalid { .. } => "The configuration contains an invalid value.",
+            ConfigError::Accounts(..) => "Could not open the account store.",
+            ConfigError::Moderation(..) => "Could not open the bans and whitelist.",
+            ConfigError::World(..) => "Could not load the world.",
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(fmt, "Could not read the configuration: {}", e),
+            ConfigError::Parse(ref e) => write!(fmt, "Could not parse the configuration: {}", e),
+            ConfigError::Invalid { ref key, ref reason } => {
+                write!(fmt, "Invalid value for '{}': {}", key, reason)
+            }
+            ConfigError::Accounts(ref e) => write!(fmt, "Could not open the account store: {}", e),
+            ConfigError::Moderation(ref e) => {
+                write!(fmt, "Could not open the bans and whitelist: {}", e)
+            }
+            ConfigError::World(ref e) => write!(fmt, "Could not load the world: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> ConfigError {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<AccountError> for ConfigError {
+    fn from(err: AccountError) -> ConfigError {
+        ConfigError::Accounts(err)
+    }
+}
+
+impl From<ModerationError> for ConfigError {
+    fn from(err: ModerationError) -> ConfigError {
+        ConfigError::Moderation(err)
+    }
+}
+
+impl From<WorldError> for ConfigError {
+    fn from(err: WorldError) -> ConfigError {
+        ConfigError::World(err)
+    }
+}
+
+fn invalid(key: &str, reason: &str) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind_address: "0.0.0.0:7777".to_string(),
+            tick_rate: 60,
+            max_players: 32,
+            max_packet_size: MAX_PACKET_SIZE,
+            max_message_size: MAX_MESSAGE_SIZE,
+            reassembly_memory: 4 * MAX_MESSAGE_SIZE,
+            reassembly_timeout_ms: 10 * 1000,
+            compression_threshold: None,
+            network_threads: 2,
+            world_path: None,
+            motd: String::new(),
+            connect_timeout_ms: 10 * 1000,
+            stop_timeout_ms: 5 * 1000,
+            ping_interval_ms: 5 * 1000,
+            idle_timeout_ms: 30 * 1000,
+            afk_timeout_ms: 0,
+            duplicate_login: DuplicateLogin::KickOld,
+            max_chat_length: 256,
+            chat_burst: 5,
+            chat_interval_ms: 1000,
+            rcon_address: None,
+            rcon_password: String::new(),
+            roles: Roles::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads and validates a TOML configuration file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ServerConfig, ConfigError> {
+        let mut contents = String::new();
+        let mut file = try!(File::open(path));
+        try!(file.read_to_string(&mut contents));
+        ServerConfig::parse(&contents)
+    }
+
+    /// Parses and validates a TOML configuration
+    ///
+    /// Missing keys take their default value, unknown keys are an error so
+    /// that typos don't go unnoticed.
+    pub fn parse(toml: &str) -> Result<ServerConfig, ConfigError> {
+        let mut parser = Parser::new(toml);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let messages: Vec<String> = parser.errors.iter().map(|e| {
+                    let (line, col) = parser.to_linecol(e.lo);
+                    format!("{}:{}: {}", line + 1, col + 1, e.desc)
+                }).collect();
+                return Err(ConfigError::Parse(messages.join(", ")));
+            }
+        };
+
+        let mut config = ServerConfig::default();
+
+        for key in table.keys() {
+            match &key[..] {
+                "bind_address" | "tick_rate" | "max_players" | "max_packet_size" |
+                "max_message_size" | "reassembly_memory" | "reassembly_timeout_ms" |
+                "compression_threshold" |
+                "network_threads" | "world_path" | "motd" | "connect_timeout_ms" | "stop_timeout_ms" |
+                "ping_interval_ms" | "idle_timeout_ms" | "afk_timeout_ms" |
+                "duplicate_login" | "max_chat_length" | "chat_burst" | "chat_interval_ms" |
+                "rcon_address" | "rcon_password" | "roles" => (),
+                _ => return Err(invalid(key, "unknown key"))
+            }
+        }
+
+        if let Some(v) = try!(get_str(&table, "bind_address")) {
+            config.bind_address = v.to_string();
+        }
+        if let Some(v) = try!(get_int(&table, "tick_rate", u32::MAX as u64)) {
+            config.tick_rate = v as u32;
+        }
+        if let Some(v) = try!(get_int(&table, "max_players", usize::MAX as u64)) {
+            config.max_players = v as usize;
+        }
+        if let Some(v) = try!(get_int(&table, "max_packet_size", usize::MAX as u64)) {
+            config.max_packet_size = v as usize;
+        }
+        if let Some(v) = try!(get_int(&table, "max_message_size", usize::MAX as u64)) {
+            config.max_message_size = v as usize;
+        }
+        if let Some(v) = try!(get_int(&table, "reassembly_memory", usize::MAX as u64)) {
+            config.reassembly_memory = v as usize;
+        }
+        if let Some(v) = try!(get_int(&table, "reassembly_timeout_ms", MAX_DURATION_MS)) {
+            config.reassembly_timeout_ms = v;
+        }
+        // It goes to the client in the `Welcome`, as a u32
+        if let Some(v) = try!(get_int(&table, "compression_threshold", u32::MAX as u64)) {
+            config.compression_threshold = Some(v as usize);
+        }
+        if let Some(v) = try!(get_int(&table, "network_threads", usize::MAX as u64)) {
+            config.network_threads = v as usize;
+        }
+        if let Some(v) = try!(get_str(&table, "world_path")) {
+            config.world_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = try!(get_str(&table, "motd")) {
+            config.motd = v.to_string();
+        }
+        if let Some(v) = try!(get_int(&table, "connect_timeout_ms", MAX_DURATION_MS)) {
+            config.connect_timeout_ms = v;
+        }
+        if let Some(v) = try!(get_int(&table, "stop_timeout_ms", MAX_DURATION_MS)) {
+            config.stop_timeout_ms = v;
+        }
+        if let Some(v) = try!(get_int(&table, "ping_interval_ms", MAX_DURATION_MS)) {
+            config.ping_interval_ms = v;
+        }
+        if let Some(v) = try!(get_int(&table, "idle_timeout_ms", MAX_DURATION_MS)) {
+            config.idle_timeout_ms = v;
+        }
+        if let Some(v) = try!(get_int(&table, "afk_timeout_ms", MAX_DURATION_MS)) {
+            config.afk_timeout_ms = v;
+        }
+        if let Some(v) = try!(get_str(&table, "duplicate_login")) {
+            config.duplicate_login = match v {
+                "kick_old" => DuplicateLogin::KickOld,
+                "refuse_new" => DuplicateLogin::RefuseNew,
+                _ => return Err(invalid("duplicate_login", "expected kick_old or refuse_new"))
+            };
+        }
+        if let Some(v) = try!(get_int(&table, "max_chat_length", usize::MAX as u64)) {
+            config.max_chat_length = v as usize;
+        }
+        if let Some(v) = try!(get_int(&table, "chat_burst", u32::MAX as u64)) {
+            config.chat_burst = v as u32;
+        }
+        if let Some(v) = try!(get_int(&table, "chat_interval_ms", MAX_DURATION_MS)) {
+            config.chat_interval_ms = v;
+        }
+        if let Some(v) = try!(get_str(&table, "rcon_address")) {
+            config.rcon_address = Some(v.to_string());
+        }
+        if let Some(v) = try!(get_str(&table, "rcon_password")) {
+            config.rcon_password = v.to_string();
+        }
+        match table.get("roles") {
+            Some(&Value::Table(ref roles)) => {
+                for (name, role) in roles.iter() {
+                    config.roles.set(name.clone(), try!(parse_role(name, role)));
+                }
+            }
+            Some(_) => return Err(invalid("roles", "expected a table")),
+            None => ()
+        }
+
+        try!(config.validate());
+        Ok(config)
+    }
+
+    /// Checks that all values are in range
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.bind_address.is_empty() {
+            return Err(invalid("bind_address", "may not be empty"));
+        }
+        if self.tick_rate == 0 || self.tick_rate > 1000 {
+            return Err(invalid("tick_rate", "must be between 1 and 1000"));
+        }
+        if self.max_players == 0 {
+            return Err(invalid("max_players", "must be at least 1"));
+        }
+        if self.max_packet_size < 64 || self.max_packet_size > MAX_FRAME_SIZE {
+            return Err(invalid("max_packet_size",
+                               &format!("must be between 64 and {}", MAX_FRAME_SIZE)));
+        }
+        if self.max_message_size < self.max_packet_size {
+            return Err(invalid("max_message_size", "must be at least max_packet_size"));
+        }
+        // Keeping track of the fragments takes some memory on top of them
+        if self.reassembly_memory / 2 < self.max_message_size {
+            return Err(invalid("reassembly_memory", "must be at least twice max_message_size"));
+        }
+        if self.reassembly_timeout_ms == 0 {
+            return Err(invalid("reassembly_timeout_ms", "must be at least 1"));
+        }
+        if self.network_threads == 0 || self.network_threads > MAX_NETWORK_THREADS {
+            return Err(invalid("network_threads",
+                               &format!("must be between 1 and {}", MAX_NETWORK_THREADS)));
+        }
+        if self.connect_timeout_ms == 0 {
+            return Err(invalid("connect_timeout_ms", "must be at least 1"));
+        }
+        if self.ping_interval_ms == 0 {
+            return Err(invalid("ping_interval_ms", "must be at least 1"));
+        }
+        if self.idle_timeout_ms <= self.ping_interval_ms {
+            return Err(invalid("idle_timeout_ms", "must be longer than ping_interval_ms"));
+        }
+        for &(key, ms) in [("reassembly_timeout_ms", self.reassembly_timeout_ms),
+                           ("connect_timeout_ms", self.connect_timeout_ms),
+                           ("stop_timeout_ms", self.stop_timeout_ms),
+                           ("ping_interval_ms", self.ping_interval_ms),
+                           ("idle_timeout_ms", self.idle_timeout_ms),
+                           ("afk_timeout_ms", self.afk_timeout_ms),
+                           ("chat_interval_ms", self.chat_interval_ms)].iter() {
+            if ms > MAX_DURATION_MS {
+                return Err(invalid(key, &format!("must be at most {}", MAX_DURATION_MS)));
+            }
+        }
+        if self.max_chat_length == 0 {
+            return Err(invalid("max_chat_length", "must be at least 1"));
+        }
+        if self.chat_burst == 0 {
+            return Err(invalid("chat_burst", "must be at least 1"));
+        }
+        if self.rcon_address.is_some() && self.rcon_password.len() < MIN_RCON_PASSWORD_LENGTH {
+            return Err(invalid("rcon_password",
+                               &format!("needs at least {} characters when rcon_address is set",
+                                        MIN_RCON_PASSWORD_LENGTH)));
+        }
+        if let Err((role, reason)) = self.roles.validate() {
+            return Err(invalid(&format!("roles.{}", role), &reason));
+        }
+        Ok(())
+    }
+
+    /// Length of a single tick in nanoseconds
+    pub fn tick_ns(&self) -> u64 {
+        1000000000 / self.tick_rate as u64
+    }
+
+    pub fn chat_limits(&self) -> ChatLimits {
+        ChatLimits {
+            max_length: self.max_chat_length,
+            burst: self.chat_burst,
+            interval_ns: ms_to_ns(self.chat_interval_ms),
+        }
+    }
+
+    pub fn reassembly_limits(&self) -> ReassemblyLimits {
+        ReassemblyLimits {
+            max_message_size: self.max_message_size,
+            max_fragment_data: self.max_packet_size - FRAGMENT_OVERHEAD,
+            max_unfinished: MAX_UNFINISHED,
+            max_buffered: self.reassembly_memory,
+            timeout_ms: self.reassembly_timeout_ms,
+        }
+    }
+
+    /// Where accounts are stored, if anywhere
+    pub fn accounts_path(&self) -> Option<PathBuf> {
+        self.world_path.as_ref().map(|p| p.join("accounts.json"))
+    }
+
+    /// Where bans and the whitelist are stored, if anywhere
+    pub fn moderation_path(&self) -> Option<PathBuf> {
+        self.world_path.as_ref().map(|p| p.join("moderation.json"))
+    }
+
+    /// Where the world itself is saved, if anywhere
+    pub fn world_state_path(&self) -> Option<PathBuf> {
+        self.world_path.as_ref().map(|p| p.join("world.json"))
+    }
+
+    /// Where the key the server proves who it is with is stored, if anywhere
+    ///
+    /// Without one, the server makes up a new key every time it starts and
+    /// clients that connected before won't trust it.
+    pub fn identity_path(&self) -> Option<PathBuf> {
+        self.world_path.as_ref().map(|p| p.join("identity.key"))
+    }
+
+    /// Where remote admin sessions are logged, besides stdout
+    pub fn audit_log_path(&self) -> Option<PathBuf> {
+        self.world_path.as_ref().map(|p| p.join("audit.log"))
+    }
+}
+
+/// Converts a duration from the config to nanoseconds
+///
+/// Loaded values are at most `MAX_DURATION_MS`, anything longer set by hand
+/// is treated as forever.
+pub fn ms_to_ns(ms: u64) -> u64 {
+    ms.checked_mul(1000000).unwrap_or(u64::MAX)
+}
+
+fn get_str<'a>(table: &'a Table, key: &str) -> Result<Option<&'a str>, ConfigError> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(&Value::String(ref s)) => Ok(Some(&s[..])),
+        Some(_) => Err(invalid(key, "expected a string"))
+    }
+}
+
+/// Reads a non-negative integer that is at most `max`
+///
+/// This way callers can cast to the type of their field without it
+/// wrapping around.
+fn get_int(table: &Table, key: &str, max: u64) -> Result<Option<u64>, ConfigError> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(&Value::Integer(i)) if i < 0 => Err(invalid(key, "may not be negative")),
+        Some(&Value::Integer(i)) if i as u64 > max => {
+            Err(invalid(key, &format!("must be at most {}", max)))
+        }
+        Some(&Value::Integer(i)) => Ok(Some(i as u64)),
+        Some(_) => Err(invalid(key, "expected an integer"))
+    }
+}
+
+fn parse_role(name: &str, value: &Value) -> Result<Role, ConfigError> {
+    let key = format!("roles.{}", name);
+    let table = match *value {
+        Value::Table(ref table) => table,
+        _ => return Err(invalid(&key, "expected a table"))
+    };
+    for field in table.keys() {
+        if field != "inherits" && field != "permissions" {
+            return Err(invalid(&format!("{}.{}", key, field), "unknown key"));
+        }
+    }
+
+    let parent = try!(get_str(table, "inherits")).map(|p| p.to_string());
+    let permissions = match table.get("permissions") {
+        None => Vec::new(),
+        Some(&Value::Array(ref nodes)) => {
+            let mut permissions = Vec::with_capacity(nodes.len());
+            for node in nodes.iter() {
+                match *node {
+                    Value::String(ref node) => permissions.push(node.clone()),
+                    _ => return Err(invalid(&format!("{}.permissions", key), "expected strings"))
+                }
+            }
+            permissions
+        }
+        Some(_) => return Err(invalid(&format!("{}.permissions", key), "expected an array"))
+    };
+
+    Ok(Role {
+        parent: parent,
+        permissions: permissions,
+    })
+}
+
+mod tests {
+    use super::*;
+    use rpgserver::DuplicateLogin;
+    use std::path::PathBuf;
+
+    #[test]
+    fn defaults() {
+        let config = ServerConfig::parse("").unwrap();
+        assert_eq!(config, ServerConfig::default());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn full() {
+        let config = ServerConfig::parse(r#"
+            bind_address = "127.0.0.1:1234"
+            tick_rate = 20
+            max_players = 4
+            max_packet_size = 2048
+            max_message_size = 65536
+            reassembly_memory = 131072
+            reassembly_timeout_ms = 3000
+            compression_threshold = 128
+            network_threads = 4
+            world_path = "my_world"
+            motd = "Hello there"
+            connect_timeout_ms = 500
+            stop_timeout_ms = 100
+            ping_interval_ms = 1000
+            idle_timeout_ms = 4000
+            afk_timeout_ms = 60000
+            duplicate_login = "refuse_new"
+            max_chat_length = 100
+            chat_burst = 3
+            chat_interval_ms = 2000
+            rcon_address = "127.0.0.1:1235"
+            rcon_password = "correct horse"
+
+            [roles.builder]
+            inherits = "player"
+            permissions = ["command.tp"]
+        "#).unwrap();
+
+        assert_eq!(config.bind_address, "127.0.0.1:1234");
+        assert_eq!(config.tick_rate, 20);
+        assert_eq!(config.tick_ns(), 50000000);
+        assert_eq!(config.max_players, 4);
+        assert_eq!(config.max_packet_size, 2048);
+        assert_eq!(config.reassembly_limits().max_message_size, 65536);
+        assert_eq!(config.reassembly_limits().max_buffered, 131072);
+        assert_eq!(config.reassembly_limits().timeout_ms, 3000);
+        assert_eq!(config.compression_threshold, Some(128));
+        assert_eq!(config.network_threads, 4);
+        assert_eq!(config.world_path, Some(PathBuf::from("my_world")));
+        assert_eq!(config.motd, "Hello there");
+        assert_eq!(config.connect_timeout_ms, 500);
+        assert_eq!(config.stop_timeout_ms, 100);
+        assert_eq!(config.ping_interval_ms, 1000);
+        assert_eq!(config.idle_timeout_ms, 4000);
+        assert_eq!(config.afk_timeout_ms, 60000);
+        assert_eq!(config.duplicate_login, DuplicateLogin::RefuseNew);
+        assert_eq!(config.max_chat_length, 100);
+        assert_eq!(config.chat_limits().burst, 3);
+        assert_eq!(config.chat_limits().interval_ns, 2000000000);
+        assert_eq!(config.rcon_address, Some("127.0.0.1:1235".to_string()));
+        assert_eq!(config.rcon_password, "correct horse");
+        assert!(config.roles.resolve("builder", &[]).has("command.tp"));
+        assert_eq!(config.roles.get("builder").unwrap().parent, Some("player".to_string()));
+        assert!(config.roles.get("admin").is_some());
+    }
+
+    #[test]
+    fn errors() {
+        match ServerConfig::parse("tick_rate = ") {
+            Err(ConfigError::Parse(_)) => (),
+            other => panic!("Expected Parse, got {:?}", other),
+        }
+        match ServerConfig::parse("tick_rate = \"fast\"") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "tick_rate" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("tick_rate = 0") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "tick_rate" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("ping_interval_ms = 5000\nidle_timeout_ms = 5000") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "idle_timeout_ms" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("max_packet_size = 100000") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "max_packet_size" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("max_message_size = 100") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "max_message_size" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("max_message_size = 65536\nreassembly_memory = 65536") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "reassembly_memory" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("chat_burst = 4294967296") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "chat_burst" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("compression_threshold = 4294967296") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "compression_threshold" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("idle_timeout_ms = 18446744073709") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "idle_timeout_ms" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("network_threads = 0") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "network_threads" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("rcon_address = \"127.0.0.1:1235\"") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "rcon_password" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("[roles.builder]\ninherits = \"nobody\"") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "roles.builder" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("[roles.builder]\npermissions = [1]") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "roles.builder.permissions" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+        match ServerConfig::parse("max_player = 3") {
+            Err(ConfigError::Invalid { ref key, .. }) if key == "max_player" => (),
+            other => panic!("Expected Invalid, got {:?}", other),
+        }
+    }
+}
@@ -2,13 +2,36 @@
 #![feature(ip_addr)]
 
 extern crate shared;
+extern crate clock_ticks;
+extern crate crypto;
+extern crate rustc_serialize;
+extern crate toml;
+#[cfg(unix)]
+extern crate libc;
 
 mod player;
+mod reactor;
+mod worker;
+mod rcon;
 pub mod worldstate;
+pub mod accounts;
+pub mod names;
+pub mod config;
+pub mod moderation;
+pub mod permissions;
+pub mod netstats;
+pub mod chat;
+pub mod commands;
+pub mod admin;
 
 pub mod servermessage;
 pub mod rpgserver;
 
-pub use rpgserver::{RpgServer, ServerStatus};
-pub use player::Player;
+pub use rpgserver::{RpgServer, ServerStatus, DuplicateLogin, StopError};
+pub use player::{Player, PlayerStatus};
 pub use worldstate::WorldState;
+pub use accounts::{AccountStore, AccountError};
+pub use config::{ServerConfig, ConfigError};
+pub use moderation::{Moderation, ModerationError};
+pub use admin::{AdminCommand, AdminHandle};
+pub use commands::{Caller, CommandRegistry};
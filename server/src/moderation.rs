@@ -0,0 +1,371 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use clock_ticks::precise_time_ns;
+use rustc_serialize::json;
+
+use names::name_key;
+
+/// Who a `Ban` applies to
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub enum BanTarget {
+    /// The `name_key` of an account
+    Name(String),
+    /// An IP address, as displayed
+    Ip(String),
+}
+
+impl BanTarget {
+    pub fn name(name: &str) -> BanTarget {
+        BanTarget::Name(name_key(name))
+    }
+
+    pub fn ip(ip: IpAddr) -> BanTarget {
+        BanTarget::Ip(format!("{}", ip))
+    }
+
+    /// Whether a player with this name and address is affected
+    pub fn matches(&self, name: Option<&str>, ip: IpAddr) -> bool {
+        match *self {
+            BanTarget::Name(ref key) => name.map_or(false, |n| name_key(n) == *key),
+            BanTarget::Ip(ref banned) => format!("{}", ip) == *banned,
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BanTarget::Name(ref name) => write!(fmt, "{}", name),
+            BanTarget::Ip(ref ip) => write!(fmt, "IP {}", ip),
+        }
+    }
+}
+
+#[derive(RustcEncodable, RustcDecodable, Clone, Debug, PartialEq)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    /// Unix timestamp in seconds, permanent if `None`
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    pub fn is_active(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expiry) => now < expiry,
+            None => true
+        }
+    }
+
+    /// What the banned player gets told
+    pub fn message(&self, now: u64) -> String {
+        match self.expires_at {
+            Some(expiry) => format!("You are banned: {} ({} left)", self.reason,
+                                    format_duration(expiry.saturating_sub(now))),
+            None => format!("You are banned: {}", self.reason),
+        }
+    }
+}
+
+/// Everything that is written to disk
+#[derive(RustcEncodable, RustcDecodable, Default)]
+struct Stored {
+    bans: Vec<Ban>,
+    whitelist_enabled: bool,
+    /// `name_key`s of the whitelisted accounts
+    whitelist: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ModerationError {
+    Io(io::Error),
+    /// The moderation file could not be parsed
+    Corrupt(String),
+}
+
+impl Error for ModerationError {
+    fn description(&self) -> &str {
+        match *self {
+            ModerationError::Io(..) => "Could not access the moderation file.",
+            ModerationError::Corrupt(..) => "The moderation file is corrupt.",
+        }
+    }
+}
+
+impl fmt::Display for ModerationError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ModerationError::Io(ref e) => write!(fmt, "Could not access the moderation file: {}", e),
+            ModerationError::Corrupt(ref e) => write!(fmt, "The moderation file is corrupt: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ModerationError {
+    fn from(err: io::Error) -> ModerationError {
+        ModerationError::Io(err)
+    }
+}
+
+/// The current time as a Unix timestamp in seconds
+pub fn now() -> u64 {
+    precise_time_ns() / 1000000000
+}
+
+/// Formats a number of seconds by its largest unit, like `3h`
+pub fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 86400 => format!("{}d", s / 86400),
+        s if s >= 3600 => format!("{}h", s / 3600),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// Bans and the whitelist, optionally backed by a JSON file
+///
+/// Every change is written back right away. Expired bans are kept until
+/// the next change, they are simply ignored.
+pub struct Moderation {
+    path: Option<PathBuf>,
+    bans: Vec<Ban>,
+    whitelist_enabled: bool,
+    whitelist: HashSet<String>,
+}
+
+impl Moderation {
+    /// Bans and a whitelist that are forgotten once dropped
+    pub fn in_memory() -> Moderation {
+        Moderation::from_stored(None, Stored::default())
+    }
+
+    /// Loads everything from `path`, a missing file means nobody is banned
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Moderation, ModerationError> {
+        let path = path.as_ref().to_path_buf();
+
+        let stored = match File::open(&path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                match json::decode(&contents) {
+                    Ok(s) => s,
+                    Err(e) => return Err(ModerationError::Corrupt(format!("{}", e)))
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Stored::default(),
+            Err(e) => return Err(ModerationError::Io(e))
+        };
+
+        Ok(Moderation::from_stored(Some(path), stored))
+    }
+
+    fn from_stored(path: Option<PathBuf>, stored: Stored) -> Moderation {
+        Moderation {
+            path: path,
+            bans: stored.bans,
+            whitelist_enabled: stored.whitelist_enabled,
+            whitelist: stored.whitelist.into_iter().collect(),
+        }
+    }
+
+    /// Bans `target`, replacing any earlier ban of it
+    pub fn ban(&mut self, target: BanTarget, reason: String, expires_at: Option<u64>)
+        -> Result<(), ModerationError> {
+        let now = now();
+        self.bans.retain(|b| b.target != target && b.is_active(now));
+        self.bans.push(Ban {
+            target: target,
+            reason: reason,
+            expires_at: expires_at,
+        });
+        self.save()
+    }
+
+    /// Lifts the ban of `target`, returns whether there was one
+    pub fn unban(&mut self, target: &BanTarget) -> Result<bool, ModerationError> {
+        let before = self.bans.len();
+        self.bans.retain(|b| b.target != *target);
+        if self.bans.len() == before {
+            return Ok(false);
+        }
+        try!(self.save());
+        Ok(true)
+    }
+
+    /// The ban in effect for `target`, if any
+    pub fn find_ban(&self, target: &BanTarget) -> Option<&Ban> {
+        let now = now();
+        self.bans.iter().find(|b| b.target == *target && b.is_active(now))
+    }
+
+    pub fn name_ban(&self, name: &str) -> Option<&Ban> {
+        self.find_ban(&BanTarget::name(name))
+    }
+
+    pub fn ip_ban(&self, ip: IpAddr) -> Option<&Ban> {
+        self.find_ban(&BanTarget::ip(ip))
+    }
+
+    /// Every ban still in effect
+    pub fn active_bans(&self) -> Vec<&Ban> {
+        let now = now();
+        self.bans.iter().filter(|b| b.is_active(now)).collect()
+    }
+
+    /// Whether only whitelisted accounts may log in
+    pub fn whitelist_enabled(&self) -> bool {
+        self.whitelist_enabled
+    }
+
+    pub fn set_whitelist_enabled(&mut self, enabled: bool) -> Result<(), ModerationError> {
+        self.whitelist_enabled = enabled;
+        self.save()
+    }
+
+    /// Returns false if the name was whitelisted already
+    pub fn whitelist_add(&mut self, name: &str) -> Result<bool, ModerationError> {
+        if !self.whitelist.insert(name_key(name)) {
+            return Ok(false);
+        }
+        try!(self.save());
+        Ok(true)
+    }
+
+    /// Returns false if the name was not whitelisted
+    pub fn whitelist_remove(&mut self, name: &str) -> Result<bool, ModerationError> {
+        if !self.whitelist.remove(&name_key(name)) {
+            return Ok(false);
+        }
+        try!(self.save());
+        Ok(true)
+    }
+
+    /// Whether `name` may log in, always true without whitelist mode
+    pub fn is_whitelisted(&self, name: &str) -> bool {
+        !self.whitelist_enabled || self.whitelist.contains(&name_key(name))
+    }
+
+    /// The whitelisted `name_key`s, sorted
+    pub fn whitelist(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.whitelist.iter().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Writes everything to disk, if there is a file
+    ///
+    /// Like the accounts, the file is replaced atomically.
+    fn save(&self) -> Result<(), ModerationError> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(())
+        };
+
+        let stored = Stored {
+            bans: self.bans.clone(),
+            whitelist_enabled: self.whitelist_enabled,
+            whitelist: self.whitelist(),
+        };
+        let encoded = match json::encode(&stored) {
+            Ok(e) => e,
+            Err(e) => return Err(ModerationError::Corrupt(format!("{}", e)))
+        };
+
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(encoded.as_bytes()));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&tmp, path));
+        Ok(())
+    }
+}
+
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn localhost() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+    }
+
+    #[test]
+    fn bans() {
+        let mut moderation = Moderation::in_memory();
+        moderation.ban(BanTarget::name("Neikos"), "spam".to_string(), None).unwrap();
+        moderation.ban(BanTarget::ip(localhost()), "more spam".to_string(), None).unwrap();
+
+        assert_eq!(moderation.name_ban("NEIKOS").unwrap().reason, "spam");
+        assert_eq!(moderation.ip_ban(localhost()).unwrap().reason, "more spam");
+        assert!(moderation.name_ban("Other").is_none());
+
+        assert!(moderation.unban(&BanTarget::name("neikos")).unwrap());
+        assert!(!moderation.unban(&BanTarget::name("neikos")).unwrap());
+        assert!(moderation.name_ban("Neikos").is_none());
+        assert_eq!(moderation.active_bans().len(), 1);
+    }
+
+    #[test]
+    fn temporary_bans() {
+        let mut moderation = Moderation::in_memory();
+        let now = now();
+        moderation.ban(BanTarget::name("Past"), "old".to_string(), Some(now - 1)).unwrap();
+        moderation.ban(BanTarget::name("Future"), "new".to_string(), Some(now + 7200)).unwrap();
+
+        assert!(moderation.name_ban("Past").is_none());
+        let ban = moderation.name_ban("Future").unwrap();
+        assert!(ban.message(now).contains("2h left"));
+    }
+
+    #[test]
+    fn whitelist() {
+        let mut moderation = Moderation::in_memory();
+        assert!(moderation.is_whitelisted("Neikos"));
+
+        moderation.set_whitelist_enabled(true).unwrap();
+        assert!(!moderation.is_whitelisted("Neikos"));
+        assert!(moderation.whitelist_add("Neikos").unwrap());
+        assert!(!moderation.whitelist_add("neikos").unwrap());
+        assert!(moderation.is_whitelisted("NEIKOS"));
+
+        assert!(moderation.whitelist_remove("Neikos").unwrap());
+        assert!(!moderation.is_whitelisted("Neikos"));
+    }
+
+    #[test]
+    fn persistence() {
+        let path = env::temp_dir().join("rpg_moderation_test.json");
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut moderation = Moderation::open(&path).unwrap();
+            moderation.ban(BanTarget::ip(localhost()), "spam".to_string(), None).unwrap();
+            moderation.set_whitelist_enabled(true).unwrap();
+            moderation.whitelist_add("Neikos").unwrap();
+        }
+
+        let moderation = Moderation::open(&path).unwrap();
+        assert!(moderation.ip_ban(localhost()).is_some());
+        assert!(moderation.whitelist_enabled());
+        assert!(moderation.is_whitelisted("Neikos"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(30), "30s");
+        assert_eq!(format_duration(600), "10m");
+        assert_eq!(format_duration(7200), "2h");
+        assert_eq!(format_duration(3 * 86400 + 5), "3d");
+    }
+}
@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::fmt;
+
+pub const MIN_NAME_LENGTH: usize = 3;
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// Names nobody may play as, compared case-insensitively
+pub const RESERVED_NAMES: &'static [&'static str] = &[
+    "admin", "administrator", "console", "moderator", "server", "system",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter(char),
+    Reserved,
+}
+
+impl Error for NameError {
+    fn description(&self) -> &str {
+        match *self {
+            NameError::TooShort => "The name is too short.",
+            NameError::TooLong => "The name is too long.",
+            NameError::InvalidCharacter(..) => "The name contains an invalid character.",
+            NameError::Reserved => "The name is reserved.",
+        }
+    }
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NameError::TooShort => {
+                write!(fmt, "The name needs at least {} characters.", MIN_NAME_LENGTH)
+            }
+            NameError::TooLong => {
+                write!(fmt, "The name may have at most {} characters.", MAX_NAME_LENGTH)
+            }
+            NameError::InvalidCharacter(c) => {
+                write!(fmt, "The name may not contain '{}'.", c)
+            }
+            ref e => e.description().fmt(fmt)
+        }
+    }
+}
+
+/// Checks whether `name` may be used as a player name
+///
+/// Names are ASCII letters, digits and underscores only, so that two
+/// names can't look the same while being different.
+pub fn validate_name(name: &str) -> Result<(), NameError> {
+    let len = name.chars().count();
+    if len < MIN_NAME_LENGTH {
+        return Err(NameError::TooShort);
+    }
+    if len > MAX_NAME_LENGTH {
+        return Err(NameError::TooLong);
+    }
+
+    if let Some(c) = name.chars().find(|c| !is_name_char(*c)) {
+        return Err(NameError::InvalidCharacter(c));
+    }
+
+    let key = name_key(name);
+    if RESERVED_NAMES.iter().any(|r| *r == key) {
+        return Err(NameError::Reserved);
+    }
+
+    Ok(())
+}
+
+fn is_name_char(c: char) -> bool {
+    match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => true,
+        _ => false
+    }
+}
+
+/// The form names are compared and indexed in
+///
+/// "Neikos" and "neikos" are the same player.
+pub fn name_key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_names() {
+        assert_eq!(validate_name("Neikos"), Ok(()));
+        assert_eq!(validate_name("the_2nd"), Ok(()));
+    }
+
+    #[test]
+    fn invalid_names() {
+        assert_eq!(validate_name("ab"), Err(NameError::TooShort));
+        assert_eq!(validate_name("abcdefghijklmnopq"), Err(NameError::TooLong));
+        assert_eq!(validate_name("Nei kos"), Err(NameError::InvalidCharacter(' ')));
+        assert_eq!(validate_name("Néikos"), Err(NameError::InvalidCharacter('é')));
+        assert_eq!(validate_name("Admin"), Err(NameError::Reserved));
+    }
+}
@@ -0,0 +1,231 @@
+//! Traffic and latency of the player connections
+//!
+//! Bytes are counted where they pass through the socket, by wrapping it
+//! in a `Metered`. The network threads count along with whoever sends, so the
+//! counters are atomic.
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use shared::net::CompressionStats;
+
+/// The counters of a single connection
+pub struct NetStats {
+    bytes_sent: AtomicUsize,
+    bytes_received: AtomicUsize,
+    packets_sent: AtomicUsize,
+    packets_received: AtomicUsize,
+    /// Frames that arrived whole, but did not decode to a `Packet`
+    decode_errors: AtomicUsize,
+    /// Frame payloads before and after compression, zero unless the
+    /// connection compresses
+    raw_sent: AtomicUsize,
+    compressed_sent: AtomicUsize,
+    raw_received: AtomicUsize,
+    compressed_received: AtomicUsize,
+}
+
+impl NetStats {
+    pub fn new() -> NetStats {
+        NetStats {
+            bytes_sent: AtomicUsize::new(0),
+            bytes_received: AtomicUsize::new(0),
+            packets_sent: AtomicUsize::new(0),
+            packets_received: AtomicUsize::new(0),
+            decode_errors: AtomicUsize::new(0),
+            raw_sent: AtomicUsize::new(0),
+            compressed_sent: AtomicUsize::new(0),
+            raw_received: AtomicUsize::new(0),
+            compressed_received: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn packet_sent(&self) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_received(&self) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a decode error, returns how many there were so far
+    pub fn decode_error(&self) -> usize {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Takes over the totals of the encoder
+    pub fn compression_sent(&self, stats: CompressionStats) {
+        self.raw_sent.store(stats.raw as usize, Ordering::Relaxed);
+        self.compressed_sent.store(stats.wire as usize, Ordering::Relaxed);
+    }
+
+    /// Takes over the totals of the decoder
+    pub fn compression_received(&self, stats: CompressionStats) {
+        self.raw_received.store(stats.raw as usize, Ordering::Relaxed);
+        self.compressed_received.store(stats.wire as usize, Ordering::Relaxed);
+    }
+}
+
+/// Round trip times of a connection, in nanoseconds
+///
+/// The smoothed value follows every new sample by an eighth, like the
+/// estimate TCP keeps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+    pub last: u64,
+    pub smoothed: u64,
+}
+
+impl Latency {
+    pub fn first(sample: u64) -> Latency {
+        Latency {
+            last: sample,
+            smoothed: sample,
+        }
+    }
+
+    pub fn update(&mut self, sample: u64) {
+        self.last = sample;
+        self.smoothed = (self.smoothed * 7 + sample) / 8;
+    }
+
+    /// The smoothed round trip time in milliseconds
+    pub fn ms(&self) -> u32 {
+        (self.smoothed / 1000000) as u32
+    }
+}
+
+/// Everything known about a connection at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetReport {
+    pub latency: Option<Latency>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub decode_errors: u64,
+    pub compression_sent: CompressionStats,
+    pub compression_received: CompressionStats,
+    /// Nanoseconds since the last packet arrived
+    pub idle_ns: u64,
+}
+
+impl NetReport {
+    pub fn new(stats: &NetStats, latency: Option<Latency>, idle_ns: u64) -> NetReport {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed) as u64;
+        NetReport {
+            latency: latency,
+            bytes_sent: load(&stats.bytes_sent),
+            bytes_received: load(&stats.bytes_received),
+            packets_sent: load(&stats.packets_sent),
+            packets_received: load(&stats.packets_received),
+            decode_errors: load(&stats.decode_errors),
+            compression_sent: CompressionStats {
+                raw: load(&stats.raw_sent),
+                wire: load(&stats.compressed_sent),
+            },
+            compression_received: CompressionStats {
+                raw: load(&stats.raw_received),
+                wire: load(&stats.compressed_received),
+            },
+            idle_ns: idle_ns,
+        }
+    }
+
+    /// The smoothed round trip time in milliseconds, if there was a pong yet
+    pub fn ping_ms(&self) -> Option<u32> {
+        self.latency.map(|l| l.ms())
+    }
+}
+
+/// Counts every byte read from or written to `inner`
+pub struct Metered<'a, S> {
+    inner: S,
+    stats: &'a NetStats,
+}
+
+impl<'a, S> Metered<'a, S> {
+    pub fn new(inner: S, stats: &'a NetStats) -> Metered<'a, S> {
+        Metered {
+            inner: inner,
+            stats: stats,
+        }
+    }
+}
+
+impl<'a, S: Read> Read for Metered<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+        self.stats.bytes_received.fetch_add(read, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl<'a, S: Write> Write for Metered<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        self.stats.bytes_sent.fetch_add(written, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Formats a byte count with a binary unit, like `2.5 KiB`
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
+
+mod tests {
+    use super::*;
+    use shared::net::CompressionStats;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn counts_bytes() {
+        let stats = NetStats::new();
+        let mut out = Vec::new();
+        Metered::new(&mut out, &stats).write_all(b"hello").unwrap();
+
+        let mut back = Vec::new();
+        Metered::new(&out[..], &stats).read_to_end(&mut back).unwrap();
+        stats.packet_sent();
+        assert_eq!(stats.decode_error(), 1);
+
+        let report = NetReport::new(&stats, None, 0);
+        assert_eq!(report.bytes_sent, 5);
+        assert_eq!(report.bytes_received, 5);
+        assert_eq!(report.packets_sent, 1);
+        assert_eq!(report.packets_received, 0);
+        assert_eq!(report.decode_errors, 1);
+        assert_eq!(report.ping_ms(), None);
+        assert_eq!(report.compression_sent.ratio(), None);
+
+        stats.compression_sent(CompressionStats { raw: 1000, wire: 400 });
+        let report = NetReport::new(&stats, None, 0);
+        assert_eq!(report.compression_sent.ratio(), Some(0.4));
+        assert_eq!(report.compression_received.ratio(), None);
+    }
+
+    #[test]
+    fn smoothing() {
+        let mut latency = Latency::first(80000000);
+        latency.update(0);
+        assert_eq!(latency.last, 0);
+        assert_eq!(latency.smoothed, 70000000);
+        assert_eq!(NetReport::new(&NetStats::new(), Some(latency), 0).ping_ms(), Some(70));
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(2560), "2.5 KiB");
+        assert_eq!(format_bytes(3 << 20), "3.0 MiB");
+    }
+}
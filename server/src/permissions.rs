@@ -0,0 +1,284 @@
+//! Roles and the permissions they grant
+//!
+//! Permissions are dotted nodes like `command.tp`. Granting a node grants
+//! everything below it as well, `moderation` covers `moderation.ban`, and
+//! `*` covers everything. A node prefixed with `-` takes it away instead.
+//!
+//! Every role may inherit from a parent. Accounts have a role, plus
+//! overrides of their own that are applied on top of it.
+
+use std::collections::HashMap;
+
+use accounts::AccountStore;
+use worldstate::WorldState;
+
+/// The role of accounts nobody assigned one to
+pub const DEFAULT_ROLE: &'static str = "player";
+
+/// Roles may not inherit deeper than this, which also stops cycles
+pub const MAX_ROLE_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Role {
+    pub parent: Option<String>,
+    /// Granted nodes, or taken away ones with a `-` in front
+    pub permissions: Vec<String>,
+}
+
+/// Every role the server knows, by name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Roles {
+    roles: HashMap<String, Role>,
+}
+
+impl Default for Roles {
+    /// `guest`, `player`, `moderator` and `admin`, each inheriting from the
+    /// one before
+    fn default() -> Roles {
+        let mut roles = Roles { roles: HashMap::new() };
+        roles.insert("guest", None, &[]);
+        roles.insert("player", Some("guest"), &[]);
+        roles.insert("moderator", Some("player"), &["command.tp", "moderation"]);
+        roles.insert("admin", Some("moderator"), &["*"]);
+        roles
+    }
+}
+
+impl Roles {
+    fn insert(&mut self, name: &str, parent: Option<&str>, permissions: &[&str]) {
+        self.set(name.to_string(), Role {
+            parent: parent.map(|p| p.to_string()),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        });
+    }
+
+    /// Adds a role, or replaces the one with the same name
+    pub fn set(&mut self, name: String, role: Role) {
+        self.roles.insert(name, role);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Role> {
+        self.roles.get(name)
+    }
+
+    /// The role names, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.roles.keys().map(|n| &n[..]).collect();
+        names.sort();
+        names
+    }
+
+    /// Checks that every parent exists and nobody inherits in a circle
+    ///
+    /// Returns the name of the first broken role and why.
+    pub fn validate(&self) -> Result<(), (String, String)> {
+        for name in self.names() {
+            let mut current = name;
+            for depth in 0.. {
+                if depth == MAX_ROLE_DEPTH {
+                    return Err((name.to_string(), "inherits in a circle, or too deep".to_string()));
+                }
+                current = match self.roles[current].parent {
+                    Some(ref parent) if self.roles.contains_key(parent) => &parent[..],
+                    Some(ref parent) => {
+                        return Err((name.to_string(), format!("unknown parent '{}'", parent)));
+                    }
+                    None => break
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Works out what an account with `role` and `overrides` may do
+    ///
+    /// An unknown role grants nothing, the overrides still apply.
+    pub fn resolve(&self, role: &str, overrides: &[String]) -> PermissionSet {
+        let mut chain = Vec::new();
+        let mut current = self.roles.get(role).map(|r| (role, r));
+        while let Some((name, role)) = current {
+            if chain.len() == MAX_ROLE_DEPTH {
+                break;
+            }
+            chain.push((name, role));
+            current = role.parent.as_ref().and_then(|p| self.roles.get(p).map(|r| (&p[..], r)));
+        }
+
+        // Parents first, so every role can change what it inherited
+        let mut set = PermissionSet::new();
+        for &(_, role) in chain.iter().rev() {
+            for node in role.permissions.iter() {
+                set.push(node);
+            }
+        }
+        set.roles = chain.iter().map(|&(name, _)| name.to_string()).collect();
+        for node in overrides.iter() {
+            set.push(node);
+        }
+        set
+    }
+}
+
+/// The resolved permissions of a player
+///
+/// The most specific node decides, between equally specific ones the last
+/// wins. So an account can get back a node its role took away, and the
+/// other way round.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PermissionSet {
+    nodes: Vec<(String, bool)>,
+    /// The role these came from, followed by the ones it inherits from
+    roles: Vec<String>,
+}
+
+impl PermissionSet {
+    /// Grants nothing at all
+    pub fn new() -> PermissionSet {
+        PermissionSet { nodes: Vec::new(), roles: Vec::new() }
+    }
+
+    /// Whether the role behind these permissions ranks above `other`'s
+    ///
+    /// A role only ranks above the roles it inherits from, so neither of
+    /// two unrelated roles outranks the other. Having no role at all ranks
+    /// below everyone.
+    pub fn outranks(&self, other: &PermissionSet) -> bool {
+        match other.roles.first() {
+            Some(role) => self.roles.iter().skip(1).any(|r| r == role),
+            None => !self.roles.is_empty()
+        }
+    }
+
+    /// Adds a node, taken away if it starts with `-`
+    pub fn push(&mut self, node: &str) {
+        if node.starts_with("-") {
+            self.nodes.push((node[1..].to_string(), false));
+        } else {
+            self.nodes.push((node.to_string(), true));
+        }
+    }
+
+    pub fn has(&self, permission: &str) -> bool {
+        let mut decided = None;
+        for &(ref node, granted) in self.nodes.iter() {
+            if let Some(specificity) = covers(node, permission) {
+                match decided {
+                    Some((best, _)) if best > specificity => (),
+                    _ => decided = Some((specificity, granted))
+                }
+            }
+        }
+        decided.map_or(false, |(_, granted)| granted)
+    }
+}
+
+/// How specific `node` is if it covers `permission`
+fn covers(node: &str, permission: &str) -> Option<usize> {
+    if node == "*" {
+        Some(0)
+    } else if node == permission ||
+              (permission.starts_with(node) && permission[node.len()..].starts_with(".")) {
+        Some(node.len())
+    } else {
+        None
+    }
+}
+
+/// Gives an online player the permissions their account has right now
+///
+/// Called after logging in, and again whenever the account changes.
+pub fn refresh(state: &mut WorldState, id: usize, accounts: &AccountStore, roles: &Roles) {
+    let name = match state.get_players().get(&id).and_then(|p| p.get_name().clone()) {
+        Some(name) => name,
+        None => return
+    };
+    let (role, overrides) = accounts.grants(&name)
+                                    .unwrap_or((DEFAULT_ROLE.to_string(), Vec::new()));
+    let set = roles.resolve(&role, &overrides);
+    if let Some(player) = state.mut_get_players().get_mut(&id) {
+        player.set_permissions(set);
+    }
+}
+
+mod tests {
+    use super::*;
+
+    fn strings(nodes: &[&str]) -> Vec<String> {
+        nodes.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn inheritance() {
+        let roles = Roles::default();
+        assert!(roles.validate().is_ok());
+
+        let player = roles.resolve("player", &[]);
+        assert!(!player.has("command.tp"));
+
+        let moderator = roles.resolve("moderator", &[]);
+        assert!(moderator.has("command.tp"));
+        assert!(moderator.has("moderation.ban"));
+        assert!(!moderator.has("moderationx"));
+        assert!(!moderator.has("command.give"));
+
+        assert!(roles.resolve("admin", &[]).has("command.give"));
+        assert!(!roles.resolve("nobody", &[]).has("command.tp"));
+    }
+
+    #[test]
+    fn ranks() {
+        let mut roles = Roles::default();
+        roles.set("builder".to_string(), Role {
+            parent: Some("player".to_string()),
+            permissions: Vec::new(),
+        });
+        let player = roles.resolve("player", &[]);
+        let builder = roles.resolve("builder", &[]);
+        let moderator = roles.resolve("moderator", &[]);
+        let admin = roles.resolve("admin", &[]);
+
+        assert!(admin.outranks(&moderator));
+        assert!(moderator.outranks(&player));
+        assert!(!moderator.outranks(&moderator));
+        assert!(!moderator.outranks(&admin));
+        assert!(!moderator.outranks(&builder));
+        assert!(player.outranks(&roles.resolve("nobody", &[])));
+    }
+
+    #[test]
+    fn overrides() {
+        let roles = Roles::default();
+
+        let muted = roles.resolve("moderator", &strings(&["-moderation.ban"]));
+        assert!(muted.has("moderation.kick"));
+        assert!(!muted.has("moderation.ban"));
+
+        // More specific nodes win over broader ones
+        let kicker = roles.resolve("player", &strings(&["-moderation", "moderation.kick"]));
+        assert!(!kicker.has("moderation.ban"));
+        assert!(kicker.has("moderation.kick"));
+
+        let admin = roles.resolve("admin", &strings(&["-command.give"]));
+        assert!(!admin.has("command.give"));
+        assert!(admin.has("command.time"));
+    }
+
+    #[test]
+    fn broken_roles() {
+        let mut roles = Roles::default();
+        roles.set("builder".to_string(), Role {
+            parent: Some("nobody".to_string()),
+            permissions: Vec::new(),
+        });
+        assert_eq!(roles.validate(),
+                   Err(("builder".to_string(), "unknown parent 'nobody'".to_string())));
+
+        roles.set("builder".to_string(), Role {
+            parent: Some("builder".to_string()),
+            permissions: Vec::new(),
+        });
+        assert!(roles.validate().is_err());
+        // Resolving still terminates
+        assert!(!roles.resolve("builder", &[]).has("command.tp"));
+    }
+}
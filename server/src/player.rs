@@ -1,22 +1,45 @@
-use std::net::TcpStream;
-use std::thread::{JoinHandle, Builder};
-use std::sync::mpsc::Sender;
-use std::io::Read;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::io;
 use std::fmt;
 use std::mem;
 use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
-use servermessage::ServerEvent;
-use shared::net::receive_packet;
-use shared::packets::Packet;
+use clock_ticks::precise_time_ns;
 
-#[derive(Debug, Display)]
+use chat::RateLimiter;
+use netstats::{Latency, NetReport, NetStats};
+use permissions::PermissionSet;
+use reactor::{Link, ReactorHandle};
+use shared::net::PacketError;
+use shared::packets::{Packet, Position};
+
+/// Where a `Player` is in its lifetime
+///
+/// A player starts out `Connecting`, becomes `Authenticated` once the
+/// server accepted its `AuthPlayer` and ends up `Disconnected`. There is
+/// no way back.
+#[derive(Debug, Display, PartialEq, Eq, Clone, Copy)]
 pub enum PlayerStatus {
     Connecting,
     Authenticated,
     Disconnected
 }
 
+impl PlayerStatus {
+    /// Whether a player may go from this status to `next`
+    pub fn can_become(&self, next: PlayerStatus) -> bool {
+        use self::PlayerStatus::*;
+        match (*self, next) {
+            (Connecting, Authenticated) |
+            (Connecting, Disconnected) |
+            (Authenticated, Disconnected) => true,
+            _ => false
+        }
+    }
+}
+
 impl fmt::Display for PlayerStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", match *self {
@@ -31,53 +54,153 @@ static GLOBAL_PLAYER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
 
 pub struct Player {
     id: usize,
-    thr: JoinHandle<()>,
-    stream: TcpStream,
+    /// `None` for players made with `Player::offline`
+    link: Option<Arc<Link>>,
+    /// What was sent to an offline player, for tests to look at
+    outbox: Vec<Packet>,
+    peer_addr: SocketAddr,
     status: PlayerStatus,
     name: Option<String>,
+    connected_at: u64,
+    /// When the last packet arrived, in nanoseconds
+    last_seen: u64,
+    /// When the player last did something in the game, pongs don't count
+    last_active: u64,
+    /// When the last `Ping` was sent
+    last_ping: u64,
+    position: Position,
+    /// The `name_key` of the party the player is in
+    party: Option<String>,
+    chat_limiter: RateLimiter,
+    /// Item names and how many of them the player carries
+    inventory: HashMap<String, u32>,
+    /// Empty until the player logged in
+    permissions: PermissionSet,
+    /// Shared with the network thread
+    stats: Arc<NetStats>,
+    /// `None` until the first `Pong` came back
+    latency: Option<Latency>,
 }
 
 impl Player {
-    pub fn new(tx: Sender<ServerEvent>, stream: TcpStream) -> Player {
-        let mut stream_clone = stream.try_clone().unwrap();
+    /// A player connected through `stream`, served by one of the network
+    /// threads of `reactor`
+    pub fn new(stream: TcpStream, reactor: &ReactorHandle) -> io::Result<Player> {
+        let peer_addr = try!(stream.peer_addr());
         let id = GLOBAL_PLAYER_ID.fetch_add(1, Ordering::SeqCst);
-        Player {
+        let link = try!(reactor.register(id, stream));
+        let now = precise_time_ns();
+
+        Ok(Player {
             id: id,
-            stream: stream,
-            thr: {
-                let name = format!("{}", stream_clone.peer_addr().unwrap().ip());
-
-                Builder::new().name(name).spawn(move|| {
-                    // TODO: Make a loop that reads packets
-
-                    loop {
-                        match receive_packet(&mut stream_clone) {
-                            Ok(p) => {
-                                use shared::packets::Packet::*;
-                                match p {
-                                    AuthPlayer(s) => {
-                                        tx.send(ServerEvent::ClientAuthed(id, s));
-                                    }
-                                }
-                            },
-                            Err(e) => {
-                                println!("Got error for player({}): {}", id, e);
-                                break;
-                            }
-                        }
-                    }
-
-                    // At the end of the thread we always disconnect.
-                    tx.send(ServerEvent::ClientDisconnected(id)).unwrap();
-                }).unwrap()
-            },
+            link: Some(link.clone()),
+            outbox: Vec::new(),
+            peer_addr: peer_addr,
+            status: PlayerStatus::Connecting,
+            name: None,
+            connected_at: now,
+            last_seen: now,
+            last_active: now,
+            last_ping: now,
+            position: Position::default(),
+            party: None,
+            chat_limiter: RateLimiter::new(),
+            inventory: HashMap::new(),
+            permissions: PermissionSet::new(),
+            stats: link.stats(),
+            latency: None,
+        })
+    }
+
+    /// A player without a connection, starting out `Connecting`
+    ///
+    /// Everything sent to them is kept, see `take_outbox`. Lets the game
+    /// logic be tested without any sockets.
+    pub fn offline() -> Player {
+        let now = precise_time_ns();
+        Player {
+            id: GLOBAL_PLAYER_ID.fetch_add(1, Ordering::SeqCst),
+            link: None,
+            outbox: Vec::new(),
+            peer_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
             status: PlayerStatus::Connecting,
-            name: None
+            name: None,
+            connected_at: now,
+            last_seen: now,
+            last_active: now,
+            last_ping: now,
+            position: Position::default(),
+            party: None,
+            chat_limiter: RateLimiter::new(),
+            inventory: HashMap::new(),
+            permissions: PermissionSet::new(),
+            stats: Arc::new(NetStats::new()),
+            latency: None,
         }
     }
 
-    pub fn auth(&mut self, name: String) {
+    /// Marks the player as authenticated under the given name
+    ///
+    /// Returns false, and changes nothing, if the player is not
+    /// `Connecting` anymore.
+    pub fn auth(&mut self, name: String) -> bool {
+        if !self.status.can_become(PlayerStatus::Authenticated) {
+            return false;
+        }
+        self.status = PlayerStatus::Authenticated;
         self.name = Some(name);
+        true
+    }
+
+    /// Closes the connection to the player
+    ///
+    /// The network thread notices and sends a `ClientDisconnected`, which
+    /// removes the player from the `WorldState`.
+    pub fn disconnect(&mut self) {
+        if self.status.can_become(PlayerStatus::Disconnected) {
+            self.status = PlayerStatus::Disconnected;
+            if let Some(ref link) = self.link {
+                link.shutdown();
+            }
+        }
+    }
+
+    /// Tells the player why, then disconnects them
+    pub fn kick(&mut self, reason: &str) {
+        if self.status != PlayerStatus::Disconnected {
+            let _ = self.send(&Packet::Kick { reason: reason.to_string() });
+        }
+        self.disconnect();
+    }
+
+    /// Lets the network thread know a login of this player was dealt with
+    ///
+    /// Until then it holds back any other login, and once the player got
+    /// in it ignores them altogether.
+    pub fn login_checked(&self, logged_in: bool) {
+        if let Some(ref link) = self.link {
+            link.login_checked(logged_in);
+        }
+    }
+
+    /// Sends a `Packet` to this player
+    pub fn send(&mut self, packet: &Packet) -> Result<(), PacketError> {
+        let result = match self.link {
+            Some(ref link) => link.send(packet),
+            None => {
+                self.outbox.push(packet.clone());
+                Ok(())
+            }
+        };
+        if result.is_ok() {
+            self.stats.packet_sent();
+        }
+        result
+    }
+
+    /// Hands out everything sent to an offline player so far
+    pub fn take_outbox(&mut self) -> Vec<Packet> {
+        mem::replace(&mut self.outbox, Vec::new())
     }
 
     pub fn get_id(&self) -> usize {
@@ -87,12 +210,125 @@ impl Player {
     pub fn get_name(&self) -> &Option<String> {
         &self.name
     }
+
+    pub fn get_status(&self) -> PlayerStatus {
+        self.status
+    }
+
+    pub fn get_peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn get_position(&self) -> Position {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: Position) {
+        self.position = position;
+    }
+
+    pub fn get_party(&self) -> &Option<String> {
+        &self.party
+    }
+
+    pub fn set_party(&mut self, party: Option<String>) {
+        self.party = party;
+    }
+
+    /// Limits how often the player may chat
+    pub fn chat_limiter(&mut self) -> &mut RateLimiter {
+        &mut self.chat_limiter
+    }
+
+    /// Adds `count` of an item, returns how many the player has now
+    pub fn give_item(&mut self, item: &str, count: u32) -> u32 {
+        let held = self.inventory.entry(item.to_string()).or_insert(0);
+        *held = held.saturating_add(count);
+        *held
+    }
+
+    pub fn get_inventory(&self) -> &HashMap<String, u32> {
+        &self.inventory
+    }
+
+    /// Whether the player may do what needs `permission`
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.has(permission)
+    }
+
+    pub fn get_permissions(&self) -> &PermissionSet {
+        &self.permissions
+    }
+
+    pub fn set_permissions(&mut self, permissions: PermissionSet) {
+        self.permissions = permissions;
+    }
+
+    /// When the connection was accepted, in nanoseconds
+    pub fn get_connected_at(&self) -> u64 {
+        self.connected_at
+    }
+
+    pub fn get_last_seen(&self) -> u64 {
+        self.last_seen
+    }
+
+    /// Records that a packet arrived at `now`
+    pub fn mark_seen(&mut self, now: u64) {
+        self.last_seen = now;
+    }
+
+    pub fn get_last_active(&self) -> u64 {
+        self.last_active
+    }
+
+    /// Records that the player did something at `now`, so isn't away
+    pub fn mark_active(&mut self, now: u64) {
+        self.last_seen = now;
+        self.last_active = now;
+    }
+
+    /// Sends a `Ping` if the last one is at least `interval` old
+    pub fn ping_every(&mut self, now: u64, interval: u64) {
+        if now.saturating_sub(self.last_ping) >= interval {
+            self.last_ping = now;
+            let _ = self.send(&Packet::Ping(now));
+        }
+    }
+
+    /// Takes the round trip time from a `Pong` that arrived at `now`
+    ///
+    /// Only the answer to the latest `Ping` counts, that one carries the
+    /// time it was sent. Returns whether `value` was it.
+    pub fn record_pong(&mut self, value: u64, now: u64) -> bool {
+        if value != self.last_ping {
+            return false;
+        }
+        let sample = now.saturating_sub(value);
+        self.latency = Some(match self.latency {
+            Some(mut latency) => {
+                latency.update(sample);
+                latency
+            }
+            None => Latency::first(sample),
+        });
+        true
+    }
+
+    pub fn get_latency(&self) -> Option<Latency> {
+        self.latency
+    }
+
+    /// The traffic and latency of the connection as of `now`
+    pub fn net_report(&self, now: u64) -> NetReport {
+        NetReport::new(&self.stats, self.latency, now.saturating_sub(self.last_seen))
+    }
 }
 
 impl fmt::Display for Player {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "({} - {} - Known as: {})",
-        self.stream.peer_addr().unwrap().ip(),
+        self.peer_addr.ip(),
         self.status, match self.name { Some(ref s) => &s[..], None => "<Unknown>" })
     }
 }
@@ -0,0 +1,237 @@
+//! The remote admin console
+//!
+//! Listens on a port of its own, separate from the players. A session has
+//! to log in with the `rcon_password` of the `ServerConfig`, after that it
+//! may run the same commands as the local console, see `admin`.
+//!
+//! Everything a session does ends up in the `AuditLog`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crypto::util::fixed_time_eq;
+use clock_ticks::precise_time_ns;
+
+use admin::{parse_command, AdminCommand, AdminHandle, CommandError};
+use moderation;
+use worker::Worker;
+use shared::net::{receive_frame_limited, send_frame_limited, MAX_FRAME_SIZE};
+use shared::packets::RconPacket;
+
+/// How long a wrong password is left waiting, to slow down guessing
+const FAILED_LOGIN_DELAY_MS: u32 = 1000;
+
+/// Sessions beyond this many are hung up on right away
+pub const MAX_SESSIONS: usize = 4;
+
+/// Time a new connection has to send its login
+const LOGIN_TIMEOUT_MS: u64 = 10 * 1000;
+
+/// Largest frame accepted before logging in, plenty for a password
+const MAX_LOGIN_SIZE: usize = 1024;
+
+/// A logged in session that sends nothing for this long is hung up on
+const IDLE_TIMEOUT_MS: u64 = 30 * 60 * 1000;
+
+/// Reads from a stream, failing once `until` has passed
+///
+/// A plain read timeout starts over with every byte, this way a
+/// connection can't take forever to log in by sending one at a time.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: u64,
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = precise_time_ns();
+        if now >= self.until {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Took too long to log in"));
+        }
+        let left = self.until - now;
+        try!(self.stream.set_read_timeout(Some(Duration::new(left / 1000000000,
+                                                             (left % 1000000000) as u32))));
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+/// Records who did what over the remote console
+///
+/// Lines always go to stdout, and to a file if there is one.
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn stdout_only() -> AuditLog {
+        AuditLog { file: None }
+    }
+
+    /// Appends to the file at `path`, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<AuditLog> {
+        let file = try!(OpenOptions::new().append(true).create(true).open(path));
+        Ok(AuditLog { file: Some(Mutex::new(file)) })
+    }
+
+    pub fn record(&self, peer: &SocketAddr, message: &str) {
+        let line = format!("{} [rcon {}] {}", moderation::now(), peer, message);
+        println!("{}", line);
+        if let Some(ref file) = self.file {
+            let _ = writeln!(file.lock().unwrap(), "{}", line);
+        }
+    }
+}
+
+/// A connected session, the stream is kept to hang up on shutdown
+pub struct Session {
+    stream: TcpStream,
+    worker: Worker,
+}
+
+pub type Sessions = Arc<Mutex<Vec<Session>>>;
+
+/// Hangs up on every session, handing out their threads to be joined
+pub fn close_sessions(sessions: &Sessions) -> Vec<Worker> {
+    let sessions = mem::replace(&mut *sessions.lock().unwrap(), Vec::new());
+    let mut workers = Vec::with_capacity(sessions.len());
+    for session in sessions.into_iter() {
+        let _ = session.stream.shutdown(Shutdown::Both);
+        workers.push(session.worker);
+    }
+    workers
+}
+
+/// Accepts sessions until `accepting` is cleared
+///
+/// Every session gets a thread of its own, so a slow admin does not keep
+/// the others waiting. At most `MAX_SESSIONS` are open at a time.
+pub fn accept(listener: TcpListener, accepting: Arc<AtomicBool>, sessions: Sessions,
+              password: String, handle: AdminHandle, log: Arc<AuditLog>) {
+    for stream in listener.incoming() {
+        // `RpgServer::stop` wakes us up by connecting, so check first
+        if !accepting.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Could not accept rcon connection: {}", e);
+                break;
+            }
+        };
+        let (peer, clone) = match (stream.peer_addr(), stream.try_clone()) {
+            (Ok(peer), Ok(clone)) => (peer, clone),
+            _ => continue
+        };
+
+        let mut open = sessions.lock().unwrap();
+        open.retain(|s| !s.worker.is_done());
+        if open.len() >= MAX_SESSIONS {
+            log.record(&peer, "refused, too many sessions");
+            let _ = stream.shutdown(Shutdown::Both);
+            continue;
+        }
+
+        let password = password.clone();
+        let handle = handle.clone();
+        let log = log.clone();
+        let worker = Worker::spawn(format!("Rcon {}", peer), move|| {
+            run_session(stream, peer, &password, &handle, &log);
+        });
+
+        match worker {
+            Ok(worker) => open.push(Session { stream: clone, worker: worker }),
+            Err(e) => println!("Could not start rcon session: {}", e)
+        }
+    }
+}
+
+/// Serves a single connection, from the login until either side hangs up
+fn run_session(mut stream: TcpStream, peer: SocketAddr, password: &str,
+               handle: &AdminHandle, log: &AuditLog) {
+    log.record(&peer, "connected");
+
+    let timeout = Duration::from_millis(IDLE_TIMEOUT_MS);
+    if let Err(e) = stream.set_write_timeout(Some(timeout)) {
+        log.record(&peer, &format!("could not set a timeout: {}", e));
+        return;
+    }
+
+    let login = {
+        let mut reader = Deadline {
+            stream: &stream,
+            until: precise_time_ns() + LOGIN_TIMEOUT_MS * 1000000,
+        };
+        receive_frame_limited(&mut reader, MAX_LOGIN_SIZE)
+    };
+    match login {
+        Ok(RconPacket::Login { password: ref given }) => {
+            let accepted = fixed_time_eq(given.as_bytes(), password.as_bytes());
+            if !accepted {
+                log.record(&peer, "login refused");
+                thread::sleep_ms(FAILED_LOGIN_DELAY_MS);
+            }
+            let result = RconPacket::LoginResult { accepted: accepted };
+            if send_frame_limited(&mut stream, &result, MAX_FRAME_SIZE).is_err() || !accepted {
+                return;
+            }
+            log.record(&peer, "logged in");
+        }
+        Ok(_) => {
+            log.record(&peer, "did not start with a login");
+            return;
+        }
+        Err(e) => {
+            log.record(&peer, &format!("disconnected before logging in: {}", e));
+            return;
+        }
+    }
+
+    if let Err(e) = stream.set_read_timeout(Some(timeout)) {
+        log.record(&peer, &format!("could not set a timeout: {}", e));
+        return;
+    }
+
+    loop {
+        let line = match receive_frame_limited(&mut stream, MAX_FRAME_SIZE) {
+            Ok(RconPacket::Command(line)) => line,
+            Ok(_) => {
+                log.record(&peer, "sent something other than a command");
+                break;
+            }
+            // Usually just the admin hanging up, or idling for too long
+            Err(_) => break
+        };
+        log.record(&peer, &format!("command: {}", line));
+
+        let (output, done) = match parse_command(&line) {
+            Ok(command) => {
+                let stop = command == AdminCommand::Stop;
+                match handle.execute(command) {
+                    Some(output) => (output, stop),
+                    None => ("The server is not running".to_string(), true)
+                }
+            }
+            Err(CommandError::Empty) => (String::new(), false),
+            Err(e) => (format!("{}", e), false)
+        };
+
+        if let Err(e) = send_frame_limited(&mut stream, &RconPacket::Output(output), MAX_FRAME_SIZE) {
+            log.record(&peer, &format!("could not send the output: {}", e));
+            break;
+        }
+        if done {
+            break;
+        }
+    }
+
+    log.record(&peer, "disconnected");
+}
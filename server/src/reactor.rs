@@ -0,0 +1,688 @@
+//! The player connections, served by a few threads
+//!
+//! Every connection is handed to one of a fixed number of network threads,
+//! which wait on all of their sockets at once with `poll`. The sockets are
+//! non-blocking: bytes are fed into a `PacketDecoder` as they come, and
+//! the decoded packets go to the tick thread as `ServerEvent`s.
+//!
+//! Sending happens right on the thread that wants to send, through the
+//! `Link` of the player. Whatever the socket does not take at once is
+//! queued, and written by the network thread once the socket is ready.
+//! Packets too large for a frame go out as fragments, and fragments that
+//! arrive are put back together before anything else sees them. If the
+//! server compresses, it says so in the `Welcome`, and every frame after
+//! it is compressed both ways. Right after the `Welcome` the client has to
+//! start a key exchange, every frame after it is encrypted.
+//!
+//! Checking passwords is slow, so logins are passed on to a few threads of
+//! their own instead of holding up every other connection. Every
+//! connection gets one login checked at a time, and none once it is in.
+
+use std::io::{self, ErrorKind};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use clock_ticks::precise_time_ns;
+
+use accounts::AccountStore;
+use netstats::{Metered, NetStats};
+use servermessage::ServerEvent;
+use shared::net::{Fragmenter, PacketDecoder, PacketEncoder, PacketError};
+use shared::net::{Reassembler, ReassemblyLimits};
+use shared::net::secure::{Cipher, Handshake, Identity};
+use shared::packets::{Packet, PROTOCOL_VERSION};
+use worker::Worker;
+
+/// Players that let this many bytes pile up unread get disconnected
+pub const MAX_PENDING_BYTES: usize = 256 * 1024;
+
+/// Players whose socket takes nothing of what is queued for this long get
+/// disconnected, in milliseconds
+pub const MAX_STALL_MS: u64 = 30 * 1000;
+
+/// Threads checking logins
+pub const LOGIN_THREADS: usize = 2;
+
+/// Logins waiting for a thread beyond this many are refused right away
+pub const MAX_QUEUED_LOGINS: usize = 64;
+
+/// What the login of a connection is at
+const LOGIN_OPEN: usize = 0;
+const LOGIN_CHECKING: usize = 1;
+const LOGGED_IN: usize = 2;
+
+/// How long a network thread waits on its sockets at most, in milliseconds
+///
+/// New connections and sends that could not go out at once are noticed
+/// after at most this long.
+const POLL_TIMEOUT_MS: i32 = 10;
+
+/// The sending side of a player connection, shared with its network thread
+pub struct Link {
+    stream: TcpStream,
+    /// Locked while writing, so frames never interleave
+    encoder: Mutex<PacketEncoder>,
+    /// Since when frames are queued without the socket taking any of them
+    stalled_since: Mutex<Option<u64>>,
+    fragmenter: Fragmenter,
+    stats: Arc<NetStats>,
+    /// One of `LOGIN_OPEN`, `LOGIN_CHECKING` and `LOGGED_IN`
+    login: AtomicUsize,
+}
+
+impl Link {
+    fn new(stream: TcpStream, max_packet_size: usize, max_message_size: usize) -> Link {
+        Link {
+            stream: stream,
+            encoder: Mutex::new(PacketEncoder::with_limit(max_packet_size)),
+            stalled_since: Mutex::new(None),
+            fragmenter: Fragmenter::new(max_packet_size, max_message_size),
+            stats: Arc::new(NetStats::new()),
+            login: AtomicUsize::new(LOGIN_OPEN),
+        }
+    }
+
+    /// Queues a `Packet` and writes as much as the socket takes right away
+    ///
+    /// Fails if the player fell too far behind, the connection is closed
+    /// then.
+    pub fn send(&self, packet: &Packet) -> Result<(), PacketError> {
+        let frames = try!(self.fragmenter.split(packet));
+        let mut encoder = self.encoder.lock().unwrap();
+        for frame in frames.iter() {
+            try!(encoder.push(frame));
+        }
+        self.stats.compression_sent(encoder.compression_stats());
+        try!(self.write_out(&mut encoder));
+        Ok(())
+    }
+
+    /// Writes what is still queued, as far as the socket takes it
+    fn flush(&self) -> io::Result<()> {
+        let mut encoder = self.encoder.lock().unwrap();
+        self.write_out(&mut encoder)
+    }
+
+    /// Writes what the socket takes, and closes the connection if the
+    /// player stopped reading
+    ///
+    /// That is, more than `MAX_PENDING_BYTES` piled up, or nothing went out
+    /// for `MAX_STALL_MS`. The socket is non-blocking, so this is what
+    /// stands in for a write timeout.
+    fn write_out(&self, encoder: &mut PacketEncoder) -> io::Result<()> {
+        let written = try!(encoder.write_to(&mut Metered::new(&self.stream, &*self.stats)));
+        let now = precise_time_ns();
+        let mut stalled_since = self.stalled_since.lock().unwrap();
+        if written > 0 || encoder.is_empty() {
+            *stalled_since = None;
+        } else if stalled_since.is_none() {
+            *stalled_since = Some(now);
+        }
+
+        let stalled = stalled_since.map_or(false, |since| {
+            now.saturating_sub(since) > MAX_STALL_MS * 1000000
+        });
+        if encoder.pending() > MAX_PENDING_BYTES || stalled {
+            self.shutdown();
+            return Err(io::Error::new(ErrorKind::Other, "The player fell too far behind"));
+        }
+        Ok(())
+    }
+
+    /// Sends `packet` as the last uncompressed frame, every frame queued
+    /// after it is compressed as `PacketEncoder` does with `threshold`
+    ///
+    /// Nothing other threads send can get in between.
+    fn send_then_compress(&self, packet: &Packet, threshold: usize) -> Result<(), PacketError> {
+        let mut encoder = self.encoder.lock().unwrap();
+        try!(encoder.push(packet));
+        encoder.set_compression(Some(threshold));
+        self.stats.compression_sent(encoder.compression_stats());
+        try!(self.write_out(&mut encoder));
+        Ok(())
+    }
+
+    /// Sends `packet` as the last frame in plaintext, every frame queued
+    /// after it is sealed with `cipher`
+    ///
+    /// Nothing other threads send can get in between.
+    fn send_then_seal(&self, packet: &Packet, cipher: Cipher) -> Result<(), PacketError> {
+        let mut encoder = self.encoder.lock().unwrap();
+        try!(encoder.push(packet));
+        encoder.set_cipher(cipher);
+        self.stats.compression_sent(encoder.compression_stats());
+        try!(self.write_out(&mut encoder));
+        Ok(())
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.encoder.lock().unwrap().is_empty()
+    }
+
+    /// Claims the login check for a login that just came in
+    ///
+    /// False if one is being checked already, or the player is logged in.
+    fn start_login(&self) -> bool {
+        self.login.compare_exchange(LOGIN_OPEN, LOGIN_CHECKING, Ordering::SeqCst,
+                                    Ordering::SeqCst).is_ok()
+    }
+
+    fn logged_in(&self) -> bool {
+        self.login.load(Ordering::SeqCst) == LOGGED_IN
+    }
+
+    /// Called by the tick thread once it dealt with a checked login
+    ///
+    /// Unless the player got in, they may try again.
+    pub fn login_checked(&self, logged_in: bool) {
+        self.login.store(if logged_in { LOGGED_IN } else { LOGIN_OPEN }, Ordering::SeqCst);
+    }
+
+    /// Closes the connection, its network thread notices and drops it
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    pub fn stats(&self) -> Arc<NetStats> {
+        self.stats.clone()
+    }
+}
+
+/// A connection as seen by its network thread
+struct Conn {
+    id: usize,
+    link: Arc<Link>,
+    decoder: PacketDecoder,
+    reassembler: Reassembler,
+    /// Whether the client said `Hello` yet, nothing else gets through before
+    greeted: bool,
+    /// Offered to the client in the `Welcome`
+    compression: Option<usize>,
+    /// Whether the client is past the key exchange
+    keyed: bool,
+    identity: Arc<Identity>,
+}
+
+/// Where a network thread sends what it decoded
+struct Sinks {
+    events: Sender<ServerEvent>,
+    logins: SyncSender<(usize, Packet)>,
+}
+
+impl Conn {
+    /// The `poll` events to wait for
+    fn interest(&self) -> i16 {
+        if self.link.has_pending() { sys::POLLIN | sys::POLLOUT } else { sys::POLLIN }
+    }
+
+    /// Handles what `poll` reported, returns false once the connection is done
+    fn ready(&mut self, revents: i16, sinks: &Sinks) -> bool {
+        if revents & sys::POLLOUT != 0 {
+            if let Err(e) = self.link.flush() {
+                println!("Got error for player({}): {}", self.id, e);
+                return false;
+            }
+        }
+        if revents & (sys::POLLIN | sys::POLLHUP | sys::POLLERR | sys::POLLNVAL) != 0 {
+            return self.receive(sinks);
+        }
+        true
+    }
+
+    fn receive(&mut self, sinks: &Sinks) -> bool {
+        let read = {
+            let mut stream = Metered::new(&self.link.stream, &*self.link.stats);
+            self.decoder.read_from(&mut stream)
+        };
+        match read {
+            Ok(0) => return false,
+            Ok(_) => (),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                          e.kind() == ErrorKind::Interrupted => return true,
+            Err(e) => {
+                println!("Got error for player({}): {}", self.id, e);
+                return false;
+            }
+        }
+
+        loop {
+            match self.decoder.next_packet() {
+                Ok(Some(packet)) => {
+                    // Only players may make the server hold on to fragments
+                    if let Packet::Fragment { .. } = packet {
+                        if !self.link.logged_in() {
+                            println!("Player({}) sent a fragment before logging in", self.id);
+                            return false;
+                        }
+                    }
+                    let packet = match self.reassembler.accept(packet, precise_time_ns()) {
+                        Ok(Some(packet)) => packet,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            println!("Got error for player({}): {}", self.id, e);
+                            return false;
+                        }
+                    };
+                    if !self.greeted {
+                        if !self.greet(packet) {
+                            return false;
+                        }
+                    } else if !self.keyed {
+                        if !self.exchange_keys(packet) {
+                            return false;
+                        }
+                    } else {
+                        dispatch(self.id, &self.link, packet, sinks);
+                    }
+                }
+                Ok(None) => {
+                    self.link.stats.compression_received(self.decoder.compression_stats());
+                    return true;
+                }
+                Err(e) => {
+                    if let PacketError::DecodeError(..) = e {
+                        self.link.stats.decode_error();
+                        // Likely a `Hello` of another version
+                        if !self.greeted {
+                            let reason = "Could not read the Hello, the client is probably \
+                                          too old or too new".to_string();
+                            println!("Rejecting player({}): {}", self.id, reason);
+                            let _ = self.link.send(&Packet::Rejected { reason: reason });
+                            return false;
+                        }
+                    }
+                    println!("Got error for player({}): {}", self.id, e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Answers the first packet, which has to be a `Hello`
+    ///
+    /// Clients speaking another protocol version, or sending anything but
+    /// a `Hello`, get a `Rejected` with the reason. Returns whether the
+    /// client was accepted.
+    fn greet(&mut self, packet: Packet) -> bool {
+        let reason = match packet {
+            Packet::Hello { protocol_version, client_build, compression } => {
+                if protocol_version == PROTOCOL_VERSION {
+                    println!("Player({}) says hello with build {}", self.id, client_build);
+                    self.greeted = true;
+                    // Only if both sides want it
+                    let threshold = if compression { self.compression } else { None };
+                    let welcome = Packet::Welcome {
+                        compression: threshold.map(|t| t as u32),
+                    };
+                    let sent = match threshold {
+                        Some(threshold) => self.link.send_then_compress(&welcome, threshold),
+                        None => self.link.send(&welcome),
+                    };
+                    if sent.is_err() {
+                        return false;
+                    }
+                    // The client answers the `Welcome` with compressed
+                    // frames already
+                    if threshold.is_some() {
+                        self.decoder.set_compression(true);
+                    }
+                    return true;
+                }
+                format!("Protocol version mismatch: server speaks {}, client speaks {}",
+                        PROTOCOL_VERSION, protocol_version)
+            }
+            _ => "Expected Hello as the first packet".to_string(),
+        };
+
+        println!("Rejecting player({}): {}", self.id, reason);
+        let _ = self.link.send(&Packet::Rejected { reason: reason });
+        false
+    }
+
+    /// Answers the packet after the `Hello`, which should be a `KeyExchange`
+    ///
+    /// After a `KeyExchange`, every frame is sealed both ways. Anything else
+    /// means the client talks in plaintext, which gets it a `Rejected`.
+    /// Returns whether the client may go on.
+    fn exchange_keys(&mut self, packet: Packet) -> bool {
+        self.keyed = true;
+        let key = match packet {
+            Packet::KeyExchange { key } => key,
+            _ => {
+                let reason = "The server only accepts encrypted connections".to_string();
+                println!("Rejecting player({}): {}", self.id, reason);
+                let _ = self.link.send(&Packet::Rejected { reason: reason });
+                return false;
+            }
+        };
+
+        let exchange = match Handshake::new() {
+            Ok(handshake) => handshake.respond(&self.identity, &key),
+            Err(e) => Err(PacketError::from(e)),
+        };
+        let (reply, keys) = match exchange {
+            Ok(exchange) => exchange,
+            Err(e) => {
+                println!("Could not secure the connection of player({}): {}", self.id, e);
+                return false;
+            }
+        };
+        if self.link.send_then_seal(&reply, keys.send).is_err() {
+            return false;
+        }
+        // The client seals everything after its `KeyExchange`
+        self.decoder.set_cipher(keys.receive);
+        true
+    }
+}
+
+/// Turns a packet of a greeted client into the event for the tick thread
+///
+/// Only packets that get here are counted, not the handshake and not
+/// single fragments.
+fn dispatch(id: usize, link: &Link, packet: Packet, sinks: &Sinks) {
+    use shared::packets::Packet::*;
+    link.stats.packet_received();
+    let event = match packet {
+        AuthPlayer { .. } | Register { .. } => {
+            if !link.start_login() {
+                println!("Player({}) sent a login while logged in or waiting for one", id);
+                return;
+            }
+            match sinks.logins.try_send((id, packet)) {
+                Ok(()) | Err(TrySendError::Disconnected(_)) => return,
+                Err(TrySendError::Full(_)) => {
+                    ServerEvent::ClientAuthFailed(id, "The server is busy, try again".to_string())
+                }
+            }
+        }
+        SendChat { channel, message } => ServerEvent::ClientChat(id, channel, message),
+        Move(position) => ServerEvent::ClientMoved(id, position),
+        SetParty(party) => ServerEvent::ClientParty(id, party),
+        Command(line) => ServerEvent::ClientCommand(id, line),
+        Ping(value) => ServerEvent::ClientPing(id, value),
+        Pong(value) => ServerEvent::ClientPong(id, value),
+        // Put together by the `Reassembler` already
+        Fragment { .. } => return,
+        RequestPlayerList => ServerEvent::ClientPlayerList(id),
+        Hello{..} | Welcome{..} | Rejected{..} | KeyExchange{..} => {
+            println!("Player({}) sent a handshake packet twice", id);
+            return;
+        }
+        AuthResult(..) | PlayerJoined(..) | PlayerLeft{..} | WorldSnapshot{..} | Chat{..} |
+        Teleport(..) | PlayerList(..) | Kick{..} | KeyExchangeReply{..} => {
+            println!("Player({}) sent a server packet", id);
+            return;
+        }
+    };
+    let _ = sinks.events.send(event);
+}
+
+/// Body of a network thread
+fn serve(incoming: Receiver<Conn>, running: Arc<AtomicBool>, sinks: Sinks) {
+    let mut conns: Vec<Conn> = Vec::new();
+    let mut fds: Vec<sys::PollFd> = Vec::new();
+
+    while running.load(Ordering::SeqCst) {
+        while let Ok(conn) = incoming.try_recv() {
+            conns.push(conn);
+        }
+
+        fds.clear();
+        for conn in conns.iter() {
+            fds.push(sys::poll_fd(&conn.link.stream, conn.interest()));
+        }
+        if let Err(e) = sys::poll(&mut fds, POLL_TIMEOUT_MS) {
+            println!("Could not poll the player connections: {}", e);
+            // Nobody is going to serve them anymore
+            while let Ok(conn) = incoming.try_recv() {
+                conns.push(conn);
+            }
+            for conn in conns.drain(..) {
+                conn.link.shutdown();
+                let _ = sinks.events.send(ServerEvent::ClientDisconnected(conn.id));
+            }
+            break;
+        }
+
+        // Backwards, so that `swap_remove` only moves connections that
+        // were handled already
+        for idx in (0..conns.len()).rev() {
+            let revents = fds[idx].revents;
+            if revents == 0 || conns[idx].ready(revents, &sinks) {
+                continue;
+            }
+            let conn = conns.swap_remove(idx);
+            conn.link.shutdown();
+            // At the end we always disconnect. The server might already be
+            // gone, in which case nobody cares.
+            let _ = sinks.events.send(ServerEvent::ClientDisconnected(conn.id));
+        }
+    }
+
+    // Whatever is still queued, like a `Kick`, gets one last chance
+    for conn in conns.iter() {
+        let _ = conn.link.flush();
+        conn.link.shutdown();
+    }
+}
+
+/// Body of a login thread, they take turns at the queue
+fn check_logins(logins: Arc<Mutex<Receiver<(usize, Packet)>>>, events: Sender<ServerEvent>,
+                accounts: Arc<AccountStore>) {
+    loop {
+        let (id, packet) = match logins.lock().unwrap().recv() {
+            Ok(login) => login,
+            // Every network thread is gone
+            Err(_) => break
+        };
+        let event = match packet {
+            Packet::AuthPlayer { name, password } => match accounts.verify(&name, &password) {
+                Ok(name) => ServerEvent::ClientAuthed(id, name),
+                Err(e) => ServerEvent::ClientAuthFailed(id, format!("{}", e)),
+            },
+            Packet::Register { name, password } => match accounts.register(&name, &password) {
+                Ok(()) => ServerEvent::ClientAuthed(id, name),
+                Err(e) => ServerEvent::ClientAuthFailed(id, format!("{}", e)),
+            },
+            _ => continue
+        };
+        let _ = events.send(event);
+    }
+}
+
+/// Hands new connections to the network threads
+#[derive(Clone)]
+pub struct ReactorHandle {
+    threads: Vec<Sender<Conn>>,
+    max_packet_size: usize,
+    reassembly: ReassemblyLimits,
+    compression: Option<usize>,
+    identity: Arc<Identity>,
+}
+
+impl ReactorHandle {
+    /// Makes `stream` non-blocking and gives it to a network thread
+    ///
+    /// Packets arriving on it are reported as coming from player `id`.
+    pub fn register(&self, id: usize, stream: TcpStream) -> io::Result<Arc<Link>> {
+        try!(sys::set_nonblocking(&stream));
+        let link = Arc::new(Link::new(stream, self.max_packet_size,
+                                      self.reassembly.max_message_size));
+        let conn = Conn {
+            id: id,
+            link: link.clone(),
+            decoder: PacketDecoder::with_limit(self.max_packet_size),
+            reassembler: Reassembler::new(self.reassembly.clone()),
+            compression: self.compression,
+            greeted: false,
+            keyed: false,
+            identity: self.identity.clone(),
+        };
+        match self.threads[id % self.threads.len()].send(conn) {
+            Ok(()) => Ok(link),
+            Err(_) => Err(io::Error::new(ErrorKind::Other, "The network threads are gone")),
+        }
+    }
+}
+
+/// The network threads, and the login threads
+pub struct Reactor {
+    running: Arc<AtomicBool>,
+    workers: Vec<Worker>,
+    handle: ReactorHandle,
+}
+
+impl Reactor {
+    /// Starts `threads` network threads, reporting to `events`
+    pub fn start(threads: usize, events: Sender<ServerEvent>, accounts: Arc<AccountStore>,
+                 max_packet_size: usize, reassembly: ReassemblyLimits,
+                 compression: Option<usize>, identity: Arc<Identity>)
+                 -> io::Result<Reactor> {
+        let running = Arc::new(AtomicBool::new(true));
+        let mut workers = Vec::with_capacity(threads + LOGIN_THREADS);
+        let mut senders = Vec::with_capacity(threads);
+
+        let (logins_tx, logins_rx) = sync_channel(MAX_QUEUED_LOGINS);
+        let logins_rx = Arc::new(Mutex::new(logins_rx));
+        for number in 0..LOGIN_THREADS {
+            let logins_rx = logins_rx.clone();
+            let login_events = events.clone();
+            let accounts = accounts.clone();
+            workers.push(try!(Worker::spawn(format!("Logins {}", number), move|| {
+                check_logins(logins_rx, login_events, accounts);
+            })));
+        }
+
+        for number in 0..threads {
+            let (tx, rx) = channel();
+            let sinks = Sinks {
+                events: events.clone(),
+                logins: logins_tx.clone(),
+            };
+            let running = running.clone();
+            workers.push(try!(Worker::spawn(format!("Network {}", number), move|| {
+                serve(rx, running, sinks);
+            })));
+            senders.push(tx);
+        }
+
+        Ok(Reactor {
+            running: running,
+            workers: workers,
+            handle: ReactorHandle {
+                threads: senders,
+                max_packet_size: max_packet_size,
+                reassembly: reassembly,
+                compression: compression,
+                identity: identity,
+            },
+        })
+    }
+
+    pub fn handle(&self) -> ReactorHandle {
+        self.handle.clone()
+    }
+
+    /// Tells the threads to close every connection and end
+    ///
+    /// Returns them, to be joined. The login threads end once every
+    /// network thread did.
+    pub fn stop(self) -> Vec<Worker> {
+        self.running.store(false, Ordering::SeqCst);
+        self.workers
+    }
+}
+
+/// The bits of `poll` and `fcntl` needed, std has neither
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::net::TcpStream;
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+
+    pub use libc::pollfd as PollFd;
+    pub use libc::{POLLIN, POLLOUT, POLLERR, POLLHUP, POLLNVAL};
+
+    pub fn poll_fd(stream: &TcpStream, events: i16) -> PollFd {
+        PollFd {
+            fd: stream.as_raw_fd(),
+            events: events,
+            revents: 0,
+        }
+    }
+
+    /// Waits until one of `fds` is ready, or `timeout_ms` passed
+    ///
+    /// Returns how many are ready, being interrupted counts as none.
+    pub fn poll(fds: &mut [PollFd], timeout_ms: i32) -> io::Result<usize> {
+        let ready = unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms)
+        };
+        if ready >= 0 {
+            return Ok(ready as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted { Ok(0) } else { Err(err) }
+    }
+
+    pub fn set_nonblocking(stream: &TcpStream) -> io::Result<()> {
+        let fd = stream.as_raw_fd();
+        let ok = unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            flags >= 0 && libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) >= 0
+        };
+        if ok { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+}
+
+/// Stands in for `poll` where there is none
+///
+/// Every connection is reported ready every few milliseconds, reading and
+/// writing the non-blocking sockets finds out whether they really are. That
+/// costs some wakeups, and a little latency.
+#[cfg(not(unix))]
+mod sys {
+    use std::cmp;
+    use std::io;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    pub const POLLIN: i16 = 0x1;
+    pub const POLLOUT: i16 = 0x4;
+    pub const POLLERR: i16 = 0x8;
+    pub const POLLHUP: i16 = 0x10;
+    pub const POLLNVAL: i16 = 0x20;
+
+    /// How long to wait between two rounds over the connections at most
+    const ROUND_MS: i32 = 5;
+
+    pub struct PollFd {
+        pub events: i16,
+        pub revents: i16,
+    }
+
+    pub fn poll_fd(_: &TcpStream, events: i16) -> PollFd {
+        PollFd {
+            events: events,
+            revents: 0,
+        }
+    }
+
+    pub fn poll(fds: &mut [PollFd], timeout_ms: i32) -> io::Result<usize> {
+        thread::sleep(Duration::from_millis(cmp::min(timeout_ms, ROUND_MS) as u64));
+        for fd in fds.iter_mut() {
+            fd.revents = fd.events;
+        }
+        Ok(fds.len())
+    }
+
+    pub fn set_nonblocking(stream: &TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(true)
+    }
+}
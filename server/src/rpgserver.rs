@@ -1,15 +1,42 @@
 use std::io;
-use std::net::{TcpListener};
-use std::thread::{JoinHandle, Builder};
+use std::fmt;
+use std::fs;
+use std::thread;
+use std::net::{TcpListener, TcpStream, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::net::SocketAddr;
 use std::io::Error;
 
 use worldstate::WorldState;
+use accounts::AccountStore;
+use chat;
+use commands::{Caller, CommandRegistry};
+use moderation::{self, Moderation};
+use permissions;
+use admin::{self, AdminCommand, AdminHandle, TickStats};
+use rcon::{self, AuditLog, Sessions};
+use config::{ServerConfig, ConfigError, ms_to_ns};
+use names::validate_name;
 use shared::{game_loop, LoopAction};
+use clock_ticks::precise_time_ns;
 use servermessage::{ServerEvent, WorldEvent};
-use player::Player;
+use player::{Player, PlayerStatus};
+use reactor::{Reactor, ReactorHandle};
+use worker::Worker;
+use shared::net::send_packet;
+use shared::net::secure::Identity;
+use shared::packets::{Packet, AuthResult, PlayerInfo};
+
+/// What happens when a player logs in under a name that is already playing
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DuplicateLogin {
+    /// The new session is refused, the old one keeps playing
+    RefuseNew,
+    /// The old session is kicked, the new one takes over
+    KickOld,
+}
 
 #[derive(PartialEq, Eq, Debug, Display)]
 pub enum ServerStatus {
@@ -21,23 +48,109 @@ pub enum ServerStatus {
     }
 }
 
+/// The threads that did not exit in time during `RpgServer::stop`
+#[derive(Debug)]
+pub struct StopError {
+    pub stuck: Vec<String>,
+}
+
+impl fmt::Display for StopError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Threads did not exit in time: {}", self.stuck.join(", "))
+    }
+}
+
 pub struct RpgServer {
     list: TcpListener,
 
     world_sender: Option<Sender<WorldEvent>>,
-    world_thread: Option<JoinHandle<()>>,
+    world_thread: Option<Worker>,
 
     server_sender: Option<Sender<ServerEvent>>,
-    server_thread: Option<JoinHandle<()>>,
+    server_thread: Option<Worker>,
 
-    socket_thread: Option<JoinHandle<()>>,
+    socket_thread: Option<Worker>,
+    /// The threads serving the player connections
+    reactor: Option<Reactor>,
+    /// Cleared by `stop`, so the socket threads stop accepting
+    accepting: Arc<AtomicBool>,
+
+    /// The remote admin console, if configured
+    rcon_list: Option<TcpListener>,
+    rcon_thread: Option<Worker>,
+    rcon_sessions: Sessions,
+    audit: Arc<AuditLog>,
 
     state: Arc<RwLock<WorldState>>,
+    accounts: Arc<AccountStore>,
+    /// The key players know this server by
+    identity: Arc<Identity>,
+
+    config: ServerConfig,
+}
+
+/// Everything the server loop needs besides the `WorldState`
+struct Context {
+    server_tx: Sender<ServerEvent>,
+    reactor: ReactorHandle,
+    accounts: Arc<AccountStore>,
+    config: ServerConfig,
+    commands: CommandRegistry,
 }
 
 impl RpgServer {
+    /// A server with the default configuration, listening on `address`
+    ///
+    /// Accounts and bans are only kept in memory.
     pub fn new(address: &str) -> Result<RpgServer, io::Error> {
-        let listener = try!(TcpListener::bind(address));
+        let mut config = ServerConfig::default();
+        config.bind_address = address.to_string();
+        RpgServer::from_parts(config, AccountStore::in_memory(), WorldState::new())
+    }
+
+    /// A server set up according to `config`
+    ///
+    /// If the configuration has a world path the world, the accounts, bans
+    /// and the whitelist are stored in there, otherwise they are only kept
+    /// in memory.
+    pub fn with_config(config: ServerConfig) -> Result<RpgServer, ConfigError> {
+        try!(config.validate());
+
+        if let Some(world) = config.world_path.as_ref() {
+            try!(fs::create_dir_all(world));
+        }
+        let accounts = match config.accounts_path() {
+            Some(path) => try!(AccountStore::open(path)),
+            None => AccountStore::in_memory()
+        };
+        let moderation = match config.moderation_path() {
+            Some(path) => try!(Moderation::open(path)),
+            None => Moderation::in_memory()
+        };
+        let state = match config.world_state_path() {
+            Some(path) => try!(WorldState::open(path, moderation)),
+            None => WorldState::with_moderation(moderation)
+        };
+
+        Ok(try!(RpgServer::from_parts(config, accounts, state)))
+    }
+
+    fn from_parts(config: ServerConfig, accounts: AccountStore, mut state: WorldState)
+        -> Result<RpgServer, io::Error> {
+        let listener = try!(TcpListener::bind(&config.bind_address[..]));
+        let rcon_list = match config.rcon_address {
+            Some(ref address) => Some(try!(TcpListener::bind(&address[..]))),
+            None => None
+        };
+        let audit = match config.audit_log_path() {
+            Some(path) => try!(AuditLog::open(path)),
+            None => AuditLog::stdout_only()
+        };
+        let identity = match config.identity_path() {
+            Some(path) => try!(Identity::open(path)),
+            None => try!(Identity::generate())
+        };
+        state.set_chat_limits(config.chat_limits());
 
         Ok(RpgServer {
             list: listener,
@@ -46,15 +159,45 @@ impl RpgServer {
             server_sender: None,
             server_thread: None,
             socket_thread: None,
-            // TODO: Don't actually do this... read it from somewhere
-            state: Arc::new(RwLock::new(WorldState::new())),
+            reactor: None,
+            accepting: Arc::new(AtomicBool::new(false)),
+            rcon_list: rcon_list,
+            rcon_thread: None,
+            rcon_sessions: Arc::new(Mutex::new(Vec::new())),
+            audit: Arc::new(audit),
+            state: Arc::new(RwLock::new(state)),
+            accounts: Arc::new(accounts),
+            identity: Arc::new(identity),
+            config: config,
         })
     }
 
+    pub fn get_config(&self) -> &ServerConfig {
+        &self.config
+    }
+
     pub fn get_state(&self) -> Arc<RwLock<WorldState>> {
         self.state.clone()
     }
 
+    /// The key the server proves who it is with, clients pin it
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Replaces the accounts players log in with
+    ///
+    /// By default the server only keeps accounts in memory. Only takes
+    /// effect on the next `start`.
+    pub fn set_accounts(&mut self, accounts: AccountStore) {
+        self.accounts = Arc::new(accounts);
+    }
+
+    /// A handle to run admin commands with, while the server is running
+    pub fn admin_handle(&self) -> Option<AdminHandle> {
+        self.server_sender.clone().map(AdminHandle::new)
+    }
+
     pub fn status(&self) -> ServerStatus {
         let sts = (self.world_thread.is_some(),
         self.server_thread.is_some(),
@@ -70,57 +213,65 @@ impl RpgServer {
         }
     }
 
-    pub fn start(&mut self) {
-        // Start the World Handler
+    /// Starts serving, fails if the network threads could not be started
+    pub fn start(&mut self) -> io::Result<()> {
         let (tx, rx) = channel();
-        self.world_sender = Some(tx);
-        self.world_thread = Builder::new().name("World".to_string()).spawn(move||{
-            for event in rx.iter() {
+        let reactor = try!(Reactor::start(self.config.network_threads, tx.clone(),
+                                          self.accounts.clone(), self.config.max_packet_size,
+                                          self.config.reassembly_limits(),
+                                          self.config.compression_threshold,
+                                          self.identity.clone()));
+
+        // Start the World Handler
+        let (world_tx, world_rx) = channel();
+        self.world_sender = Some(world_tx);
+        self.world_thread = Worker::spawn("World".to_string(), move||{
+            for event in world_rx.iter() {
                 match event {
                     WorldEvent::Quit => break
                 }
             }
         }).ok();
 
-        // Start the Server Loop, which is the thread that updates at a fixed
-        // tick of 60 Ticks per Second for now
-        let (tx, rx) = channel();
-        let mut state = self.state.clone();
-        let server_tx = tx.clone();
+        // Start the Server Loop, which is the thread that updates at the
+        // fixed tick rate of the configuration
+        let state = self.state.clone();
+        let ctx = Context {
+            server_tx: tx.clone(),
+            reactor: reactor.handle(),
+            accounts: self.accounts.clone(),
+            config: self.config.clone(),
+            commands: CommandRegistry::with_defaults(),
+        };
         self.server_sender = Some(tx);
-        self.server_thread = Builder::new().name("Server".to_string()).spawn(move||{
-            let mut state = state;
-            let server_tx = server_tx;
+        self.reactor = Some(reactor);
+        self.server_thread = Worker::spawn("Server".to_string(), move||{
+            let state = state;
+            let mut stats = TickStats::new();
 
-            // 16 million nano seconds should be enough right?!
             // TODO: Check for possible adaptive solution?
-            game_loop(16666667, move|tick| {
+            game_loop(ctx.config.tick_ns(), move|_| {
+                let tick_start = precise_time_ns();
+                let mut events = 0;
                 loop {
-                    use servermessage::ServerEvent::*;
                     match rx.try_recv() {
-                        Ok(event) => {
-                            match event {
-                                Quit => return LoopAction::Quit,
-                                ClientConnected(stream) => {
-                                    let mut state = (*state).write().unwrap();
-                                    let mut players = state.mut_get_players();
-                                    let new_player = Player::new(server_tx.clone(),stream);
-                                    players.insert(new_player.get_id(), new_player);
-                                },
-                                ClientDisconnected(id) => {
-                                    let mut state = (*state).write().unwrap();
-                                    let mut players = state.mut_get_players();
-                                    players.remove(&id);
-                                },
-                                ClientAuthed(id, name) => {
-                                    let mut state = (*state).write().unwrap();
-                                    let mut players = state.mut_get_players();
-                                    let mut player = players.get_mut(&id).unwrap();
-                                    player.auth(name);
-                                    //TODO: Do something else than auth?
-                                }
+                        Ok(ServerEvent::Quit) => return LoopAction::Quit,
+                        Ok(ServerEvent::Admin(command, reply)) => {
+                            let output = {
+                                let mut state = (*state).write().unwrap();
+                                admin::execute(&mut state, &command, &ctx.config, &stats,
+                                               &ctx.commands, &ctx.accounts)
+                            };
+                            let _ = reply.send(output);
+                            if command == AdminCommand::Stop {
+                                return LoopAction::Quit;
                             }
                         },
+                        Ok(event) => {
+                            let mut state = (*state).write().unwrap();
+                            handle_event(&mut state, event, &ctx);
+                            events += 1;
+                        },
                         Err(err) => {
                             use std::sync::mpsc::TryRecvError::*;
                             match err {
@@ -131,50 +282,452 @@ impl RpgServer {
                     };
                 }
 
+                let mut state = (*state).write().unwrap();
+                time_out_connecting(&mut state, ms_to_ns(ctx.config.connect_timeout_ms));
+                keep_alive(&mut state, &ctx);
+                state.advance_time(ctx.config.tick_ns());
+
+                stats.record(precise_time_ns().saturating_sub(tick_start), events);
                 LoopAction::Continue
             })
         }).ok();
 
         let server_sender = self.server_sender.clone().unwrap();
         let socket = self.list.try_clone();
-        self.socket_thread = Builder::new().name("Socket".to_string()).spawn(move||{
+        let accepting = self.accepting.clone();
+        accepting.store(true, Ordering::SeqCst);
+        self.socket_thread = Worker::spawn("Socket".to_string(), move||{
             for stream in socket.unwrap().incoming() {
+                // `stop` wakes us up by connecting, so check before anything
+                if !accepting.load(Ordering::SeqCst) {
+                    break;
+                }
                 match stream {
                     Ok(stream) => {
                         let _ = server_sender.send(ServerEvent::ClientConnected(stream));
                     }
                     Err(e) => {
-                        println!("EEEP ERRORR11");
+                        println!("Could not accept connection: {}", e);
                         break;
                     }
                 }
             }
         }).ok();
+
+        if let Some(listener) = self.rcon_list.as_ref().and_then(|l| l.try_clone().ok()) {
+            let accepting = self.accepting.clone();
+            let sessions = self.rcon_sessions.clone();
+            let password = self.config.rcon_password.clone();
+            let handle = self.admin_handle().unwrap();
+            let audit = self.audit.clone();
+            self.rcon_thread = Worker::spawn("Rcon".to_string(), move||{
+                rcon::accept(listener, accepting, sessions, password, handle, audit);
+            }).ok();
+        }
+        Ok(())
     }
 
-    pub fn stop(&mut self) {
-        if let Some(world_send) = self.world_sender.take() {
-            let _ = world_send.send(WorldEvent::Quit);
+    /// Blocks until the server loop ends, then shuts everything down
+    pub fn wait(&mut self) -> Result<(), StopError> {
+        loop {
+            match self.server_thread {
+                Some(ref worker) if !worker.is_done() => thread::sleep_ms(100),
+                _ => break
+            }
+        }
+        self.stop()
+    }
+
+    /// Shuts the server down
+    ///
+    /// New connections are refused first, then the server loop is stopped
+    /// and every player is told about the shutdown and disconnected. All
+    /// threads, including the network threads, get joined. Threads that
+    /// take longer than the stop timeout are left running and reported.
+    pub fn stop(&mut self) -> Result<(), StopError> {
+        let deadline = precise_time_ns().saturating_add(ms_to_ns(self.config.stop_timeout_ms));
+        let mut stuck = Vec::new();
+
+        // The socket threads are blocked in `accept`, so we have to knock
+        if self.accepting.swap(false, Ordering::SeqCst) {
+            if let Ok(addr) = self.list.local_addr() {
+                let _ = TcpStream::connect(wake_address(addr));
+            }
+            if let Some(Ok(addr)) = self.rcon_list.as_ref().map(|l| l.local_addr()) {
+                let _ = TcpStream::connect(wake_address(addr));
+            }
         }
+        join_worker(self.socket_thread.take(), deadline, &mut stuck);
+        join_worker(self.rcon_thread.take(), deadline, &mut stuck);
+
         if let Some(server_send) = self.server_sender.take() {
             let _ = server_send.send(ServerEvent::Quit);
         }
+        join_worker(self.server_thread.take(), deadline, &mut stuck);
+
+        for worker in rcon::close_sessions(&self.rcon_sessions).into_iter() {
+            join_worker(Some(worker), deadline, &mut stuck);
+        }
 
-        if let Some(world_thr) = self.world_thread.take() {
-            let _ = world_thr.join();
+        let players = self.state.write().unwrap().drain_players();
+        for mut player in players.into_iter() {
+            player.kick("The server is shutting down");
         }
-        if let Some(server_thr) = self.server_thread.take() {
-            let _ = server_thr.join();
+        if let Err(e) = self.state.read().unwrap().save() {
+            println!("Could not save the world: {}", e);
+        }
+        if let Some(reactor) = self.reactor.take() {
+            for worker in reactor.stop().into_iter() {
+                join_worker(Some(worker), deadline, &mut stuck);
+            }
         }
 
-        if let Some(socket_thr) = self.socket_thread.take() {
-            let _ = socket_thr.join();
+        if let Some(world_send) = self.world_sender.take() {
+            let _ = world_send.send(WorldEvent::Quit);
+        }
+        join_worker(self.world_thread.take(), deadline, &mut stuck);
+
+        if stuck.is_empty() {
+            Ok(())
+        } else {
+            Err(StopError { stuck: stuck })
         }
     }
 
     pub fn local_addr(&self) -> Result<SocketAddr, Error> {
         self.list.local_addr()
     }
+
+    /// Where the remote admin console listens, if it is enabled
+    pub fn rcon_addr(&self) -> Option<SocketAddr> {
+        self.rcon_list.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// Sets how long, in milliseconds, a player may stay `Connecting`
+    ///
+    /// Only takes effect on the next `start`.
+    pub fn set_connect_timeout(&mut self, timeout_ms: u64) {
+        self.config.connect_timeout_ms = timeout_ms;
+    }
+
+    /// Sets how long, in milliseconds, `stop` waits for threads to exit
+    pub fn set_stop_timeout(&mut self, timeout_ms: u64) {
+        self.config.stop_timeout_ms = timeout_ms;
+    }
+
+    /// Sets what happens when a name logs in twice
+    ///
+    /// Only takes effect on the next `start`.
+    pub fn set_duplicate_login(&mut self, policy: DuplicateLogin) {
+        self.config.duplicate_login = policy;
+    }
+}
+
+/// Joins `worker` if there is one, remembering its name if it is stuck
+fn join_worker(worker: Option<Worker>, deadline: u64, stuck: &mut Vec<String>) {
+    if let Some(worker) = worker {
+        if let Err(name) = worker.join_until(deadline) {
+            stuck.push(name);
+        }
+    }
+}
+
+/// The address to connect to in order to reach a listener bound to `addr`
+///
+/// Listeners bound to the unspecified address can't be connected to as
+/// such, so loopback is used instead.
+fn wake_address(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip == Ipv4Addr::new(0, 0, 0, 0) => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), addr.port())
+        }
+        IpAddr::V6(ip) if ip == Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0) => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), addr.port())
+        }
+        _ => addr
+    }
+}
+
+/// Applies a single `ServerEvent` to the `WorldState`
+///
+/// This is where all `PlayerStatus` transitions happen, packets that need
+/// an authenticated player are dropped, and the player kicked, otherwise.
+fn handle_event(state: &mut WorldState, event: ServerEvent, ctx: &Context) {
+    use servermessage::ServerEvent::*;
+    if let Some(id) = event.from_player() {
+        if let Some(player) = state.mut_get_players().get_mut(&id) {
+            player.mark_seen(precise_time_ns());
+        }
+    }
+
+    match event {
+        // Both are taken care of by the server loop itself
+        Quit | Admin(..) => (),
+        ClientConnected(mut stream) => {
+            let ban = match stream.peer_addr() {
+                Ok(addr) => state.get_moderation().ip_ban(addr.ip())
+                                 .map(|ban| ban.message(moderation::now())),
+                Err(_) => None
+            };
+            if let Some(reason) = ban {
+                let _ = send_packet(&mut stream, &Packet::Rejected { reason: reason });
+                return;
+            }
+            if state.get_players().len() >= ctx.config.max_players {
+                let _ = send_packet(&mut stream, &Packet::Rejected {
+                    reason: "The server is full".to_string()
+                });
+                return;
+            }
+            match Player::new(stream, &ctx.reactor) {
+                Ok(new_player) => {
+                    state.mut_get_players().insert(new_player.get_id(), new_player);
+                }
+                Err(e) => println!("Could not set up connection: {}", e),
+            }
+        },
+        ClientDisconnected(id) => {
+            // Kicked players are already `Disconnected`, but were still
+            // known to everyone else by their name.
+            let was_ingame = match state.remove_player(id) {
+                Some(player) => player.get_name().is_some(),
+                None => false
+            };
+            if was_ingame {
+                state.broadcast(&Packet::PlayerLeft {
+                    player_id: id as u64
+                });
+            }
+        },
+        ClientTimedOut(id) => {
+            // Nothing arrives on a dead connection, not even the hang up,
+            // so the player is dropped right here instead of waiting for it
+            let name = match state.remove_player(id) {
+                Some(mut player) => {
+                    println!("Player({}) timed out", id);
+                    player.disconnect();
+                    player.get_name().clone()
+                }
+                None => return
+            };
+            if let Some(name) = name {
+                state.broadcast(&Packet::PlayerLeft {
+                    player_id: id as u64
+                });
+                chat::announce(state, &format!("{} timed out", name));
+            }
+        },
+        ClientPing(id, value) => {
+            state.send_to(id, &Packet::Pong(value));
+        },
+        ClientPong(id, value) => {
+            if let Some(player) = state.mut_get_players().get_mut(&id) {
+                player.record_pong(value, precise_time_ns());
+            }
+        },
+        ClientPlayerList(id) => {
+            if require_authenticated(state, id).is_none() {
+                return;
+            }
+            let list = state.player_list();
+            state.send_to(id, &Packet::PlayerList(list));
+        },
+        ClientAuthed(id, name) => {
+            match state.get_players().get(&id).map(|p| p.get_status()) {
+                Some(PlayerStatus::Connecting) => (),
+                Some(status) => {
+                    println!("Player({}) tried to authenticate while {}", id, status);
+                    return;
+                }
+                // Disconnected in the meantime
+                None => return
+            }
+
+            if let Err(e) = validate_name(&name) {
+                refuse(state, id, format!("{}", e));
+                return;
+            }
+
+            let ban = state.get_moderation().name_ban(&name)
+                           .map(|ban| ban.message(moderation::now()));
+            if let Some(reason) = ban {
+                refuse(state, id, reason);
+                return;
+            }
+            if !state.get_moderation().is_whitelisted(&name) {
+                refuse(state, id, "You are not on the whitelist".to_string());
+                return;
+            }
+
+            if let Some(old) = state.find_player(&name) {
+                match ctx.config.duplicate_login {
+                    DuplicateLogin::RefuseNew => {
+                        refuse(state, id, "You are already logged in".to_string());
+                        return;
+                    }
+                    DuplicateLogin::KickOld => {
+                        state.kick(old, "Logged in from somewhere else");
+                    }
+                }
+            }
+
+            if !state.auth_player(id, name.clone()) {
+                refuse(state, id, "Could not log in".to_string());
+                return;
+            }
+            permissions::refresh(state, id, &ctx.accounts, &ctx.config.roles);
+            if let Some(player) = state.get_players().get(&id) {
+                player.login_checked(true);
+            }
+
+            // The new player learns about themselves from the snapshot
+            state.broadcast_except(id, &Packet::PlayerJoined(PlayerInfo {
+                player_id: id as u64,
+                name: name,
+            }));
+
+            let snapshot = Packet::WorldSnapshot {
+                players: state.player_infos()
+            };
+            state.send_to(id, &Packet::AuthResult(AuthResult::Accepted {
+                player_id: id as u64
+            }));
+            state.send_to(id, &snapshot);
+
+            if !ctx.config.motd.is_empty() {
+                chat::notice(state, id, &ctx.config.motd);
+            }
+        },
+        ClientAuthFailed(id, reason) => {
+            refuse(state, id, reason);
+        },
+        ClientChat(id, channel, message) => {
+            if require_authenticated(state, id).is_none() {
+                return;
+            }
+            mark_active(state, id);
+
+            let limits = ctx.config.chat_limits();
+            if let Err(e) = chat::route(state, id, channel, &message, &limits, precise_time_ns()) {
+                chat::notice(state, id, &format!("{}", e));
+            }
+        },
+        ClientMoved(id, position) => {
+            if require_authenticated(state, id).is_none() {
+                return;
+            }
+            mark_active(state, id);
+            if let Some(player) = state.mut_get_players().get_mut(&id) {
+                player.set_position(position);
+            }
+        },
+        ClientParty(id, party) => {
+            if require_authenticated(state, id).is_none() {
+                return;
+            }
+            mark_active(state, id);
+
+            let joined = party.clone();
+            match chat::set_party(state, id, party) {
+                Ok(()) => match joined {
+                    Some(party) => chat::notice(state, id, &format!("You joined the party {}", party)),
+                    None => chat::notice(state, id, "You left your party"),
+                },
+                Err(e) => chat::notice(state, id, &format!("{}", e)),
+            }
+        },
+        ClientCommand(id, line) => {
+            if require_authenticated(state, id).is_none() {
+                return;
+            }
+            mark_active(state, id);
+
+            match ctx.commands.execute(state, &Caller::Player(id), &line) {
+                Ok(Some(output)) => chat::notice(state, id, &output),
+                Ok(None) => (),
+                Err(e) => chat::notice(state, id, &format!("{}", e)),
+            }
+        }
+    }
+}
+
+/// Tells a still connecting player why they could not log in
+fn refuse(state: &mut WorldState, id: usize, reason: String) {
+    let connecting = match state.get_players().get(&id) {
+        Some(player) => {
+            player.login_checked(false);
+            player.get_status() == PlayerStatus::Connecting
+        }
+        None => false
+    };
+    if connecting {
+        state.send_to(id, &Packet::AuthResult(AuthResult::Refused {
+            reason: reason
+        }));
+    }
+}
+
+/// Returns the name of the player if they are authenticated
+///
+/// Players that are still `Connecting` get kicked, they have no business
+/// sending gameplay packets yet.
+fn require_authenticated(state: &mut WorldState, id: usize) -> Option<String> {
+    let player = match state.mut_get_players().get_mut(&id) {
+        Some(player) => player,
+        None => return None
+    };
+
+    match player.get_status() {
+        PlayerStatus::Authenticated => player.get_name().clone(),
+        PlayerStatus::Connecting => {
+            println!("Player({}) sent a gameplay packet before authenticating", id);
+            player.kick("Not authenticated");
+            None
+        }
+        PlayerStatus::Disconnected => None
+    }
+}
+
+fn mark_active(state: &mut WorldState, id: usize) {
+    if let Some(player) = state.mut_get_players().get_mut(&id) {
+        player.mark_active(precise_time_ns());
+    }
+}
+
+/// Pings the players in the game and drops the ones that went quiet
+///
+/// Players that only answer pings but don't play are kicked once they hit
+/// the AFK timeout, if there is one.
+fn keep_alive(state: &mut WorldState, ctx: &Context) {
+    let now = precise_time_ns();
+    let idle_timeout = ms_to_ns(ctx.config.idle_timeout_ms);
+    let afk_timeout = ms_to_ns(ctx.config.afk_timeout_ms);
+
+    for (id, player) in state.mut_get_players().iter_mut() {
+        if player.get_status() != PlayerStatus::Authenticated {
+            continue;
+        }
+        if now.saturating_sub(player.get_last_seen()) > idle_timeout {
+            let _ = ctx.server_tx.send(ServerEvent::ClientTimedOut(*id));
+        } else if afk_timeout > 0 && now.saturating_sub(player.get_last_active()) > afk_timeout {
+            println!("Player({}) was away for too long", id);
+            player.kick("You were away for too long");
+        } else {
+            player.ping_every(now, ms_to_ns(ctx.config.ping_interval_ms));
+        }
+    }
+}
+
+/// Kicks every player that stayed `Connecting` for longer than `timeout`
+fn time_out_connecting(state: &mut WorldState, timeout: u64) {
+    let now = precise_time_ns();
+    for (id, player) in state.mut_get_players().iter_mut() {
+        if player.get_status() == PlayerStatus::Connecting &&
+            now.saturating_sub(player.get_connected_at()) > timeout {
+            println!("Player({}) timed out while connecting", id);
+            player.kick("Timed out while connecting");
+        }
+    }
 }
 
 
@@ -193,13 +746,13 @@ mod tests {
 
         assert_eq!(server.status(), ServerStatus::Stopped);
 
-        server.start();
+        server.start().unwrap();
 
         assert_eq!(server.status(), ServerStatus::Running{
             world_running: true, server_running: true, socket_running: true
         });
 
-        server.stop();
+        server.stop().unwrap();
 
         assert_eq!(server.status(), ServerStatus::Stopped);
     }
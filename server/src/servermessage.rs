@@ -1,4 +1,8 @@
 use std::net::TcpStream;
+use std::sync::mpsc::Sender;
+
+use admin::AdminCommand;
+use shared::packets::{ChatChannel, Position};
 
 pub enum WorldEvent {
     Quit,
@@ -7,6 +11,37 @@ pub enum WorldEvent {
 pub enum ServerEvent {
     Quit,
     ClientConnected(TcpStream),
+    /// The player proved to own the named account
     ClientAuthed(usize, String),
+    /// The player could not log in, with the reason
+    ClientAuthFailed(usize, String),
+    ClientChat(usize, ChatChannel, String),
+    ClientMoved(usize, Position),
+    /// The player wants to join a party, or leave theirs with `None`
+    ClientParty(usize, Option<String>),
+    /// A slash command, without the slash
+    ClientCommand(usize, String),
     ClientDisconnected(usize),
+    ClientPing(usize, u64),
+    ClientPong(usize, u64),
+    /// The player asked who is online
+    ClientPlayerList(usize),
+    /// The player did not send anything for too long
+    ClientTimedOut(usize),
+    /// A command from the console, the output goes back through the sender
+    Admin(AdminCommand, Sender<String>),
+}
+
+impl ServerEvent {
+    /// The player that sent the packet behind this event, if any
+    pub fn from_player(&self) -> Option<usize> {
+        use self::ServerEvent::*;
+        match *self {
+            ClientAuthed(id, _) | ClientAuthFailed(id, _) | ClientChat(id, _, _) |
+            ClientMoved(id, _) | ClientParty(id, _) | ClientCommand(id, _) |
+            ClientPing(id, _) | ClientPong(id, _) | ClientPlayerList(id) => Some(id),
+            Quit | ClientConnected(..) | ClientDisconnected(..) | ClientTimedOut(..) |
+            Admin(..) => None,
+        }
+    }
 }
@@ -0,0 +1,67 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, Builder, JoinHandle};
+
+use clock_ticks::precise_time_ns;
+
+/// A named thread that can be joined with a deadline
+///
+/// The standard `JoinHandle` can only block forever, which is not an
+/// option while shutting down, when a thread might be stuck on a socket.
+pub struct Worker {
+    name: String,
+    handle: JoinHandle<()>,
+    done: Arc<AtomicBool>,
+}
+
+/// Marks the worker as done once the thread function returns or panics
+struct DoneGuard(Arc<AtomicBool>);
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Worker {
+    pub fn spawn<F>(name: String, f: F) -> io::Result<Worker> where F: FnOnce() + Send + 'static {
+        let done = Arc::new(AtomicBool::new(false));
+        let guard = DoneGuard(done.clone());
+        let handle = try!(Builder::new().name(name.clone()).spawn(move|| {
+            let _guard = guard;
+            f();
+        }));
+
+        Ok(Worker {
+            name: name,
+            handle: handle,
+            done: done,
+        })
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the thread function has returned
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    /// Joins the thread, waiting at most until `deadline`
+    ///
+    /// `deadline` is a `precise_time_ns` timestamp. If the thread is still
+    /// running by then it is left to itself and its name returned.
+    pub fn join_until(self, deadline: u64) -> Result<(), String> {
+        while !self.is_done() {
+            if precise_time_ns() >= deadline {
+                return Err(self.name);
+            }
+            thread::sleep_ms(10);
+        }
+
+        let _ = self.handle.join();
+        Ok(())
+    }
+}
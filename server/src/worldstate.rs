@@ -1,18 +1,203 @@
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
 
-use player::Player;
+use rustc_serialize::json;
+
+use chat::ChatLimits;
+use config::ServerConfig;
+use player::{Player, PlayerStatus};
+use moderation::Moderation;
+use names::name_key;
+use netstats::NetReport;
+use shared::packets::{Packet, PlayerInfo, PlayerListEntry};
 
 pub type PlayerMap = HashMap<usize, Player>;
 
+/// Maps the `name_key` of every authenticated player to their id
+pub type NameIndex = HashMap<String, usize>;
+
+/// How long a day in the game takes, in real nanoseconds
+pub const DAY_LENGTH_NS: u64 = 20 * 60 * 1000000000;
+
+/// What is kept of the world between runs
+#[derive(RustcEncodable, RustcDecodable)]
+struct Stored {
+    time_of_day: u64,
+}
+
+#[derive(Debug)]
+pub enum WorldError {
+    Io(io::Error),
+    /// The world file could not be parsed
+    Corrupt(String),
+}
+
+impl Error for WorldError {
+    fn description(&self) -> &str {
+        match *self {
+            WorldError::Io(..) => "Could not access the world file.",
+            WorldError::Corrupt(..) => "The world file is corrupt.",
+        }
+    }
+}
+
+impl fmt::Display for WorldError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WorldError::Io(ref e) => write!(fmt, "Could not access the world file: {}", e),
+            WorldError::Corrupt(ref e) => write!(fmt, "The world file is corrupt: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for WorldError {
+    fn from(err: io::Error) -> WorldError {
+        WorldError::Io(err)
+    }
+}
+
 pub struct WorldState {
-    players: PlayerMap
+    /// Where the world is saved to, if anywhere
+    path: Option<PathBuf>,
+    players: PlayerMap,
+    names: NameIndex,
+    moderation: Moderation,
+    /// How far into the current day the world is, in real nanoseconds
+    time_of_day: u64,
+    /// For chat that does not come through the router, like `/me`
+    chat_limits: ChatLimits,
 }
 
 impl WorldState {
+    /// A world without any bans, that forgets them once dropped
     pub fn new() -> WorldState {
+        WorldState::with_moderation(Moderation::in_memory())
+    }
+
+    pub fn with_moderation(moderation: Moderation) -> WorldState {
         WorldState {
-            players: PlayerMap::new()
+            path: None,
+            players: PlayerMap::new(),
+            names: NameIndex::new(),
+            moderation: moderation,
+            time_of_day: DAY_LENGTH_NS / 4,
+            chat_limits: ServerConfig::default().chat_limits(),
+        }
+    }
+
+    /// Loads the world from `path`, a missing file means a fresh world
+    ///
+    /// `save` writes it back there.
+    pub fn open<P: AsRef<Path>>(path: P, moderation: Moderation)
+        -> Result<WorldState, WorldError> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = WorldState::with_moderation(moderation);
+
+        match File::open(&path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                let stored: Stored = match json::decode(&contents) {
+                    Ok(s) => s,
+                    Err(e) => return Err(WorldError::Corrupt(format!("{}", e)))
+                };
+                state.time_of_day = stored.time_of_day % DAY_LENGTH_NS;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(WorldError::Io(e))
+        }
+
+        state.path = Some(path);
+        Ok(state)
+    }
+
+    /// Writes the world to disk, if it was opened from a file
+    ///
+    /// Like the accounts, the file is replaced atomically.
+    pub fn save(&self) -> Result<(), WorldError> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(())
+        };
+
+        let stored = Stored {
+            time_of_day: self.time_of_day,
+        };
+        let encoded = match json::encode(&stored) {
+            Ok(e) => e,
+            Err(e) => return Err(WorldError::Corrupt(format!("{}", e)))
+        };
+
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            try!(file.write_all(encoded.as_bytes()));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&tmp, path));
+        Ok(())
+    }
+
+    pub fn get_chat_limits(&self) -> ChatLimits {
+        self.chat_limits
+    }
+
+    pub fn set_chat_limits(&mut self, limits: ChatLimits) {
+        self.chat_limits = limits;
+    }
+
+    /// Authenticates a player and indexes them by name
+    ///
+    /// Returns false if the player does not exist, can't be authenticated
+    /// or somebody else is already playing under that name.
+    pub fn auth_player(&mut self, id: usize, name: String) -> bool {
+        let key = name_key(&name);
+        if self.names.contains_key(&key) {
+            return false;
+        }
+
+        let authed = match self.players.get_mut(&id) {
+            Some(player) => player.auth(name),
+            None => false
+        };
+        if authed {
+            self.names.insert(key, id);
         }
+        authed
+    }
+
+    /// Removes a player, keeping the name index in sync
+    pub fn remove_player(&mut self, id: usize) -> Option<Player> {
+        let player = self.players.remove(&id);
+        if let Some(ref player) = player {
+            unindex(&mut self.names, player);
+        }
+        player
+    }
+
+    /// Removes every player at once
+    pub fn drain_players(&mut self) -> Vec<Player> {
+        self.names.clear();
+        mem::replace(&mut self.players, PlayerMap::new())
+            .into_iter().map(|(_, player)| player).collect()
+    }
+
+    /// Kicks a player, freeing their name right away
+    pub fn kick(&mut self, id: usize, reason: &str) {
+        if let Some(player) = self.players.get_mut(&id) {
+            player.kick(reason);
+            unindex(&mut self.names, player);
+        }
+    }
+
+    /// Finds the id of the authenticated player with the given name
+    pub fn find_player(&self, name: &str) -> Option<usize> {
+        self.names.get(&name_key(name)).cloned()
     }
 
     pub fn mut_get_players(&mut self) -> &mut PlayerMap {
@@ -22,5 +207,133 @@ impl WorldState {
     pub fn get_players(&self) -> &PlayerMap {
         &self.players
     }
+
+    pub fn get_moderation(&self) -> &Moderation {
+        &self.moderation
+    }
+
+    pub fn mut_get_moderation(&mut self) -> &mut Moderation {
+        &mut self.moderation
+    }
+
+    /// Lets `ns` real nanoseconds pass in the world
+    pub fn advance_time(&mut self, ns: u64) {
+        self.time_of_day = (self.time_of_day + ns % DAY_LENGTH_NS) % DAY_LENGTH_NS;
+    }
+
+    /// The time of day on the clock of the game, as hours and minutes
+    pub fn get_time_of_day(&self) -> (u32, u32) {
+        let minutes = self.time_of_day * 24 * 60 / DAY_LENGTH_NS;
+        ((minutes / 60) as u32, (minutes % 60) as u32)
+    }
+
+    /// Sets the clock of the game, hours past 23 wrap around
+    pub fn set_time_of_day(&mut self, hours: u32, minutes: u32) {
+        let minutes = (hours as u64 * 60 + minutes as u64) % (24 * 60);
+        self.time_of_day = minutes * DAY_LENGTH_NS / (24 * 60);
+    }
+
+    /// Sends `packet` to the player with the given id
+    ///
+    /// Returns false if there is no such player or sending failed.
+    pub fn send_to(&mut self, id: usize, packet: &Packet) -> bool {
+        match self.players.get_mut(&id) {
+            Some(player) => player.send(packet).is_ok(),
+            None => false
+        }
+    }
+
+    /// Sends `packet` to every authenticated player
+    pub fn broadcast(&mut self, packet: &Packet) {
+        for (_, player) in self.players.iter_mut() {
+            if player.get_status() == PlayerStatus::Authenticated {
+                let _ = player.send(packet);
+            }
+        }
+    }
+
+    /// Sends `packet` to every authenticated player but one
+    pub fn broadcast_except(&mut self, except: usize, packet: &Packet) {
+        for (id, player) in self.players.iter_mut() {
+            if *id != except && player.get_status() == PlayerStatus::Authenticated {
+                let _ = player.send(packet);
+            }
+        }
+    }
+
+    /// Lists every authenticated player, as sent to the clients
+    pub fn player_infos(&self) -> Vec<PlayerInfo> {
+        self.players.values().filter(|player| {
+            player.get_status() == PlayerStatus::Authenticated
+        }).filter_map(|player| {
+            player.get_name().as_ref().map(|name| PlayerInfo {
+                player_id: player.get_id() as u64,
+                name: name.clone(),
+            })
+        }).collect()
+    }
+
+    /// Lists every authenticated player with their ping, sorted by name
+    pub fn player_list(&self) -> Vec<PlayerListEntry> {
+        let mut list: Vec<PlayerListEntry> = self.players.values().filter(|player| {
+            player.get_status() == PlayerStatus::Authenticated
+        }).filter_map(|player| {
+            player.get_name().as_ref().map(|name| PlayerListEntry {
+                player_id: player.get_id() as u64,
+                name: name.clone(),
+                ping_ms: player.get_latency().map(|l| l.ms()),
+            })
+        }).collect();
+        list.sort_by(|a, b| name_key(&a.name).cmp(&name_key(&b.name)));
+        list
+    }
+
+    /// The network statistics of a player as of `now`
+    pub fn net_report(&self, id: usize, now: u64) -> Option<NetReport> {
+        self.players.get(&id).map(|player| player.net_report(now))
+    }
+
+    /// The network statistics of every connected player, sorted by id
+    pub fn net_reports(&self, now: u64) -> Vec<(usize, NetReport)> {
+        let mut reports: Vec<(usize, NetReport)> = self.players.iter().map(|(id, player)| {
+            (*id, player.net_report(now))
+        }).collect();
+        reports.sort_by(|a, b| a.0.cmp(&b.0));
+        reports
+    }
 }
 
+/// Drops the player from the index, if the name still points to them
+fn unindex(names: &mut NameIndex, player: &Player) {
+    if let Some(ref name) = *player.get_name() {
+        let key = name_key(name);
+        if names.get(&key) == Some(&player.get_id()) {
+            names.remove(&key);
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use moderation::Moderation;
+
+    #[test]
+    fn persistence() {
+        let path = env::temp_dir().join("rpg_world_test.json");
+        let _ = fs::remove_file(&path);
+
+        {
+            let mut state = WorldState::open(&path, Moderation::in_memory()).unwrap();
+            assert_eq!(state.get_time_of_day(), (6, 0));
+            state.set_time_of_day(18, 30);
+            state.save().unwrap();
+        }
+
+        let state = WorldState::open(&path, Moderation::in_memory()).unwrap();
+        assert_eq!(state.get_time_of_day(), (18, 30));
+
+        let _ = fs::remove_file(&path);
+    }
+}
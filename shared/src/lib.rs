@@ -1,6 +1,8 @@
 extern crate clock_ticks;
 extern crate rustc_serialize;
 extern crate bincode;
+extern crate crypto;
+extern crate rand;
 
 mod gameloop;
 pub mod net;
@@ -0,0 +1,638 @@
+//! Framing for non-blocking streams
+//!
+//! A `PacketDecoder` is fed whatever bytes arrived and hands out the
+//! frames that are complete, a `PacketEncoder` queues whole frames and
+//! writes as much of them as the stream takes. Neither ever blocks, so a
+//! single thread can serve many sockets with them.
+//!
+//! The frames are exactly those of `send_packet` and `receive_packet`,
+//! both ways of talking can be mixed freely. Once a connection negotiated
+//! compression, `set_compression` switches either of them to the framing
+//! of `send_packet_with` and `receive_packet_with`, and `set_cipher` does
+//! the same once it is encrypted.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use rustc_serialize::{Decodable, Encodable};
+
+use packets::Packet;
+use super::{decode_header, decode_payload, encode_payload, frame_payload, seal_payload};
+use super::{PacketError, HEADER_SIZE, MAX_FRAME_SIZE, MAX_PACKET_SIZE};
+use super::compress::{compress_payload, decompress_payload, CompressionStats};
+use super::compress::COMPRESSION_OVERHEAD;
+use super::secure::{Cipher, SEAL_OVERHEAD};
+
+/// How many bytes `PacketDecoder::read_from` reads at most per call
+pub const READ_CHUNK: usize = 4096;
+
+/// Consumed bytes are only moved out of a buffer once there are this many
+const COMPACT_AFTER: usize = 4096;
+
+/// Drops the bytes before `start`, if that's worth it
+fn compact(buffer: &mut Vec<u8>, start: &mut usize) {
+    if *start == buffer.len() {
+        buffer.clear();
+        *start = 0;
+    } else if *start >= COMPACT_AFTER {
+        *buffer = buffer[*start..].to_vec();
+        *start = 0;
+    }
+}
+
+/// Turns chunks of bytes into frames
+///
+/// # Examples
+///
+/// ```
+/// use shared::net::{send_packet, PacketDecoder};
+/// use shared::packets::Packet;
+///
+/// let mut bytes = Vec::new();
+/// send_packet(&mut bytes, &Packet::Ping(7)).unwrap();
+///
+/// let mut decoder = PacketDecoder::new();
+/// decoder.feed(&bytes[..3]);
+/// assert_eq!(decoder.next_packet().unwrap(), None);
+/// decoder.feed(&bytes[3..]);
+/// assert_eq!(decoder.next_packet().unwrap(), Some(Packet::Ping(7)));
+/// ```
+pub struct PacketDecoder {
+    buffer: Vec<u8>,
+    /// Where the bytes not decoded yet start in `buffer`
+    start: usize,
+    limit: usize,
+    compressed: bool,
+    stats: CompressionStats,
+    /// Opens every frame, once the connection is encrypted
+    cipher: Option<Cipher>,
+}
+
+impl PacketDecoder {
+    /// A decoder for frames of up to `MAX_PACKET_SIZE` bytes
+    pub fn new() -> PacketDecoder {
+        PacketDecoder::with_limit(MAX_PACKET_SIZE)
+    }
+
+    /// A decoder for frames of up to `limit` bytes, capped at `MAX_FRAME_SIZE`
+    pub fn with_limit(limit: usize) -> PacketDecoder {
+        PacketDecoder {
+            buffer: Vec::new(),
+            start: 0,
+            limit: cmp::min(limit, MAX_FRAME_SIZE),
+            compressed: false,
+            stats: CompressionStats::default(),
+            cipher: None,
+        }
+    }
+
+    /// Whether the frames from now on have a compression flag
+    pub fn set_compression(&mut self, compressed: bool) {
+        self.compressed = compressed;
+    }
+
+    /// The payloads decoded since compression was switched on
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    /// Opens every frame from now on with `cipher`
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Adds bytes that arrived
+    pub fn feed(&mut self, bytes: &[u8]) {
+        compact(&mut self.buffer, &mut self.start);
+        self.buffer.extend(bytes.iter().cloned());
+    }
+
+    /// Reads once from `reader` and feeds what it got
+    ///
+    /// Returns how many bytes were read, 0 meaning the stream ended. Errors
+    /// are passed on as they are, including `WouldBlock`.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let mut chunk = [0; READ_CHUNK];
+        let read = try!(reader.read(&mut chunk));
+        self.feed(&chunk[..read]);
+        Ok(read)
+    }
+
+    /// Decodes the next complete frame as `T`, if there is one
+    ///
+    /// A `DecodeError` consumes the frame, the next one can be decoded
+    /// just fine. After a `TooLarge` the stream can't be trusted anymore,
+    /// the decoder keeps returning it. Neither can it after an `Insecure`,
+    /// the frames that follow won't open either.
+    pub fn next_frame<T: Decodable>(&mut self) -> Result<Option<T>, PacketError> {
+        let available = self.buffer.len() - self.start;
+        if available < HEADER_SIZE {
+            return Ok(None);
+        }
+        let size = decode_header([self.buffer[self.start], self.buffer[self.start + 1]]);
+        let mut overhead = if self.compressed { COMPRESSION_OVERHEAD } else { 0 };
+        if self.cipher.is_some() {
+            overhead += SEAL_OVERHEAD;
+        }
+        if size > self.limit + overhead {
+            return Err(PacketError::TooLarge);
+        }
+        if available < HEADER_SIZE + size {
+            return Ok(None);
+        }
+
+        let payload = self.start + HEADER_SIZE;
+        self.start = payload + size;
+        let opened = match self.cipher {
+            Some(ref mut cipher) => try!(cipher.open(&self.buffer[payload..self.start])),
+            None => self.buffer[payload..self.start].to_vec(),
+        };
+        if !self.compressed {
+            return decode_payload(&opened).map(Some);
+        }
+        let raw = try!(decompress_payload(&opened, self.limit));
+        self.stats.add(raw.len(), opened.len());
+        decode_payload(&raw).map(Some)
+    }
+
+    /// Decodes the next complete `Packet`, if there is one
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, PacketError> {
+        self.next_frame()
+    }
+
+    /// How many bytes are waiting for the rest of their frame
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    /// Checks that the stream did not end in the middle of a frame
+    ///
+    /// Fails with `MismatchedSize` if it did, like `receive_packet` would.
+    pub fn finish(&self) -> Result<(), PacketError> {
+        if self.buffered() == 0 { Ok(()) } else { Err(PacketError::MismatchedSize) }
+    }
+}
+
+/// Queues frames until the stream takes them
+///
+/// # Examples
+///
+/// ```
+/// use shared::net::{receive_packet, PacketEncoder};
+/// use shared::packets::Packet;
+///
+/// let mut encoder = PacketEncoder::new();
+/// encoder.push(&Packet::Ping(7)).unwrap();
+///
+/// let mut bytes = Vec::new();
+/// encoder.write_to(&mut bytes).unwrap();
+/// assert!(encoder.is_empty());
+/// assert_eq!(receive_packet(&mut &bytes[..]).unwrap(), Packet::Ping(7));
+/// ```
+pub struct PacketEncoder {
+    buffer: Vec<u8>,
+    /// Where the bytes not written yet start in `buffer`
+    start: usize,
+    limit: usize,
+    /// Payloads of at least this many bytes get compressed, if set
+    compression: Option<usize>,
+    stats: CompressionStats,
+    /// Seals every frame, once the connection is encrypted
+    cipher: Option<Cipher>,
+}
+
+impl PacketEncoder {
+    /// An encoder for frames of up to `MAX_PACKET_SIZE` bytes
+    pub fn new() -> PacketEncoder {
+        PacketEncoder::with_limit(MAX_PACKET_SIZE)
+    }
+
+    /// An encoder for frames of up to `limit` bytes, capped at `MAX_FRAME_SIZE`
+    pub fn with_limit(limit: usize) -> PacketEncoder {
+        PacketEncoder {
+            buffer: Vec::new(),
+            start: 0,
+            limit: limit,
+            compression: None,
+            stats: CompressionStats::default(),
+            cipher: None,
+        }
+    }
+
+    /// Gives the frames from now on a compression flag, and compresses
+    /// payloads of at least `threshold` bytes. `None` switches back.
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.compression = threshold;
+    }
+
+    /// The payloads encoded since compression was switched on
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    /// Seals every frame queued from now on with `cipher`
+    pub fn set_cipher(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Encodes `message` and queues it as a single frame
+    ///
+    /// Nothing is queued if encoding fails.
+    pub fn push_frame<T: Encodable>(&mut self, message: &T) -> Result<(), PacketError> {
+        let mut payload = try!(encode_payload(message, self.limit));
+        let raw = payload.len();
+        if let Some(threshold) = self.compression {
+            payload = compress_payload(payload, threshold);
+        }
+        let compressed = payload.len();
+        let frame = try!(frame_payload(try!(seal_payload(payload, self.cipher.as_mut()))));
+        if self.compression.is_some() {
+            self.stats.add(raw, compressed);
+        }
+        compact(&mut self.buffer, &mut self.start);
+        self.buffer.extend(frame.into_iter());
+        Ok(())
+    }
+
+    /// Encodes a `Packet` and queues it
+    pub fn push(&mut self, packet: &Packet) -> Result<(), PacketError> {
+        self.push_frame(packet)
+    }
+
+    /// Writes queued bytes until they are all gone or `writer` would block
+    ///
+    /// Returns how many bytes were written. Whatever `writer` did not take
+    /// stays queued for the next call.
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let mut written = 0;
+        while self.start < self.buffer.len() {
+            match writer.write(&self.buffer[self.start..]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                                              "The stream does not take any more bytes"));
+                }
+                Ok(n) => {
+                    self.start += n;
+                    written += n;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        compact(&mut self.buffer, &mut self.start);
+        Ok(written)
+    }
+
+    /// How many bytes still wait to be written
+    pub fn pending(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending() == 0
+    }
+}
+
+mod test {
+    use super::*;
+    use net::{send_packet, send_frame_limited, PacketError, HEADER_SIZE, MAX_FRAME_SIZE,
+              MAX_PACKET_SIZE};
+    use net::{receive_packet_with, send_packet_with};
+    use net::secure::{Handshake, Identity, SessionKeys};
+    use packets::{Packet, ChatChannel, RconPacket, PROTOCOL_VERSION};
+    use std::io::{self, Write};
+
+    fn chat(message: &str) -> Packet {
+        Packet::SendChat {
+            channel: ChatChannel::Global,
+            message: message.to_string(),
+        }
+    }
+
+    fn valid_frame() -> Vec<u8> {
+        let mut frame = Vec::<u8>::new();
+        send_packet(&mut frame, &chat("Neikos")).unwrap();
+        frame
+    }
+
+    fn decode_all(bytes: &[u8]) -> Result<Option<Packet>, PacketError> {
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(bytes);
+        decoder.next_packet()
+    }
+
+    /// Takes a few bytes per `write`, then would block
+    struct Congested {
+        written: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for Congested {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "full"));
+            }
+            let n = ::std::cmp::min(::std::cmp::min(buf.len(), self.room), 3);
+            self.written.extend(buf[..n].iter().cloned());
+            self.room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_read_write() {
+        let test_packet = Packet::AuthPlayer {
+            name: "Neikos".to_string(),
+            password: "hunter22".to_string(),
+        };
+
+        let mut encoder = PacketEncoder::new();
+        encoder.push(&test_packet).unwrap();
+        let mut bytes = Vec::new();
+        encoder.write_to(&mut bytes).unwrap();
+
+        assert_eq!(decode_all(&bytes[..]).unwrap(), Some(test_packet));
+    }
+
+    #[test]
+    fn test_hello_round_trip() {
+        let hello = Packet::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "test".to_string(),
+            compression: false,
+        };
+        let mut frame = Vec::<u8>::new();
+        send_packet(&mut frame, &hello).unwrap();
+
+        assert_eq!(decode_all(&frame[..]).unwrap(), Some(hello));
+    }
+
+    #[test]
+    fn test_other_messages() {
+        let mut frame = Vec::<u8>::new();
+        send_frame_limited(&mut frame, &RconPacket::Command("list".to_string()),
+                           MAX_FRAME_SIZE).unwrap();
+
+        let mut decoder = PacketDecoder::with_limit(MAX_FRAME_SIZE);
+        decoder.feed(&frame[..]);
+        let message: Option<RconPacket> = decoder.next_frame().unwrap();
+        assert_eq!(message, Some(RconPacket::Command("list".to_string())));
+    }
+
+    #[test]
+    fn test_same_bytes() {
+        let mut encoder = PacketEncoder::new();
+        encoder.push(&chat("Neikos")).unwrap();
+        let mut bytes = Vec::new();
+        encoder.write_to(&mut bytes).unwrap();
+
+        assert_eq!(bytes, valid_frame());
+    }
+
+    #[test]
+    fn test_byte_by_byte() {
+        let mut stream = valid_frame();
+        stream.extend(valid_frame().into_iter());
+
+        let mut decoder = PacketDecoder::new();
+        let mut packets = Vec::new();
+        for byte in stream.iter() {
+            decoder.feed(&[*byte]);
+            while let Some(packet) = decoder.next_packet().unwrap() {
+                packets.push(packet);
+            }
+        }
+        assert_eq!(packets, vec![chat("Neikos"), chat("Neikos")]);
+        assert_eq!(decoder.buffered(), 0);
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn test_many_frames_in_one_chunk() {
+        let mut stream = Vec::new();
+        for i in 0..100 {
+            send_packet(&mut stream, &Packet::Ping(i)).unwrap();
+        }
+        let mut decoder = PacketDecoder::new();
+        decoder.read_from(&mut &stream[..]).unwrap();
+
+        for i in 0..100 {
+            assert_eq!(decoder.next_packet().unwrap(), Some(Packet::Ping(i)));
+        }
+        assert_eq!(decoder.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let mut encoder = PacketEncoder::new();
+        encoder.push(&chat("Neikos")).unwrap();
+        encoder.push(&chat("Neikos")).unwrap();
+        let total = encoder.pending();
+
+        let mut congested = Congested { written: Vec::new(), room: 5 };
+        assert_eq!(encoder.write_to(&mut congested).unwrap(), 5);
+        assert_eq!(encoder.pending(), total - 5);
+
+        congested.room = total;
+        assert_eq!(encoder.write_to(&mut congested).unwrap(), total - 5);
+        assert!(encoder.is_empty());
+
+        let mut expected = valid_frame();
+        expected.extend(valid_frame().into_iter());
+        assert_eq!(congested.written, expected);
+    }
+
+    #[test]
+    fn test_send_too_large() {
+        let message = (0..MAX_PACKET_SIZE).map(|_| 'a').collect::<String>();
+        let mut encoder = PacketEncoder::new();
+
+        match encoder.push(&chat(&message)) {
+            Err(PacketError::EncodeTooLarge(size)) => assert!(size > MAX_PACKET_SIZE),
+            other => panic!("Expected EncodeTooLarge, got {:?}", other.err()),
+        }
+        assert!(encoder.is_empty());
+    }
+
+    #[test]
+    fn test_custom_limit() {
+        let frame = valid_frame();
+        let payload = frame.len() - HEADER_SIZE;
+
+        let mut decoder = PacketDecoder::with_limit(payload - 1);
+        decoder.feed(&frame[..]);
+        match decoder.next_packet() {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+
+        let mut decoder = PacketDecoder::with_limit(payload);
+        decoder.feed(&frame[..]);
+        assert!(decoder.next_packet().unwrap().is_some());
+
+        match PacketEncoder::with_limit(payload - 1).push(&chat("Neikos")) {
+            Err(PacketError::EncodeTooLarge(size)) => assert_eq!(size, payload),
+            other => panic!("Expected EncodeTooLarge, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let frame = valid_frame();
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&frame[..1]);
+
+        assert_eq!(decoder.next_packet().unwrap(), None);
+        match decoder.finish() {
+            Err(PacketError::MismatchedSize) => (),
+            other => panic!("Expected MismatchedSize, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let frame = valid_frame();
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&frame[..frame.len() - 3]);
+
+        assert_eq!(decoder.next_packet().unwrap(), None);
+        assert_eq!(decoder.buffered(), frame.len() - 3);
+        match decoder.finish() {
+            Err(PacketError::MismatchedSize) => (),
+            other => panic!("Expected MismatchedSize, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        // Only the header has to be there to tell
+        match decode_all(&[0xFF, 0xFF]) {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_corrupted_frame() {
+        let mut stream = valid_frame();
+        let len = stream.len();
+        for byte in stream[2..6].iter_mut() {
+            *byte = 0xFF;
+        }
+        stream.extend(valid_frame().into_iter());
+
+        let mut decoder = PacketDecoder::new();
+        decoder.feed(&stream[..]);
+        match decoder.next_packet() {
+            Err(PacketError::DecodeError(msg, size)) => {
+                assert!(!msg.is_empty());
+                assert_eq!(size, len - 2);
+            }
+            other => panic!("Expected DecodeError, got {:?}", other),
+        }
+        // The broken frame is skipped, the stream goes on
+        assert_eq!(decoder.next_packet().unwrap(), Some(chat("Neikos")));
+    }
+
+    /// Both sides of a fresh encrypted session, client first
+    fn session() -> (SessionKeys, SessionKeys) {
+        let identity = Identity::generate().unwrap();
+        let client = Handshake::new().unwrap();
+        let key = match client.packet() {
+            Packet::KeyExchange { key } => key,
+            other => panic!("Expected a KeyExchange, got {:?}", other),
+        };
+        match Handshake::new().unwrap().respond(&identity, &key).unwrap() {
+            (Packet::KeyExchangeReply { identity, key, signature }, server) => {
+                (client.finish(&identity, &key, &signature).unwrap(), server)
+            }
+            (other, _) => panic!("Expected a KeyExchangeReply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        let long: String = (0..300).map(|_| "ab").collect();
+        let (client, server) = session();
+        let (mut client_send, mut client_receive) = (client.send, client.receive);
+
+        let mut encoder = PacketEncoder::new();
+        encoder.set_compression(Some(64));
+        encoder.set_cipher(server.send);
+        encoder.push(&chat("hunter22")).unwrap();
+        encoder.push(&chat(&long)).unwrap();
+        let mut bytes = Vec::new();
+        encoder.write_to(&mut bytes).unwrap();
+        assert!(bytes.windows(8).all(|w| w != b"hunter22"));
+
+        let mut reader = &bytes[..];
+        assert_eq!(receive_packet_with(&mut reader, MAX_PACKET_SIZE, true,
+                                       Some(&mut client_receive)).unwrap(), chat("hunter22"));
+        assert_eq!(receive_packet_with(&mut reader, MAX_PACKET_SIZE, true,
+                                       Some(&mut client_receive)).unwrap(), chat(&long));
+
+        let mut sent = Vec::new();
+        send_packet_with(&mut sent, &chat(&long), MAX_PACKET_SIZE, Some(64),
+                         Some(&mut client_send)).unwrap();
+        send_packet_with(&mut sent, &chat("hunter22"), MAX_PACKET_SIZE, Some(64),
+                         Some(&mut client_send)).unwrap();
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(true);
+        decoder.set_cipher(server.receive);
+        decoder.feed(&sent);
+        assert_eq!(decoder.next_packet().unwrap(), Some(chat(&long)));
+        assert_eq!(decoder.next_packet().unwrap(), Some(chat("hunter22")));
+
+        // A frame that was tampered with does not open
+        let mut tampered = Vec::new();
+        send_packet_with(&mut tampered, &chat("Neikos"), MAX_PACKET_SIZE, Some(64),
+                         Some(&mut client_send)).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x80;
+        decoder.feed(&tampered);
+        match decoder.next_packet() {
+            Err(PacketError::Insecure(..)) => (),
+            other => panic!("Expected Insecure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let long: String = (0..300).map(|_| "ab").collect();
+        let mut encoder = PacketEncoder::new();
+        encoder.set_compression(Some(64));
+        encoder.push(&chat("short")).unwrap();
+        encoder.push(&chat(&long)).unwrap();
+        let mut bytes = Vec::new();
+        encoder.write_to(&mut bytes).unwrap();
+
+        let stats = encoder.compression_stats();
+        assert!(stats.wire < stats.raw / 2, "{:?}", stats);
+
+        // Both framings read the same
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(true);
+        decoder.feed(&bytes);
+        assert_eq!(decoder.next_packet().unwrap(), Some(chat("short")));
+        assert_eq!(decoder.next_packet().unwrap(), Some(chat(&long)));
+        assert_eq!(decoder.compression_stats(), stats);
+
+        let mut reader = &bytes[..];
+        assert_eq!(receive_packet_with(&mut reader, MAX_PACKET_SIZE, true, None).unwrap(),
+                   chat("short"));
+        assert_eq!(receive_packet_with(&mut reader, MAX_PACKET_SIZE, true, None).unwrap(),
+                   chat(&long));
+
+        let mut sent = Vec::new();
+        send_packet_with(&mut sent, &chat(&long), MAX_PACKET_SIZE, Some(64), None).unwrap();
+        // Only a decoder that knows about the flag can read it
+        assert!(decode_all(&sent).is_err());
+        let mut decoder = PacketDecoder::new();
+        decoder.set_compression(true);
+        decoder.feed(&sent);
+        assert_eq!(decoder.next_packet().unwrap(), Some(chat(&long)));
+    }
+}
@@ -0,0 +1,309 @@
+//! Compression of frame payloads
+//!
+//! Connections that negotiated compression start every frame payload
+//! with a flag byte:
+//!
+//! ```text
+//! +---------+--------------------------------------------------+
+//! | 0       | the payload as it is                             |
+//! +---------+--------------------------------------------------+
+//! | 1       | size: u32 LE | the payload, compressed           |
+//! +---------+--------------------------------------------------+
+//! ```
+//!
+//! Payloads below a threshold are sent as they are, as well as those that
+//! would not get any smaller.
+//!
+//! The compressor is a plain LZ77 with a hash table, in the spirit of LZ4.
+//! The compressed data is a series of sequences, each of them a token
+//! byte, literals to copy, and a match to copy from the output so far:
+//!
+//! ```text
+//! token: literals << 4 | (match - 4), 15 meaning more length bytes follow
+//! [more literal length] literals [offset: u16 LE] [more match length]
+//! ```
+//!
+//! The last sequence has literals only. Lengths of 15 and more continue in
+//! bytes that are added up, until one is not 255.
+
+use std::cmp;
+
+use super::PacketError;
+
+/// Bytes the flag and size add to a compressed payload at most
+pub const COMPRESSION_OVERHEAD: usize = 1 + 4;
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// Shortest match worth a sequence
+const MIN_MATCH: usize = 4;
+
+/// Matches are looked for this far back at most
+const MAX_OFFSET: usize = 0xFFFF;
+
+const HASH_BITS: usize = 12;
+
+/// Payload bytes before and after compression, of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CompressionStats {
+    /// Payload bytes as they were encoded
+    pub raw: u64,
+    /// The same payloads as they went over the wire
+    pub wire: u64,
+}
+
+impl CompressionStats {
+    pub fn add(&mut self, raw: usize, wire: usize) {
+        self.raw += raw as u64;
+        self.wire += wire as u64;
+    }
+
+    /// How large payloads are on the wire compared to before, `None`
+    /// if there were none
+    pub fn ratio(&self) -> Option<f64> {
+        if self.raw == 0 { None } else { Some(self.wire as f64 / self.raw as f64) }
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = (bytes[0] as u32) | (bytes[1] as u32) << 8 |
+               (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24;
+    (word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        out.push(255);
+        length -= 255;
+    }
+    out.push(length as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let match_length = found.map_or(0, |(_, length)| length - MIN_MATCH);
+    let token = (cmp::min(literals.len(), 15) << 4) | cmp::min(match_length, 15);
+    out.push(token as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend(literals.iter().cloned());
+    if let Some((offset, _)) = found {
+        out.push((offset & 0xFF) as u8);
+        out.push((offset >> 8) as u8);
+        if match_length >= 15 {
+            write_length(out, match_length - 15);
+        }
+    }
+}
+
+/// Compresses `input`, see the module documentation for the format
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    // Where each hash was seen last, plus one so that 0 means never
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos + MIN_MATCH <= input.len() {
+        let slot = hash(&input[pos..]);
+        let candidate = table[slot];
+        table[slot] = pos + 1;
+
+        if candidate > 0 && pos - (candidate - 1) <= MAX_OFFSET &&
+           input[candidate - 1..candidate - 1 + MIN_MATCH] == input[pos..pos + MIN_MATCH] {
+            let from = candidate - 1;
+            let mut length = MIN_MATCH;
+            while pos + length < input.len() && input[from + length] == input[pos + length] {
+                length += 1;
+            }
+            write_sequence(&mut out, &input[anchor..pos], Some((pos - from, length)));
+            pos += length;
+            anchor = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+fn corrupt(input: &[u8]) -> PacketError {
+    PacketError::DecodeError("Corrupt compressed payload".to_string(), input.len())
+}
+
+fn read_length(input: &[u8], pos: &mut usize, mut length: usize) -> Result<usize, PacketError> {
+    if length < 15 {
+        return Ok(length);
+    }
+    loop {
+        let byte = match input.get(*pos) {
+            Some(&byte) => byte,
+            None => return Err(corrupt(input)),
+        };
+        *pos += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+/// Decompresses `input`, which has to come out as exactly `size` bytes
+///
+/// Nothing larger than `size` is ever allocated, whatever `input` says.
+pub fn decompress(input: &[u8], size: usize) -> Result<Vec<u8>, PacketError> {
+    let mut out: Vec<u8> = Vec::with_capacity(size);
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let token = input[pos] as usize;
+        pos += 1;
+
+        let literals = try!(read_length(input, &mut pos, token >> 4));
+        if pos + literals > input.len() || out.len() + literals > size {
+            return Err(corrupt(input));
+        }
+        out.extend(input[pos..pos + literals].iter().cloned());
+        pos += literals;
+        if pos == input.len() {
+            break;
+        }
+
+        if pos + 2 > input.len() {
+            return Err(corrupt(input));
+        }
+        let offset = input[pos] as usize | (input[pos + 1] as usize) << 8;
+        pos += 2;
+        let length = try!(read_length(input, &mut pos, token & 15)) + MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + length > size {
+            return Err(corrupt(input));
+        }
+        // Byte by byte, a match may overlap what it produces
+        let from = out.len() - offset;
+        for n in 0..length {
+            let byte = out[from + n];
+            out.push(byte);
+        }
+    }
+
+    if out.len() != size {
+        return Err(corrupt(input));
+    }
+    Ok(out)
+}
+
+/// Adds the flag to an encoded payload, compressing it if that's worth it
+pub fn compress_payload(payload: Vec<u8>, threshold: usize) -> Vec<u8> {
+    if payload.len() >= threshold {
+        let compressed = compress(&payload);
+        if compressed.len() + COMPRESSION_OVERHEAD <= payload.len() {
+            let size = payload.len();
+            let mut out = Vec::with_capacity(COMPRESSION_OVERHEAD + compressed.len());
+            out.push(COMPRESSED);
+            out.extend([size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]
+                       .iter().cloned());
+            out.extend(compressed.into_iter());
+            return out;
+        }
+    }
+    let mut out = Vec::with_capacity(1 + payload.len());
+    out.push(RAW);
+    out.extend(payload.into_iter());
+    out
+}
+
+/// Undoes `compress_payload`, for payloads of up to `limit` bytes
+pub fn decompress_payload(payload: &[u8], limit: usize) -> Result<Vec<u8>, PacketError> {
+    match payload.first() {
+        Some(&RAW) => {
+            if payload.len() - 1 > limit {
+                return Err(PacketError::TooLarge);
+            }
+            Ok(payload[1..].to_vec())
+        }
+        Some(&COMPRESSED) if payload.len() >= COMPRESSION_OVERHEAD => {
+            let size = (payload[1] as usize) | (payload[2] as usize) << 8 |
+                       (payload[3] as usize) << 16 | (payload[4] as usize) << 24;
+            if size > limit {
+                return Err(PacketError::TooLarge);
+            }
+            decompress(&payload[COMPRESSION_OVERHEAD..], size)
+        }
+        _ => Err(corrupt(payload))
+    }
+}
+
+mod test {
+    use super::*;
+    use net::PacketError;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        compressed
+    }
+
+    #[test]
+    fn test_round_trips() {
+        round_trip(b"");
+        round_trip(b"abc");
+        round_trip(b"abcdefghijklmnopqrstuvwxyz0123456789");
+
+        // Runs overlap their own output
+        let zeros = vec![0u8; 10000];
+        assert!(round_trip(&zeros).len() < 100);
+
+        let mut chunk = Vec::new();
+        for n in 0..4096u32 {
+            chunk.push((n % 7) as u8);
+            chunk.push((n / 256) as u8);
+        }
+        assert!(round_trip(&chunk).len() < chunk.len() / 4);
+
+        // Nothing to find
+        let mut x = 12345u32;
+        let noise: Vec<u8> = (0..5000).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        }).collect();
+        round_trip(&noise);
+    }
+
+    #[test]
+    fn test_payloads() {
+        let small = b"tiny".to_vec();
+        let flagged = compress_payload(small.clone(), 64);
+        assert_eq!(flagged.len(), small.len() + 1);
+        assert_eq!(decompress_payload(&flagged, 64).unwrap(), small);
+
+        let large = vec![7u8; 1000];
+        let flagged = compress_payload(large.clone(), 64);
+        assert!(flagged.len() < 100);
+        assert_eq!(decompress_payload(&flagged, 1000).unwrap(), large);
+        match decompress_payload(&flagged, 999) {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let compressed = compress(&vec![1u8; 1000]);
+        assert!(decompress(&compressed, 999).is_err());
+        assert!(decompress(&compressed, 1001).is_err());
+        assert!(decompress(&compressed[..compressed.len() - 1], 1000).is_err());
+        // A match reaching back before the start
+        assert!(decompress(&[0x00, 0x05, 0x00], 4).is_err());
+        assert!(decompress_payload(&[2, 1, 2, 3], 100).is_err());
+        assert!(decompress_payload(&[], 100).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = CompressionStats::default();
+        assert_eq!(stats.ratio(), None);
+        stats.add(1000, 250);
+        assert_eq!(stats.ratio(), Some(0.25));
+    }
+}
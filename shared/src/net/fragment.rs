@@ -0,0 +1,483 @@
+//! `Packet`s larger than a frame
+//!
+//! A `Fragmenter` encodes a `Packet` and, if that does not fit into a
+//! single frame, cuts it into `Packet::Fragment`s which do. Every one of
+//! them carries the id of the message it belongs to, its index and how
+//! many there are. A `Reassembler` on the other side collects them and
+//! hands out the original `Packet` once all of them arrived.
+//!
+//! The frame limit stays as it is, so a peer can't make the other side
+//! buffer a huge frame. What a peer can make it buffer through fragments
+//! is bounded by the `ReassemblyLimits`: the size of a single message,
+//! how many may be unfinished at a time, the memory taken by all of those
+//! together, and how long a message may take to complete.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{u16, u64};
+
+use clock_ticks::precise_time_ns;
+
+use packets::Packet;
+use super::{decode_payload, encode_message, receive_packet_with, send_packet_with};
+use super::{PacketError, MAX_FRAME_SIZE, MAX_PACKET_SIZE};
+use super::secure::Cipher;
+
+/// Bytes a `Fragment` takes besides its data
+pub const FRAGMENT_OVERHEAD: usize = 4 + 4 + 2 + 2 + 8;
+
+/// Largest message that gets reassembled, unless configured otherwise
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Unfinished messages allowed at a time, unless configured otherwise
+pub const MAX_UNFINISHED: usize = 64;
+
+/// Bytes an unfinished message is counted with besides its data
+const PARTIAL_OVERHEAD: usize = 4 + 64;
+
+/// Bytes every fragment held is counted with besides its data
+const PART_OVERHEAD: usize = 32;
+
+/// What a `Reassembler` accepts
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReassemblyLimits {
+    /// Largest encoded `Packet` that gets put back together
+    pub max_message_size: usize,
+    /// Most data a single fragment carries, what fits into a frame
+    pub max_fragment_data: usize,
+    /// How many messages may be unfinished at a time
+    pub max_unfinished: usize,
+    /// Bytes all unfinished messages may take together, including what it
+    /// takes to keep track of them
+    pub max_buffered: usize,
+    /// Unfinished messages are dropped after this long
+    pub timeout_ms: u64,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> ReassemblyLimits {
+        ReassemblyLimits {
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_fragment_data: MAX_PACKET_SIZE - FRAGMENT_OVERHEAD,
+            max_unfinished: MAX_UNFINISHED,
+            max_buffered: 4 * MAX_MESSAGE_SIZE,
+            timeout_ms: 10 * 1000,
+        }
+    }
+}
+
+/// Cuts `Packet`s into frames
+///
+/// Can be shared by every thread sending on the same connection.
+pub struct Fragmenter {
+    frame_limit: usize,
+    message_limit: usize,
+    next_id: AtomicUsize,
+}
+
+impl Fragmenter {
+    /// Sends frames of up to `frame_limit` bytes, capped at `MAX_FRAME_SIZE`,
+    /// and messages of up to `message_limit` bytes
+    pub fn new(frame_limit: usize, message_limit: usize) -> Fragmenter {
+        assert!(frame_limit > FRAGMENT_OVERHEAD, "Frames are too small to hold any fragment");
+        Fragmenter {
+            frame_limit: cmp::min(frame_limit, MAX_FRAME_SIZE),
+            message_limit: message_limit,
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// The frames to send for `packet`, in order
+    ///
+    /// That's `packet` itself if it fits. Fails with `EncodeTooLarge` if
+    /// it is larger than the message limit.
+    pub fn split(&self, packet: &Packet) -> Result<Vec<Packet>, PacketError> {
+        let encoded = try!(encode_message(packet));
+        if encoded.len() <= self.frame_limit {
+            return Ok(vec![packet.clone()]);
+        }
+        let chunk = self.frame_limit - FRAGMENT_OVERHEAD;
+        let count = (encoded.len() + chunk - 1) / chunk;
+        if encoded.len() > self.message_limit || count > u16::MAX as usize {
+            return Err(PacketError::EncodeTooLarge(encoded.len()));
+        }
+
+        let message_id = self.next_id.fetch_add(1, Ordering::Relaxed) as u32;
+        Ok(encoded.chunks(chunk).enumerate().map(|(index, data)| {
+            Packet::Fragment {
+                message_id: message_id,
+                index: index as u16,
+                count: count as u16,
+                data: data.to_vec(),
+            }
+        }).collect())
+    }
+
+    /// Sends `packet` over a blocking stream, in as many frames as needed
+    ///
+    /// `compression` and `cipher` are those of `send_packet_with`.
+    pub fn send<W: Write>(&self, writer: &mut W, packet: &Packet, compression: Option<usize>,
+                          mut cipher: Option<&mut Cipher>) -> Result<(), PacketError> {
+        for frame in try!(self.split(packet)).iter() {
+            try!(send_packet_with(writer, frame, self.frame_limit, compression,
+                                  cipher.as_mut().map(|c| &mut **c)));
+        }
+        Ok(())
+    }
+}
+
+/// A message of which only some fragments arrived
+struct Partial {
+    count: u16,
+    started: u64,
+    parts: Vec<(u16, Vec<u8>)>,
+    /// Data in `parts`
+    bytes: usize,
+    /// What this is counted with towards `max_buffered`
+    held: usize,
+}
+
+/// Puts `Fragment`s back together
+pub struct Reassembler {
+    limits: ReassemblyLimits,
+    partial: HashMap<u32, Partial>,
+    /// What all of `partial` are counted with
+    buffered: usize,
+    expired: usize,
+}
+
+impl Reassembler {
+    pub fn new(limits: ReassemblyLimits) -> Reassembler {
+        Reassembler {
+            limits: limits,
+            partial: HashMap::new(),
+            buffered: 0,
+            expired: 0,
+        }
+    }
+
+    /// Bytes held for unfinished messages, bookkeeping included
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// How many messages were dropped for taking too long
+    pub fn expired(&self) -> usize {
+        self.expired
+    }
+
+    /// Takes in a received `Packet`, `now` in nanoseconds
+    ///
+    /// Anything but a `Fragment` comes right back. A `Fragment` completing
+    /// its message gives the reassembled `Packet`, others give `None`.
+    ///
+    /// Fails with `TooLarge` when a limit is exceeded and `BadFragment` for
+    /// fragments that don't fit with the others. The connection should be
+    /// closed then, the peer is either broken or malicious.
+    pub fn accept(&mut self, packet: Packet, now: u64) -> Result<Option<Packet>, PacketError> {
+        let (message_id, index, count, data) = match packet {
+            Packet::Fragment { message_id, index, count, data } => {
+                (message_id, index, count, data)
+            }
+            packet => return Ok(Some(packet))
+        };
+        self.expire(now);
+
+        if count < 2 || index >= count || data.is_empty() {
+            return Err(PacketError::BadFragment(format!(
+                "Fragment {} of {} with {} bytes", index, count, data.len())));
+        }
+        if data.len() > self.limits.max_fragment_data {
+            return Err(PacketError::BadFragment(format!(
+                "Fragment {} of message {} carries {} bytes", index, message_id, data.len())));
+        }
+        // Even with every fragment full, all but the last one exceed the limit
+        if (count as usize - 1) * self.limits.max_fragment_data >= self.limits.max_message_size {
+            return Err(PacketError::TooLarge);
+        }
+
+        let mut cost = data.len() + PART_OVERHEAD;
+        if !self.partial.contains_key(&message_id) {
+            if self.partial.len() >= self.limits.max_unfinished {
+                return Err(PacketError::TooLarge);
+            }
+            cost += PARTIAL_OVERHEAD;
+        }
+        if self.buffered + cost > self.limits.max_buffered {
+            return Err(PacketError::TooLarge);
+        }
+
+        {
+            let partial = self.partial.entry(message_id).or_insert(Partial {
+                count: count,
+                started: now,
+                parts: Vec::new(),
+                bytes: 0,
+                held: 0,
+            });
+            if partial.count != count {
+                return Err(PacketError::BadFragment(format!(
+                    "Message {} has {} fragments, not {}", message_id, partial.count, count)));
+            }
+            if partial.parts.iter().any(|&(i, _)| i == index) {
+                return Err(PacketError::BadFragment(format!(
+                    "Fragment {} of message {} arrived twice", index, message_id)));
+            }
+            if partial.bytes + data.len() > self.limits.max_message_size {
+                return Err(PacketError::TooLarge);
+            }
+            partial.bytes += data.len();
+            partial.held += cost;
+            self.buffered += cost;
+            partial.parts.push((index, data));
+            if partial.parts.len() < count as usize {
+                return Ok(None);
+            }
+        }
+
+        let mut partial = self.partial.remove(&message_id).unwrap();
+        self.buffered -= partial.held;
+        partial.parts.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut encoded = Vec::with_capacity(partial.bytes);
+        for (_, data) in partial.parts.into_iter() {
+            encoded.extend(data.into_iter());
+        }
+        match try!(decode_payload(&encoded)) {
+            Packet::Fragment { .. } => {
+                Err(PacketError::BadFragment("Fragments may not be nested".to_string()))
+            }
+            packet => Ok(Some(packet))
+        }
+    }
+
+    /// Drops the messages that took too long, returns how many
+    pub fn expire(&mut self, now: u64) -> usize {
+        let timeout = self.limits.timeout_ms.checked_mul(1000000).unwrap_or(u64::MAX);
+        let before = self.partial.len();
+        let mut freed = 0;
+        let expired: Vec<u32> = self.partial.iter()
+            .filter(|&(_, partial)| now.saturating_sub(partial.started) >= timeout)
+            .map(|(&message_id, _)| message_id)
+            .collect();
+        for message_id in expired.iter() {
+            if let Some(partial) = self.partial.remove(message_id) {
+                freed += partial.held;
+            }
+        }
+        self.buffered -= freed;
+        let expired = before - self.partial.len();
+        self.expired += expired;
+        expired
+    }
+
+    /// Reads from a blocking stream until a whole `Packet` arrived
+    ///
+    /// Frames may be up to `frame_limit` bytes, `compressed` and `cipher`
+    /// are those of `receive_packet_with`.
+    pub fn receive<R: Read>(&mut self, reader: &mut R, frame_limit: usize, compressed: bool,
+                            mut cipher: Option<&mut Cipher>) -> Result<Packet, PacketError> {
+        loop {
+            let packet = try!(receive_packet_with(reader, frame_limit, compressed,
+                                                  cipher.as_mut().map(|c| &mut **c)));
+            if let Some(packet) = try!(self.accept(packet, precise_time_ns())) {
+                return Ok(packet);
+            }
+        }
+    }
+}
+
+mod test {
+    use super::*;
+    use net::{send_packet, PacketError, MAX_PACKET_SIZE};
+    use packets::{Packet, PlayerInfo};
+
+    fn snapshot(players: usize) -> Packet {
+        Packet::WorldSnapshot {
+            players: (0..players).map(|i| PlayerInfo {
+                player_id: i as u64,
+                name: format!("Player number {}", i),
+            }).collect(),
+        }
+    }
+
+    fn limits() -> ReassemblyLimits {
+        ReassemblyLimits {
+            max_message_size: 64 * 1024,
+            max_fragment_data: MAX_PACKET_SIZE - FRAGMENT_OVERHEAD,
+            max_unfinished: MAX_UNFINISHED,
+            max_buffered: 128 * 1024,
+            timeout_ms: 1000,
+        }
+    }
+
+    #[test]
+    fn test_small_packets_pass() {
+        let fragmenter = Fragmenter::new(MAX_PACKET_SIZE, MAX_MESSAGE_SIZE);
+        assert_eq!(fragmenter.split(&Packet::Ping(1)).unwrap(), vec![Packet::Ping(1)]);
+
+        let mut reassembler = Reassembler::new(limits());
+        assert_eq!(reassembler.accept(Packet::Ping(1), 0).unwrap(), Some(Packet::Ping(1)));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let fragmenter = Fragmenter::new(MAX_PACKET_SIZE, MAX_MESSAGE_SIZE);
+        let packet = snapshot(500);
+        let mut fragments = fragmenter.split(&packet).unwrap();
+        assert!(fragments.len() > 10);
+
+        let mut bytes = Vec::new();
+        for fragment in fragments.iter() {
+            // Every one of them fits into a frame
+            send_packet(&mut bytes, fragment).unwrap();
+        }
+
+        // In any order
+        fragments.reverse();
+        let mut reassembler = Reassembler::new(limits());
+        let last = fragments.pop().unwrap();
+        for fragment in fragments.into_iter() {
+            assert_eq!(reassembler.accept(fragment, 0).unwrap(), None);
+        }
+        assert!(reassembler.buffered() > 0);
+        assert_eq!(reassembler.accept(last, 0).unwrap(), Some(packet));
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn test_blocking_streams() {
+        let fragmenter = Fragmenter::new(MAX_PACKET_SIZE, MAX_MESSAGE_SIZE);
+        let mut bytes = Vec::new();
+        fragmenter.send(&mut bytes, &snapshot(100), None, None).unwrap();
+        fragmenter.send(&mut bytes, &Packet::Ping(3), None, None).unwrap();
+
+        let mut reassembler = Reassembler::new(limits());
+        let mut reader = &bytes[..];
+        assert_eq!(reassembler.receive(&mut reader, MAX_PACKET_SIZE, false, None).unwrap(),
+                   snapshot(100));
+        assert_eq!(reassembler.receive(&mut reader, MAX_PACKET_SIZE, false, None).unwrap(),
+                   Packet::Ping(3));
+    }
+
+    #[test]
+    fn test_message_limit() {
+        let fragmenter = Fragmenter::new(MAX_PACKET_SIZE, 4096);
+        match fragmenter.split(&snapshot(500)) {
+            Err(PacketError::EncodeTooLarge(..)) => (),
+            other => panic!("Expected EncodeTooLarge, got {:?}", other),
+        }
+
+        // A sender with a larger limit gets cut off
+        let fragments = Fragmenter::new(MAX_PACKET_SIZE, MAX_MESSAGE_SIZE)
+            .split(&snapshot(500)).unwrap();
+        let mut reassembler = Reassembler::new(ReassemblyLimits {
+            max_message_size: 4096,
+            ..limits()
+        });
+        let mut result = Ok(None);
+        for fragment in fragments.into_iter() {
+            result = reassembler.accept(fragment, 0);
+            if result.is_err() {
+                break;
+            }
+        }
+        match result {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let fragmenter = Fragmenter::new(MAX_PACKET_SIZE, MAX_MESSAGE_SIZE);
+        let mut reassembler = Reassembler::new(ReassemblyLimits {
+            max_buffered: 8 * 1024,
+            ..limits()
+        });
+        // Lots of messages, none of them ever finished
+        let mut result = Ok(None);
+        for _ in 0..20 {
+            let first = fragmenter.split(&snapshot(100)).unwrap().remove(0);
+            result = reassembler.accept(first, 0);
+            if result.is_err() {
+                break;
+            }
+        }
+        match result {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+        assert!(reassembler.buffered() <= 8 * 1024);
+    }
+
+    #[test]
+    fn test_tiny_fragments() {
+        let fragment = |message_id, count| Packet::Fragment {
+            message_id: message_id,
+            index: 0,
+            count: count,
+            data: vec![1],
+        };
+        // Too many fragments for the largest message, however full they are
+        let mut reassembler = Reassembler::new(limits());
+        match reassembler.accept(fragment(1, 1000), 0) {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+
+        // Lots of messages that hardly hold anything
+        let mut reassembler = Reassembler::new(limits());
+        for message_id in 0..MAX_UNFINISHED as u32 {
+            assert_eq!(reassembler.accept(fragment(message_id, 2), 0).unwrap(), None);
+        }
+        assert!(reassembler.buffered() > MAX_UNFINISHED * 64);
+        match reassembler.accept(fragment(MAX_UNFINISHED as u32, 2), 0) {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let fragmenter = Fragmenter::new(MAX_PACKET_SIZE, MAX_MESSAGE_SIZE);
+        let mut fragments = fragmenter.split(&snapshot(100)).unwrap();
+        let last = fragments.pop().unwrap();
+
+        let mut reassembler = Reassembler::new(limits());
+        for fragment in fragments.into_iter() {
+            assert_eq!(reassembler.accept(fragment, 0).unwrap(), None);
+        }
+        // Too late, the rest is gone and the last one starts a new message
+        assert_eq!(reassembler.accept(last, 1000 * 1000000).unwrap(), None);
+        assert_eq!(reassembler.expired(), 1);
+        assert_eq!(reassembler.expire(2000 * 1000000), 1);
+        assert_eq!(reassembler.buffered(), 0);
+    }
+
+    #[test]
+    fn test_bad_fragments() {
+        let fragment = |index, count| Packet::Fragment {
+            message_id: 1,
+            index: index,
+            count: count,
+            data: vec![1, 2, 3],
+        };
+        let mut reassembler = Reassembler::new(limits());
+        for &(index, count) in [(0, 1), (2, 2)].iter() {
+            match reassembler.accept(fragment(index, count), 0) {
+                Err(PacketError::BadFragment(..)) => (),
+                other => panic!("Expected BadFragment, got {:?}", other),
+            }
+        }
+
+        assert_eq!(reassembler.accept(fragment(0, 3), 0).unwrap(), None);
+        for &(index, count) in [(0, 3), (1, 4)].iter() {
+            match reassembler.accept(fragment(index, count), 0) {
+                Err(PacketError::BadFragment(..)) => (),
+                other => panic!("Expected BadFragment, got {:?}", other),
+            }
+        }
+    }
+}
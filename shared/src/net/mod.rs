@@ -1,18 +1,90 @@
+//! Framing of `Packet`s over byte streams
+//!
+//! Every `Packet` travels as a single frame:
+//!
+//! ```text
+//! +----------------+----------------------------+
+//! | length: u16 LE | payload: `length` bytes    |
+//! +----------------+----------------------------+
+//! ```
+//!
+//! The length prefix is always little-endian, regardless of the
+//! architecture of either peer. The payload is the bincode encoding of
+//! the `Packet` and may never exceed `MAX_PACKET_SIZE` bytes, this is
+//! enforced when sending as well as when receiving. The `_limited`
+//! variants take a different limit, up to `MAX_FRAME_SIZE`.
+//!
+//! Other messages, like the `RconPacket`s of the remote console, use the
+//! same framing through `send_frame_limited` and `receive_frame_limited`.
+//!
+//! These functions block until a whole frame went through. Non-blocking
+//! sockets are better served by the `PacketDecoder` and `PacketEncoder`
+//! of the `codec` module, which speak the same framing a chunk at a time.
+//!
+//! The `udp` module has a transport over datagrams instead, which does not
+//! hold up state updates behind lost packets.
+//!
+//! Tests can put any of these behind a bad network with the `sim` module.
+//!
+//! Packets too large for a single frame are cut into `Fragment`s and put
+//! back together by the `fragment` module.
+//!
+//! Connections can negotiate compression, their frame payloads then carry
+//! a flag telling whether they are compressed, see the `compress` module.
+//! After a key exchange, frame payloads are sealed as well, see the `secure`
+//! module. The `_with` variants speak any of these framings.
+
+use std::cmp;
 use std::error::Error;
 use std::fmt;
-use std::io::{Read, Write};
-use std::mem;
+use std::io::{self, Read, Write};
+
+use rustc_serialize::{Decodable, Encodable};
 
 use packets::Packet;
+use self::compress::{compress_payload, decompress_payload, COMPRESSION_OVERHEAD};
+use self::secure::{Cipher, SEAL_OVERHEAD};
+
+pub mod codec;
+pub mod compress;
+pub mod fragment;
+pub mod secure;
+pub mod sim;
+pub mod udp;
+
+pub use self::codec::{PacketDecoder, PacketEncoder};
+pub use self::compress::CompressionStats;
+pub use self::fragment::{Fragmenter, Reassembler, ReassemblyLimits};
+
+/// Size of the frame header in bytes
+pub const HEADER_SIZE: usize = 2;
 
+/// Default maximum size of a frame payload in bytes
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+/// The largest payload the frame header can describe
+pub const MAX_FRAME_SIZE: usize = 0xFFFF;
 
 #[derive(Debug)]
 pub enum PacketError {
     TooLarge,
     ZeroRead,
-    ErroredOut,
     MismatchedSize,
-    DecodeError,
+    /// The frame was read completely, but its contents are not a valid
+    /// `Packet`. Carries the decoder's message and the size of the frame.
+    DecodeError(String, usize),
+    /// The underlying stream returned an error.
+    IoError(io::Error),
+    /// The `Packet` would encode to the given amount of bytes, which is
+    /// more than the limit.
+    EncodeTooLarge(usize),
+    /// The other side refused to connect, with the reason
+    Refused(String),
+    /// A `Fragment` that does not fit with the others, with the reason
+    BadFragment(String),
+    /// The key exchange failed, or a sealed frame did not open, with the
+    /// reason. The connection can't be trusted anymore.
+    Insecure(String),
 }
 
 impl Error for PacketError {
@@ -20,25 +92,66 @@ impl Error for PacketError {
         match *self {
             PacketError::TooLarge => "Client tried to send a packet that was too large.",
             PacketError::ZeroRead => "Stream either hung up, or Client sent 0 bytes.",
-            PacketError::ErroredOut => "The client errored out.",
             PacketError::MismatchedSize => "Client said we get X, we received Y.",
-            PacketError::DecodeError => "Could not decode Packet",
+            PacketError::DecodeError(..) => "Could not decode Packet",
+            PacketError::IoError(..) => "The stream errored out.",
+            PacketError::EncodeTooLarge(..) => "Tried to send a packet that was too large.",
+            PacketError::Refused(..) => "The other side refused to connect.",
+            PacketError::BadFragment(..) => "Received an invalid fragment.",
+            PacketError::Insecure(..) => "The connection is not secure.",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            PacketError::IoError(ref e) => Some(e),
+            _ => None
         }
     }
 }
 
 impl fmt::Display for PacketError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        (match *self {
-            PacketError::TooLarge => "Client tried to send a packet that was too large.",
-            PacketError::ZeroRead => "Stream either hung up, or Client sent 0 bytes.",
-            PacketError::ErroredOut => "The client errored out.",
-            PacketError::MismatchedSize => "Client said we get X, we received Y.",
-            PacketError::DecodeError => "Could not decode Packet",
-        }).fmt(fmt)
+        match *self {
+            PacketError::DecodeError(ref msg, size) => {
+                write!(fmt, "Could not decode Packet of {} bytes: {}", size, msg)
+            }
+            PacketError::IoError(ref e) => {
+                write!(fmt, "The stream errored out: {}", e)
+            }
+            PacketError::EncodeTooLarge(size) => {
+                write!(fmt, "Packet encodes to {} bytes, which is over the limit", size)
+            }
+            PacketError::Refused(ref reason) => {
+                write!(fmt, "The other side refused to connect: {}", reason)
+            }
+            PacketError::BadFragment(ref reason) => {
+                write!(fmt, "Received an invalid fragment: {}", reason)
+            }
+            PacketError::Insecure(ref reason) => {
+                write!(fmt, "The connection is not secure: {}", reason)
+            }
+            ref e => e.description().fmt(fmt)
+        }
     }
 }
 
+impl From<io::Error> for PacketError {
+    fn from(err: io::Error) -> PacketError {
+        PacketError::IoError(err)
+    }
+}
+
+/// Encodes the frame header for a payload of `size` bytes
+fn encode_header(size: usize) -> [u8; HEADER_SIZE] {
+    [(size & 0xFF) as u8, ((size >> 8) & 0xFF) as u8]
+}
+
+/// Decodes a frame header into the size of the payload that follows
+fn decode_header(header: [u8; HEADER_SIZE]) -> usize {
+    (header[0] as usize) | ((header[1] as usize) << 8)
+}
+
 /// Reads in a new `Packet`
 ///
 /// This function blocks on R until either all the required
@@ -68,76 +181,373 @@ impl fmt::Display for PacketError {
 ///
 /// ```
 pub fn receive_packet<R>(reader: &mut R) -> Result<Packet, PacketError> where R: Read {
-    use bincode::decode;
-    let mut slice_buf = [0; 2];
+    receive_packet_limited(reader, MAX_PACKET_SIZE)
+}
+
+/// Reads in a new `Packet` of at most `limit` bytes
+///
+/// Works like `receive_packet`, `limit` is capped at `MAX_FRAME_SIZE`.
+pub fn receive_packet_limited<R>(reader: &mut R, limit: usize) -> Result<Packet, PacketError>
+    where R: Read {
+    receive_frame_limited(reader, limit)
+}
+
+/// Reads in a single frame of at most `limit` bytes and decodes it as `T`
+///
+/// Works like `receive_packet`, `limit` is capped at `MAX_FRAME_SIZE`.
+pub fn receive_frame_limited<R, T>(reader: &mut R, limit: usize) -> Result<T, PacketError>
+    where R: Read, T: Decodable {
+    decode_payload(&try!(receive_payload(reader, limit)))
+}
+
+/// Reads in a `Packet` of at most `limit` bytes, from a connection that
+/// negotiated compression if `compressed`, and that is encrypted if there
+/// is a `cipher`
+///
+/// Works like `receive_packet`. The limit is on the payload as it was
+/// encoded, a compressed or sealed payload takes up a few more bytes on
+/// the wire.
+pub fn receive_packet_with<R>(reader: &mut R, limit: usize, compressed: bool,
+                              cipher: Option<&mut Cipher>) -> Result<Packet, PacketError>
+    where R: Read {
+    let mut wire_limit = limit;
+    if compressed {
+        wire_limit += COMPRESSION_OVERHEAD;
+    }
+    if cipher.is_some() {
+        wire_limit += SEAL_OVERHEAD;
+    }
+    let mut payload = try!(receive_payload(reader, wire_limit));
+    if let Some(cipher) = cipher {
+        payload = try!(cipher.open(&payload));
+    }
+    if compressed {
+        payload = try!(decompress_payload(&payload, limit));
+    }
+    decode_payload(&payload)
+}
+
+/// Reads in the payload of a single frame of at most `limit` bytes
+fn receive_payload<R: Read>(reader: &mut R, limit: usize) -> Result<Vec<u8>, PacketError> {
+    let mut header = [0; HEADER_SIZE];
     let mut idx = 0;
-    for byte in reader.bytes().take(2) {
-        match byte {
-            Ok(n) => {
-                slice_buf[idx] = n;
-                idx += 1;
-            },
-            Err(_) => {
-                return Err(PacketError::ErroredOut);
-            }
-        }
+    for byte in reader.bytes().take(HEADER_SIZE) {
+        header[idx] = try!(byte);
+        idx += 1;
     }
     // We did not read any bytes? Error!
     if idx == 0 {
         return Err(PacketError::ZeroRead);
     }
+    // The stream ended in the middle of the header
+    if idx != HEADER_SIZE {
+        return Err(PacketError::MismatchedSize);
+    }
 
-    let buffer_size : u16 = unsafe{ mem::transmute(slice_buf) };
+    let buffer_size = decode_header(header);
 
-    // If the buffer is above 1024 bytes we reject it.
-    if buffer_size > 1024 {
+    if buffer_size > cmp::min(limit, MAX_FRAME_SIZE) {
         return Err(PacketError::TooLarge);
     }
 
-    let mut buffer = Vec::<u8>::with_capacity(buffer_size as usize);
-    let read = match reader.take(buffer_size as u64).read_to_end(&mut buffer) {
-        Ok(b) => b,
-        Err(_) => return Err(PacketError::ErroredOut)
-    };
+    let mut buffer = Vec::<u8>::with_capacity(buffer_size);
+    let read = try!(reader.take(buffer_size as u64).read_to_end(&mut buffer));
 
-    if read != buffer_size as usize {
-        println!("Got {} expected {}", read, buffer_size);
+    if read != buffer_size {
         return Err(PacketError::MismatchedSize);
     }
 
-    match decode(&buffer[..]) {
+    Ok(buffer)
+}
+
+/// Encodes `message`, no matter how large it gets
+fn encode_message<T: Encodable>(message: &T) -> Result<Vec<u8>, PacketError> {
+    use bincode::{encode, SizeLimit};
+    match encode(message, SizeLimit::Infinite) {
+        Ok(e) => Ok(e),
+        Err(e) => Err(PacketError::IoError(
+                io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e))))
+    }
+}
+
+/// Encodes the payload of a frame, of at most `limit` bytes
+fn encode_payload<T: Encodable>(message: &T, limit: usize) -> Result<Vec<u8>, PacketError> {
+    let encoded = try!(encode_message(message));
+    if encoded.len() > cmp::min(limit, MAX_FRAME_SIZE) {
+        return Err(PacketError::EncodeTooLarge(encoded.len()));
+    }
+    Ok(encoded)
+}
+
+/// Decodes the payload of a frame
+fn decode_payload<T: Decodable>(payload: &[u8]) -> Result<T, PacketError> {
+    use bincode::decode;
+    match decode(payload) {
         Ok(p) => Ok(p),
-        Err(e) => {
-            println!("{:?}", e);
-            panic!();
+        Err(e) => Err(PacketError::DecodeError(format!("{}", e), payload.len()))
+    }
+}
+
+/// Writes a `Packet` as a single frame
+///
+/// The header and payload are written with `write_all`, so either the
+/// whole frame made it into the writer or an error is returned. A
+/// `Packet` that encodes to more than `MAX_PACKET_SIZE` bytes is
+/// rejected before anything is written.
+pub fn send_packet<W>(writer: &mut W, pack: &Packet) -> Result<(), PacketError> where W: Write {
+    send_packet_limited(writer, pack, MAX_PACKET_SIZE)
+}
+
+/// Writes a `Packet` of at most `limit` bytes as a single frame
+///
+/// Works like `send_packet`, `limit` is capped at `MAX_FRAME_SIZE`.
+pub fn send_packet_limited<W>(writer: &mut W, pack: &Packet, limit: usize)
+    -> Result<(), PacketError> where W: Write {
+    send_frame_limited(writer, pack, limit)
+}
+
+/// Encodes `message` and writes it as a single frame of at most `limit` bytes
+///
+/// Works like `send_packet`, `limit` is capped at `MAX_FRAME_SIZE`.
+pub fn send_frame_limited<W, T>(writer: &mut W, message: &T, limit: usize)
+    -> Result<(), PacketError> where W: Write, T: Encodable {
+    let frame = try!(encode_frame(message, limit));
+    try!(writer.write_all(&frame[..]));
+    Ok(())
+}
+
+/// Writes a `Packet` of at most `limit` bytes as a single frame, to a
+/// connection that negotiated compression if `compression` is set, and
+/// that is encrypted if there is a `cipher`
+///
+/// Works like `send_packet`. Payloads of at least `compression` bytes
+/// are compressed.
+pub fn send_packet_with<W>(writer: &mut W, pack: &Packet, limit: usize,
+                           compression: Option<usize>, cipher: Option<&mut Cipher>)
+    -> Result<(), PacketError> where W: Write {
+    let mut payload = try!(encode_payload(pack, limit));
+    if let Some(threshold) = compression {
+        payload = compress_payload(payload, threshold);
+    }
+    let frame = try!(frame_payload(try!(seal_payload(payload, cipher))));
+    try!(writer.write_all(&frame[..]));
+    Ok(())
+}
+
+/// Encodes `message` into a whole frame, header included
+fn encode_frame<T: Encodable>(message: &T, limit: usize) -> Result<Vec<u8>, PacketError> {
+    frame_payload(try!(encode_payload(message, limit)))
+}
+
+/// Seals `payload` if there is a `cipher`
+///
+/// Payloads that would not fit into a frame sealed are refused before
+/// the cipher is used, so that it stays in step with the other side.
+fn seal_payload(payload: Vec<u8>, cipher: Option<&mut Cipher>)
+    -> Result<Vec<u8>, PacketError> {
+    match cipher {
+        Some(cipher) => {
+            if payload.len() + SEAL_OVERHEAD > MAX_FRAME_SIZE {
+                return Err(PacketError::EncodeTooLarge(payload.len() + SEAL_OVERHEAD));
+            }
+            Ok(cipher.seal(&payload))
         }
+        None => Ok(payload),
     }
 }
 
-pub fn send_packet<W>(writer: &mut W, pack: &Packet) where W: Write {
-    use bincode::{encode, SizeLimit};
-    let encoded: Vec<u8> = encode(pack, SizeLimit::Bounded(1024)).unwrap();
-    let size : [u8; 2] = unsafe{ mem::transmute((encoded.len() as u16)) };
-    writer.write(&size).unwrap();
-    writer.write(&encoded[..]).unwrap();
+/// Puts the header in front of `payload`
+fn frame_payload(payload: Vec<u8>) -> Result<Vec<u8>, PacketError> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(PacketError::EncodeTooLarge(payload.len()));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend(encode_header(payload.len()).iter().cloned());
+    frame.extend(payload.into_iter());
+    Ok(frame)
 }
 
 mod test {
     use super::*;
-    use packets::Packet;
+    use packets::{Packet, ChatChannel, RconPacket, PROTOCOL_VERSION};
+    use std::io::{self, Write};
+
+    fn chat(message: &str) -> Packet {
+        Packet::SendChat {
+            channel: ChatChannel::Global,
+            message: message.to_string(),
+        }
+    }
+
     #[test]
     fn test_read_write() {
-        let test_packet = Packet::AuthPlayer("Neikos".to_string());
+        let test_packet = Packet::AuthPlayer {
+            name: "Neikos".to_string(),
+            password: "hunter22".to_string(),
+        };
 
         let mut test = Vec::<u8>::new();
-        send_packet(&mut test, &test_packet);
+        send_packet(&mut test, &test_packet).unwrap();
 
         let result = receive_packet(&mut &test[..]);
 
         match result.unwrap() {
-            Packet::AuthPlayer(name) => {
-                assert!(name == "Neikos")
+            Packet::AuthPlayer { name, password } => {
+                assert!(name == "Neikos");
+                assert!(password == "hunter22");
+            }
+            _ => panic!("Wrong packet")
+        }
+    }
+
+    fn valid_frame() -> Vec<u8> {
+        let mut frame = Vec::<u8>::new();
+        send_packet(&mut frame, &chat("Neikos")).unwrap();
+        frame
+    }
+
+    /// Only ever accepts a single byte per `write` call
+    struct Trickle(Vec<u8>);
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match buf.first() {
+                Some(&b) => { self.0.push(b); Ok(1) }
+                None => Ok(0)
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn test_hello_round_trip() {
+        let mut frame = Vec::<u8>::new();
+        send_packet(&mut frame, &Packet::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_build: "test".to_string(),
+            compression: true,
+        }).unwrap();
+
+        match receive_packet(&mut &frame[..]).unwrap() {
+            Packet::Hello { protocol_version, client_build, compression } => {
+                assert_eq!(protocol_version, PROTOCOL_VERSION);
+                assert!(client_build == "test");
+                assert!(compression);
+            }
+            _ => panic!("Wrong packet")
+        }
+    }
+
+    #[test]
+    fn test_other_messages() {
+        let mut frame = Vec::<u8>::new();
+        send_frame_limited(&mut frame, &RconPacket::Command("list".to_string()),
+                           MAX_FRAME_SIZE).unwrap();
+
+        let message: RconPacket = receive_frame_limited(&mut &frame[..], MAX_FRAME_SIZE).unwrap();
+        assert_eq!(message, RconPacket::Command("list".to_string()));
+    }
+
+    #[test]
+    fn test_header_is_little_endian() {
+        let frame = valid_frame();
+        let payload = frame.len() - HEADER_SIZE;
+        assert_eq!(frame[0] as usize, payload & 0xFF);
+        assert_eq!(frame[1] as usize, payload >> 8);
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let mut trickle = Trickle(Vec::new());
+        send_packet(&mut trickle, &chat("Neikos")).unwrap();
+
+        assert_eq!(trickle.0, valid_frame());
+        match receive_packet(&mut &trickle.0[..]).unwrap() {
+            Packet::SendChat { message, .. } => assert!(message == "Neikos"),
+            _ => panic!("Wrong packet")
+        }
+    }
+
+    #[test]
+    fn test_send_too_large() {
+        let message = (0..MAX_PACKET_SIZE).map(|_| 'a').collect::<String>();
+        let mut frame = Vec::<u8>::new();
+
+        match send_packet(&mut frame, &chat(&message)) {
+            Err(PacketError::EncodeTooLarge(size)) => assert!(size > MAX_PACKET_SIZE),
+            other => panic!("Expected EncodeTooLarge, got {:?}", other.err()),
+        }
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn test_custom_limit() {
+        let frame = valid_frame();
+        let payload = frame.len() - HEADER_SIZE;
+
+        match receive_packet_limited(&mut &frame[..], payload - 1) {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other.err()),
+        }
+        assert!(receive_packet_limited(&mut &frame[..], payload).is_ok());
+
+        let mut out = Vec::<u8>::new();
+        match send_packet_limited(&mut out, &chat("Neikos"), payload - 1) {
+            Err(PacketError::EncodeTooLarge(size)) => assert_eq!(size, payload),
+            other => panic!("Expected EncodeTooLarge, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let frame = valid_frame();
+
+        match receive_packet(&mut &frame[..1]) {
+            Err(PacketError::MismatchedSize) => (),
+            other => panic!("Expected MismatchedSize, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let frame = valid_frame();
+        let truncated = &frame[..frame.len() - 3];
+
+        match receive_packet(&mut &truncated[..]) {
+            Err(PacketError::MismatchedSize) => (),
+            other => panic!("Expected MismatchedSize, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        // Whatever the byte order, 0xFFFF is way above the limit
+        let mut frame = vec![0xFF, 0xFF];
+        frame.extend(vec![0; 2048].into_iter());
+
+        match receive_packet(&mut &frame[..]) {
+            Err(PacketError::TooLarge) => (),
+            other => panic!("Expected TooLarge, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn test_corrupted_frame() {
+        let mut frame = valid_frame();
+        let len = frame.len();
+        // Overwrite the enum discriminant with garbage, the frame size stays
+        // intact so we only fail at decoding.
+        for byte in frame[2..6].iter_mut() {
+            *byte = 0xFF;
+        }
+
+        match receive_packet(&mut &frame[..]) {
+            Err(PacketError::DecodeError(msg, size)) => {
+                assert!(!msg.is_empty());
+                assert_eq!(size, len - 2);
             }
+            other => panic!("Expected DecodeError, got {:?}", other.err()),
         }
     }
 }
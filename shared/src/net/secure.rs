@@ -0,0 +1,529 @@
+//! Encrypted sessions
+//!
+//! Right after the `Welcome`, the client sends a `KeyExchange` with a fresh
+//! X25519 key. The server answers with a `KeyExchangeReply`, carrying a
+//! fresh key of its own, its long-term Ed25519 `identity`, and a signature
+//! of both fresh keys made with that identity. Both sides then derive a key
+//! for either direction from the shared secret with HKDF-SHA256.
+//!
+//! Every frame after those two, both ways, is sealed with ChaCha20-Poly1305:
+//!
+//! ```text
+//! +------------------------------+---------------+
+//! | payload, encrypted           | tag: 16 bytes |
+//! +------------------------------+---------------+
+//! ```
+//!
+//! The nonce is the number of frames sent that way before, so a frame that
+//! was changed, dropped, replayed or reordered does not open. Compression,
+//! if negotiated, happens before sealing.
+//!
+//! The signature only tells that whoever answered holds the identity.
+//! Clients remember the identity of every server in `KnownServers` the
+//! first time they connect, and refuse to go on if it ever changes.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::ed25519;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+use rustc_serialize::hex::{FromHex, ToHex};
+
+use packets::Packet;
+use super::PacketError;
+
+/// Bytes sealing adds to a frame payload
+pub const SEAL_OVERHEAD: usize = 16;
+
+/// Size of the public keys, of the exchange as well as of identities
+pub const KEY_SIZE: usize = 32;
+
+const SIGNATURE_SIZE: usize = 64;
+
+/// Signed along with the keys, so that the signature can't be mistaken
+/// for one of something else
+const CONTEXT: &'static [u8] = b"rpg key exchange v1";
+
+fn insecure(reason: &str) -> PacketError {
+    PacketError::Insecure(reason.to_string())
+}
+
+fn random_bytes() -> io::Result<[u8; 32]> {
+    let mut bytes = [0; 32];
+    try!(OsRng::new()).fill_bytes(&mut bytes);
+    Ok(bytes)
+}
+
+/// How an identity is shown to people, hex of its public key
+pub fn fingerprint(identity: &[u8]) -> String {
+    identity.to_hex()
+}
+
+/// One direction of an encrypted session
+pub struct Cipher {
+    key: [u8; 32],
+    /// Frames sealed or opened so far, the nonce of the next one
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: [u8; 32]) -> Cipher {
+        Cipher {
+            key: key,
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 8] {
+        let mut nonce = [0; 8];
+        for (n, byte) in nonce.iter_mut().enumerate() {
+            *byte = (self.counter >> (8 * n)) as u8;
+        }
+        self.counter += 1;
+        nonce
+    }
+
+    /// Encrypts `payload`, `SEAL_OVERHEAD` bytes longer
+    pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        let mut sealed = vec![0; payload.len() + SEAL_OVERHEAD];
+        {
+            let (data, tag) = sealed.split_at_mut(payload.len());
+            ChaCha20Poly1305::new(&self.key, &nonce, &[]).encrypt(payload, data, tag);
+        }
+        sealed
+    }
+
+    /// Decrypts what the other side sealed, failing with `Insecure` if it
+    /// was not exactly the next frame it sealed
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, PacketError> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(insecure("A sealed frame is too short"));
+        }
+        let nonce = self.next_nonce();
+        let (data, tag) = sealed.split_at(sealed.len() - SEAL_OVERHEAD);
+        let mut payload = vec![0; data.len()];
+        if !ChaCha20Poly1305::new(&self.key, &nonce, &[]).decrypt(data, &mut payload, tag) {
+            return Err(insecure("A frame failed authentication"));
+        }
+        Ok(payload)
+    }
+}
+
+/// The keys of an encrypted session, one for either direction
+pub struct SessionKeys {
+    /// Seals the frames this side sends
+    pub send: Cipher,
+    /// Opens the frames the other side sends
+    pub receive: Cipher,
+}
+
+/// The long-term key a server proves who it is with
+pub struct Identity {
+    seed: [u8; 32],
+    secret: [u8; 64],
+    public: [u8; 32],
+}
+
+impl Identity {
+    /// A new, random identity
+    pub fn generate() -> io::Result<Identity> {
+        Ok(Identity::from_seed(try!(random_bytes())))
+    }
+
+    pub fn from_seed(seed: [u8; 32]) -> Identity {
+        let (secret, public) = ed25519::keypair(&seed);
+        Identity {
+            seed: seed,
+            secret: secret,
+            public: public,
+        }
+    }
+
+    /// Loads the identity stored at `path`, or creates one there
+    ///
+    /// The file holds the secret seed in hex, only its owner may read it.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Identity> {
+        let path = path.as_ref();
+        match File::open(path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                let bytes = match contents.trim().from_hex() {
+                    Ok(ref b) if b.len() == 32 => b.clone(),
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                   "The server identity file is corrupt")),
+                };
+                let mut seed = [0; 32];
+                for (to, from) in seed.iter_mut().zip(bytes.iter()) {
+                    *to = *from;
+                }
+                Ok(Identity::from_seed(seed))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = try!(Identity::generate());
+                let tmp = path.with_extension("tmp");
+                {
+                    let mut file = try!(create_private(&tmp));
+                    try!(writeln!(file, "{}", identity.seed.to_hex()));
+                    try!(file.sync_all());
+                }
+                try!(fs::rename(&tmp, path));
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public)
+    }
+
+    fn sign(&self, message: &[u8]) -> [u8; 64] {
+        ed25519::signature(message, &self.secret)
+    }
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    File::create(path)
+}
+
+/// What gets signed: both fresh keys, client's first
+fn transcript(client_key: &[u8], server_key: &[u8]) -> Vec<u8> {
+    let mut transcript = CONTEXT.to_vec();
+    transcript.extend(client_key.iter().cloned());
+    transcript.extend(server_key.iter().cloned());
+    transcript
+}
+
+/// One side of a key exchange in progress
+pub struct Handshake {
+    secret: [u8; 32],
+    public: [u8; 32],
+}
+
+impl Handshake {
+    /// Starts an exchange with a fresh key
+    pub fn new() -> io::Result<Handshake> {
+        let secret = try!(random_bytes());
+        Ok(Handshake {
+            secret: secret,
+            public: curve25519_base(&secret),
+        })
+    }
+
+    /// The `KeyExchange` a client starts with
+    pub fn packet(&self) -> Packet {
+        Packet::KeyExchange { key: self.public.to_vec() }
+    }
+
+    /// Answers the `KeyExchange` of a client, on the server
+    ///
+    /// The reply has to be sent before the keys are used.
+    pub fn respond(self, identity: &Identity, client_key: &[u8])
+        -> Result<(Packet, SessionKeys), PacketError> {
+        let shared = try!(self.agree(client_key));
+        let signature = identity.sign(&transcript(client_key, &self.public));
+        let (to_server, to_client) = derive(&shared, client_key, &self.public);
+        let reply = Packet::KeyExchangeReply {
+            identity: identity.public_key().to_vec(),
+            key: self.public.to_vec(),
+            signature: signature.to_vec(),
+        };
+        Ok((reply, SessionKeys { send: to_client, receive: to_server }))
+    }
+
+    /// Checks the `KeyExchangeReply` of the server, on the client
+    ///
+    /// This only proves that the server holds `identity`, whether that is
+    /// the right one is up to the caller.
+    pub fn finish(self, identity: &[u8], server_key: &[u8], signature: &[u8])
+        -> Result<SessionKeys, PacketError> {
+        if identity.len() != KEY_SIZE || signature.len() != SIGNATURE_SIZE {
+            return Err(insecure("The server sent a malformed identity"));
+        }
+        if !ed25519::verify(&transcript(&self.public, server_key), identity, signature) {
+            return Err(insecure("The key exchange is not signed by the server"));
+        }
+        let shared = try!(self.agree(server_key));
+        let (to_server, to_client) = derive(&shared, &self.public, server_key);
+        Ok(SessionKeys { send: to_server, receive: to_client })
+    }
+
+    fn agree(&self, other: &[u8]) -> Result<[u8; 32], PacketError> {
+        if other.len() != KEY_SIZE {
+            return Err(insecure("The other side sent a malformed key"));
+        }
+        let shared = curve25519(&self.secret, other);
+        // Keys of small order make the secret known to anyone
+        if shared.iter().all(|&b| b == 0) {
+            return Err(insecure("The other side sent a weak key"));
+        }
+        Ok(shared)
+    }
+}
+
+/// The keys from client to server and from server to client
+fn derive(shared: &[u8], client_key: &[u8], server_key: &[u8]) -> (Cipher, Cipher) {
+    let mut salt = client_key.to_vec();
+    salt.extend(server_key.iter().cloned());
+    let mut prk = [0; 32];
+    hkdf_extract(Sha256::new(), &salt, shared, &mut prk);
+
+    let mut to_server = [0; 32];
+    let mut to_client = [0; 32];
+    hkdf_expand(Sha256::new(), &prk, b"client to server", &mut to_server);
+    hkdf_expand(Sha256::new(), &prk, b"server to client", &mut to_client);
+    (Cipher::new(to_server), Cipher::new(to_client))
+}
+
+/// What `KnownServers` knew about an identity
+#[derive(Debug, PartialEq)]
+pub enum Pin {
+    /// Never talked to that server before, it is remembered now
+    New,
+    /// The server has the identity it had before
+    Known,
+    /// The server has another identity than before, with the old one
+    Changed(Vec<u8>),
+}
+
+/// The identities of the servers a client talked to, by address
+///
+/// Stored as lines of the address and the fingerprint.
+pub struct KnownServers {
+    path: Option<PathBuf>,
+    servers: Vec<(String, Vec<u8>)>,
+}
+
+impl KnownServers {
+    /// Remembers servers until it is dropped
+    pub fn in_memory() -> KnownServers {
+        KnownServers {
+            path: None,
+            servers: Vec::new(),
+        }
+    }
+
+    /// Loads the servers from `path`, new ones are written back to it
+    ///
+    /// A missing file is treated as no servers at all.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<KnownServers> {
+        let path = path.as_ref().to_path_buf();
+        let mut servers = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                try!(file.read_to_string(&mut contents));
+                for line in contents.lines() {
+                    let mut fields = line.split_whitespace();
+                    let (address, key) = match (fields.next(), fields.next()) {
+                        (Some(a), Some(k)) => (a, k),
+                        (None, _) => continue,
+                        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                       "The known servers file is corrupt")),
+                    };
+                    match key.from_hex() {
+                        Ok(key) => servers.push((address.to_string(), key)),
+                        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                            "The known servers file is corrupt")),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(KnownServers {
+            path: Some(path),
+            servers: servers,
+        })
+    }
+
+    /// Compares `identity` with what `server` had before, remembering it
+    /// if it is new
+    pub fn check(&mut self, server: &str, identity: &[u8]) -> io::Result<Pin> {
+        if let Some(&(_, ref known)) = self.servers.iter().find(|&&(ref s, _)| s == server) {
+            if known[..] == identity[..] {
+                return Ok(Pin::Known);
+            }
+            return Ok(Pin::Changed(known.clone()));
+        }
+        self.servers.push((server.to_string(), identity.to_vec()));
+        try!(self.save());
+        Ok(Pin::New)
+    }
+
+    /// Forgets `server`, so that the next identity it shows is taken
+    ///
+    /// Returns whether it was known at all.
+    pub fn forget(&mut self, server: &str) -> io::Result<bool> {
+        let before = self.servers.len();
+        self.servers.retain(|&(ref s, _)| s != server);
+        if self.servers.len() == before {
+            return Ok(false);
+        }
+        try!(self.save());
+        Ok(true)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref p) => p,
+            None => return Ok(())
+        };
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = try!(File::create(&tmp));
+            for &(ref server, ref identity) in self.servers.iter() {
+                try!(writeln!(file, "{} {}", server, fingerprint(identity)));
+            }
+            try!(file.sync_all());
+        }
+        fs::rename(&tmp, path)
+    }
+}
+
+mod test {
+    use super::*;
+    use net::PacketError;
+    use packets::Packet;
+    use std::env;
+    use std::fs;
+
+    fn exchange(identity: &Identity) -> (SessionKeys, SessionKeys) {
+        let client = Handshake::new().unwrap();
+        let key = match client.packet() {
+            Packet::KeyExchange { key } => key,
+            other => panic!("Expected a KeyExchange, got {:?}", other),
+        };
+        let (reply, server_keys) = Handshake::new().unwrap().respond(identity, &key).unwrap();
+        match reply {
+            Packet::KeyExchangeReply { identity, key, signature } => {
+                (client.finish(&identity, &key, &signature).unwrap(), server_keys)
+            }
+            other => panic!("Expected a KeyExchangeReply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_exchange() {
+        let identity = Identity::generate().unwrap();
+        let (mut client, mut server) = exchange(&identity);
+
+        let sealed = client.send.seal(b"hunter22");
+        assert_eq!(sealed.len(), 8 + SEAL_OVERHEAD);
+        assert!(sealed.windows(8).all(|w| w != b"hunter22"));
+        assert_eq!(server.receive.open(&sealed).unwrap(), b"hunter22");
+
+        let sealed = server.send.seal(b"welcome");
+        assert_eq!(client.receive.open(&sealed).unwrap(), b"welcome");
+        // Each direction has its own key
+        assert!(server.receive.open(&server.send.seal(b"welcome")).is_err());
+    }
+
+    #[test]
+    fn test_tampering() {
+        let identity = Identity::generate().unwrap();
+        let (mut client, mut server) = exchange(&identity);
+
+        let mut sealed = client.send.seal(b"hunter22");
+        sealed[3] ^= 1;
+        match server.receive.open(&sealed) {
+            Err(PacketError::Insecure(..)) => (),
+            other => panic!("Expected Insecure, got {:?}", other),
+        }
+
+        // Replays and reordering don't open either
+        let (mut client, mut server) = exchange(&identity);
+        let first = client.send.seal(b"first");
+        let second = client.send.seal(b"second");
+        assert!(server.receive.open(&second).is_err());
+        let (mut client, mut server) = exchange(&identity);
+        let first_again = client.send.seal(b"first");
+        assert_eq!(server.receive.open(&first_again).unwrap(), b"first");
+        assert!(server.receive.open(&first_again).is_err());
+        assert!(server.receive.open(&first[..4]).is_err());
+    }
+
+    #[test]
+    fn test_impostor() {
+        let identity = Identity::generate().unwrap();
+        let impostor = Identity::generate().unwrap();
+
+        let client = Handshake::new().unwrap();
+        let key = client.public.to_vec();
+        let (reply, _) = Handshake::new().unwrap().respond(&impostor, &key).unwrap();
+        let (key, signature) = match reply {
+            Packet::KeyExchangeReply { key, signature, .. } => (key, signature),
+            other => panic!("Expected a KeyExchangeReply, got {:?}", other),
+        };
+        // Claiming the real identity with a signature of another one
+        match client.finish(identity.public_key(), &key, &signature) {
+            Err(PacketError::Insecure(..)) => (),
+            Err(e) => panic!("Expected Insecure, got {:?}", e),
+            Ok(_) => panic!("Accepted an impostor"),
+        }
+
+        let server = Handshake::new().unwrap();
+        assert!(server.respond(&identity, &[0; KEY_SIZE]).is_err());
+        let server = Handshake::new().unwrap();
+        assert!(server.respond(&identity, &[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_identity_file() {
+        let path = env::temp_dir().join("rpg_identity_test.key");
+        let _ = fs::remove_file(&path);
+
+        let first = Identity::open(&path).unwrap();
+        let again = Identity::open(&path).unwrap();
+        assert_eq!(first.public_key(), again.public_key());
+        assert_eq!(first.fingerprint().len(), 2 * KEY_SIZE);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_known_servers() {
+        let path = env::temp_dir().join("rpg_known_servers_test");
+        let _ = fs::remove_file(&path);
+        let first = Identity::generate().unwrap();
+        let second = Identity::generate().unwrap();
+
+        {
+            let mut known = KnownServers::open(&path).unwrap();
+            assert_eq!(known.check("example.com:7777", first.public_key()).unwrap(), Pin::New);
+            assert_eq!(known.check("example.com:7777", first.public_key()).unwrap(), Pin::Known);
+            assert_eq!(known.check("localhost:7777", second.public_key()).unwrap(), Pin::New);
+        }
+
+        let mut known = KnownServers::open(&path).unwrap();
+        assert_eq!(known.check("example.com:7777", first.public_key()).unwrap(), Pin::Known);
+        assert_eq!(known.check("example.com:7777", second.public_key()).unwrap(),
+                   Pin::Changed(first.public_key().to_vec()));
+
+        assert!(known.forget("example.com:7777").unwrap());
+        assert!(!known.forget("example.com:7777").unwrap());
+        assert_eq!(known.check("example.com:7777", second.public_key()).unwrap(), Pin::New);
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,528 @@
+//! Bad networks, for tests
+//!
+//! A `Simulator` decides what happens to every chunk of bytes that goes
+//! over a simulated link: how long it takes, whether it gets lost or
+//! overtaken, and how much of a write goes through in one piece. All of
+//! that comes from a seeded generator, so a test sees the same network on
+//! every run.
+//!
+//! A `Wire` carries datagrams in virtual time, for driving a `Session`
+//! without any sockets. `SimStream` puts a stream like a `TcpStream` and
+//! `SimSocket` anything that sends `Datagrams` behind the same conditions,
+//! in real time.
+
+use std::cmp;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clock_ticks::precise_time_ns;
+
+use super::udp::Datagrams;
+
+/// How long a reordered datagram is held back, besides the usual delay
+pub const REORDER_NS: u64 = 5 * 1000000;
+
+/// What a simulated link is like
+///
+/// Streams never lose or reorder anything, so they only see the latency,
+/// the bandwidth and the fragmentation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conditions {
+    /// One way delay of everything
+    pub latency_ms: u64,
+    /// Up to this much is added to the latency, at random
+    pub jitter_ms: u64,
+    /// Chance of a datagram getting lost, from 0 to 1
+    pub loss: f64,
+    /// Chance of a datagram getting held back, so that later ones overtake it
+    pub reorder: f64,
+    /// Bytes per second the link carries, `None` for no limit
+    pub bandwidth: Option<u64>,
+    /// Writes and reads go through in pieces of at most this many bytes
+    pub max_fragment: Option<usize>,
+}
+
+impl Conditions {
+    /// A link that does nothing to what goes over it
+    pub fn perfect() -> Conditions {
+        Conditions {
+            latency_ms: 0,
+            jitter_ms: 0,
+            loss: 0.0,
+            reorder: 0.0,
+            bandwidth: None,
+            max_fragment: None,
+        }
+    }
+}
+
+impl Default for Conditions {
+    fn default() -> Conditions {
+        Conditions::perfect()
+    }
+}
+
+/// A xorshift generator, good enough to pick what goes wrong
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Zero is the one state xorshift never leaves
+        Rng(if seed == 0 { 0x9E3779B97F4A7C15 } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    /// A number from `0` up to, but not including, `n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        if n == 0 { 0 } else { self.next_u64() % n }
+    }
+
+    /// `true` with a chance of `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// Decides the fate of everything sent over one direction of a link
+pub struct Simulator {
+    conditions: Conditions,
+    rng: Rng,
+    /// When the link is done with what was sent so far
+    free_at: u64,
+}
+
+impl Simulator {
+    pub fn new(conditions: Conditions, seed: u64) -> Simulator {
+        Simulator {
+            conditions: conditions,
+            rng: Rng::new(seed),
+            free_at: 0,
+        }
+    }
+
+    pub fn conditions(&self) -> &Conditions {
+        &self.conditions
+    }
+
+    /// When `len` bytes sent at `now` arrive over a stream
+    pub fn delay(&mut self, len: usize, now: u64) -> u64 {
+        let start = cmp::max(now, self.free_at);
+        self.free_at = match self.conditions.bandwidth {
+            Some(rate) if rate > 0 => start + len as u64 * 1000000000 / rate,
+            _ => start,
+        };
+        let jitter = self.rng.below(self.conditions.jitter_ms * 1000000 + 1);
+        self.free_at + self.conditions.latency_ms * 1000000 + jitter
+    }
+
+    /// When a datagram of `len` bytes sent at `now` arrives, `None` if it
+    /// gets lost
+    pub fn arrival(&mut self, len: usize, now: u64) -> Option<u64> {
+        // Lost datagrams took up the link all the same
+        let at = self.delay(len, now);
+        if self.rng.chance(self.conditions.loss) {
+            return None;
+        }
+        if self.rng.chance(self.conditions.reorder) {
+            let held = (self.conditions.latency_ms + self.conditions.jitter_ms) * 1000000;
+            return Some(at + held + REORDER_NS);
+        }
+        Some(at)
+    }
+
+    /// How many of `len` bytes go through in one piece
+    pub fn fragment(&mut self, len: usize) -> usize {
+        match self.conditions.max_fragment {
+            Some(max) if max > 0 && len > 0 => {
+                1 + self.rng.below(cmp::min(len, max) as u64) as usize
+            }
+            _ => len
+        }
+    }
+}
+
+struct InFlight<T> {
+    at: u64,
+    /// Tells apart what arrives at the same time, by when it was sent
+    sequence: u64,
+    item: T,
+}
+
+/// One direction of a datagram link, in virtual time
+///
+/// What is sent is handed out again by `deliver`, once it arrived.
+pub struct Wire<T> {
+    sim: Simulator,
+    in_flight: Vec<InFlight<T>>,
+    sent: u64,
+    lost: usize,
+}
+
+impl<T> Wire<T> {
+    pub fn new(conditions: Conditions, seed: u64) -> Wire<T> {
+        Wire {
+            sim: Simulator::new(conditions, seed),
+            in_flight: Vec::new(),
+            sent: 0,
+            lost: 0,
+        }
+    }
+
+    /// Sends a datagram of `len` bytes at `now`
+    pub fn send(&mut self, item: T, len: usize, now: u64) {
+        match self.sim.arrival(len, now) {
+            Some(at) => self.in_flight.push(InFlight {
+                at: at,
+                sequence: self.sent,
+                item: item,
+            }),
+            None => self.lost += 1,
+        }
+        self.sent += 1;
+    }
+
+    /// Everything that arrived by `now`, in the order it arrived
+    pub fn deliver(&mut self, now: u64) -> Vec<T> {
+        let (mut due, later): (Vec<_>, Vec<_>) = mem::replace(&mut self.in_flight, Vec::new())
+            .into_iter().partition(|datagram| datagram.at <= now);
+        self.in_flight = later;
+        due.sort_by(|a, b| (a.at, a.sequence).cmp(&(b.at, b.sequence)));
+        due.into_iter().map(|datagram| datagram.item).collect()
+    }
+
+    /// When the next datagram arrives, if any is on its way
+    pub fn next_arrival(&self) -> Option<u64> {
+        self.in_flight.iter().map(|datagram| datagram.at).min()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// How many datagrams got lost so far
+    pub fn lost(&self) -> usize {
+        self.lost
+    }
+}
+
+fn sleep_until(at: u64) {
+    let now = precise_time_ns();
+    if at > now {
+        thread::sleep(Duration::from_millis((at - now + 999999) / 1000000));
+    }
+}
+
+/// A stream behind simulated conditions
+///
+/// Reads and writes are cut short at random, writes arrive late. What is
+/// written is handed to a thread, which writes it to the real stream once
+/// it is due, so a slow link does not hold up the writer.
+pub struct SimStream<S> {
+    inner: S,
+    sim: Simulator,
+    /// When the last write arrives, nothing may overtake it
+    last_due: u64,
+    outgoing: Option<Sender<(u64, Vec<u8>)>>,
+    /// Bytes that were written, but did not reach the real stream yet
+    pending: Arc<AtomicUsize>,
+    failed: Arc<AtomicBool>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl<S: Read> SimStream<S> {
+    /// Reads from `inner` and writes to `writer`, which are usually two
+    /// handles of the same stream
+    pub fn new<W>(inner: S, writer: W, conditions: Conditions, seed: u64) -> SimStream<S>
+        where W: Write + Send + 'static {
+        let (tx, rx) = channel::<(u64, Vec<u8>)>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicBool::new(false));
+
+        let still_pending = pending.clone();
+        let has_failed = failed.clone();
+        let handle = thread::spawn(move|| {
+            let mut writer = writer;
+            for (due, chunk) in rx.iter() {
+                sleep_until(due);
+                if writer.write_all(&chunk).and_then(|_| writer.flush()).is_err() {
+                    has_failed.store(true, Ordering::SeqCst);
+                    break;
+                }
+                still_pending.fetch_sub(chunk.len(), Ordering::SeqCst);
+            }
+        });
+
+        SimStream {
+            inner: inner,
+            sim: Simulator::new(conditions, seed),
+            last_due: 0,
+            outgoing: Some(tx),
+            pending: pending,
+            failed: failed,
+            writer: Some(handle),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl SimStream<TcpStream> {
+    pub fn tcp(stream: TcpStream, conditions: Conditions, seed: u64)
+        -> io::Result<SimStream<TcpStream>> {
+        let writer = try!(stream.try_clone());
+        Ok(SimStream::new(stream, writer, conditions, seed))
+    }
+}
+
+impl<S: Read> Read for SimStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.sim.fragment(buf.len());
+        self.inner.read(&mut buf[..len])
+    }
+}
+
+impl<S> Write for SimStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.failed.load(Ordering::SeqCst) {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "The simulated stream failed"));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = self.sim.fragment(buf.len());
+        let due = cmp::max(self.sim.delay(len, precise_time_ns()), self.last_due);
+        self.last_due = due;
+
+        self.pending.fetch_add(len, Ordering::SeqCst);
+        let sent = match self.outgoing {
+            Some(ref outgoing) => outgoing.send((due, buf[..len].to_vec())).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "The simulated stream failed"));
+        }
+        Ok(len)
+    }
+
+    /// Waits until everything written reached the real stream
+    fn flush(&mut self) -> io::Result<()> {
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            if self.failed.load(Ordering::SeqCst) {
+                return Err(io::Error::new(ErrorKind::BrokenPipe,
+                                          "The simulated stream failed"));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+}
+
+impl<S> Drop for SimStream<S> {
+    /// Lets everything written arrive, like closing a socket would
+    fn drop(&mut self) {
+        self.outgoing = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Datagrams behind simulated conditions
+pub struct SimSocket<S: Datagrams> {
+    socket: Arc<S>,
+    wire: Arc<Mutex<Wire<(Vec<u8>, SocketAddr)>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl<S: Datagrams> SimSocket<S> {
+    pub fn new(socket: S, conditions: Conditions, seed: u64) -> SimSocket<S> {
+        let socket = Arc::new(socket);
+        let wire = Arc::new(Mutex::new(Wire::<(Vec<u8>, SocketAddr)>::new(conditions, seed)));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let (socket_ref, wire_ref, still_running) = (socket.clone(), wire.clone(), running.clone());
+        thread::spawn(move|| {
+            while still_running.load(Ordering::SeqCst) {
+                let due = wire_ref.lock().unwrap().deliver(precise_time_ns());
+                for (datagram, addr) in due.into_iter() {
+                    let _ = socket_ref.send_to(&datagram, addr);
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        SimSocket {
+            socket: socket,
+            wire: wire,
+            running: running,
+        }
+    }
+
+    /// How many datagrams got lost so far
+    pub fn lost(&self) -> usize {
+        self.wire.lock().unwrap().lost()
+    }
+}
+
+impl<S: Datagrams> Datagrams for SimSocket<S> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.wire.lock().unwrap().send((buf.to_vec(), addr), buf.len(), precise_time_ns());
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl<S: Datagrams> Drop for SimSocket<S> {
+    /// Whatever is still on its way gets lost
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+mod test {
+    use super::*;
+    use net::{send_packet, receive_packet};
+    use packets::{ChatChannel, Packet};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn ms(n: u64) -> u64 {
+        n * 1000000
+    }
+
+    fn send_all(wire: &mut Wire<u32>, count: u32, now: u64) {
+        for n in 0..count {
+            wire.send(n, 100, now);
+        }
+    }
+
+    /// What arrives in the first 10ms of sending 100 datagrams
+    fn arrived(seed: u64) -> Vec<u32> {
+        let conditions = Conditions { loss: 0.3, jitter_ms: 10, ..Conditions::perfect() };
+        let mut wire = Wire::new(conditions, seed);
+        send_all(&mut wire, 100, 0);
+        wire.deliver(ms(10))
+    }
+
+    #[test]
+    fn test_same_seed_same_network() {
+        assert_eq!(arrived(42), arrived(42));
+        assert!(arrived(42) != arrived(43));
+    }
+
+    #[test]
+    fn test_loss() {
+        let mut wire = Wire::new(Conditions { loss: 0.25, ..Conditions::perfect() }, 1);
+        send_all(&mut wire, 1000, 0);
+        assert!(wire.lost() > 150 && wire.lost() < 350, "Lost {}", wire.lost());
+        assert_eq!(wire.deliver(0).len() + wire.lost(), 1000);
+    }
+
+    #[test]
+    fn test_latency_and_jitter() {
+        let mut wire = Wire::new(Conditions { latency_ms: 50, jitter_ms: 20, ..Conditions::perfect() }, 1);
+        send_all(&mut wire, 100, 0);
+        assert!(wire.deliver(ms(50) - 1).is_empty());
+        assert!(wire.next_arrival().unwrap() >= ms(50));
+        assert_eq!(wire.deliver(ms(70)).len(), 100);
+        assert_eq!(wire.in_flight(), 0);
+        assert_eq!(wire.next_arrival(), None);
+    }
+
+    #[test]
+    fn test_reordering() {
+        let mut wire = Wire::new(Conditions { reorder: 0.2, ..Conditions::perfect() }, 1);
+        send_all(&mut wire, 100, 0);
+        let mut arrived = wire.deliver(0);
+        assert!(arrived.len() < 100);
+        arrived.extend(wire.deliver(REORDER_NS).into_iter());
+        assert_eq!(arrived.len(), 100);
+
+        let in_order: Vec<u32> = (0..100).collect();
+        assert!(arrived != in_order);
+        arrived.sort();
+        assert_eq!(arrived, in_order);
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let mut wire = Wire::new(Conditions { bandwidth: Some(1000), ..Conditions::perfect() }, 1);
+        wire.send(1, 500, 0);
+        wire.send(2, 500, 0);
+        assert_eq!(wire.deliver(ms(500)), vec![1]);
+        assert!(wire.deliver(ms(999)).is_empty());
+        assert_eq!(wire.deliver(ms(1000)), vec![2]);
+    }
+
+    #[test]
+    fn test_fragments() {
+        let mut sim = Simulator::new(Conditions { max_fragment: Some(3), ..Conditions::perfect() }, 1);
+        for _ in 0..100 {
+            let len = sim.fragment(10);
+            assert!(len >= 1 && len <= 3);
+        }
+        assert_eq!(sim.fragment(0), 0);
+        assert_eq!(Simulator::new(Conditions::perfect(), 1).fragment(10), 10);
+    }
+
+    #[test]
+    fn test_stream_framing() {
+        let conditions = Conditions {
+            latency_ms: 5,
+            jitter_ms: 5,
+            max_fragment: Some(3),
+            ..Conditions::perfect()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server_conditions = conditions.clone();
+        let server = thread::spawn(move|| {
+            let stream = listener.accept().unwrap().0;
+            let mut stream = SimStream::tcp(stream, server_conditions, 2).unwrap();
+            for _ in 0..20 {
+                let packet = receive_packet(&mut stream).unwrap();
+                send_packet(&mut stream, &packet).unwrap();
+            }
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut stream = SimStream::tcp(stream, conditions, 1).unwrap();
+        for n in 0..20 {
+            let packet = Packet::SendChat {
+                channel: ChatChannel::Global,
+                message: format!("line {}", n),
+            };
+            send_packet(&mut stream, &packet).unwrap();
+            assert_eq!(receive_packet(&mut stream).unwrap(), packet);
+        }
+        server.join().unwrap();
+    }
+}
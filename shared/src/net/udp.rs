@@ -0,0 +1,985 @@
+//! `Packet`s over UDP
+//!
+//! Every `Packet` goes on the `Channel` its kind asks for. On the reliable
+//! ordered channel every message carries a sequence number, the receiver
+//! acknowledges what it got and the sender resends whatever was not
+//! acknowledged in time. Messages that arrive early are held back until
+//! the gap before them is filled. On the unreliable sequenced channel
+//! messages are sent once, and dropped by the receiver if a newer one was
+//! there first, so a lost `Move` never holds up the next one.
+//!
+//! A connection starts with a handshake: the client sends `Connect` with a
+//! nonce until the server answers with an `Accept` carrying it back.
+//!
+//! `Session` is the protocol itself, it never touches a socket and is
+//! driven with the current time. `UdpConnection` runs a `Session` over
+//! anything that sends and receives `Datagrams`, like a `UdpSocket`.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clock_ticks::precise_time_ns;
+use rand;
+
+use packets::{Channel, Packet, PROTOCOL_VERSION};
+use super::{decode_payload, encode_payload, PacketError, MAX_PACKET_SIZE};
+
+/// Starts every `Connect`, so stray datagrams are not taken for one
+pub const PROTOCOL_ID: u32 = 0x52504755;
+
+/// Largest datagram ever sent, small enough not to be split on the way
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+/// How many reliable messages may be on their way at once
+///
+/// The receiver holds back at most this many that arrived early, and
+/// acknowledges them in a bit field of the same size.
+pub const WINDOW: usize = 32;
+
+/// How long until a message that was not acknowledged is sent again
+pub const RESEND_NS: u64 = 100 * 1000000;
+
+/// How long a quiet connection goes without sending anything
+pub const KEEPALIVE_NS: u64 = 1000 * 1000000;
+
+/// A connection that received nothing for this long is closed
+pub const TIMEOUT_NS: u64 = 10 * 1000 * 1000000;
+
+/// Reliable messages that may wait to be acknowledged, before `send` fails
+pub const MAX_QUEUED: usize = 4096;
+
+/// Longest a connection waits for datagrams at a time, so that whatever
+/// is due to be resent still goes out in time
+const MAX_WAIT_NS: u64 = RESEND_NS / 10;
+
+/// How long the reader thread blocks on the socket before it checks
+/// whether it should stop, in milliseconds
+const READER_TIMEOUT_MS: u64 = 100;
+
+/// Bytes a `Data` datagram takes besides its messages
+const DATA_OVERHEAD: usize = 4 + 4 + 4 + 8;
+
+/// Bytes a `Message` takes besides its payload
+const MESSAGE_OVERHEAD: usize = 4 + 4 + 8;
+
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
+enum Datagram {
+    Connect {
+        protocol_id: u32,
+        protocol_version: u32,
+        nonce: u64,
+    },
+    Accept {
+        nonce: u64,
+    },
+    Refused {
+        reason: String,
+    },
+    Data {
+        /// The sender has every reliable message before this one
+        ack: u32,
+        /// Bit `n` is set if the sender has message `ack + n + 1` as well
+        ack_bits: u32,
+        messages: Vec<Message>,
+    },
+    Disconnect,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
+struct Message {
+    channel: Channel,
+    sequence: u32,
+    /// An encoded `Packet`
+    payload: Vec<u8>,
+}
+
+fn encode_datagram(datagram: &Datagram) -> Result<Vec<u8>, PacketError> {
+    super::encode_message(datagram)
+}
+
+/// Whether sequence number `a` comes after `b`, allowing for wrap around
+fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+/// What a server sends back to a `Connect` it does not want
+pub fn refusal(reason: &str) -> Result<Vec<u8>, PacketError> {
+    encode_datagram(&Datagram::Refused { reason: reason.to_string() })
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SessionState {
+    /// Waiting for the server to `Accept`
+    Connecting,
+    Connected,
+    /// Either side hung up, or the connection timed out
+    Closed,
+}
+
+/// A reliable message that was not acknowledged yet
+struct Pending {
+    sequence: u32,
+    payload: Vec<u8>,
+    /// `None` until it was sent the first time
+    sent_at: Option<u64>,
+}
+
+/// One end of a connection
+pub struct Session {
+    state: SessionState,
+    nonce: u64,
+    limit: usize,
+    /// Whether `close` was called, so a `Disconnect` has to go out
+    closing: bool,
+    /// The server answers every `Connect` of its client, in case the
+    /// `Accept` got lost
+    accept_due: bool,
+    last_sent: Option<u64>,
+    last_received: u64,
+
+    next_reliable: u32,
+    /// Oldest first
+    unacked: Vec<Pending>,
+    /// The next reliable message to be delivered
+    expected: u32,
+    /// Messages from `expected` on, which arrived but can't be delivered
+    held: VecDeque<Option<Vec<u8>>>,
+    ack_due: bool,
+
+    next_unreliable: u32,
+    unreliable: Vec<Message>,
+    last_unreliable: Option<u32>,
+
+    delivered: VecDeque<Packet>,
+    decode_errors: usize,
+}
+
+impl Session {
+    fn new(state: SessionState, nonce: u64, now: u64) -> Session {
+        Session {
+            state: state,
+            nonce: nonce,
+            limit: MAX_PACKET_SIZE,
+            closing: false,
+            accept_due: false,
+            last_sent: None,
+            last_received: now,
+            next_reliable: 0,
+            unacked: Vec::new(),
+            expected: 0,
+            held: (0..WINDOW + 1).map(|_| None).collect(),
+            ack_due: false,
+            next_unreliable: 0,
+            unreliable: Vec::new(),
+            last_unreliable: None,
+            delivered: VecDeque::new(),
+            decode_errors: 0,
+        }
+    }
+
+    /// The client end, `nonce` tells this attempt from earlier ones
+    ///
+    /// It should be random, so nobody can guess it and answer in place of
+    /// the server.
+    pub fn connect(nonce: u64, now: u64) -> Session {
+        Session::new(SessionState::Connecting, nonce, now)
+    }
+
+    /// The server end, made from the first datagram of the client
+    ///
+    /// Fails with `Refused` if it is not a `Connect` of this protocol
+    /// version, `refusal` makes the answer to that.
+    pub fn accept(datagram: &[u8], now: u64) -> Result<Session, PacketError> {
+        match try!(decode_payload(datagram)) {
+            Datagram::Connect { protocol_id, protocol_version, nonce } => {
+                if protocol_id != PROTOCOL_ID {
+                    return Err(PacketError::Refused("Not a connection attempt".to_string()));
+                }
+                if protocol_version != PROTOCOL_VERSION {
+                    return Err(PacketError::Refused(format!(
+                        "Protocol version mismatch: server speaks {}, client speaks {}",
+                        PROTOCOL_VERSION, protocol_version)));
+                }
+                let mut session = Session::new(SessionState::Connected, nonce, now);
+                session.accept_due = true;
+                Ok(session)
+            }
+            _ => Err(PacketError::Refused("Expected Connect as the first datagram".to_string()))
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// When the last valid datagram arrived
+    pub fn last_received(&self) -> u64 {
+        self.last_received
+    }
+
+    /// Reliable messages the other side did not acknowledge yet
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Messages that arrived, but did not decode to a `Packet`
+    pub fn decode_errors(&self) -> usize {
+        self.decode_errors
+    }
+
+    /// Queues a `Packet` on its channel, it goes out with `transmit`
+    ///
+    /// Packets sent while connecting wait for the handshake.
+    pub fn send(&mut self, packet: &Packet) -> Result<(), PacketError> {
+        if self.state == SessionState::Closed {
+            return Err(PacketError::IoError(io::Error::new(ErrorKind::NotConnected,
+                                                           "The session is closed")));
+        }
+        let payload = try!(encode_payload(packet, self.limit));
+        match packet.channel() {
+            Channel::ReliableOrdered => {
+                if self.unacked.len() >= MAX_QUEUED {
+                    return Err(PacketError::IoError(io::Error::new(ErrorKind::Other,
+                        "Too many packets are waiting to be acknowledged")));
+                }
+                self.unacked.push(Pending {
+                    sequence: self.next_reliable,
+                    payload: payload,
+                    sent_at: None,
+                });
+                self.next_reliable = self.next_reliable.wrapping_add(1);
+            }
+            Channel::UnreliableSequenced => {
+                self.unreliable.push(Message {
+                    channel: Channel::UnreliableSequenced,
+                    sequence: self.next_unreliable,
+                    payload: payload,
+                });
+                self.next_unreliable = self.next_unreliable.wrapping_add(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// The next `Packet` that arrived, in the order of its channel
+    pub fn next_packet(&mut self) -> Option<Packet> {
+        self.delivered.pop_front()
+    }
+
+    /// Hangs up, the other side is told with the next `transmit`
+    pub fn close(&mut self) {
+        if self.state != SessionState::Closed {
+            self.state = SessionState::Closed;
+            self.closing = true;
+        }
+    }
+
+    /// Takes in a datagram from the other side
+    ///
+    /// Datagrams that don't decode are an error, and otherwise ignored.
+    pub fn receive(&mut self, datagram: &[u8], now: u64) -> Result<(), PacketError> {
+        let datagram = try!(decode_payload(datagram));
+        if self.state == SessionState::Closed {
+            return Ok(());
+        }
+        self.last_received = now;
+
+        match datagram {
+            Datagram::Connect { nonce, .. } => {
+                if nonce == self.nonce {
+                    self.accept_due = true;
+                }
+            }
+            Datagram::Accept { nonce } => {
+                if self.state == SessionState::Connecting && nonce == self.nonce {
+                    self.state = SessionState::Connected;
+                }
+            }
+            Datagram::Refused { reason } => {
+                self.state = SessionState::Closed;
+                return Err(PacketError::Refused(reason));
+            }
+            Datagram::Data { ack, ack_bits, messages } => {
+                // The server only sends data after accepting, so a lost
+                // `Accept` does not matter
+                self.state = SessionState::Connected;
+                self.acknowledged(ack, ack_bits);
+                for message in messages.into_iter() {
+                    match message.channel {
+                        Channel::ReliableOrdered => {
+                            self.receive_reliable(message.sequence, message.payload);
+                        }
+                        Channel::UnreliableSequenced => {
+                            self.receive_unreliable(message.sequence, message.payload);
+                        }
+                    }
+                }
+            }
+            Datagram::Disconnect => self.state = SessionState::Closed,
+        }
+        Ok(())
+    }
+
+    /// Forgets the messages the other side has
+    fn acknowledged(&mut self, ack: u32, ack_bits: u32) {
+        self.unacked.retain(|pending| {
+            if is_newer(ack, pending.sequence) {
+                return false;
+            }
+            let offset = pending.sequence.wrapping_sub(ack) as usize;
+            !(offset >= 1 && offset <= WINDOW && ack_bits & (1 << (offset - 1)) != 0)
+        });
+    }
+
+    fn receive_reliable(&mut self, sequence: u32, payload: Vec<u8>) {
+        // Duplicates get acknowledged again too, the first ack may be lost
+        self.ack_due = true;
+        let offset = sequence.wrapping_sub(self.expected) as usize;
+        if offset > WINDOW {
+            return;
+        }
+        if self.held[offset].is_none() {
+            self.held[offset] = Some(payload);
+        }
+
+        loop {
+            match self.held.front() {
+                Some(&Some(_)) => (),
+                _ => break
+            }
+            let payload = self.held.pop_front().unwrap().unwrap();
+            self.held.push_back(None);
+            self.expected = self.expected.wrapping_add(1);
+            self.deliver(&payload);
+        }
+    }
+
+    fn receive_unreliable(&mut self, sequence: u32, payload: Vec<u8>) {
+        if self.last_unreliable.map_or(true, |last| is_newer(sequence, last)) {
+            self.last_unreliable = Some(sequence);
+            self.deliver(&payload);
+        }
+    }
+
+    fn deliver(&mut self, payload: &[u8]) {
+        match decode_payload(payload) {
+            Ok(packet) => self.delivered.push_back(packet),
+            Err(_) => self.decode_errors += 1,
+        }
+    }
+
+    /// What the acknowledgement of a `Data` datagram says right now
+    fn ack(&self) -> (u32, u32) {
+        let mut bits = 0;
+        for n in 0..WINDOW {
+            if self.held[n + 1].is_some() {
+                bits |= 1 << n;
+            }
+        }
+        (self.expected, bits)
+    }
+
+    /// The datagrams that have to go out by `now`
+    ///
+    /// Call this regularly, even with nothing to send: it resends what
+    /// got lost, acknowledges and keeps the connection alive.
+    pub fn transmit(&mut self, now: u64) -> Result<Vec<Vec<u8>>, PacketError> {
+        let mut out = Vec::new();
+        if self.state == SessionState::Connected &&
+           now.saturating_sub(self.last_received) >= TIMEOUT_NS {
+            self.state = SessionState::Closed;
+        }
+        let due = |last: Option<u64>, interval: u64| {
+            last.map_or(true, |last| now.saturating_sub(last) >= interval)
+        };
+
+        match self.state {
+            SessionState::Closed => {
+                if self.closing {
+                    self.closing = false;
+                    out.push(try!(encode_datagram(&Datagram::Disconnect)));
+                }
+                return Ok(out);
+            }
+            SessionState::Connecting => {
+                if due(self.last_sent, RESEND_NS) {
+                    self.last_sent = Some(now);
+                    out.push(try!(encode_datagram(&Datagram::Connect {
+                        protocol_id: PROTOCOL_ID,
+                        protocol_version: PROTOCOL_VERSION,
+                        nonce: self.nonce,
+                    })));
+                }
+                return Ok(out);
+            }
+            SessionState::Connected => ()
+        }
+
+        if self.accept_due {
+            self.accept_due = false;
+            out.push(try!(encode_datagram(&Datagram::Accept { nonce: self.nonce })));
+        }
+
+        let (ack, ack_bits) = self.ack();
+        let mut messages = Vec::new();
+        let mut size = DATA_OVERHEAD;
+        let oldest = self.unacked.first().map(|p| p.sequence).unwrap_or(0);
+        for pending in self.unacked.iter_mut() {
+            // The receiver would not hold on to anything further ahead
+            if pending.sequence.wrapping_sub(oldest) as usize >= WINDOW {
+                break;
+            }
+            if !due(pending.sent_at, RESEND_NS) {
+                continue;
+            }
+            pending.sent_at = Some(now);
+            let message = Message {
+                channel: Channel::ReliableOrdered,
+                sequence: pending.sequence,
+                payload: pending.payload.clone(),
+            };
+            try!(pack(&mut out, &mut messages, &mut size, ack, ack_bits, message));
+        }
+        for message in mem::replace(&mut self.unreliable, Vec::new()).into_iter() {
+            try!(pack(&mut out, &mut messages, &mut size, ack, ack_bits, message));
+        }
+
+        if !messages.is_empty() || self.ack_due || due(self.last_sent, KEEPALIVE_NS) {
+            out.push(try!(encode_datagram(&Datagram::Data {
+                ack: ack,
+                ack_bits: ack_bits,
+                messages: messages,
+            })));
+        }
+        if !out.is_empty() {
+            self.ack_due = false;
+            self.last_sent = Some(now);
+        }
+        Ok(out)
+    }
+}
+
+/// Adds `message` to the `Data` datagram being filled, sending that one
+/// off first if the message would not fit anymore
+fn pack(out: &mut Vec<Vec<u8>>, messages: &mut Vec<Message>, size: &mut usize,
+        ack: u32, ack_bits: u32, message: Message) -> Result<(), PacketError> {
+    let needed = MESSAGE_OVERHEAD + message.payload.len();
+    if *size + needed > MAX_DATAGRAM_SIZE && !messages.is_empty() {
+        out.push(try!(encode_datagram(&Datagram::Data {
+            ack: ack,
+            ack_bits: ack_bits,
+            messages: mem::replace(messages, Vec::new()),
+        })));
+        *size = DATA_OVERHEAD;
+    }
+    *size += needed;
+    messages.push(message);
+    Ok(())
+}
+
+/// Something that sends and receives datagrams, like a `UdpSocket`
+pub trait Datagrams: Send + Sync + 'static {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+    /// Blocks until a datagram arrives, or the read timeout passed
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Datagrams for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// A thread receiving datagrams, since the socket can only block
+///
+/// The socket gets a read timeout, so the thread notices when it should
+/// stop without anything arriving.
+struct Reader {
+    running: Arc<AtomicBool>,
+    incoming: Receiver<(Vec<u8>, SocketAddr)>,
+}
+
+impl Reader {
+    fn spawn<S: Datagrams>(socket: Arc<S>) -> io::Result<Reader> {
+        try!(socket.set_read_timeout(Some(Duration::from_millis(READER_TIMEOUT_MS))));
+        let running = Arc::new(AtomicBool::new(true));
+        let (tx, rx) = channel();
+        let still_running = running.clone();
+        thread::spawn(move|| {
+            let mut buf = [0; MAX_DATAGRAM_SIZE];
+            while still_running.load(Ordering::SeqCst) {
+                match socket.recv_from(&mut buf) {
+                    Ok((len, from)) => {
+                        if tx.send((buf[..len].to_vec(), from)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                                  e.kind() == ErrorKind::TimedOut ||
+                                  e.kind() == ErrorKind::Interrupted => (),
+                    Err(_) => break
+                }
+            }
+        });
+        Ok(Reader {
+            running: running,
+            incoming: rx,
+        })
+    }
+}
+
+fn duration(ns: u64) -> Duration {
+    Duration::new(ns / 1000000000, (ns % 1000000000) as u32)
+}
+
+/// A `Session` with a single peer, over `socket`
+pub struct UdpConnection<S: Datagrams = UdpSocket> {
+    socket: Arc<S>,
+    peer: SocketAddr,
+    session: Session,
+    reader: Reader,
+}
+
+impl<S: Datagrams> UdpConnection<S> {
+    /// Connects to `peer`, giving up after `timeout_ms`
+    pub fn connect(socket: S, peer: SocketAddr, timeout_ms: u64)
+        -> Result<UdpConnection<S>, PacketError> {
+        let socket = Arc::new(socket);
+        let now = precise_time_ns();
+        let mut connection = UdpConnection {
+            reader: try!(Reader::spawn(socket.clone())),
+            socket: socket,
+            peer: peer,
+            session: Session::connect(rand::random(), now),
+        };
+
+        let deadline = now + timeout_ms * 1000000;
+        try!(connection.pump());
+        while connection.session.state() == SessionState::Connecting {
+            if precise_time_ns() >= deadline {
+                return Err(PacketError::IoError(io::Error::new(ErrorKind::TimedOut,
+                                                               "No answer from the server")));
+            }
+            try!(connection.pump_until(deadline));
+        }
+        Ok(connection)
+    }
+
+    /// Waits for a client to connect, for at most `timeout_ms`
+    ///
+    /// The first one to send a valid `Connect` becomes the peer, the
+    /// others are refused.
+    pub fn accept(socket: S, timeout_ms: u64) -> Result<UdpConnection<S>, PacketError> {
+        let socket = Arc::new(socket);
+        let reader = try!(Reader::spawn(socket.clone()));
+
+        let deadline = precise_time_ns() + timeout_ms * 1000000;
+        loop {
+            let now = precise_time_ns();
+            if now >= deadline {
+                return Err(PacketError::IoError(io::Error::new(ErrorKind::TimedOut,
+                                                               "Nobody connected")));
+            }
+            let (datagram, from) = match reader.incoming.recv_timeout(duration(deadline - now)) {
+                Ok(received) => received,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(PacketError::IoError(io::Error::new(ErrorKind::Other,
+                                                                   "Could not read the socket")));
+                }
+            };
+            match Session::accept(&datagram, precise_time_ns()) {
+                Ok(session) => {
+                    let mut connection = UdpConnection {
+                        socket: socket,
+                        peer: from,
+                        session: session,
+                        reader: reader,
+                    };
+                    try!(connection.pump());
+                    return Ok(connection);
+                }
+                Err(PacketError::Refused(reason)) => {
+                    try!(socket.send_to(&try!(refusal(&reason)), from));
+                }
+                Err(_) => ()
+            }
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Queues a `Packet` and sends whatever is due
+    pub fn send(&mut self, packet: &Packet) -> Result<(), PacketError> {
+        try!(self.session.send(packet));
+        self.pump()
+    }
+
+    /// Waits up to `timeout_ms` for a `Packet`
+    ///
+    /// Returns `None` if none arrived in time, and fails with `ZeroRead`
+    /// once the connection is closed.
+    pub fn receive(&mut self, timeout_ms: u64) -> Result<Option<Packet>, PacketError> {
+        let deadline = precise_time_ns() + timeout_ms * 1000000;
+        try!(self.pump());
+        loop {
+            if let Some(packet) = self.session.next_packet() {
+                return Ok(Some(packet));
+            }
+            if self.session.state() == SessionState::Closed {
+                return Err(PacketError::ZeroRead);
+            }
+            if precise_time_ns() >= deadline {
+                return Ok(None);
+            }
+            try!(self.pump_until(deadline));
+        }
+    }
+
+    /// Takes in what arrived from the peer and sends what is due
+    pub fn pump(&mut self) -> Result<(), PacketError> {
+        let now = precise_time_ns();
+        while let Ok((datagram, from)) = self.reader.incoming.try_recv() {
+            try!(self.take_in(&datagram, from, now));
+        }
+        for datagram in try!(self.session.transmit(now)).into_iter() {
+            try!(self.socket.send_to(&datagram, self.peer));
+        }
+        Ok(())
+    }
+
+    /// Like `pump`, but first waits for a datagram until `deadline`
+    ///
+    /// Never waits longer than `MAX_WAIT_NS`, resends may be due by then.
+    fn pump_until(&mut self, deadline: u64) -> Result<(), PacketError> {
+        let now = precise_time_ns();
+        let wait = cmp::min(deadline.saturating_sub(now), MAX_WAIT_NS);
+        if let Ok((datagram, from)) = self.reader.incoming.recv_timeout(duration(wait)) {
+            try!(self.take_in(&datagram, from, precise_time_ns()));
+        }
+        self.pump()
+    }
+
+    /// Hands a datagram to the session, if it came from the peer
+    fn take_in(&mut self, datagram: &[u8], from: SocketAddr, now: u64)
+        -> Result<(), PacketError> {
+        if from != self.peer {
+            return Ok(());
+        }
+        match self.session.receive(datagram, now) {
+            Ok(()) | Err(PacketError::DecodeError(..)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<S: Datagrams> Drop for UdpConnection<S> {
+    /// Says goodbye and stops the reader thread
+    ///
+    /// The reader notices with its next read timeout.
+    fn drop(&mut self) {
+        self.session.close();
+        let _ = self.pump();
+        self.reader.running.store(false, Ordering::SeqCst);
+    }
+}
+
+mod test {
+    use super::*;
+    use net::PacketError;
+    use net::sim::{Conditions, SimSocket, Wire};
+    use packets::{ChatChannel, Packet, Position, PROTOCOL_VERSION};
+    use std::net::UdpSocket;
+    use std::thread;
+
+    fn chat(message: &str) -> Packet {
+        Packet::SendChat {
+            channel: ChatChannel::Global,
+            message: message.to_string(),
+        }
+    }
+
+    fn moved(x: f32) -> Packet {
+        Packet::Move(Position::new(x, 0.0, 0.0))
+    }
+
+    /// Moves every datagram due at `now` across, unless `lose` says no
+    fn exchange<F>(from: &mut Session, to: &mut Session, now: u64, lose: &mut F)
+        where F: FnMut() -> bool {
+        for datagram in from.transmit(now).unwrap().into_iter() {
+            if !lose() {
+                to.receive(&datagram, now).unwrap();
+            }
+        }
+    }
+
+    fn connected_pair() -> (Session, Session) {
+        let mut client = Session::connect(7, 0);
+        let connect = client.transmit(0).unwrap();
+        assert_eq!(connect.len(), 1);
+        let mut server = Session::accept(&connect[0], 0).unwrap();
+        exchange(&mut server, &mut client, 0, &mut || false);
+        assert_eq!(client.state(), SessionState::Connected);
+        assert_eq!(server.state(), SessionState::Connected);
+        (client, server)
+    }
+
+    fn received(session: &mut Session) -> Vec<Packet> {
+        let mut packets = Vec::new();
+        while let Some(packet) = session.next_packet() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn test_handshake_and_round_trip() {
+        let (mut client, mut server) = connected_pair();
+
+        client.send(&chat("hello")).unwrap();
+        exchange(&mut client, &mut server, 1, &mut || false);
+        assert_eq!(received(&mut server), vec![chat("hello")]);
+
+        // The acknowledgement goes out even with nothing else to send
+        exchange(&mut server, &mut client, 2, &mut || false);
+        assert_eq!(client.unacked(), 0);
+    }
+
+    #[test]
+    fn test_refused() {
+        let mut client = Session::connect(7, 0);
+        let mut connect = client.transmit(0).unwrap().pop().unwrap();
+        // The protocol version is the second field
+        for byte in connect[8..12].iter_mut() {
+            *byte = 0xFF;
+        }
+        let reason = match Session::accept(&connect, 0) {
+            Err(PacketError::Refused(reason)) => reason,
+            other => panic!("Expected Refused, got {:?}", other.err()),
+        };
+        assert!(reason.contains(&format!("{}", PROTOCOL_VERSION)));
+
+        match client.receive(&refusal(&reason).unwrap(), 1) {
+            Err(PacketError::Refused(ref r)) if *r == reason => (),
+            other => panic!("Expected Refused, got {:?}", other),
+        }
+        assert_eq!(client.state(), SessionState::Closed);
+    }
+
+    #[test]
+    fn test_lost_handshake() {
+        let mut client = Session::connect(7, 0);
+        assert_eq!(client.transmit(0).unwrap().len(), 1);
+        // Too early to try again
+        assert_eq!(client.transmit(1).unwrap().len(), 0);
+
+        let connect = client.transmit(RESEND_NS).unwrap().pop().unwrap();
+        let mut server = Session::accept(&connect, RESEND_NS).unwrap();
+        // The `Accept` gets lost, the next `Connect` is answered again
+        server.transmit(RESEND_NS).unwrap();
+        let connect = client.transmit(2 * RESEND_NS).unwrap().pop().unwrap();
+        server.receive(&connect, 2 * RESEND_NS).unwrap();
+        exchange(&mut server, &mut client, 2 * RESEND_NS, &mut || false);
+        assert_eq!(client.state(), SessionState::Connected);
+    }
+
+    #[test]
+    fn test_reliable_under_loss() {
+        let (mut client, mut server) = connected_pair();
+        let sent: Vec<Packet> = (0..200).map(|i| chat(&format!("line {}", i))).collect();
+        for packet in sent.iter() {
+            client.send(packet).unwrap();
+        }
+
+        // Every third datagram gets lost, both ways
+        let mut count = 0;
+        let mut lose = || { count += 1; count % 3 == 0 };
+        let mut got = Vec::new();
+        let mut now = 0;
+        while got.len() < sent.len() {
+            now += RESEND_NS;
+            exchange(&mut client, &mut server, now, &mut lose);
+            exchange(&mut server, &mut client, now, &mut lose);
+            got.extend(received(&mut server).into_iter());
+            assert!(now < 1000 * RESEND_NS, "Only got {} packets", got.len());
+        }
+        assert_eq!(got, sent);
+
+        exchange(&mut server, &mut client, now + RESEND_NS, &mut || false);
+        assert_eq!(client.unacked(), 0);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let (mut client, mut server) = connected_pair();
+        client.send(&chat("first")).unwrap();
+        let first = client.transmit(1).unwrap();
+        client.send(&chat("second")).unwrap();
+        let second = client.transmit(2).unwrap();
+
+        server.receive(&second[0], 3).unwrap();
+        assert_eq!(server.next_packet(), None);
+        server.receive(&first[0], 4).unwrap();
+        assert_eq!(received(&mut server), vec![chat("first"), chat("second")]);
+
+        assert_eq!(server.ack(), (2, 0));
+        // A duplicate is acknowledged again, but not delivered
+        server.receive(&second[0], 5).unwrap();
+        assert_eq!(server.next_packet(), None);
+    }
+
+    #[test]
+    fn test_unreliable_sequenced() {
+        let (mut client, mut server) = connected_pair();
+        let mut datagrams = Vec::new();
+        for i in 0..3 {
+            client.send(&moved(i as f32)).unwrap();
+            datagrams.push(client.transmit(i + 1).unwrap().pop().unwrap());
+        }
+        // Never resent, and nothing to acknowledge
+        assert_eq!(client.unacked(), 0);
+
+        server.receive(&datagrams[1], 10).unwrap();
+        server.receive(&datagrams[0], 11).unwrap();
+        server.receive(&datagrams[2], 12).unwrap();
+        assert_eq!(received(&mut server), vec![moved(1.0), moved(2.0)]);
+    }
+
+    #[test]
+    fn test_close_and_timeout() {
+        let (mut client, mut server) = connected_pair();
+        client.close();
+        exchange(&mut client, &mut server, 1, &mut || false);
+        assert_eq!(server.state(), SessionState::Closed);
+        assert!(client.send(&chat("late")).is_err());
+
+        let (_, mut server) = connected_pair();
+        server.transmit(TIMEOUT_NS).unwrap();
+        assert_eq!(server.state(), SessionState::Closed);
+    }
+
+    #[test]
+    fn test_bad_network() {
+        let conditions = Conditions {
+            latency_ms: 40,
+            jitter_ms: 30,
+            loss: 0.2,
+            reorder: 0.1,
+            ..Conditions::perfect()
+        };
+        let mut to_server = Wire::new(conditions.clone(), 1);
+        let mut to_client = Wire::new(conditions, 2);
+
+        let mut client = Session::connect(7, 0);
+        let mut server = None;
+        let sent: Vec<Packet> = (0..100).map(|i| chat(&format!("line {}", i))).collect();
+        for packet in sent.iter() {
+            client.send(packet).unwrap();
+        }
+
+        let mut got = Vec::new();
+        let mut now = 0;
+        while got.len() < sent.len() {
+            now += 1000000;
+            assert!(now < TIMEOUT_NS, "Only got {} packets", got.len());
+            for datagram in client.transmit(now).unwrap().into_iter() {
+                let len = datagram.len();
+                to_server.send(datagram, len, now);
+            }
+            for datagram in to_server.deliver(now).into_iter() {
+                // The first to arrive is always a `Connect`
+                if server.is_none() {
+                    server = Some(Session::accept(&datagram, now).unwrap());
+                } else {
+                    server.as_mut().unwrap().receive(&datagram, now).unwrap();
+                }
+            }
+            if let Some(ref mut server) = server {
+                for datagram in server.transmit(now).unwrap().into_iter() {
+                    let len = datagram.len();
+                    to_client.send(datagram, len, now);
+                }
+                got.extend(received(server).into_iter());
+            }
+            for datagram in to_client.deliver(now).into_iter() {
+                client.receive(&datagram, now).unwrap();
+            }
+        }
+        assert_eq!(got, sent);
+        assert!(to_server.lost() > 0);
+    }
+
+    #[test]
+    fn test_loopback_with_loss() {
+        let conditions = Conditions {
+            latency_ms: 5,
+            jitter_ms: 5,
+            loss: 0.25,
+            reorder: 0.1,
+            ..Conditions::perfect()
+        };
+        let server_socket = SimSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap(),
+                                           conditions.clone(), 1);
+        let server_addr = server_socket.local_addr().unwrap();
+
+        let server = thread::spawn(move|| {
+            let mut connection = UdpConnection::accept(server_socket, 5000).unwrap();
+            let mut got = Vec::new();
+            while got.len() < 50 {
+                if let Some(packet) = connection.receive(100).unwrap() {
+                    got.push(packet);
+                }
+            }
+            connection.send(&chat("done")).unwrap();
+            // Stay around until the client got it
+            while connection.session().unacked() > 0 {
+                connection.receive(100).unwrap();
+            }
+            got
+        });
+
+        let mut client = UdpConnection::connect(
+            SimSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap(), conditions, 2),
+            server_addr, 5000).unwrap();
+        let sent: Vec<Packet> = (0..50).map(|i| chat(&format!("line {}", i))).collect();
+        for packet in sent.iter() {
+            client.send(packet).unwrap();
+        }
+        let mut answer = None;
+        while answer.is_none() {
+            answer = client.receive(100).unwrap();
+        }
+        assert_eq!(answer, Some(chat("done")));
+        drop(client);
+
+        assert_eq!(server.join().unwrap(), sent);
+    }
+}
@@ -1,5 +1,215 @@
+/// Version of the protocol spoken by this build
+///
+/// Bump this whenever `Packet` changes in a way older builds can't decode.
+pub const PROTOCOL_VERSION: u32 = 11;
 
-#[derive(RustcEncodable, RustcDecodable)]
+/// Outcome of an `AuthPlayer` or `Register` request
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
+pub enum AuthResult {
+    Accepted {
+        player_id: u64,
+    },
+    Refused {
+        reason: String,
+    },
+}
+
+/// What clients know about another player
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
+pub struct PlayerInfo {
+    pub player_id: u64,
+    pub name: String,
+}
+
+/// A line of the player list
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
+pub struct PlayerListEntry {
+    pub player_id: u64,
+    pub name: String,
+    /// Smoothed round trip time, `None` until the player answered a ping
+    pub ping_ms: Option<u32>,
+}
+
+/// Where a player is in the world
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Position {
+    pub fn new(x: f32, y: f32, z: f32) -> Position {
+        Position { x: x, y: y, z: z }
+    }
+
+    pub fn distance(&self, other: &Position) -> f32 {
+        let (dx, dy, dz) = (self.x - other.x, self.y - other.y, self.z - other.z);
+        (dx * dx + dy * dy + dz * dz).sqrt()
+    }
+}
+
+/// Who gets to read a chat message
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
+pub enum ChatChannel {
+    /// Everyone in the game
+    Global,
+    /// Everyone close to the sender
+    Local,
+    /// Everyone in the same party as the sender
+    Party,
+    /// A single player, by name. In a `Chat` this is always the recipient,
+    /// the sender gets a copy of what they whispered.
+    Whisper(String),
+    /// Announcements and notices, players can't send these
+    Server,
+    /// An action of the sender shown to everyone, from the `/me` command
+    Emote,
+}
+
+/// How a `Packet` travels over a datagram transport
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Channel {
+    /// Arrives exactly once and in order, like over TCP
+    ReliableOrdered,
+    /// May get lost, and arrives only if nothing newer arrived before
+    UnreliableSequenced,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
 pub enum Packet {
-    AuthPlayer(String)
+    /// First packet a client sends after connecting.
+    ///
+    /// This has to stay the first variant, so that every build can decode
+    /// it no matter how the rest of the enum grows. Fields only get added
+    /// at the end, a `Hello` the server can't decode is rejected as coming
+    /// from another version.
+    Hello {
+        protocol_version: u32,
+        client_build: String,
+        /// Whether the client wants its frames compressed
+        compression: bool,
+    },
+    /// The server accepted the `Hello`, the client may now authenticate
+    ///
+    /// The server only sets `compression` if the client asked for it in
+    /// its `Hello`. Then every frame after this one, both ways, has
+    /// the compression flag. Payloads of at least that many bytes should be
+    /// compressed.
+    Welcome {
+        compression: Option<u32>,
+    },
+    /// The server refused the client, the connection will be closed
+    Rejected {
+        reason: String,
+    },
+    /// Sent by the client right after the `Welcome`, starts an encrypted
+    /// session with a fresh X25519 public key, see the `secure` module
+    /// of `net`
+    KeyExchange {
+        key: Vec<u8>,
+    },
+    /// Answer to the `KeyExchange`: the fresh key of the server, and both
+    /// keys signed with its long-term Ed25519 `identity`. Every frame after
+    /// this one, both ways, is sealed.
+    KeyExchangeReply {
+        identity: Vec<u8>,
+        key: Vec<u8>,
+        signature: Vec<u8>,
+    },
+
+    // Both ways
+    /// Asks the other side for a `Pong` with the same value, to tell that
+    /// the connection is still alive
+    Ping(u64),
+    Pong(u64),
+    /// A piece of a `Packet` too large for a single frame, see the
+    /// `fragment` module of `net`
+    Fragment {
+        message_id: u32,
+        index: u16,
+        count: u16,
+        data: Vec<u8>,
+    },
+
+    // Client to Server
+    /// Log in to an existing account
+    AuthPlayer {
+        name: String,
+        password: String,
+    },
+    /// Create a new account and log in to it
+    Register {
+        name: String,
+        password: String,
+    },
+    /// A chat line typed by the player
+    SendChat {
+        channel: ChatChannel,
+        message: String,
+    },
+    /// The player moved to this position
+    Move(Position),
+    /// Joins the named party, or leaves the current one with `None`
+    SetParty(Option<String>),
+    /// A slash command typed into the chat, without the slash
+    Command(String),
+    /// Asks for a `PlayerList`
+    RequestPlayerList,
+
+    // Server to Client
+    AuthResult(AuthResult),
+    PlayerJoined(PlayerInfo),
+    PlayerLeft {
+        player_id: u64,
+    },
+    /// Everything a freshly authenticated client needs to know
+    WorldSnapshot {
+        players: Vec<PlayerInfo>,
+    },
+    Chat {
+        channel: ChatChannel,
+        from: String,
+        message: String,
+    },
+    /// The player was moved, by a command for example
+    Teleport(Position),
+    /// Everyone online, answer to a `RequestPlayerList`
+    PlayerList(Vec<PlayerListEntry>),
+    /// The server is about to close the connection
+    Kick {
+        reason: String,
+    },
+}
+
+impl Packet {
+    /// The channel this kind of packet is sent on
+    ///
+    /// Only state updates, which are outdated by the next one anyway, may
+    /// get lost.
+    pub fn channel(&self) -> Channel {
+        match *self {
+            Packet::Move(..) => Channel::UnreliableSequenced,
+            _ => Channel::ReliableOrdered,
+        }
+    }
+}
+
+/// Messages of the remote admin console
+///
+/// Spoken on a port of its own, framed like `Packet`s. A session starts
+/// with a `Login`, after that every `Command` is answered with exactly one
+/// `Output`.
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
+pub enum RconPacket {
+    Login {
+        password: String,
+    },
+    /// Answer to the `Login`, the server hangs up if it was refused
+    LoginResult {
+        accepted: bool,
+    },
+    /// A line as it would be typed into the server console
+    Command(String),
+    Output(String),
 }
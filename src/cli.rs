@@ -0,0 +1,166 @@
+use std::path::PathBuf;
+
+use getopts::Options;
+
+use server::{ServerConfig, ConfigError};
+
+/// Where to find the server and its data, shared by all subcommands
+#[derive(Debug, PartialEq, Default)]
+pub struct ServerOptions {
+    pub address: Option<String>,
+    pub config: Option<PathBuf>,
+    pub world: Option<PathBuf>,
+}
+
+impl ServerOptions {
+    /// Loads the configuration file, if any, and applies the overrides
+    pub fn to_config(&self) -> Result<ServerConfig, ConfigError> {
+        let mut config = match self.config {
+            Some(ref path) => try!(ServerConfig::load(path)),
+            None => ServerConfig::default()
+        };
+
+        if let Some(ref address) = self.address {
+            config.bind_address = address.clone();
+        }
+        if let Some(ref world) = self.world {
+            config.world_path = Some(world.clone());
+        }
+
+        try!(config.validate());
+        Ok(config)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    /// A dedicated server without any window
+    Server(ServerOptions),
+    /// Only the client, `address` prefills the server to connect to
+    Client {
+        address: Option<String>,
+    },
+    /// A server running inside the client process
+    Local(ServerOptions),
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("a", "address", "address to listen on, or to connect to", "ADDR");
+    opts.optopt("c", "config", "server configuration file", "FILE");
+    opts.optopt("w", "world", "directory the world is stored in", "PATH");
+    opts.optflag("h", "help", "print this help");
+    opts
+}
+
+pub fn usage() -> String {
+    let brief = if cfg!(feature = "client") {
+        "Usage: rpg [client|server|local] [options]"
+    } else {
+        "Usage: rpg [server] [options]\n\nThis build has no client."
+    };
+    options().usage(brief)
+}
+
+/// Parses the arguments, without the program name
+///
+/// Without a subcommand the client is started, or the server if this
+/// build has no client.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let (subcommand, rest) = match args.first() {
+        Some(first) if !first.starts_with("-") => (&first[..], &args[1..]),
+        _ => (if cfg!(feature = "client") { "client" } else { "server" }, args)
+    };
+
+    let matches = match options().parse(rest) {
+        Ok(m) => m,
+        Err(e) => return Err(format!("{}", e))
+    };
+
+    if matches.opt_present("h") || subcommand == "help" {
+        return Ok(Command::Help);
+    }
+
+    if !matches.free.is_empty() {
+        return Err(format!("Unexpected argument: {}", matches.free[0]));
+    }
+
+    let server = ServerOptions {
+        address: matches.opt_str("a"),
+        config: matches.opt_str("c").map(PathBuf::from),
+        world: matches.opt_str("w").map(PathBuf::from),
+    };
+
+    match subcommand {
+        "server" => Ok(Command::Server(server)),
+        "local" => Ok(Command::Local(server)),
+        "client" => {
+            if server.config.is_some() || server.world.is_some() {
+                return Err("The client takes no server configuration".to_string());
+            }
+            Ok(Command::Client { address: server.address })
+        }
+        other => Err(format!("Unknown subcommand: {}", other))
+    }
+}
+
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn server() {
+        let cmd = parse(&args(&["server", "--address", "0.0.0.0:1234", "-w", "world"]));
+        assert_eq!(cmd, Ok(Command::Server(ServerOptions {
+            address: Some("0.0.0.0:1234".to_string()),
+            config: None,
+            world: Some(PathBuf::from("world")),
+        })));
+    }
+
+    #[test]
+    fn local() {
+        let cmd = parse(&args(&["local", "-c", "server.toml"]));
+        assert_eq!(cmd, Ok(Command::Local(ServerOptions {
+            address: None,
+            config: Some(PathBuf::from("server.toml")),
+            world: None,
+        })));
+    }
+
+    #[test]
+    fn default_and_help() {
+        if cfg!(feature = "client") {
+            assert_eq!(parse(&args(&[])), Ok(Command::Client { address: None }));
+        } else {
+            assert_eq!(parse(&args(&[])), Ok(Command::Server(ServerOptions::default())));
+        }
+        assert_eq!(parse(&args(&["server", "--help"])), Ok(Command::Help));
+        assert_eq!(parse(&args(&["help"])), Ok(Command::Help));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse(&args(&["dance"])).is_err());
+        assert!(parse(&args(&["server", "extra"])).is_err());
+        assert!(parse(&args(&["client", "--world", "w"])).is_err());
+        assert!(parse(&args(&["server", "--bogus"])).is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let options = ServerOptions {
+            address: Some("127.0.0.1:4242".to_string()),
+            config: None,
+            world: Some(PathBuf::from("elsewhere")),
+        };
+        let config = options.to_config().unwrap();
+        assert_eq!(config.bind_address, "127.0.0.1:4242");
+        assert_eq!(config.world_path, Some(PathBuf::from("elsewhere")));
+    }
+}
@@ -2,14 +2,125 @@
 
 extern crate server;
 extern crate shared;
+#[cfg(feature = "client")]
 extern crate client;
+extern crate getopts;
 
+mod cli;
 mod tests;
 
-use client::Client;
+use std::env;
+use std::io;
+use std::process;
+use std::thread;
+
+use server::RpgServer;
+use server::admin;
+
+use cli::{Command, ServerOptions};
 
 fn main() {
-    let client = Client::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            println!("{}\n\n{}", e, cli::usage());
+            process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Help => println!("{}", cli::usage()),
+        Command::Server(options) => run_server(options),
+        Command::Client { address } => run_client(address),
+        Command::Local(options) => run_local(options),
+    }
+}
+
+fn start_server(options: &ServerOptions) -> RpgServer {
+    let config = match options.to_config() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut server = match RpgServer::with_config(config) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Could not create the server: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = server.start() {
+        println!("Could not start the network threads: {}", e);
+        process::exit(1);
+    }
+    if let Ok(addr) = server.local_addr() {
+        println!("Listening on {}", addr);
+    }
+    println!("Server identity: {}", server.identity().fingerprint());
+    server
+}
+
+fn run_server(options: ServerOptions) {
+    let mut server = start_server(&options);
+
+    // The console is left to itself, the server might as well be stopped
+    // remotely while it waits for input. Without a terminal stdin ends
+    // right away and the server keeps running.
+    if let Some(handle) = server.admin_handle() {
+        println!("Type 'help' for a list of commands");
+        thread::spawn(move|| {
+            let stdin = io::stdin();
+            let mut stdout = io::stdout();
+            admin::run_console(&handle, stdin.lock(), &mut stdout);
+        });
+    }
+
+    if let Err(e) = server.wait() {
+        println!("{}", e);
+    }
+}
+
+#[cfg(feature = "client")]
+fn run_client(address: Option<String>) {
+    let client = match address {
+        Some(address) => client::Client::with_address(address),
+        None => client::Client::new()
+    };
+
+    client.join();
+}
+
+#[cfg(feature = "client")]
+fn run_local(mut options: ServerOptions) {
+    // Nobody else needs to reach an embedded server
+    if options.address.is_none() {
+        options.address = Some("127.0.0.1:0".to_string());
+    }
 
+    let mut server = start_server(&options);
+    let address = server.local_addr().unwrap();
+
+    let client = client::Client::with_address(format!("{}", address));
     client.join();
+
+    if let Err(e) = server.stop() {
+        println!("{}", e);
+    }
+}
+
+#[cfg(not(feature = "client"))]
+fn run_client(_: Option<String>) {
+    println!("This build has no client, rebuild it with the client feature.");
+    process::exit(1);
+}
+
+#[cfg(not(feature = "client"))]
+fn run_local(_: ServerOptions) {
+    run_client(None);
 }
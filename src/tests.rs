@@ -1,16 +1,149 @@
-use server::{RpgServer, WorldState, ServerStatus, Player};
+use server::{RpgServer, WorldState, ServerStatus, Player, PlayerStatus, AccountStore, DuplicateLogin};
+use server::{ServerConfig, AdminCommand, admin};
+use server::admin::WhitelistCommand;
 
-use shared::net::send_packet;
-use shared::packets::Packet;
+use shared::net::{send_packet, receive_packet, send_frame_limited, receive_frame_limited};
+use shared::net::{send_packet_with, receive_packet_with, Fragmenter, PacketError};
+use shared::net::{MAX_FRAME_SIZE, MAX_PACKET_SIZE};
+use shared::net::sim::{Conditions, SimStream};
+use shared::net::secure::{Handshake, KnownServers, Pin, SessionKeys};
+use shared::packets::{Packet, AuthResult, RconPacket, ChatChannel, Position, PROTOCOL_VERSION};
 
+use std::io::{Read, Write};
+use std::iter;
 use std::thread;
-use std::net::{Shutdown, TcpStream};
+use std::net::{Shutdown, TcpStream, SocketAddr};
+
+/// A server that only keeps accounts in memory and hashes quickly
+fn test_server() -> RpgServer {
+    let mut server = RpgServer::new("127.0.0.1:0").unwrap();
+    let mut accounts = AccountStore::in_memory();
+    accounts.set_work_factor(4);
+    server.set_accounts(accounts);
+    server
+}
+
+fn hello(compression: bool) -> Packet {
+    Packet::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_build: "test".to_string(),
+        compression: compression,
+    }
+}
+
+/// A client past the handshake, every frame after it is sealed
+struct Client<S = TcpStream> {
+    stream: S,
+    keys: SessionKeys,
+    /// Payloads of at least this many bytes get compressed
+    compression: Option<usize>,
+    /// What the server proved it is
+    identity: Vec<u8>,
+}
+
+impl<S: Read + Write> Client<S> {
+    /// Says hello over `stream`, asking for compression if `compression`,
+    /// and exchanges keys
+    fn handshake(mut stream: S, compression: bool) -> Client<S> {
+        send_packet(&mut stream, &hello(compression)).unwrap();
+        let compression = match receive_packet(&mut stream).unwrap() {
+            Packet::Welcome { compression } => compression.map(|t| t as usize),
+            other => panic!("Server did not welcome us, got {:?}", other),
+        };
+        let handshake = Handshake::new().unwrap();
+        send_packet_with(&mut stream, &handshake.packet(), MAX_PACKET_SIZE, compression,
+                         None).unwrap();
+        loop {
+            match receive_packet_with(&mut stream, MAX_PACKET_SIZE, compression.is_some(),
+                                      None).unwrap() {
+                Packet::KeyExchangeReply { identity, key, signature } => {
+                    return Client {
+                        stream: stream,
+                        keys: handshake.finish(&identity, &key, &signature).unwrap(),
+                        compression: compression,
+                        identity: identity,
+                    };
+                }
+                // The server may have pinged before it got to our packet
+                Packet::Ping(..) => (),
+                other => panic!("Expected a KeyExchangeReply, got {:?}", other),
+            }
+        }
+    }
+
+    fn send(&mut self, packet: &Packet) -> Result<(), PacketError> {
+        send_packet_with(&mut self.stream, packet, MAX_PACKET_SIZE, self.compression,
+                         Some(&mut self.keys.send))
+    }
+
+    fn receive(&mut self) -> Result<Packet, PacketError> {
+        receive_packet_with(&mut self.stream, MAX_PACKET_SIZE, self.compression.is_some(),
+                            Some(&mut self.keys.receive))
+    }
+}
+
+impl Client {
+    fn shutdown(&self) {
+        self.stream.shutdown(Shutdown::Both).unwrap();
+    }
+}
+
+/// Connects and gets through the handshake
+fn connect(addr: SocketAddr) -> Client {
+    Client::handshake(TcpStream::connect(addr).unwrap(), false)
+}
+
+fn login_packet(name: &str) -> Packet {
+    Packet::AuthPlayer {
+        name: name.to_string(),
+        password: "hunter22".to_string(),
+    }
+}
+
+fn register_packet(name: &str) -> Packet {
+    Packet::Register {
+        name: name.to_string(),
+        password: "hunter22".to_string(),
+    }
+}
+
+fn chat_packet(channel: ChatChannel, message: &str) -> Packet {
+    Packet::SendChat {
+        channel: channel,
+        message: message.to_string(),
+    }
+}
+
+/// Registers a new account and waits until the player is in the game
+fn join_game(addr: SocketAddr, name: &str) -> Client {
+    let mut client = connect(addr);
+    client.send(&register_packet(name)).unwrap();
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+        _ => panic!("Could not join as {}", name),
+    }
+    match client.receive().unwrap() {
+        Packet::WorldSnapshot { .. } => (),
+        _ => panic!("Expected a WorldSnapshot"),
+    }
+    client
+}
+
+/// Skips everything up to the next chat message
+fn next_chat<S: Read + Write>(client: &mut Client<S>) -> (ChatChannel, String, String) {
+    loop {
+        match client.receive().unwrap() {
+            Packet::Chat { channel, from, message } => return (channel, from, message),
+            _ => ()
+        }
+    }
+}
 
 #[test]
 fn test_server_connection() {
-    let mut server = RpgServer::new("127.0.0.1:0").unwrap();
+    let mut server = test_server();
     let addr = server.local_addr().unwrap();
-    server.start();
+    server.start().unwrap();
 
     assert!(server.status() == ServerStatus::Running{
         world_running: true,
@@ -26,7 +159,7 @@ fn test_server_connection() {
         assert!(players.len() == 0);
     }
 
-    let mut client = TcpStream::connect(addr).unwrap();
+    let stream = TcpStream::connect(addr).unwrap();
 
     thread::sleep_ms(100); // This is a big ugly, but no choice really
                            // It does give a QA test, it shouldn't take
@@ -37,12 +170,28 @@ fn test_server_connection() {
         let state = arc_state.read().unwrap();
         let players = state.get_players();
         assert!(players.len() == 1);
+        for (_, ply) in players.iter() {
+            assert_eq!(ply.get_status(), PlayerStatus::Connecting);
+        }
     }
 
 
-    send_packet(&mut client, &Packet::AuthPlayer("Neikos".to_string()));
+    let mut client = Client::handshake(stream, false);
 
-    thread::sleep_ms(100);
+    client.send(&register_packet("Neikos")).unwrap();
+
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+        _ => panic!("Server did not accept us"),
+    }
+
+    match client.receive().unwrap() {
+        Packet::WorldSnapshot { players } => {
+            assert!(players.len() == 1);
+            assert!(players[0].name == "Neikos");
+        }
+        _ => panic!("Server did not send a snapshot"),
+    }
 
 
     {
@@ -56,10 +205,48 @@ fn test_server_connection() {
             if let &Some(ref name) = ply.get_name() {
                 assert!(name == "Neikos");
             }
+            assert_eq!(ply.get_status(), PlayerStatus::Authenticated);
         }
     }
 
-    client.shutdown(Shutdown::Both);
+    client.shutdown();
+
+    thread::sleep_ms(100);
+
+    {
+        let arc_state = server.get_state();
+        let state = arc_state.read().unwrap();
+        let players = state.get_players();
+        assert!(players.len() == 0);
+    }
+}
+
+#[test]
+fn test_protocol_mismatch() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut client = TcpStream::connect(addr).unwrap();
+
+    send_packet(&mut client, &Packet::Hello {
+        protocol_version: PROTOCOL_VERSION + 1,
+        client_build: "test".to_string(),
+        compression: false,
+    }).unwrap();
+
+    match receive_packet(&mut client).unwrap() {
+        Packet::Rejected { reason } => assert!(reason.contains("version")),
+        _ => panic!("Server did not reject us"),
+    }
+
+    // A `Hello` from before it said whether the client compresses
+    let mut old = TcpStream::connect(addr).unwrap();
+    send_frame_limited(&mut old, &(0u32, 10u32, "old".to_string()), MAX_FRAME_SIZE).unwrap();
+    match receive_packet(&mut old).unwrap() {
+        Packet::Rejected { reason } => assert!(reason.contains("too old")),
+        _ => panic!("Server did not reject the old client"),
+    }
 
     thread::sleep_ms(100);
 
@@ -70,3 +257,987 @@ fn test_server_connection() {
         assert!(players.len() == 0);
     }
 }
+
+#[test]
+fn test_auth_before_hello() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut client = TcpStream::connect(addr).unwrap();
+
+    send_packet(&mut client, &register_packet("Neikos")).unwrap();
+
+    match receive_packet(&mut client).unwrap() {
+        Packet::Rejected { .. } => (),
+        _ => panic!("Server did not reject us"),
+    }
+}
+
+#[test]
+fn test_join_and_chat() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let join = |name: &str| {
+        let mut client = connect(addr);
+        client.send(&register_packet(name)).unwrap();
+        // AuthResult and WorldSnapshot
+        client.receive().unwrap();
+        client.receive().unwrap();
+        client
+    };
+
+    let mut first = join("Neikos");
+    let mut second = join("Other");
+
+    match first.receive().unwrap() {
+        Packet::PlayerJoined(info) => assert!(info.name == "Other"),
+        _ => panic!("Expected PlayerJoined"),
+    }
+
+    second.send(&chat_packet(ChatChannel::Global, "Hi!")).unwrap();
+
+    for client in vec![&mut first, &mut second] {
+        match client.receive().unwrap() {
+            Packet::Chat { from, message, .. } => {
+                assert!(from == "Other");
+                assert!(message == "Hi!");
+            }
+            _ => panic!("Expected Chat"),
+        }
+    }
+
+    second.shutdown();
+
+    match first.receive().unwrap() {
+        Packet::PlayerLeft { .. } => (),
+        _ => panic!("Expected PlayerLeft"),
+    }
+}
+
+#[test]
+fn test_connect_timeout() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.set_connect_timeout(200);
+    server.start().unwrap();
+
+    let mut client = TcpStream::connect(addr).unwrap();
+
+    // We never say hello
+    match receive_packet(&mut client).unwrap() {
+        Packet::Kick { .. } => (),
+        _ => panic!("Expected a Kick"),
+    }
+
+    thread::sleep_ms(100);
+
+    {
+        let arc_state = server.get_state();
+        let state = arc_state.read().unwrap();
+        let players = state.get_players();
+        assert!(players.len() == 0);
+    }
+}
+
+#[test]
+fn test_chat_before_auth() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut client = connect(addr);
+
+    client.send(&chat_packet(ChatChannel::Global, "Hi!")).unwrap();
+
+    match client.receive().unwrap() {
+        Packet::Kick { .. } => (),
+        _ => panic!("Expected a Kick"),
+    }
+}
+
+#[test]
+fn test_login() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    {
+        let mut client = connect(addr);
+        client.send(&register_packet("Neikos")).unwrap();
+        match client.receive().unwrap() {
+            Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+            _ => panic!("Could not register"),
+        }
+        client.shutdown();
+    }
+
+    let mut client = connect(addr);
+
+    // Registering twice does not work
+    client.send(&register_packet("Neikos")).unwrap();
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Refused { .. }) => (),
+        _ => panic!("Registered twice"),
+    }
+
+    client.send(&Packet::AuthPlayer {
+        name: "Neikos".to_string(),
+        password: "hunter23".to_string(),
+    }).unwrap();
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Refused { reason }) => {
+            assert!(reason.contains("password"));
+        }
+        _ => panic!("Logged in with the wrong password"),
+    }
+
+    client.send(&Packet::AuthPlayer {
+        name: "Neikos".to_string(),
+        password: "hunter22".to_string(),
+    }).unwrap();
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+        _ => panic!("Could not log in"),
+    }
+
+    // Logins of players in the game aren't even checked
+    client.send(&Packet::AuthPlayer {
+        name: "Neikos".to_string(),
+        password: "hunter22".to_string(),
+    }).unwrap();
+    client.send(&Packet::RequestPlayerList).unwrap();
+    loop {
+        match client.receive().unwrap() {
+            Packet::PlayerList(..) => break,
+            Packet::AuthResult(..) => panic!("Logged in twice"),
+            _ => ()
+        }
+    }
+}
+
+fn duplicate_login(policy: DuplicateLogin) -> (Client, Client) {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.set_duplicate_login(policy);
+    server.start().unwrap();
+
+    let mut first = connect(addr);
+    first.send(&register_packet("Neikos")).unwrap();
+    // AuthResult and WorldSnapshot
+    first.receive().unwrap();
+    first.receive().unwrap();
+
+    let mut second = connect(addr);
+    second.send(&login_packet("neikos")).unwrap();
+
+    (first, second)
+}
+
+#[test]
+fn test_duplicate_login_kicks_old() {
+    let (mut first, mut second) = duplicate_login(DuplicateLogin::KickOld);
+
+    match first.receive().unwrap() {
+        Packet::Kick { .. } => (),
+        _ => panic!("Old session was not kicked"),
+    }
+
+    match second.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+        _ => panic!("New session was not accepted"),
+    }
+
+    match second.receive().unwrap() {
+        Packet::WorldSnapshot { players } => {
+            assert!(players.len() == 1);
+            assert!(players[0].name == "Neikos");
+        }
+        _ => panic!("Server did not send a snapshot"),
+    }
+}
+
+#[test]
+fn test_duplicate_login_refuses_new() {
+    let (_first, mut second) = duplicate_login(DuplicateLogin::RefuseNew);
+
+    match second.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Refused { .. }) => (),
+        _ => panic!("New session was not refused"),
+    }
+}
+
+#[test]
+fn test_invalid_name() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut client = connect(addr);
+    client.send(&register_packet("Nei kos")).unwrap();
+
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Refused { .. }) => (),
+        _ => panic!("Invalid name was accepted"),
+    }
+}
+
+#[test]
+fn test_graceful_stop() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut client = connect(addr);
+    client.send(&register_packet("Neikos")).unwrap();
+    // AuthResult and WorldSnapshot
+    client.receive().unwrap();
+    client.receive().unwrap();
+
+    // One that never finishes the handshake
+    let mut lurker = TcpStream::connect(addr).unwrap();
+    thread::sleep_ms(100);
+
+    server.stop().unwrap();
+    assert_eq!(server.status(), ServerStatus::Stopped);
+
+    match client.receive().unwrap() {
+        Packet::Kick { .. } => (),
+        _ => panic!("Expected a Kick"),
+    }
+    // Still in plaintext
+    match receive_packet(&mut lurker).unwrap() {
+        Packet::Kick { .. } => (),
+        _ => panic!("Expected a Kick"),
+    }
+
+    {
+        let arc_state = server.get_state();
+        let state = arc_state.read().unwrap();
+        assert!(state.get_players().len() == 0);
+    }
+}
+
+#[test]
+fn test_with_config() {
+    let config = ServerConfig::parse(r#"
+        bind_address = "127.0.0.1:0"
+        max_players = 1
+        motd = "Be nice"
+    "#).unwrap();
+    let mut server = RpgServer::with_config(config).unwrap();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut client = connect(addr);
+    client.send(&register_packet("Neikos")).unwrap();
+    // AuthResult and WorldSnapshot
+    client.receive().unwrap();
+    client.receive().unwrap();
+
+    match client.receive().unwrap() {
+        Packet::Chat { message, .. } => assert!(message == "Be nice"),
+        _ => panic!("Expected the MOTD"),
+    }
+
+    let mut second = TcpStream::connect(addr).unwrap();
+    match receive_packet(&mut second).unwrap() {
+        Packet::Rejected { reason } => assert!(reason.contains("full")),
+        _ => panic!("Expected to be rejected"),
+    }
+}
+
+#[test]
+fn test_admin_console() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut client = connect(addr);
+    client.send(&register_packet("Neikos")).unwrap();
+    // AuthResult and WorldSnapshot
+    client.receive().unwrap();
+    client.receive().unwrap();
+
+    let input = b"list\nsay hello\nban neikos spamming\nbogus\nstop\nlist\n";
+    let mut output = Vec::new();
+    admin::run_console(&server.admin_handle().unwrap(), &input[..], &mut output);
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains("1 connected"));
+    assert!(output.contains("Neikos"));
+    assert!(output.contains("Banned neikos"));
+    assert!(output.contains("Unknown command 'bogus'"));
+    // Nothing gets read after stop
+    assert!(output.ends_with("Stopping the server\n"));
+
+    match client.receive().unwrap() {
+        Packet::Chat { channel: ChatChannel::Server, ref message, .. } if message == "hello" => (),
+        _ => panic!("Expected the chat from the console"),
+    }
+    match client.receive().unwrap() {
+        Packet::Kick { ref reason } if reason.contains("spamming") => (),
+        _ => panic!("Expected a Kick"),
+    }
+    assert!(server.get_state().read().unwrap().get_moderation().name_ban("NEIKOS").is_some());
+
+    server.wait().unwrap();
+    assert_eq!(server.status(), ServerStatus::Stopped);
+}
+
+fn rcon_exchange(stream: &mut TcpStream, packet: RconPacket) -> RconPacket {
+    send_frame_limited(stream, &packet, MAX_FRAME_SIZE).unwrap();
+    receive_frame_limited(stream, MAX_FRAME_SIZE).unwrap()
+}
+
+#[test]
+fn test_rcon() {
+    let mut config = ServerConfig::default();
+    config.bind_address = "127.0.0.1:0".to_string();
+    config.rcon_address = Some("127.0.0.1:0".to_string());
+    config.rcon_password = "correct horse".to_string();
+    let mut server = RpgServer::with_config(config).unwrap();
+    let rcon_addr = server.rcon_addr().unwrap();
+    server.start().unwrap();
+
+    let mut intruder = TcpStream::connect(rcon_addr).unwrap();
+    let result = rcon_exchange(&mut intruder, RconPacket::Login {
+        password: "battery staple".to_string()
+    });
+    assert_eq!(result, RconPacket::LoginResult { accepted: false });
+    // Refused sessions get hung up on
+    assert!(receive_frame_limited::<_, RconPacket>(&mut intruder, MAX_FRAME_SIZE).is_err());
+
+    // Nothing this large is a password, so it is not even looked at
+    let mut flood = TcpStream::connect(rcon_addr).unwrap();
+    send_frame_limited(&mut flood, &RconPacket::Login {
+        password: iter::repeat('x').take(4096).collect()
+    }, MAX_FRAME_SIZE).unwrap();
+    assert!(receive_frame_limited::<_, RconPacket>(&mut flood, MAX_FRAME_SIZE).is_err());
+
+    let mut admin = TcpStream::connect(rcon_addr).unwrap();
+    let result = rcon_exchange(&mut admin, RconPacket::Login {
+        password: "correct horse".to_string()
+    });
+    assert_eq!(result, RconPacket::LoginResult { accepted: true });
+
+    match rcon_exchange(&mut admin, RconPacket::Command("list".to_string())) {
+        RconPacket::Output(ref output) if output == "0 connected" => (),
+        other => panic!("Expected the player list, got {:?}", other),
+    }
+    match rcon_exchange(&mut admin, RconPacket::Command("dance".to_string())) {
+        RconPacket::Output(ref output) if output.contains("Unknown command") => (),
+        other => panic!("Expected an error, got {:?}", other),
+    }
+    match rcon_exchange(&mut admin, RconPacket::Command("stop".to_string())) {
+        RconPacket::Output(ref output) if output == "Stopping the server" => (),
+        other => panic!("Expected the server to stop, got {:?}", other),
+    }
+
+    server.wait().unwrap();
+    assert_eq!(server.status(), ServerStatus::Stopped);
+}
+
+#[test]
+fn test_bans_and_whitelist() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+    let handle = server.admin_handle().unwrap();
+
+    handle.execute(AdminCommand::Whitelist(WhitelistCommand::On)).unwrap();
+    let mut client = connect(addr);
+    client.send(&register_packet("Neikos")).unwrap();
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Refused { ref reason }) if reason.contains("whitelist") => (),
+        _ => panic!("Expected to be refused"),
+    }
+
+    handle.execute(AdminCommand::Whitelist(WhitelistCommand::Add("neikos".to_string()))).unwrap();
+    client.send(&login_packet("Neikos")).unwrap();
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+        _ => panic!("Expected to be accepted"),
+    }
+    client.receive().unwrap();
+
+    // Banning the address of a player catches everyone connected from it
+    let output = handle.execute(admin::parse_command("tempban-ip Neikos 1h cheating").unwrap());
+    assert!(output.unwrap().contains("kicked 1"));
+    match client.receive().unwrap() {
+        Packet::Kick { ref reason } if reason.contains("cheating") => (),
+        _ => panic!("Expected a Kick"),
+    }
+
+    let mut banned = TcpStream::connect(addr).unwrap();
+    match receive_packet(&mut banned).unwrap() {
+        Packet::Rejected { ref reason } if reason.contains("1h left") => (),
+        _ => panic!("Expected to be rejected"),
+    }
+
+    let output = handle.execute(AdminCommand::Unban("127.0.0.1".to_string())).unwrap();
+    assert_eq!(output, "Unbanned 127.0.0.1");
+    let mut client = connect(addr);
+    client.send(&login_packet("Neikos")).unwrap();
+    match client.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+        _ => panic!("Expected to be accepted"),
+    }
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_chat_channels() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut alice = join_game(addr, "Alice");
+    let mut bob = join_game(addr, "Bob");
+    let mut carol = join_game(addr, "Carol");
+
+    carol.send(&Packet::Move(Position::new(1000.0, 0.0, 0.0))).unwrap();
+    thread::sleep_ms(100);
+
+    // Carol is too far away to hear this
+    alice.send(&chat_packet(ChatChannel::Local, "near")).unwrap();
+    for client in vec![&mut alice, &mut bob] {
+        let (channel, from, message) = next_chat(client);
+        assert_eq!(channel, ChatChannel::Local);
+        assert_eq!(from, "Alice");
+        assert_eq!(message, "near");
+    }
+
+    for client in vec![&mut alice, &mut carol] {
+        client.send(&Packet::SetParty(Some("Crew".to_string()))).unwrap();
+        let (channel, _, message) = next_chat(client);
+        assert_eq!(channel, ChatChannel::Server);
+        assert!(message.contains("Crew"));
+    }
+
+    alice.send(&chat_packet(ChatChannel::Party, "plan")).unwrap();
+    for client in vec![&mut alice, &mut carol] {
+        let (channel, _, message) = next_chat(client);
+        assert_eq!(channel, ChatChannel::Party);
+        assert_eq!(message, "plan");
+    }
+
+    bob.send(&chat_packet(ChatChannel::Whisper("alice".to_string()), "psst")).unwrap();
+    for client in vec![&mut alice, &mut bob] {
+        let (channel, from, message) = next_chat(client);
+        assert_eq!(channel, ChatChannel::Whisper("Alice".to_string()));
+        assert_eq!(from, "Bob");
+        assert_eq!(message, "psst");
+    }
+
+    let long = (0..300).map(|_| 'a').collect::<String>();
+    bob.send(&chat_packet(ChatChannel::Global, &long)).unwrap();
+    let (channel, _, message) = next_chat(&mut bob);
+    assert_eq!(channel, ChatChannel::Server);
+    assert!(message.contains("too long"));
+
+    // Every message gets either echoed or refused
+    for _ in 0..10 {
+        bob.send(&chat_packet(ChatChannel::Global, "spam")).unwrap();
+    }
+    let refused = (0..10).filter(|_| {
+        next_chat(&mut bob).0 == ChatChannel::Server
+    }).count();
+    assert!(refused > 0);
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_slash_commands() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut alice = join_game(addr, "Alice");
+    let mut bob = join_game(addr, "Bob");
+
+    alice.send(&Packet::Command("who".to_string())).unwrap();
+    let (channel, _, message) = next_chat(&mut alice);
+    assert_eq!(channel, ChatChannel::Server);
+    assert_eq!(message, "2 in the game: Alice, Bob");
+
+    alice.send(&Packet::Command("me waves".to_string())).unwrap();
+    let (channel, from, message) = next_chat(&mut bob);
+    assert_eq!(channel, ChatChannel::Emote);
+    assert_eq!(from, "Alice");
+    assert_eq!(message, "waves");
+
+    alice.send(&Packet::Command("tp 0 0 0".to_string())).unwrap();
+    let (_, _, message) = next_chat(&mut alice);
+    assert_eq!(message, "You are not allowed to do that.");
+
+    // The console may, the player learns where they ended up
+    let handle = server.admin_handle().unwrap();
+    let output = handle.execute(AdminCommand::Slash("tp 1 2 3 bob".to_string())).unwrap();
+    assert_eq!(output, "Teleported Bob to 1, 2, 3");
+    loop {
+        match bob.receive().unwrap() {
+            Packet::Teleport(position) => {
+                assert_eq!(position, Position::new(1.0, 2.0, 3.0));
+                break;
+            }
+            _ => ()
+        }
+    }
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_roles() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+    let handle = server.admin_handle().unwrap();
+
+    let mut alice = join_game(addr, "Alice");
+    let mut bob = join_game(addr, "Bob");
+
+    alice.send(&Packet::Command("kick Bob".to_string())).unwrap();
+    let (_, _, message) = next_chat(&mut alice);
+    assert_eq!(message, "You are not allowed to do that.");
+
+    // Takes effect without logging in again
+    let output = handle.execute(AdminCommand::SetRole {
+        name: "alice".to_string(),
+        role: "moderator".to_string(),
+    }).unwrap();
+    assert_eq!(output, "alice is a moderator now");
+
+    alice.send(&Packet::Command("kick Bob behave".to_string())).unwrap();
+    let (_, _, message) = next_chat(&mut alice);
+    assert_eq!(message, "Kicked Bob");
+    loop {
+        match bob.receive().unwrap() {
+            Packet::Kick { ref reason } if reason == "behave" => break,
+            _ => ()
+        }
+    }
+
+    let output = handle.execute(AdminCommand::SetRole {
+        name: "alice".to_string(),
+        role: "king".to_string(),
+    }).unwrap();
+    assert!(output.starts_with("Unknown role king"));
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_idle_timeout() {
+    let mut config = ServerConfig::default();
+    config.bind_address = "127.0.0.1:0".to_string();
+    config.ping_interval_ms = 50;
+    config.idle_timeout_ms = 500;
+    let mut server = RpgServer::with_config(config).unwrap();
+    let mut accounts = AccountStore::in_memory();
+    accounts.set_work_factor(4);
+    server.set_accounts(accounts);
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut alice = join_game(addr, "Alice");
+    // Bob never answers, like a client whose connection died silently
+    let _bob = join_game(addr, "Bob");
+
+    let mut pings = 0;
+    loop {
+        match alice.receive().unwrap() {
+            Packet::Ping(value) => {
+                pings += 1;
+                alice.send(&Packet::Pong(value)).unwrap();
+            }
+            Packet::PlayerLeft { .. } => break,
+            _ => ()
+        }
+    }
+    assert!(pings > 1);
+    assert_eq!(next_chat(&mut alice).2, "Bob timed out");
+
+    {
+        let state = server.get_state();
+        let state = state.read().unwrap();
+        assert_eq!(state.get_players().len(), 1);
+        assert!(state.find_player("Alice").is_some());
+    }
+
+    alice.send(&Packet::Ping(42)).unwrap();
+    loop {
+        match alice.receive().unwrap() {
+            Packet::Pong(value) => {
+                assert_eq!(value, 42);
+                break;
+            }
+            _ => ()
+        }
+    }
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_net_stats() {
+    let mut config = ServerConfig::default();
+    config.bind_address = "127.0.0.1:0".to_string();
+    config.ping_interval_ms = 50;
+    let mut server = RpgServer::with_config(config).unwrap();
+    let mut accounts = AccountStore::in_memory();
+    accounts.set_work_factor(4);
+    server.set_accounts(accounts);
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut alice = join_game(addr, "Alice");
+    // Bob never answers a ping
+    let _bob = join_game(addr, "Bob");
+
+    let mut pongs = 0;
+    let mut list = None;
+    while list.is_none() {
+        match alice.receive().unwrap() {
+            Packet::Ping(value) if pongs < 2 => {
+                alice.send(&Packet::Pong(value)).unwrap();
+                pongs += 1;
+                if pongs == 2 {
+                    alice.send(&Packet::RequestPlayerList).unwrap();
+                }
+            }
+            Packet::PlayerList(entries) => list = Some(entries),
+            _ => ()
+        }
+    }
+    let list = list.unwrap();
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].name, "Alice");
+    assert!(list[0].ping_ms.is_some());
+    assert_eq!(list[1].name, "Bob");
+    assert_eq!(list[1].ping_ms, None);
+
+    {
+        let state = server.get_state();
+        let state = state.read().unwrap();
+        let id = state.find_player("Alice").unwrap();
+        let report = state.net_report(id, 0).unwrap();
+        assert!(report.latency.is_some());
+        assert_eq!(report.decode_errors, 0);
+        // Register, two pongs and the request, the handshake isn't counted
+        assert_eq!(report.packets_received, 4);
+        assert!(report.bytes_received > 0);
+        assert!(report.packets_sent >= 4);
+        assert!(report.bytes_sent > 0);
+    }
+
+    // A frame that does not decode gets the player dropped
+    let mut frame = alice.keys.send.seal(&[0xFF, 0xFF, 0xFF, 0xFF]);
+    let size = frame.len();
+    frame.insert(0, (size >> 8) as u8);
+    frame.insert(0, size as u8);
+    alice.stream.write_all(&frame).unwrap();
+    loop {
+        match alice.receive() {
+            Ok(_) => (),
+            Err(_) => break
+        }
+    }
+
+    server.stop().unwrap();
+}
+
+/// Lets the test process open as many files as it is allowed to
+#[cfg(target_os = "linux")]
+fn raise_file_limit() {
+    #[repr(C)]
+    struct RLimit {
+        current: usize,
+        max: usize,
+    }
+    extern {
+        fn getrlimit(resource: i32, limit: *mut RLimit) -> i32;
+        fn setrlimit(resource: i32, limit: *const RLimit) -> i32;
+    }
+    const RLIMIT_NOFILE: i32 = 7;
+
+    let mut limit = RLimit { current: 0, max: 0 };
+    unsafe {
+        if getrlimit(RLIMIT_NOFILE, &mut limit) == 0 {
+            limit.current = limit.max;
+            setrlimit(RLIMIT_NOFILE, &limit);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn raise_file_limit() {}
+
+#[test]
+fn test_thousand_connections() {
+    const CONNECTIONS: usize = 1000;
+    raise_file_limit();
+
+    let mut config = ServerConfig::default();
+    config.bind_address = "127.0.0.1:0".to_string();
+    config.max_players = CONNECTIONS;
+    config.connect_timeout_ms = 60000;
+    let mut server = RpgServer::with_config(config).unwrap();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    // Everyone connects first, so that all of them are waiting at once
+    let streams: Vec<TcpStream> = (0..CONNECTIONS).map(|_| {
+        TcpStream::connect(addr).unwrap()
+    }).collect();
+    let mut clients: Vec<Client> = streams.into_iter().map(|stream| {
+        Client::handshake(stream, false)
+    }).collect();
+
+    for (idx, client) in clients.iter_mut().enumerate() {
+        client.send(&Packet::Ping(idx as u64)).unwrap();
+    }
+    for (idx, client) in clients.iter_mut().enumerate() {
+        assert_eq!(client.receive().unwrap(), Packet::Pong(idx as u64));
+    }
+
+    {
+        let state = server.get_state();
+        let state = state.read().unwrap();
+        assert_eq!(state.get_players().len(), CONNECTIONS);
+    }
+
+    server.stop().unwrap();
+    for client in clients.iter_mut() {
+        match client.receive().unwrap() {
+            Packet::Kick { .. } => (),
+            other => panic!("Expected a Kick, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_bad_connection() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    let mut bob = join_game(addr, "Bob");
+
+    // Alice is far away, on a slow line that cuts every frame into pieces
+    let conditions = Conditions {
+        latency_ms: 30,
+        jitter_ms: 20,
+        bandwidth: Some(16 * 1024),
+        max_fragment: Some(3),
+        ..Conditions::perfect()
+    };
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut alice = Client::handshake(SimStream::tcp(stream, conditions, 1).unwrap(), false);
+    alice.send(&register_packet("Alice")).unwrap();
+    match alice.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+        other => panic!("Expected to be accepted, got {:?}", other),
+    }
+
+    // No more than the chat burst, or the server would start refusing
+    for n in 0..5 {
+        let message = format!("Can you hear me? {}", n);
+        alice.send(&chat_packet(ChatChannel::Global, &message)).unwrap();
+        assert_eq!(next_chat(&mut bob), (ChatChannel::Global, "Alice".to_string(), message));
+    }
+    bob.send(&chat_packet(ChatChannel::Global, "Loud and clear")).unwrap();
+    loop {
+        let (_, from, message) = next_chat(&mut alice);
+        if from == "Bob" {
+            assert_eq!(message, "Loud and clear");
+            break;
+        }
+    }
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_fragments() {
+    let mut config = ServerConfig::default();
+    config.bind_address = "127.0.0.1:0".to_string();
+    config.max_message_size = 2048;
+    config.reassembly_memory = 4096;
+    let mut server = RpgServer::with_config(config).unwrap();
+    let mut accounts = AccountStore::in_memory();
+    accounts.set_work_factor(4);
+    server.set_accounts(accounts);
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    // Nobody gets to send fragments before logging in
+    let mut eve = connect(addr);
+    eve.send(&Packet::Fragment {
+        message_id: 0,
+        index: 0,
+        count: 2,
+        data: vec![0; 100],
+    }).unwrap();
+    assert!(eve.receive().is_err());
+
+    let mut alice = join_game(addr, "Alice");
+    let mut bob = join_game(addr, "Bob");
+
+    // Tiny frames, so that a chat line takes several of them
+    let fragmenter = Fragmenter::new(64, 2048);
+    let message: String = (0..200).map(|_| 'a').collect();
+    fragmenter.send(&mut alice.stream, &chat_packet(ChatChannel::Global, &message), None,
+                    Some(&mut alice.keys.send)).unwrap();
+    assert_eq!(next_chat(&mut bob), (ChatChannel::Global, "Alice".to_string(), message));
+
+    // Fragments that are never finished may only take up so much memory
+    for message_id in 0..8 {
+        let fragment = Packet::Fragment {
+            message_id: message_id,
+            index: 0,
+            count: 2,
+            data: vec![0; 1000],
+        };
+        if bob.send(&fragment).is_err() {
+            break;
+        }
+    }
+    loop {
+        match bob.receive() {
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_compression() {
+    let mut config = ServerConfig::default();
+    config.bind_address = "127.0.0.1:0".to_string();
+    config.compression_threshold = Some(64);
+    let mut server = RpgServer::with_config(config).unwrap();
+    let mut accounts = AccountStore::in_memory();
+    accounts.set_work_factor(4);
+    server.set_accounts(accounts);
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    // Only if the client wants it too
+    let mut bob = TcpStream::connect(addr).unwrap();
+    send_packet(&mut bob, &hello(false)).unwrap();
+    assert_eq!(receive_packet(&mut bob).unwrap(), Packet::Welcome { compression: None });
+
+    let mut alice = Client::handshake(TcpStream::connect(addr).unwrap(), true);
+    assert_eq!(alice.compression, Some(64));
+    alice.send(&register_packet("Alice")).unwrap();
+    match alice.receive().unwrap() {
+        Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+        other => panic!("Expected to be accepted, got {:?}", other),
+    }
+
+    let message: String = (0..200).map(|_| 'a').collect();
+    alice.send(&chat_packet(ChatChannel::Global, &message)).unwrap();
+    loop {
+        match alice.receive().unwrap() {
+            Packet::Chat { message: ref m, .. } if *m == message => break,
+            _ => ()
+        }
+    }
+
+    // The totals are stored right after the frames went out, give them a moment
+    let mut compressed = false;
+    for _ in 0..100 {
+        let report = {
+            let state = server.get_state();
+            let state = state.read().unwrap();
+            let id = state.find_player("Alice").unwrap();
+            state.net_report(id, 0).unwrap()
+        };
+        let below = |ratio: Option<f64>| ratio.map_or(false, |r| r < 1.0);
+        if below(report.compression_received.ratio()) && below(report.compression_sent.ratio()) {
+            compressed = true;
+            break;
+        }
+        thread::sleep_ms(10);
+    }
+    assert!(compressed);
+
+    server.stop().unwrap();
+}
+
+/// Connects, and checks the identity of the server against `known` under
+/// `server`
+fn connect_pinned(addr: SocketAddr, known: &mut KnownServers, server: &str) -> (Client, Pin) {
+    let client = connect(addr);
+    let pin = known.check(server, &client.identity).unwrap();
+    (client, pin)
+}
+
+#[test]
+fn test_encryption() {
+    let mut server = test_server();
+    let addr = server.local_addr().unwrap();
+    server.start().unwrap();
+
+    // Plaintext is refused
+    let mut plain = TcpStream::connect(addr).unwrap();
+    send_packet(&mut plain, &hello(false)).unwrap();
+    assert_eq!(receive_packet(&mut plain).unwrap(), Packet::Welcome { compression: None });
+    send_packet(&mut plain, &register_packet("Mallory")).unwrap();
+    match receive_packet(&mut plain).unwrap() {
+        Packet::Rejected { .. } => (),
+        other => panic!("Expected to be rejected, got {:?}", other),
+    }
+
+    let mut known = KnownServers::in_memory();
+    let mut clients = Vec::new();
+    for (n, name) in ["Alice", "Bob"].iter().enumerate() {
+        let (mut client, pin) = connect_pinned(addr, &mut known, "test server");
+        assert_eq!(pin, if n == 0 { Pin::New } else { Pin::Known });
+        client.send(&register_packet(name)).unwrap();
+        match client.receive().unwrap() {
+            Packet::AuthResult(AuthResult::Accepted { .. }) => (),
+            other => panic!("Expected to be accepted, got {:?}", other),
+        }
+        clients.push(client);
+    }
+
+    clients[0].send(&chat_packet(ChatChannel::Global, "Only for you")).unwrap();
+    let (_, from, message) = next_chat(&mut clients[1]);
+    assert_eq!((from, message), ("Alice".to_string(), "Only for you".to_string()));
+
+    // A frame that was tampered with ends the connection
+    {
+        let alice = &mut clients[0];
+        let mut frame = Vec::new();
+        send_packet_with(&mut frame, &chat_packet(ChatChannel::Global, "Changed"),
+                         MAX_PACKET_SIZE, None, Some(&mut alice.keys.send)).unwrap();
+        let last = frame.len() - 1;
+        frame[last] ^= 0x80;
+        alice.stream.write_all(&frame).unwrap();
+        loop {
+            match alice.receive() {
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+    }
+
+    // Another server under the same name is noticed
+    let mut impostor = test_server();
+    let impostor_addr = impostor.local_addr().unwrap();
+    impostor.start().unwrap();
+    let (_, pin) = connect_pinned(impostor_addr, &mut known, "test server");
+    assert_eq!(pin, Pin::Changed(server.identity().public_key().to_vec()));
+
+    impostor.stop().unwrap();
+    server.stop().unwrap();
+}